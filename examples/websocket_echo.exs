@@ -18,14 +18,14 @@ defmodule WebSocketEcho do
   alias Sparx.Native
 
   def handle_request(request) do
-    IO.puts("Received request: #{request.method} #{request.path}")
+    IO.puts("Received request: #{inspect(request.method)} #{request.path}")
 
     # Check if this is a WebSocket upgrade request
     if is_websocket_upgrade?(request) do
       IO.puts("Upgrading to WebSocket...")
 
-      case Native.upgrade_websocket(request.handle) do
-        {:ok, ws_handle} ->
+      case Sparx.WebSocket.upgrade(request.handle) do
+        {:ok, {ws_handle, _protocol}} ->
           IO.puts("WebSocket connection established!")
           handle_websocket(ws_handle)
 
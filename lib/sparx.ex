@@ -31,6 +31,15 @@ defmodule Sparx do
   4. Request/response bodies stream with backpressure
 
   This architecture provides excellent performance while maintaining BEAM's fault tolerance.
+
+  ## Hot code upgrades
+
+  Servers keep running across a hot upgrade of the application. When
+  `Sparx.Native` is reloaded, the new version takes over the server, request,
+  and WebSocket handles of the old one, and the native library stays loaded
+  after the old version is purged, so listeners and in-flight requests carry
+  on. Remote handler captures such as `&MyHandler.handle_request/1` pick up
+  the new code with the next request.
   """
 
   use GenServer
@@ -38,6 +47,11 @@ defmodule Sparx do
 
   @type server_ref :: GenServer.server()
   @type handler :: (reference() -> :ok)
+  @type histogram :: %{
+          count: non_neg_integer(),
+          sum_us: non_neg_integer(),
+          counts: [non_neg_integer()]
+        }
 
   ## Client API
 
@@ -46,13 +60,128 @@ defmodule Sparx do
 
   ## Options
 
-    * `:handler` - Function that handles requests (required)
-    * `:port` - Port to listen on (default: 7779)
-    * `:host` - Host to bind to (default: "127.0.0.1")
+    * `:handler` - Function that handles requests; without one, no requests are
+      handled until your own processes take them with `receive_request/2`
+    * `:port` - Port to listen on; `0` picks a free ephemeral port, reported by
+      `info/1` (default: 7779)
+    * `:host` - Host to bind to, IPv4 or IPv6, e.g. `"0.0.0.0"` or `"::"`
+      (default: "127.0.0.1")
+    * `:ipv6_only` - With an IPv6 host, `true` accepts IPv6 only, so `"0.0.0.0"` can
+      be bound on the same port by another listener, and `false` accepts IPv4 as
+      well (default: `nil`, the OS default)
     * `:name` - Name to register the server under (optional)
     * `:max_connections` - Maximum concurrent connections (default: 100,000)
-    * `:request_timeout_ms` - Request timeout in milliseconds (default: 30,000)
-    * `:keep_alive_timeout_ms` - Keep-alive timeout in milliseconds (default: 60,000)
+    * `:connection_overflow` - What happens past `:max_connections`: `:shed` answers
+      the new connection's requests with a 503 and closes it, `:wait` stops accepting
+      until a connection closes (default: `:shed`)
+    * `:request_timeout_ms` - Time from arrival until the response starts (its first body
+      chunk or `finish/1`) before the client gets a 503; `0` disables it (default: 30,000)
+    * `:keep_alive_timeout_ms` - Close connections with no request in flight
+      for this long (default: 60,000; 0 turns keep-alive off)
+    * `:header_read_timeout_ms` - Close connections that take longer than this
+      to send a complete request head (default: 10,000; 0 disables it)
+    * `:max_header_count` - Requests with more headers get a 431 (default: 100)
+    * `:max_header_size` - Requests whose header names and values add up to more bytes
+      get a 431 (default: 32,768)
+    * `:max_uri_length` - Requests with a longer request target get a 414 (default: 8,192)
+    * `:http1_max_buf_size` - Size of the HTTP/1 read buffer, which caps a request head
+      before it is parsed; at least 8,192 (default: `nil`, hyper's default of about 400KB)
+    * `:ws_allowed_origins` - Origins allowed to open WebSocket connections (default: `[]`, any).
+      Upgrades from other origins are rejected with 403, and upgrades with an unsupported
+      `Sec-WebSocket-Version` are rejected with 426 before reaching the handler.
+    * `:runtime_profile` - `:shared`, `:low_latency`, or `:simulation` (default: `:shared`).
+      The low-latency profile runs the server on a dedicated runtime that trades CPU for
+      wakeup latency; the simulation profile pauses the server's clock so tests can move
+      it with `Sparx.Testing.advance/2`.
+    * `:event_interval`, `:global_queue_interval`, `:worker_threads` - Fine-grained tuning
+      for a dedicated server runtime (default: `nil`)
+    * `:pool_capacity` - Reusable per-request allocations kept by each pool (default: 1024)
+    * `:min_chunk_size` - Merge small, already-buffered request body frames into chunks of
+      at least this many bytes before handing them to `read_chunk` (default: 0, disabled)
+    * `:thread_per_core` - Give each core its own single-threaded runtime and
+      `SO_REUSEPORT` listener (default: `false`)
+    * `:acceptors` - Accept loops feeding the request queue (default: 1)
+    * `:reuse_port` - Give each accept loop its own `SO_REUSEPORT` listener instead of
+      sharing one socket (default: `false`)
+    * `:acceptor_threads` - Run accept loops on dedicated threads (default: `false`)
+    * `:listen_backlog` - Pending connections queued per listener (default: 1024)
+    * `:tcp_nodelay` - Disable Nagle's algorithm on accepted connections
+      (default: `true`)
+    * `:send_buffer_size`, `:recv_buffer_size` - Socket buffer sizes in bytes
+      (default: `nil`, the OS default)
+    * `:linger_secs` - `SO_LINGER` for accepted connections; only `0`, resetting them on
+      close, is supported (default: `nil`)
+    * `:response_buffer_limit` - Bytes of a buffered response kept in memory before the
+      rest is spilled to a temp file (default: 8MB)
+    * `:warmup` - Prefill buffer pools and wake the runtime workers with a no-op task
+      before accepting connections (default: `false`)
+    * `:priority_paths` - Paths served from a high-priority queue lane so they never wait
+      behind slow requests; a trailing `*` matches a prefix (default: `[]`)
+    * `:priority_header` - Header that places a request in the high-priority lane (default: `nil`)
+    * `:max_queue_wait_ms` - Requests that waited longer than this in the queue are answered
+      with a 503 instead of being handed to the handler (default: `nil`, never shed)
+    * `:shed_retry_after_secs` - `Retry-After` value sent with shed requests and
+      connections (default: `1`)
+    * `:queue_capacity` - Requests each priority lane of the queue holds (default: 1,024)
+    * `:queue_overflow` - What happens to requests arriving at a full queue lane: `:wait`
+      holds them, `:reject` answers them with a 503, and `:drop_oldest` answers the oldest
+      queued request with a 503 instead (default: `:wait`)
+    * `:numa_aware` - Spread runtime threads across NUMA nodes, with node-local buffer
+      pools (default: `false`)
+    * `:numa_nodes` - NUMA node ids to run on; implies `:numa_aware` (default: `[]`, all nodes)
+    * `:pin_threads` - Pin each runtime thread to its own CPU; implies `:numa_aware`
+      (default: `false`)
+    * `:idle_reclaim_ms` - Close keep-alive connections idle this long and trim buffer
+      pools to match; only closes connections sooner if below `:keep_alive_timeout_ms`
+      (default: `nil`, disabled)
+    * `:memory_budget` - Bytes of response chunks and WebSocket frames buffered at once;
+      beyond it writes fail with `{:error, :overloaded}` (default: `nil`, unlimited)
+    * `:max_body_size` - Largest request body in bytes. Larger declared bodies get a 413
+      without reaching the handler; chunked ones make `Sparx.Request.read_chunk/1` return
+      `{:error, :body_too_large}` and are answered with a 413 (default: `nil`, unlimited)
+    * `:transport` - `:tcp`, or `:memory` to bind nothing and accept in-memory
+      connections from `Sparx.Testing` (default: `:tcp`)
+    * `:faults` - Inject network failures (dropped connections, request body resets,
+      delayed chunks, truncated responses) to exercise error handling; see
+      `Sparx.Config.Faults` (default: `nil`, none)
+    * `:compression` - Compress responses for clients that accept it: `true` for
+      the defaults, or options for `Sparx.Config.Compression` (default: `nil`,
+      disabled)
+    * `:mime_types` - Content types for `Sparx.Response.send_file/3` and
+      `Sparx.Response.serve_file/3` by extension, e.g. `%{"glb" => "model/gltf-binary"}`,
+      on top of the built-in table for common web assets (default: `%{}`)
+    * `:inspector_path` - Serve a JSON listing of recent requests at this path, e.g.
+      `"/__sparx/requests"`, for local debugging (default: `nil`, disabled)
+    * `:inspector_history` - Requests kept for the inspector (default: 50)
+    * `:metrics_path` - Serve Prometheus metrics at this path, e.g. `"/metrics"`, without
+      going through the handler (default: `nil`, disabled)
+    * `:telemetry` - Emit `:telemetry` events for every request and connection; see
+      `Sparx.Telemetry` for the events (default: `false`)
+    * `:access_log` - Log every response, in `:common`, `:combined`, or `:json` format, to
+      a file or a process, e.g. `[format: :combined, path: "log/access.log"]`; see
+      `Sparx.Config.AccessLog` (default: `nil`, disabled)
+    * `:drain_timeout_ms` - How long `drain/2` waits for open connections to finish
+      by default (default: 30,000)
+    * `:http2` - Serve HTTP/2 (prior knowledge, or ALPN over TLS) alongside HTTP/1.1;
+      `false` serves HTTP/1.1 only (default: `true`)
+    * `:tls` - Serve HTTPS with this certificate and key, e.g.
+      `[certfile: "cert.pem", keyfile: "key.pem"]` or `[cert_pem: pem, key_pem: pem]`;
+      see `Sparx.Config.Tls` (default: `nil`, plain HTTP)
+    * `:listeners` - Listen on several addresses at once instead of `:host` and `:port`,
+      e.g. `[[name: :public, port: 4443, tls: tls], [name: :local, path: "/tmp/app.sock"]]`;
+      each request's metadata names the listener it arrived on. See
+      `Sparx.Config.Listener` (default: `[]`)
+    * `:listener_fd` - Take over a listening socket from `export_listener/1` instead of
+      binding `:host` and `:port` (default: `nil`)
+    * `:proxy_protocol` - Behind HAProxy or ELB, expect a PROXY protocol header on each
+      connection and report the original client as the request's `:peer`; see
+      `Sparx.Config.Listener` for per-listener settings (default: `false`)
+
+  The listening socket is bound before this returns. If it cannot be, the
+  result is `{:error, {:failed_to_start, reason}}`, where `reason` is
+  `:eaddrinuse`, `:eacces`, or `:eaddrnotavail` for the common bind failures,
+  `:einval` for a host that is not an IP address, `:eafnosupport` for an IPv6
+  host on a machine without IPv6, and a message string otherwise.
 
   ## Examples
 
@@ -68,6 +197,19 @@ defmodule Sparx do
     GenServer.start_link(__MODULE__, sparx_opts, gen_opts)
   end
 
+  @doc """
+  Start a server on a listening socket taken over from another server.
+
+  `fd` comes from `export_listener/1`. This is `start_link/1` with
+  `:listener_fd` set, so `:host` and `:port` are not bound; see
+  `export_listener/1` for the whole handoff. A descriptor that is not an
+  open, listening stream socket fails with `{:error, {:failed_to_start, :einval}}`.
+  """
+  @spec start_with_listener(non_neg_integer(), keyword()) :: GenServer.on_start()
+  def start_with_listener(fd, opts) when is_integer(fd) do
+    start_link(Keyword.put(opts, :listener_fd, fd))
+  end
+
   @doc """
   Stop a Sparx HTTP server.
 
@@ -81,27 +223,327 @@ defmodule Sparx do
     GenServer.stop(server)
   end
 
+  @doc """
+  Drain a server before stopping it.
+
+  Closes the listening sockets so no new connections are accepted, closes idle
+  keep-alive connections, and lets requests already in flight (queued or being
+  handled) finish. Returns `:ok` once every connection has closed, or
+  `{:error, :timeout}` if some are still open after `timeout_ms` (default: the
+  server's `:drain_timeout_ms`). The server keeps running, without accepting,
+  until `stop/1` abandons whatever is left.
+
+  ## Examples
+
+      _ = Sparx.drain(server, 10_000)
+      :ok = Sparx.stop(server)
+
+  """
+  @spec drain(server_ref(), non_neg_integer() | nil) :: :ok | {:error, :timeout}
+  def drain(server, timeout_ms \\ nil) do
+    GenServer.call(server, {:drain, timeout_ms}, :infinity)
+  end
+
+  @doc """
+  Hand a server's listening sockets to a new server without closing them.
+
+  Returns `{:ok, fds}`, a map from listener name (`:default` unless started
+  with `:listeners`) to a duplicate of its socket's file descriptor. A new
+  server in the same OS process, say after a hot code upgrade or a config
+  change, takes a socket over with `start_with_listener/2` (or `fd:` in a
+  `Sparx.Config.Listener`). Both servers then accept from the same socket;
+  draining the old one leaves the new one accepting alone. The port is never
+  unbound, so no connection is refused and nothing can take the port in
+  between.
+
+  Each descriptor can be taken over once, and stays open until it is.
+  Returns `{:error, :not_supported}` for the memory transport and for servers
+  whose acceptors each bind their own `:reuse_port` socket, since one
+  descriptor cannot carry them all, and `{:error, :closed}` once the server is
+  draining.
+
+  ## Examples
+
+      {:ok, %{default: fd}} = Sparx.export_listener(old)
+      {:ok, new} = Sparx.start_with_listener(fd, handler: &MyApp.handle_request/1)
+      _ = Sparx.drain(old)
+      :ok = Sparx.stop(old)
+
+  """
+  @spec export_listener(server_ref()) ::
+          {:ok, %{atom() => non_neg_integer()}} | {:error, :not_supported | :closed}
+  def export_listener(server) do
+    GenServer.call(server, :export_listener)
+  end
+
+  @doc """
+  Stop accepting new connections, keeping open ones alive.
+
+  Requests on connections that are already open are still served. The listening
+  socket stays bound, so new connections wait in the kernel's backlog (and are
+  refused once it fills up) until `resume/1` is called. Useful for maintenance
+  windows, backpressure from downstream dependencies, and coordinated deploys.
+
+  ## Examples
+
+      :ok = Sparx.pause(server)
+      # ... drain, deploy, wait for a dependency ...
+      :ok = Sparx.resume(server)
+
+  """
+  @spec pause(server_ref()) :: :ok
+  def pause(server) do
+    GenServer.call(server, :pause)
+  end
+
+  @doc """
+  Start accepting new connections again after `pause/1`.
+  """
+  @spec resume(server_ref()) :: :ok
+  def resume(server) do
+    GenServer.call(server, :resume)
+  end
+
+  @doc """
+  Get the address a server is listening on.
+
+  Returns a map with `:host`, `:port`, `:tls`, and `:transport`. `:port` is the
+  port actually bound, so a server started with `port: 0` reports the
+  ephemeral port the OS picked. With `:listeners`, these describe the first
+  TCP listener, and `:listeners` lists every one with its bound `:host` and
+  `:port`, or its `:path`.
+
+  ## Examples
+
+      {:ok, server} = Sparx.start_link(handler: handler, port: 0)
+      %{port: port} = Sparx.info(server)
+
+  """
+  @spec info(server_ref()) :: %{
+          host: String.t(),
+          port: :inet.port_number(),
+          tls: boolean(),
+          transport: :tcp | :memory,
+          listeners: [
+            %{
+              name: atom(),
+              host: String.t() | nil,
+              port: :inet.port_number() | nil,
+              path: String.t() | nil,
+              tls: boolean()
+            }
+          ]
+        }
+  def info(server) do
+    GenServer.call(server, :info)
+  end
+
+  @doc """
+  Get runtime statistics for a Sparx HTTP server.
+
+  `:queue_depth` (requests waiting for a handler) and `:in_flight_requests`
+  (requests not yet answered) show saturation as it happens;
+  `:accepted_connections`, `:closed_connections`, and `:requests_served` are
+  running totals.
+
+  ## Examples
+
+      %{queue_depth: depth, in_flight_requests: in_flight} = Sparx.stats(server)
+      %{header_pool: %{hits: hits, misses: misses}} = Sparx.stats(server)
+      %{timings_pool: %{available: available}} = Sparx.stats(server)
+      %{open_connections: open, shed_connections: shed} = Sparx.stats(server)
+      %{errors: %{connection_reset: resets, parse_error: bad_requests}} = Sparx.stats(server)
+
+  """
+  @spec stats(server_ref()) :: map()
+  def stats(server) do
+    GenServer.call(server, :stats)
+  end
+
+  @doc """
+  Get latency histograms for a Sparx HTTP server, by method and status class.
+
+  Each request is counted in the histograms for its method (`:other` for
+  methods outside RFC 9110) and status class (`1` through `5`): `:queue` is the
+  time it waited for a handler, `:handler` the time from pickup until the
+  response head was ready, and `:total` the time from arrival until then.
+  Requests answered without a handler, such as shed ones, only count toward
+  `:total`. Only combinations that have seen a request are returned.
+
+  The histograms are aggregated natively, so they cost nothing per request on
+  the Elixir side. Every histogram has a `:count`, a `:sum_us`, and `:counts`:
+  the requests in each bucket of `:bounds_us` (upper bounds in microseconds),
+  plus a last bucket for anything slower.
+
+  ## Examples
+
+      %{bounds_us: bounds, histograms: histograms} = Sparx.metrics_snapshot(server)
+
+      for %{method: :get, status_class: 2, total: %{count: count, sum_us: sum}} <- histograms do
+        sum / count
+      end
+
+  """
+  @spec metrics_snapshot(server_ref()) :: %{
+          bounds_us: [pos_integer()],
+          histograms: [
+            %{
+              method: Sparx.Request.Metadata.method() | :other,
+              status_class: 1..5,
+              queue: histogram(),
+              handler: histogram(),
+              total: histogram()
+            }
+          ]
+        }
+  def metrics_snapshot(server) do
+    GenServer.call(server, :metrics_snapshot)
+  end
+
+  @doc """
+  Get a handle on a server's request queue.
+
+  Pass it to `receive_request/2` in place of the server to take requests
+  without a call through the server process each time.
+  """
+  @spec queue(server_ref()) :: reference()
+  def queue(server) do
+    GenServer.call(server, :queue)
+  end
+
+  @doc """
+  Take the next request off a server's queue, for your own acceptor processes.
+
+  Waits up to `timeout` milliseconds (default: `:infinity`) and returns
+  `{:error, :timeout}` if no request arrives, so an acceptor can wake up to
+  look at its own mailbox. Returns `{:error, :closed}` once the server has
+  stopped. Each request goes to exactly one waiting caller, whether an
+  acceptor or the worker that runs `:handler`.
+
+  ## Examples
+
+      {:ok, server} = Sparx.start_link(port: 4000)
+      queue = Sparx.queue(server)
+
+      case Sparx.receive_request(queue, 1_000) do
+        {:ok, request} -> Sparx.Response.send_text(request, 200, "Hello")
+        {:error, :timeout} -> :idle
+      end
+
+  """
+  @spec receive_request(server_ref() | reference(), timeout()) ::
+          {:ok, reference()} | {:error, :timeout | :closed}
+  def receive_request(server, timeout \\ :infinity)
+
+  def receive_request(queue, timeout) when is_reference(queue) do
+    Native.receive_request(queue, native_timeout(timeout))
+  end
+
+  def receive_request(server, timeout) do
+    server |> queue() |> receive_request(timeout)
+  end
+
+  @doc """
+  Take up to `max` requests off a server's queue in one call.
+
+  Waits like `receive_request/2` for the first request, then adds only those
+  already queued, so the batch returns as soon as one request is available.
+  At high request rates this spreads the fixed cost of a native call over many
+  requests. The list is never empty.
+
+  ## Examples
+
+      {:ok, requests} = Sparx.receive_requests(queue, 64, 1_000)
+      Enum.each(requests, &handle_request/1)
+
+  """
+  @spec receive_requests(server_ref() | reference(), pos_integer(), timeout()) ::
+          {:ok, [reference(), ...]} | {:error, :timeout | :closed}
+  def receive_requests(server, max, timeout \\ :infinity)
+
+  def receive_requests(queue, max, timeout)
+      when is_reference(queue) and is_integer(max) and max > 0 do
+    Native.receive_requests(queue, max, native_timeout(timeout))
+  end
+
+  def receive_requests(server, max, timeout) do
+    server |> queue() |> receive_requests(max, timeout)
+  end
+
+  @doc """
+  Push requests to a pool of processes instead of having them pulled.
+
+  Each queued request is sent to the next of `pids`, round-robin, as a
+  `{:sparx_request, request}` message, so no process has to wait in
+  `receive_request/2`. Processes that have exited are dropped from the set.
+  Pass an empty list to go back to pulling.
+
+  The `:handler` worker and any acceptors keep taking requests alongside the
+  dispatchers, so start the server without `:handler` to have every request
+  pushed.
+
+  ## Examples
+
+      {:ok, server} = Sparx.start_link(port: 4000)
+      :ok = Sparx.set_dispatchers(server, [self()])
+
+      receive do
+        {:sparx_request, request} -> Sparx.Response.send_text(request, 200, "Hello")
+      end
+
+  """
+  @spec set_dispatchers(server_ref(), [pid()]) :: :ok
+  def set_dispatchers(server, pids) when is_list(pids) do
+    GenServer.call(server, {:set_dispatchers, pids})
+  end
+
+  @doc """
+  Check server options for problems before starting a server with them.
+
+  Takes the same options as `start_link/1` and returns every problem found:
+  conflicting settings, limits of zero, an address that cannot be bound on
+  this machine, features the native crate was built without, and so on.
+  Each diagnostic is a map with `:severity` (`:error` if the server would
+  fail to start or could not serve requests, `:warning` otherwise), the
+  `:field` it concerns, and a `:message` saying how to fix it. An empty list
+  means no problems were found.
+
+  ## Examples
+
+      [] = Sparx.validate_config(port: 4000)
+
+      [%{severity: :error, field: :max_connections}] =
+        Sparx.validate_config(max_connections: 0)
+
+  """
+  @spec validate_config(keyword()) :: [
+          %{severity: :error | :warning, field: atom(), message: String.t()}
+        ]
+  def validate_config(opts) do
+    opts
+    |> build_config()
+    |> Native.validate_config()
+    |> Enum.map(&%{&1 | field: String.to_existing_atom(&1.field)})
+  end
+
   ## Server Callbacks
 
   @impl true
   def init(opts) do
-    handler = Keyword.fetch!(opts, :handler)
-
-    config = %Config{
-      host: Keyword.get(opts, :host, "127.0.0.1"),
-      port: Keyword.get(opts, :port, 7779),
-      max_connections: Keyword.get(opts, :max_connections, 100_000),
-      request_timeout_ms: Keyword.get(opts, :request_timeout_ms, 30_000),
-      keep_alive_timeout_ms: Keyword.get(opts, :keep_alive_timeout_ms, 60_000)
-    }
+    handler = Keyword.get(opts, :handler)
+    config = build_config(opts)
+
+    config =
+      if Keyword.get(opts, :telemetry, false) do
+        %{config | telemetry_pid: Sparx.Telemetry.start_link(self())}
+      else
+        config
+      end
 
     case Native.server_start(config) do
       {:ok, server_ref} ->
         # Spawn worker process to pull and handle requests
-        worker_pid =
-          spawn_link(fn ->
-            request_loop(server_ref, handler)
-          end)
+        worker_pid = if handler, do: spawn_link(fn -> request_loop(server_ref, handler) end)
 
         {:ok, %{server_ref: server_ref, worker: worker_pid, handler: handler}}
 
@@ -110,6 +552,68 @@ defmodule Sparx do
     end
   end
 
+  @impl true
+  def handle_call(:stats, _from, state) do
+    {:reply, Native.server_stats(state.server_ref), state}
+  end
+
+  def handle_call(:metrics_snapshot, _from, state) do
+    {:reply, Native.metrics_snapshot(state.server_ref), state}
+  end
+
+  def handle_call({:drain, timeout_ms}, from, state) do
+    # Reply from another process so stats and other calls keep working
+    server_ref = state.server_ref
+    spawn_link(fn -> GenServer.reply(from, Native.server_drain(server_ref, timeout_ms)) end)
+    {:noreply, state}
+  end
+
+  def handle_call({:set_dispatchers, pids}, _from, state) do
+    {:reply, Native.server_set_dispatchers(state.server_ref, pids), state}
+  end
+
+  def handle_call(:queue, _from, state) do
+    {:reply, state.server_ref, state}
+  end
+
+  def handle_call(:info, _from, state) do
+    {:reply, Native.server_info(state.server_ref), state}
+  end
+
+  def handle_call(:export_listener, _from, state) do
+    reply =
+      case Native.server_export_listener(state.server_ref) do
+        {:ok, fds} -> {:ok, Map.new(fds)}
+        {:error, reason} -> {:error, reason}
+      end
+
+    {:reply, reply, state}
+  end
+
+  def handle_call(:pause, _from, state) do
+    {:reply, Native.server_pause(state.server_ref), state}
+  end
+
+  def handle_call(:resume, _from, state) do
+    {:reply, Native.server_resume(state.server_ref), state}
+  end
+
+  def handle_call(:test_connect, _from, state) do
+    {:reply, Native.test_connect(state.server_ref), state}
+  end
+
+  def handle_call({:sim_advance, ms}, _from, state) do
+    {:reply, Native.sim_advance(state.server_ref, ms), state}
+  end
+
+  def handle_call({:inject_request, method, path, headers, body}, _from, state) do
+    {:reply, Native.inject_request(state.server_ref, method, path, headers, body), state}
+  end
+
+  def handle_call({:ws_broadcast, topic, frame}, _from, state) do
+    {:reply, Native.ws_broadcast(state.server_ref, topic, frame), state}
+  end
+
   @impl true
   def terminate(_reason, state) do
     Native.server_stop(state.server_ref)
@@ -118,8 +622,79 @@ defmodule Sparx do
 
   ## Private Functions
 
+  defp build_config(opts) do
+    %Config{
+      host: Keyword.get(opts, :host, "127.0.0.1"),
+      ipv6_only: Keyword.get(opts, :ipv6_only),
+      port: Keyword.get(opts, :port, 7779),
+      max_connections: Keyword.get(opts, :max_connections, 100_000),
+      connection_overflow: Keyword.get(opts, :connection_overflow, :shed),
+      request_timeout_ms: Keyword.get(opts, :request_timeout_ms, 30_000),
+      keep_alive_timeout_ms: Keyword.get(opts, :keep_alive_timeout_ms, 60_000),
+      header_read_timeout_ms: Keyword.get(opts, :header_read_timeout_ms, 10_000),
+      max_header_count: Keyword.get(opts, :max_header_count, 100),
+      max_header_size: Keyword.get(opts, :max_header_size, 32_768),
+      max_uri_length: Keyword.get(opts, :max_uri_length, 8_192),
+      http1_max_buf_size: Keyword.get(opts, :http1_max_buf_size),
+      ws_allowed_origins: Keyword.get(opts, :ws_allowed_origins, []),
+      runtime_profile: Keyword.get(opts, :runtime_profile, :shared),
+      event_interval: Keyword.get(opts, :event_interval),
+      global_queue_interval: Keyword.get(opts, :global_queue_interval),
+      worker_threads: Keyword.get(opts, :worker_threads),
+      pool_capacity: Keyword.get(opts, :pool_capacity, 1024),
+      min_chunk_size: Keyword.get(opts, :min_chunk_size, 0),
+      thread_per_core: Keyword.get(opts, :thread_per_core, false),
+      acceptors: Keyword.get(opts, :acceptors, 1),
+      reuse_port: Keyword.get(opts, :reuse_port, false),
+      acceptor_threads: Keyword.get(opts, :acceptor_threads, false),
+      listen_backlog: Keyword.get(opts, :listen_backlog, 1024),
+      tcp_nodelay: Keyword.get(opts, :tcp_nodelay, true),
+      send_buffer_size: Keyword.get(opts, :send_buffer_size),
+      recv_buffer_size: Keyword.get(opts, :recv_buffer_size),
+      linger_secs: Keyword.get(opts, :linger_secs),
+      response_buffer_limit: Keyword.get(opts, :response_buffer_limit, 8 * 1024 * 1024),
+      warmup: Keyword.get(opts, :warmup, false),
+      priority_paths: Keyword.get(opts, :priority_paths, []),
+      priority_header: Keyword.get(opts, :priority_header),
+      max_queue_wait_ms: Keyword.get(opts, :max_queue_wait_ms),
+      shed_retry_after_secs: Keyword.get(opts, :shed_retry_after_secs, 1),
+      queue_capacity: Keyword.get(opts, :queue_capacity, 1024),
+      queue_overflow: Keyword.get(opts, :queue_overflow, :wait),
+      numa_aware: Keyword.get(opts, :numa_aware, false),
+      numa_nodes: Keyword.get(opts, :numa_nodes, []),
+      pin_threads: Keyword.get(opts, :pin_threads, false),
+      idle_reclaim_ms: Keyword.get(opts, :idle_reclaim_ms),
+      memory_budget: Keyword.get(opts, :memory_budget),
+      max_body_size: Keyword.get(opts, :max_body_size),
+      transport: Keyword.get(opts, :transport, :tcp),
+      faults: opts |> Keyword.get(:faults) |> Sparx.Config.Faults.new(),
+      compression: opts |> Keyword.get(:compression) |> Sparx.Config.Compression.new(),
+      mime_types: opts |> Keyword.get(:mime_types, %{}) |> normalize_mime_types(),
+      inspector_path: Keyword.get(opts, :inspector_path),
+      metrics_path: Keyword.get(opts, :metrics_path),
+      access_log: opts |> Keyword.get(:access_log) |> Sparx.Config.AccessLog.new(),
+      inspector_history: Keyword.get(opts, :inspector_history, 50),
+      drain_timeout_ms: Keyword.get(opts, :drain_timeout_ms, 30_000),
+      http2: Keyword.get(opts, :http2, true),
+      tls: opts |> Keyword.get(:tls) |> Sparx.Config.Tls.new(),
+      listeners: opts |> Keyword.get(:listeners, []) |> Enum.map(&Sparx.Config.Listener.new/1),
+      listener_fd: Keyword.get(opts, :listener_fd),
+      proxy_protocol: Keyword.get(opts, :proxy_protocol, false)
+    }
+  end
+
+  # Extensions are matched in lowercase and without the dot
+  defp normalize_mime_types(mime_types) do
+    Map.new(mime_types, fn {extension, content_type} ->
+      {extension |> to_string() |> String.trim_leading(".") |> String.downcase(), content_type}
+    end)
+  end
+
+  defp native_timeout(:infinity), do: nil
+  defp native_timeout(timeout) when is_integer(timeout) and timeout >= 0, do: timeout
+
   defp request_loop(server_ref, handler) do
-    case Native.receive_request(server_ref) do
+    case Native.receive_request(server_ref, nil) do
       {:ok, request} ->
         # Handle request (catches errors to prevent worker crash)
         try do
@@ -0,0 +1,54 @@
+defmodule Sparx.Bench do
+  @moduledoc """
+  A small wrk-style load generator, for benchmarking handlers from IEx.
+
+  Each of `:concurrency` keep-alive connections sends `GET` requests back to
+  back for `:duration_ms`, waiting for every response before sending the
+  next. The load runs on the native runtime, not in BEAM processes, so it
+  measures the server rather than the client.
+
+  ## Examples
+
+      {:ok, _server} = Sparx.start_link(handler: &MyApp.handle_request/1, port: 4000)
+      {:ok, report} = Sparx.Bench.run("http://127.0.0.1:4000/", concurrency: 50)
+
+      report.requests_per_sec
+      report.latency_us.p99
+
+  """
+
+  alias Sparx.Native
+
+  @type report :: %{
+          requests: non_neg_integer(),
+          non_2xx: non_neg_integer(),
+          errors: non_neg_integer(),
+          bytes: non_neg_integer(),
+          duration_ms: non_neg_integer(),
+          requests_per_sec: float(),
+          latency_us: %{
+            min: non_neg_integer(),
+            mean: non_neg_integer(),
+            p50: non_neg_integer(),
+            p90: non_neg_integer(),
+            p99: non_neg_integer(),
+            max: non_neg_integer()
+          }
+        }
+
+  @doc """
+  Load-test `url`, an `http://` URL.
+
+  ## Options
+
+    * `:concurrency` - Number of connections (default: 10)
+    * `:duration_ms` - How long to send requests for (default: 10,000)
+
+  """
+  @spec run(String.t(), keyword()) :: {:ok, report()} | {:error, String.t()}
+  def run(url, opts \\ []) do
+    concurrency = Keyword.get(opts, :concurrency, 10)
+    duration_ms = Keyword.get(opts, :duration_ms, 10_000)
+    Native.bench(url, concurrency, duration_ms)
+  end
+end
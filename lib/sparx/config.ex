@@ -4,11 +4,158 @@ defmodule Sparx.Config do
 
   ## Fields
 
-    * `:host` - Host to bind to (e.g., "127.0.0.1", "0.0.0.0")
+    * `:host` - Host to bind to (e.g., "127.0.0.1", "0.0.0.0", "::"); IPv6 addresses
+      may be bracketed (`"[::1]"`)
+    * `:ipv6_only` - For an IPv6 host, `true` accepts IPv6 connections only and
+      `false` IPv4 ones as well (dual-stack). Also the default for listeners in
+      `:listeners` (default: `nil`, the OS default)
     * `:port` - Port to listen on (default: 7779)
     * `:max_connections` - Maximum number of concurrent connections (default: 100,000)
-    * `:request_timeout_ms` - Request timeout in milliseconds (default: 30,000)
-    * `:keep_alive_timeout_ms` - Keep-alive timeout in milliseconds (default: 60,000)
+    * `:connection_overflow` - `:shed` to accept connections beyond `:max_connections`
+      and answer them with `503 Service Unavailable` and `Retry-After`, or `:wait` to
+      stop accepting until one closes, leaving new connections in the kernel backlog
+      (default: `:shed`)
+    * `:request_timeout_ms` - Time from arrival until the response starts (its first body
+      chunk or `finish/1`) before the client gets a 503; `0` disables it (default: 30,000)
+    * `:keep_alive_timeout_ms` - Close connections with no request in flight
+      for this long (default: 60,000; 0 turns keep-alive off)
+    * `:header_read_timeout_ms` - Close connections that take longer than this
+      to send a complete request head (default: 10,000; 0 disables it)
+    * `:max_header_count` - Requests with more headers than this are answered with
+      `431 Request Header Fields Too Large` before reaching the handler (default: 100)
+    * `:max_header_size` - Requests whose header names and values add up to more than
+      this many bytes are answered with a 431 (default: 32,768)
+    * `:max_uri_length` - Requests whose request target is longer than this many bytes
+      are answered with `414 URI Too Long` (default: 8,192)
+    * `:http1_max_buf_size` - Size of hyper's HTTP/1 read buffer. A request head that
+      does not fit is refused before it is parsed, bounding the memory a hostile client
+      can tie up per connection; at least 8,192 (default: `nil`, about 400KB)
+    * `:ws_allowed_origins` - Origins allowed to open WebSocket connections,
+      e.g. `["https://example.com"]` (default: `[]`, any origin)
+    * `:runtime_profile` - `:shared` to run on the shared NIF runtime, or `:low_latency`
+      to run on a dedicated runtime that polls for I/O more often, trading CPU for
+      lower tail latency. `:simulation` runs the server on a single-threaded runtime
+      whose clock is paused and only moves through `Sparx.Testing.advance/2`, making
+      timeouts deterministic in tests; it requires the native crate's `simulation`
+      feature (default: `:shared`)
+    * `:event_interval` - Scheduler ticks between I/O polls; setting any runtime
+      tuning option gives the server a dedicated runtime (default: `nil`)
+    * `:global_queue_interval` - Scheduler ticks between global queue checks (default: `nil`)
+    * `:worker_threads` - Worker threads for a dedicated runtime (default: `nil`, CPU count)
+    * `:pool_capacity` - Reusable per-request allocations kept by each pool (default: 1024)
+    * `:min_chunk_size` - Merge already-buffered request body frames smaller than this
+      many bytes into one chunk, e.g. `8192` (default: 0, disabled)
+    * `:thread_per_core` - Run one single-threaded runtime per core, each with its own
+      `SO_REUSEPORT` listener, so a connection stays on one core (default: `false`).
+      Uses `:worker_threads` cores and requires a fixed `:port`.
+    * `:acceptors` - Accept loops per server, feeding the same request queue; raise it
+      when a single loop cannot keep up with the connection rate (default: 1). Ignored
+      with `:thread_per_core`, which runs one per core
+    * `:reuse_port` - Give each accept loop its own `SO_REUSEPORT` listener so the kernel
+      balances connections between them, rather than having them share one socket
+      (default: `false`)
+    * `:acceptor_threads` - Run each accept loop on a thread of its own, handing accepted
+      connections to the server's runtime, so accepting never waits behind busy
+      connections (default: `false`)
+    * `:listen_backlog` - Pending connections the kernel queues per listener before
+      refusing more (default: 1024)
+    * `:tcp_nodelay` - Set `TCP_NODELAY` on accepted connections, so small writes such
+      as streamed chunks are sent without waiting on Nagle's algorithm (default: `true`)
+    * `:send_buffer_size` - `SO_SNDBUF` for accepted connections, in bytes, set on the
+      listener so connections inherit it (default: `nil`, the OS default)
+    * `:recv_buffer_size` - `SO_RCVBUF` for accepted connections, in bytes, set on the
+      listener so the TCP window is scaled for it (default: `nil`, the OS default)
+    * `:linger_secs` - `SO_LINGER` for accepted connections. Only `0`, which resets the
+      connection on close, is accepted: any other value makes closing block a runtime
+      worker until the data is sent (default: `nil`, a graceful close in the background)
+    * `:response_buffer_limit` - Bytes of a buffered response body (one the handler
+      finished before it could be sent) kept in memory; anything beyond is spilled to a
+      temp file and streamed from disk (default: 8MB)
+    * `:warmup` - Prefill buffer pools and wake every runtime worker with a no-op task
+      before accepting connections, so the first requests do not pay for allocating
+      pooled buffers (default: `false`)
+    * `:priority_paths` - Paths whose requests are queued in a high-priority lane that is
+      always drained first, e.g. `["/health", "/admin/*"]` (default: `[]`)
+    * `:priority_header` - Requests carrying this header are queued in the high-priority
+      lane (default: `nil`)
+    * `:max_queue_wait_ms` - Requests that sat in the queue longer than this are answered
+      with `503 Service Unavailable` instead of being delivered, keeping tail latency
+      bounded under overload (default: `nil`, never shed)
+    * `:shed_retry_after_secs` - `Retry-After` value, in seconds, sent with shed
+      requests and connections (default: `1`)
+    * `:queue_capacity` - Requests each priority lane of the request queue holds
+      (default: 1,024)
+    * `:queue_overflow` - What happens to a request arriving at a full lane: `:wait`
+      holds it on its connection until there is room, `:reject` answers it with
+      `503 Service Unavailable`, and `:drop_oldest` answers the oldest queued request
+      with a 503 to make room for it (default: `:wait`). Rejected requests are counted
+      in `rejected_requests` of `Sparx.stats/1`
+    * `:numa_aware` - On multi-socket hosts, run the server on its own runtime whose
+      threads (and thread-per-core acceptors) are spread across NUMA nodes, with a
+      buffer pool per node allocated from that node's memory (default: `false`)
+    * `:numa_nodes` - Restrict the server to these NUMA node ids, e.g. `[0]`; implies
+      `:numa_aware` (default: `[]`, every node)
+    * `:pin_threads` - Pin each runtime thread to a single CPU instead of letting it
+      float within its node; implies `:numa_aware` (default: `false`)
+    * `:idle_reclaim_ms` - A background sweeper gracefully closes keep-alive connections
+      that have been idle this long, freeing their buffers, and shrinks buffer pools to
+      the connections still open so a traffic spike does not pin memory. Each connection
+      already closes itself after `:keep_alive_timeout_ms`, so a value at or above that
+      only trims pools and `Sparx.validate_config/1` warns (default: `nil`, disabled)
+    * `:memory_budget` - Server-wide limit, in bytes, on buffered response chunks and
+      queued WebSocket frames. When it is used up, `Sparx.Response.write_chunk/2` and
+      the WebSocket send functions return `{:error, :overloaded}` and request bodies
+      stop being read from the socket until memory is released (default: `nil`, unlimited)
+    * `:max_body_size` - Largest request body accepted, in bytes. A request whose
+      `content-length` is larger is answered with `413 Payload Too Large` before it is
+      queued; a chunked body is counted as it is read, and going over the limit makes
+      `Sparx.Request.read_chunk/1` return `{:error, :body_too_large}` and answers the
+      request with a 413 if the handler has not started its response (default: `nil`,
+      unlimited)
+    * `:transport` - `:tcp` to listen on `:host` and `:port`, or `:memory` to bind
+      nothing and serve in-memory connections opened with `Sparx.Testing.connect/1`
+      (default: `:tcp`)
+    * `:faults` - A `Sparx.Config.Faults` struct of network failures to inject, for
+      testing how an application copes with dropped connections, request body resets,
+      delayed response chunks, and truncated responses (default: `nil`, none)
+    * `:compression` - A `Sparx.Config.Compression` struct of which responses to
+      compress with gzip, Brotli, or zstd (default: `nil`, disabled)
+    * `:mime_types` - Content types for file responses, keyed by lowercase extension
+      without the dot; these take precedence over the built-in table (default: `%{}`)
+    * `:inspector_path` - Development endpoint, e.g. `"/__sparx/requests"`, answered
+      directly by the server with the most recent requests as JSON: method, target,
+      headers, status, phase timings, and queue wait. Not for production use, as it
+      exposes request headers (default: `nil`, disabled)
+    * `:inspector_history` - Number of recent requests the inspector keeps (default: 50)
+    * `:metrics_path` - Path, e.g. `"/metrics"`, answered directly by the server with
+      Prometheus metrics in the text format: requests by status class, a histogram of
+      the time until the response head is ready, open connections, queue depth, and
+      request and response body bytes (default: `nil`, disabled)
+    * `:telemetry_pid` - Process the server sends request and connection events to;
+      `Sparx.start_link/1` sets it to a `Sparx.Telemetry` forwarder when started with
+      `telemetry: true` (default: `nil`, disabled)
+    * `:access_log` - A `Sparx.Config.AccessLog` struct with the format and destination
+      of the native access log, which covers responses Elixir never handled as well
+      (default: `nil`, disabled)
+    * `:drain_timeout_ms` - Time `Sparx.drain/2` waits for in-flight requests and open
+      connections to finish when no timeout is given (default: 30,000)
+    * `:http2` - Serve HTTP/2 alongside HTTP/1.1, detected from the connection preface
+      (prior knowledge) or negotiated through ALPN over TLS. Each HTTP/2 stream is
+      delivered to the handler as its own request. `false` serves HTTP/1.1 only
+      (default: `true`)
+    * `:tls` - A `Sparx.Config.Tls` struct with the certificate and private key to serve
+      HTTPS with, as file paths or PEM binaries; TLS handshake failures are logged and
+      counted in `Sparx.stats/1` (default: `nil`, plain HTTP)
+    * `:listeners` - A list of `Sparx.Config.Listener` structs, each a named TCP address
+      or Unix socket with its own optional TLS, replacing `:host`, `:port`, and `:tls`.
+      Requests carry the name of the listener they arrived on (default: `[]`, a single
+      `:default` listener from `:host`, `:port`, and `:tls`)
+    * `:listener_fd` - File descriptor of a listening socket exported by another server
+      with `Sparx.export_listener/1`, taken over instead of binding `:host` and `:port`
+      (default: `nil`)
+    * `:proxy_protocol` - Read a PROXY protocol (v1 or v2) header from each connection
+      to the `:default` listener and report the client it relays as the request's
+      `:peer`; listeners set it individually (default: `false`)
 
   ## Examples
 
@@ -22,15 +169,119 @@ defmodule Sparx.Config do
 
   @type t :: %__MODULE__{
           host: String.t(),
+          ipv6_only: boolean() | nil,
           port: :inet.port_number(),
           max_connections: pos_integer(),
-          request_timeout_ms: pos_integer(),
-          keep_alive_timeout_ms: pos_integer()
+          connection_overflow: :shed | :wait,
+          request_timeout_ms: non_neg_integer(),
+          keep_alive_timeout_ms: non_neg_integer(),
+          header_read_timeout_ms: non_neg_integer(),
+          max_header_count: pos_integer(),
+          max_header_size: pos_integer(),
+          max_uri_length: pos_integer(),
+          http1_max_buf_size: pos_integer() | nil,
+          ws_allowed_origins: [String.t()],
+          runtime_profile: :shared | :low_latency | :simulation,
+          event_interval: pos_integer() | nil,
+          global_queue_interval: pos_integer() | nil,
+          worker_threads: pos_integer() | nil,
+          pool_capacity: non_neg_integer(),
+          min_chunk_size: non_neg_integer(),
+          thread_per_core: boolean(),
+          acceptors: pos_integer(),
+          reuse_port: boolean(),
+          acceptor_threads: boolean(),
+          listen_backlog: pos_integer(),
+          tcp_nodelay: boolean(),
+          send_buffer_size: pos_integer() | nil,
+          recv_buffer_size: pos_integer() | nil,
+          linger_secs: non_neg_integer() | nil,
+          response_buffer_limit: non_neg_integer(),
+          warmup: boolean(),
+          priority_paths: [String.t()],
+          priority_header: String.t() | nil,
+          max_queue_wait_ms: non_neg_integer() | nil,
+          shed_retry_after_secs: non_neg_integer(),
+          queue_capacity: pos_integer(),
+          queue_overflow: :wait | :reject | :drop_oldest,
+          numa_aware: boolean(),
+          numa_nodes: [non_neg_integer()],
+          pin_threads: boolean(),
+          idle_reclaim_ms: pos_integer() | nil,
+          memory_budget: pos_integer() | nil,
+          max_body_size: non_neg_integer() | nil,
+          transport: :tcp | :memory,
+          faults: Sparx.Config.Faults.t() | nil,
+          compression: Sparx.Config.Compression.t() | nil,
+          mime_types: %{String.t() => String.t()},
+          inspector_path: String.t() | nil,
+          metrics_path: String.t() | nil,
+          telemetry_pid: pid() | nil,
+          access_log: Sparx.Config.AccessLog.t() | nil,
+          inspector_history: non_neg_integer(),
+          drain_timeout_ms: non_neg_integer(),
+          http2: boolean(),
+          tls: Sparx.Config.Tls.t() | nil,
+          listeners: [Sparx.Config.Listener.t()],
+          listener_fd: non_neg_integer() | nil,
+          proxy_protocol: boolean()
         }
 
   defstruct host: "127.0.0.1",
+            ipv6_only: nil,
             port: 7779,
             max_connections: 100_000,
+            connection_overflow: :shed,
             request_timeout_ms: 30_000,
-            keep_alive_timeout_ms: 60_000
+            keep_alive_timeout_ms: 60_000,
+            header_read_timeout_ms: 10_000,
+            max_header_count: 100,
+            max_header_size: 32_768,
+            max_uri_length: 8_192,
+            http1_max_buf_size: nil,
+            ws_allowed_origins: [],
+            runtime_profile: :shared,
+            event_interval: nil,
+            global_queue_interval: nil,
+            worker_threads: nil,
+            pool_capacity: 1024,
+            min_chunk_size: 0,
+            thread_per_core: false,
+            acceptors: 1,
+            reuse_port: false,
+            acceptor_threads: false,
+            listen_backlog: 1024,
+            tcp_nodelay: true,
+            send_buffer_size: nil,
+            recv_buffer_size: nil,
+            linger_secs: nil,
+            response_buffer_limit: 8 * 1024 * 1024,
+            warmup: false,
+            priority_paths: [],
+            priority_header: nil,
+            max_queue_wait_ms: nil,
+            shed_retry_after_secs: 1,
+            queue_capacity: 1024,
+            queue_overflow: :wait,
+            numa_aware: false,
+            numa_nodes: [],
+            pin_threads: false,
+            idle_reclaim_ms: nil,
+            memory_budget: nil,
+            max_body_size: nil,
+            transport: :tcp,
+            faults: nil,
+            compression: nil,
+            mime_types: %{},
+            inspector_path: nil,
+            metrics_path: nil,
+            telemetry_pid: nil,
+            access_log: nil,
+            inspector_history: 50,
+            drain_timeout_ms: 30_000,
+            http2: true,
+            tls: nil,
+            listeners: [],
+            listener_fd: nil,
+            proxy_protocol: false
 end
@@ -0,0 +1,60 @@
+defmodule Sparx.Config.AccessLog do
+  @moduledoc """
+  Access logging done natively, one line per response.
+
+  Lines are formatted and written off the request path, so logging never
+  delays a response. Every response is logged, including the ones Elixir
+  never sees: requests answered from the queue with a 503, connections shed
+  at `:max_connections`, and requests too malformed to parse (logged with
+  status 400 and `"-"` for the request line).
+
+  ## Fields
+
+    * `:format` - `:common` (NCSA Common Log Format), `:combined` (Common plus
+      the Referer and User-Agent), or `:json` (one object per line with the
+      peer, method, target, version, referer, user agent, status, body bytes, and
+      `duration_us`) (default: `:common`)
+    * `:path` - File to append lines to, created if missing
+    * `:pid` - Process to send each line to as `{:sparx_access_log, line}`
+
+  Exactly one of `:path` and `:pid` must be set. Peers are logged by IP
+  address; in-memory connections appear as `memory`.
+
+  ## Examples
+
+      Sparx.start_link(
+        handler: handler,
+        access_log: [format: :combined, path: "log/access.log"]
+      )
+
+      # Hand lines to Logger instead of a file
+      Sparx.start_link(handler: handler, access_log: [format: :json, pid: log_pid])
+
+  """
+
+  @type format :: :common | :combined | :json
+
+  @type t :: %__MODULE__{
+          format: format(),
+          path: Path.t() | nil,
+          pid: pid() | nil
+        }
+
+  defstruct format: :common,
+            path: nil,
+            pid: nil
+
+  @doc """
+  Build the access log settings from a keyword list or map; `nil` disables it.
+  """
+  @spec new(keyword() | map() | t() | nil) :: t() | nil
+  def new(nil), do: nil
+  def new(%__MODULE__{} = access_log), do: access_log
+
+  def new(opts) do
+    access_log = struct!(__MODULE__, opts)
+
+    # Paths may be given as charlists or `Path` results
+    %{access_log | path: access_log.path && IO.chardata_to_string(access_log.path)}
+  end
+end
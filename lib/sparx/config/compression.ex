@@ -0,0 +1,71 @@
+defmodule Sparx.Config.Compression do
+  @moduledoc """
+  On-the-fly compression of response bodies.
+
+  A response is compressed when the client's `Accept-Encoding` allows one of
+  the configured encodings, its `content-type` is listed, and its body is at
+  least `:min_size` bytes. Streamed bodies of unknown length are compressed
+  chunk by chunk as they are written, so nothing is buffered for it.
+
+  Compressed responses get `content-encoding` and lose their `content-length`.
+  Responses that already have a `content-encoding`, partial (`206`) responses,
+  and those marked `cache-control: no-transform` are sent as is. Every
+  response that could be compressed gets `vary: accept-encoding`.
+
+  ## Fields
+
+    * `:encodings` - `:zstd`, `:brotli`, and `:gzip`, in order of preference for
+      clients that accept several equally (default: `[:zstd, :brotli, :gzip]`)
+    * `:min_size` - Smallest body, in bytes, worth compressing (default: 1024)
+    * `:content_types` - Media types to compress; an entry ending in `/`, such as
+      `"text/"`, matches every subtype (default: text, JSON, JavaScript, XML, SVG,
+      and WebAssembly)
+
+  ## Examples
+
+      Sparx.start_link(handler: handler, compression: true)
+
+      Sparx.start_link(
+        handler: handler,
+        compression: [encodings: [:gzip], min_size: 256]
+      )
+
+  """
+
+  @type encoding :: :gzip | :brotli | :zstd
+
+  @type t :: %__MODULE__{
+          encodings: [encoding()],
+          min_size: non_neg_integer(),
+          content_types: [String.t()]
+        }
+
+  defstruct encodings: [:zstd, :brotli, :gzip],
+            min_size: 1024,
+            content_types: [
+              "text/",
+              "application/json",
+              "application/javascript",
+              "application/xml",
+              "application/manifest+json",
+              "application/wasm",
+              "image/svg+xml"
+            ]
+
+  @doc """
+  Build the compression settings from a keyword list or map; `true` uses the
+  defaults and `nil` or `false` disables compression.
+  """
+  @spec new(keyword() | map() | t() | boolean() | nil) :: t() | nil
+  def new(nil), do: nil
+  def new(false), do: nil
+  def new(true), do: %__MODULE__{}
+  def new(%__MODULE__{} = compression), do: compression
+
+  def new(opts) do
+    compression = struct!(__MODULE__, opts)
+
+    # Media types are matched in lowercase
+    %{compression | content_types: Enum.map(compression.content_types, &String.downcase/1)}
+  end
+end
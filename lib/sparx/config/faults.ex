@@ -0,0 +1,60 @@
+defmodule Sparx.Config.Faults do
+  @moduledoc """
+  Network failures injected by a server, for testing error handling.
+
+  Every rate is a probability between `0.0` and `1.0`, drawn independently
+  for each connection, body read, or response chunk. All rates default to
+  `0.0`, so only the faults that are set are injected.
+
+  ## Fields
+
+    * `:accept_failure_rate` - Close accepted connections before serving them
+    * `:body_reset_rate` - Fail a request body read with an error, as if the client
+      reset the connection mid-upload
+    * `:chunk_delay_rate` - Hold back a response chunk for `:chunk_delay_ms`
+    * `:chunk_delay_ms` - How long delayed chunks are held back (default: 100)
+    * `:truncate_rate` - Send only part of a response body, then drop the connection
+
+  ## Examples
+
+      Sparx.start_link(
+        handler: handler,
+        faults: [body_reset_rate: 0.05, truncate_rate: 0.01]
+      )
+
+  """
+
+  @type t :: %__MODULE__{
+          accept_failure_rate: float(),
+          body_reset_rate: float(),
+          chunk_delay_rate: float(),
+          chunk_delay_ms: non_neg_integer(),
+          truncate_rate: float()
+        }
+
+  defstruct accept_failure_rate: 0.0,
+            body_reset_rate: 0.0,
+            chunk_delay_rate: 0.0,
+            chunk_delay_ms: 100,
+            truncate_rate: 0.0
+
+  @doc """
+  Build the fault settings from a keyword list or map; `nil` disables faults.
+  """
+  @spec new(keyword() | map() | t() | nil) :: t() | nil
+  def new(nil), do: nil
+  def new(%__MODULE__{} = faults), do: faults
+
+  def new(opts) do
+    faults = struct!(__MODULE__, opts)
+
+    # Rates cross into Rust as floats, so accept integer rates like `1`
+    %{
+      faults
+      | accept_failure_rate: faults.accept_failure_rate / 1,
+        body_reset_rate: faults.body_reset_rate / 1,
+        chunk_delay_rate: faults.chunk_delay_rate / 1,
+        truncate_rate: faults.truncate_rate / 1
+    }
+  end
+end
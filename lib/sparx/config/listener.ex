@@ -0,0 +1,85 @@
+defmodule Sparx.Config.Listener do
+  @moduledoc """
+  One address a server accepts connections on.
+
+  A server started with `:listeners` binds each of them, instead of the
+  top-level `:host`, `:port`, and `:tls`. They all share one request queue
+  and handler; each request's `:listener` metadata says which one it arrived
+  on, so a handler can tell, say, public traffic from an admin socket.
+
+  ## Fields
+
+    * `:name` - Atom reported as `:listener` in request metadata (required)
+    * `:host` - Host to bind to (default: `"127.0.0.1"`)
+    * `:port` - TCP port to listen on, or 0 for an ephemeral one
+    * `:path` - Unix domain socket to listen on instead of a port. A socket file
+      left behind by a server that is no longer running is replaced
+    * `:tls` - Serve HTTPS with this certificate and key; see `Sparx.Config.Tls`
+      (default: `nil`, plain HTTP)
+    * `:ipv6_only` - For an IPv6 host, `true` accepts IPv6 only and `false` IPv4
+      as well (default: `nil`, the server's `:ipv6_only`)
+    * `:fd` - Listening socket to take over, from `Sparx.export_listener/1`
+    * `:proxy_protocol` - Expect a PROXY protocol (v1 or v2) header from a load
+      balancer such as HAProxy or ELB on every connection, and report the client
+      it relays as the request's `:peer` (default: `false`). Connections without
+      a valid header are closed
+
+  Exactly one of `:port`, `:path`, and `:fd` must be set. To serve both IP stacks
+  from separate listeners, bind `"0.0.0.0"` and `"::"` with `ipv6_only: true`;
+  one `"::"` listener with `ipv6_only: false` serves both by itself. `:acceptors`,
+  `:reuse_port`, and `:acceptor_threads` apply to every listener; Unix
+  sockets always share one socket between their accept loops. Peers on a
+  Unix socket appear as `unix` in logs.
+
+  ## Examples
+
+      tls = [certfile: "priv/cert.pem", keyfile: "priv/key.pem"]
+
+      Sparx.start_link(
+        handler: handler,
+        listeners: [
+          [name: :http, port: 4000],
+          [name: :https, host: "0.0.0.0", port: 4443, tls: tls],
+          [name: :admin, path: "/run/my_app/admin.sock"]
+        ]
+      )
+
+  """
+
+  @type t :: %__MODULE__{
+          name: atom(),
+          host: String.t(),
+          port: :inet.port_number() | nil,
+          path: Path.t() | nil,
+          tls: Sparx.Config.Tls.t() | nil,
+          ipv6_only: boolean() | nil,
+          fd: non_neg_integer() | nil,
+          proxy_protocol: boolean()
+        }
+
+  @enforce_keys [:name]
+  defstruct name: nil,
+            host: "127.0.0.1",
+            port: nil,
+            path: nil,
+            tls: nil,
+            ipv6_only: nil,
+            fd: nil,
+            proxy_protocol: false
+
+  @doc """
+  Build a listener from a keyword list or map.
+  """
+  @spec new(keyword() | map() | t()) :: t()
+  def new(%__MODULE__{} = listener), do: listener
+
+  def new(opts) do
+    listener = struct!(__MODULE__, opts)
+
+    %{
+      listener
+      | path: listener.path && IO.chardata_to_string(listener.path),
+        tls: Sparx.Config.Tls.new(listener.tls)
+    }
+  end
+end
@@ -0,0 +1,74 @@
+defmodule Sparx.Config.Tls do
+  @moduledoc """
+  Certificate and private key for serving HTTPS.
+
+  The certificate chain and the key can each be given as a path to a PEM file
+  or as the PEM binary itself, but not both. Connections negotiate HTTP/2 or
+  HTTP/1.1 through ALPN.
+
+  ## Fields
+
+    * `:certfile` - Path to the PEM certificate chain, leaf certificate first
+    * `:keyfile` - Path to the PEM private key
+    * `:cert_pem` - PEM certificate chain, as a binary or iodata
+    * `:key_pem` - PEM private key (PKCS#8, PKCS#1, or SEC1), as a binary or iodata
+
+  ## Examples
+
+      Sparx.start_link(
+        handler: handler,
+        port: 4443,
+        tls: [certfile: "priv/cert.pem", keyfile: "priv/key.pem"]
+      )
+
+      # Certificates from `Sparx.DevCert` can be passed straight through
+      {:ok, cert} = Sparx.DevCert.generate()
+      Sparx.start_link(handler: handler, port: 4443, tls: cert)
+
+      # A certificate file with a key fetched from a secret manager
+      Sparx.start_link(
+        handler: handler,
+        port: 4443,
+        tls: [certfile: "priv/cert.pem", key_pem: fetch_secret!("tls-key")]
+      )
+
+  """
+
+  @type t :: %__MODULE__{
+          certfile: Path.t() | nil,
+          keyfile: Path.t() | nil,
+          cert_pem: iodata() | nil,
+          key_pem: iodata() | nil
+        }
+
+  defstruct certfile: nil,
+            keyfile: nil,
+            cert_pem: nil,
+            key_pem: nil
+
+  @doc """
+  Build the TLS settings from a keyword list or map; `nil` serves plain HTTP.
+  """
+  @spec new(keyword() | map() | t() | nil) :: t() | nil
+  def new(nil), do: nil
+  def new(%__MODULE__{} = tls), do: tls
+
+  def new(opts) do
+    tls = struct!(__MODULE__, opts)
+
+    # Paths may be given as charlists or `Path` results, PEM as iodata
+    %{
+      tls
+      | certfile: to_path(tls.certfile),
+        keyfile: to_path(tls.keyfile),
+        cert_pem: to_pem(tls.cert_pem),
+        key_pem: to_pem(tls.key_pem)
+    }
+  end
+
+  defp to_path(nil), do: nil
+  defp to_path(path), do: IO.chardata_to_string(path)
+
+  defp to_pem(nil), do: nil
+  defp to_pem(pem), do: IO.iodata_to_binary(pem)
+end
@@ -0,0 +1,30 @@
+defmodule Sparx.DevCert do
+  @moduledoc """
+  Throwaway self-signed certificates for local HTTPS.
+
+  Certificates are generated in-process, so development servers can serve
+  HTTPS without `openssl` or any other external step. Browsers and clients
+  will not trust them; never use them in production.
+
+  ## Examples
+
+      {:ok, %{cert_pem: cert, key_pem: key}} = Sparx.DevCert.generate()
+      {:ok, _} = Sparx.DevCert.generate(["myapp.test", "127.0.0.1"])
+
+  """
+
+  alias Sparx.Native
+
+  @type t :: %{cert_pem: String.t(), key_pem: String.t()}
+
+  @doc """
+  Generate a certificate covering `hostnames`, and its private key, as PEM.
+
+  Hostnames that are IP addresses are added as IP subject alternative names.
+  With no hostnames, the certificate covers `localhost`, `127.0.0.1`, and `::1`.
+  """
+  @spec generate([String.t()]) :: {:ok, t()} | {:error, String.t()}
+  def generate(hostnames \\ []) when is_list(hostnames) do
+    Native.generate_dev_cert(hostnames)
+  end
+end
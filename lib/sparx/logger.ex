@@ -0,0 +1,90 @@
+defmodule Sparx.Logger do
+  @moduledoc """
+  Routes the native side's log events into `Logger`.
+
+  By default Rust log events are written straight to stderr, past any
+  `Logger` handlers and metadata. While this process runs, they are sent to
+  it instead and logged through `Logger`, with tracing levels mapped to
+  `Logger` levels (`TRACE` and `DEBUG` both become `:debug`). Each event
+  carries `domain: [:sparx]` and its Rust module as `:sparx_target`
+  metadata.
+
+  Which events are produced is set by the `SPARX_LOG` environment variable
+  (default: `warn`) when the library loads, and by `set_filter/1` after
+  that; `Logger`'s own level applies on top.
+
+  Add it to a supervision tree, ahead of any servers:
+
+      children = [
+        Sparx.Logger,
+        {Sparx, handler: &MyApp.handle_request/1}
+      ]
+
+  Only one process receives events; starting another takes them over. When
+  it exits, events go back to stderr.
+
+  ## Distributed tracing
+
+  Each request runs in an info-level `request` span. When the native crate is
+  built with the `otlp` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` is set, those
+  spans are exported over OTLP/HTTP as children of the caller's `traceparent`:
+
+      config :sparx, Sparx.Native, features: ["otlp"]
+
+  The span level means `SPARX_LOG` must let info through for `sparx`, e.g.
+  `warn,sparx=info`. The endpoint, headers, and service name are read from the
+  standard `OTEL_*` environment variables.
+  """
+
+  use GenServer
+
+  require Logger
+
+  alias Sparx.Native
+
+  @doc """
+  Start forwarding native log events to `Logger`.
+  """
+  @spec start_link(keyword()) :: GenServer.on_start()
+  def start_link(opts \\ []) do
+    GenServer.start_link(__MODULE__, opts, Keyword.take(opts, [:name]))
+  end
+
+  @doc """
+  Replace the native log filter while running.
+
+  Takes the same directives as `SPARX_LOG`, e.g. `"debug"` or
+  `"sparx=debug,hyper=warn"`, and applies whether or not events are being
+  forwarded to `Logger`.
+
+  ## Examples
+
+      :ok = Sparx.Logger.set_filter("sparx=debug")
+
+  """
+  @spec set_filter(String.t()) :: :ok | {:error, String.t()}
+  def set_filter(filter) when is_binary(filter) do
+    Native.set_log_filter(filter)
+  end
+
+  @impl true
+  def init(_opts) do
+    # Restore stderr in `terminate/2` when stopped by a supervisor
+    Process.flag(:trap_exit, true)
+    :ok = Native.set_log_handler(self())
+    {:ok, nil}
+  end
+
+  @impl true
+  def handle_info({:sparx_log, level, target, message}, state) do
+    Logger.log(level, message, domain: [:sparx], sparx_target: target)
+    {:noreply, state}
+  end
+
+  def handle_info(_message, state), do: {:noreply, state}
+
+  @impl true
+  def terminate(_reason, _state) do
+    Native.set_log_handler(nil)
+  end
+end
@@ -7,23 +7,89 @@ defmodule Sparx.Native do
   # Server management
   def server_start(_config), do: err()
   def server_stop(_server_ref), do: err()
-  def receive_request(_server_ref), do: err()
+  def server_stats(_server_ref), do: err()
+  def metrics_snapshot(_server_ref), do: err()
+  def server_info(_server_ref), do: err()
+  def server_pause(_server_ref), do: err()
+  def server_resume(_server_ref), do: err()
+  def server_drain(_server_ref, _timeout_ms), do: err()
+  def server_export_listener(_server_ref), do: err()
+  def validate_config(_config), do: err()
+  def receive_request(_server_ref, _timeout_ms), do: err()
+  def receive_requests(_server_ref, _max_requests, _timeout_ms), do: err()
+  def server_set_dispatchers(_server_ref, _pids), do: err()
 
   # Request streaming
+  def request_metadata(_request_handle), do: err()
+  def request_timings(_request_handle), do: err()
+  def request_events(_request_handle), do: err()
+  def parse_query(_query), do: err()
+  def parse_query_pairs(_query), do: err()
+  def request_monitor(_request_handle, _pid), do: err()
   def read_chunk(_request_handle), do: err()
+  def read_chunks(_request_handle, _max_chunks, _max_bytes), do: err()
+  def read_body(_request_handle, _max_bytes), do: err()
+  def read_trailers(_request_handle), do: err()
+  def stream_body_to_file(_request_handle, _path, _max_bytes), do: err()
+  def multipart_next_part(_request_handle), do: err()
+  def multipart_read_chunk(_request_handle), do: err()
 
   # Response streaming
   def send_status(_request_handle, _status), do: err()
+  def send_informational(_request_handle, _status, _headers), do: err()
   def send_header(_request_handle, _name, _value), do: err()
+  def send_headers(_request_handle, _headers), do: err()
+  def send_trailer(_request_handle, _name, _value), do: err()
   def write_chunk(_request_handle, _data), do: err()
+  def try_write_chunk(_request_handle, _data), do: err()
+  def sse_init(_request_handle, _headers, _keepalive_ms), do: err()
+  def sse_send_event(_request_handle, _event), do: err()
   def finish(_request_handle), do: err()
+  def send_file(_request_handle, _path, _offset, _length), do: err()
+  def serve_file(_request_handle, _path, _options), do: err()
+  def send_response(_request_handle, _status, _headers, _body), do: err()
 
   # WebSocket
-  def upgrade_websocket(_request_handle), do: err()
+  def upgrade_websocket(_request_handle, _options), do: err()
+  def reject_upgrade(_request_handle, _status, _headers, _body), do: err()
+  def upgrade_raw(_request_handle), do: err()
+  def raw_read(_raw_stream, _max_bytes), do: err()
+  def raw_write(_raw_stream, _data), do: err()
+  def raw_close(_raw_stream), do: err()
   def ws_send_text(_ws_handle, _text), do: err()
   def ws_send_binary(_ws_handle, _data), do: err()
   def ws_recv(_ws_handle), do: err()
+  def ws_set_owner(_ws_handle, _pid), do: err()
+  def ws_queue_depth(_ws_handle), do: err()
+  def ws_join(_ws_handle, _topic), do: err()
+  def ws_leave(_ws_handle, _topic), do: err()
+  def ws_broadcast(_server_ref, _topic, _frame), do: err()
   def ws_close(_ws_handle), do: err()
 
+  # In-memory transport
+  def test_connect(_server_ref), do: err()
+  def test_write(_conn, _data), do: err()
+  def test_read(_conn, _timeout_ms), do: err()
+  def test_close(_conn), do: err()
+  def sim_advance(_server_ref, _ms), do: err()
+  def inject_request(_server_ref, _method, _path, _headers, _body), do: err()
+  def await_response(_capture, _timeout_ms), do: err()
+  def capture_response(_request_handle), do: err()
+  def captured_status(_capture), do: err()
+  def captured_headers(_capture), do: err()
+  def captured_body(_capture), do: err()
+
+  # Development
+  def generate_dev_cert(_hostnames), do: err()
+  def bench(_url, _concurrency, _duration_ms), do: err()
+
+  # Logging
+  def set_log_handler(_pid), do: err()
+  def set_log_filter(_filter), do: err()
+
+  # Profiling
+  def profiler_start(_frequency), do: err()
+  def profiler_stop, do: err()
+
   defp err, do: :erlang.nif_error(:nif_not_loaded)
 end
@@ -0,0 +1,46 @@
+defmodule Sparx.Profiler do
+  @moduledoc """
+  In-process CPU profiling of the native (Rust) side of Sparx.
+
+  Samples the server and WebSocket hot paths and produces a profile in
+  [pprof](https://github.com/google/pprof) format, which can be rendered as a
+  flamegraph with `go tool pprof` or uploaded to any pprof-compatible viewer.
+
+  Profiling support is compiled in only when the native crate is built with the
+  `profiling` feature:
+
+      config :sparx, Sparx.Native, features: ["profiling"]
+
+  Without it, `start/1` and `stop/0` return `{:error, :not_supported}`.
+
+  ## Examples
+
+      :ok = Sparx.Profiler.start()
+      Process.sleep(30_000)
+      {:ok, profile} = Sparx.Profiler.stop()
+      File.write!("sparx.pb", profile)
+
+  """
+
+  alias Sparx.Native
+
+  @doc """
+  Start sampling at `frequency` samples per second (default: 99).
+
+  Returns `{:error, :already_started}` if a profile is already being captured.
+  """
+  @spec start(pos_integer()) :: :ok | {:error, :already_started | :not_supported | term()}
+  def start(frequency \\ 99) when is_integer(frequency) and frequency > 0 do
+    Native.profiler_start(frequency)
+  end
+
+  @doc """
+  Stop sampling and return the encoded pprof profile.
+
+  Returns `{:error, :not_started}` if no profile is being captured.
+  """
+  @spec stop() :: {:ok, binary()} | {:error, :not_started | :not_supported | term()}
+  def stop do
+    Native.profiler_stop()
+  end
+end
@@ -11,31 +11,256 @@ defmodule Sparx.Request do
 
     ## Fields
 
-      * `:method` - HTTP method as a string (e.g., "GET", "POST")
+      * `:method` - HTTP method as a lowercase atom (e.g., `:get`, `:post`), or
+        `{:other, name}` for methods outside RFC 9110 (e.g., `{:other, "PURGE"}`)
+      * `:scheme` - `:https` on TLS connections, otherwise `:http`
+      * `:host` - Authority the request was sent to, with any port (e.g.,
+        `"example.com:8080"`): from an absolute-form target or HTTP/2's
+        `:authority`, else the `Host` header; `nil` if neither is present
       * `:path` - Request path
       * `:query` - Query string (optional)
-      * `:version` - HTTP version string (e.g., "HTTP/1.1")
-      * `:headers` - List of {name, value} tuples
+      * `:target` - Form of the request-target: `:origin` (`/path`), `:absolute`
+        (`http://host/path`, as sent to proxies), `:authority` (`CONNECT`), or
+        `:asterisk` (`OPTIONS *`). HTTP/2 requests count as `:origin`
+      * `:version` - HTTP version (`:http_1_0`, `:http_1_1`, `:http_2`, ...)
+      * `:headers` - List of `{name, value}` tuples in the order received. Values
+        are the raw bytes from the wire, so they may not be valid UTF-8; names are
+        lowercase, as the original casing is not kept
+      * `:trace_context` - The caller's W3C trace context, parsed from a valid
+        `traceparent` header: a map with `:trace_id`, `:parent_id`, `:trace_flags`
+        (bit 0 is sampled), and `:tracestate` (the raw `tracestate`, or `nil`);
+        `nil` when `traceparent` is missing or malformed
+      * `:listener` - Name of the listener the request arrived on; `:default`
+        unless the server was started with `:listeners`
+      * `:peer` - The client: a map with `:address` (IP address string), `:port`,
+        `:protocol` (`:tcp4`, `:tcp6`, `:udp4`, `:udp6`, or `:unix`), and
+        `:proxied` (`true` when it came from a PROXY protocol header rather than
+        the socket). On a listener with `:proxy_protocol`, this is the client the
+        load balancer relayed; `nil` on Unix sockets and test connections
 
     """
+    @type method ::
+            :get
+            | :post
+            | :put
+            | :patch
+            | :delete
+            | :head
+            | :options
+            | :connect
+            | :trace
+            | {:other, String.t()}
+
+    @type version :: :http_0_9 | :http_1_0 | :http_1_1 | :http_2 | :http_3
+
+    @type target :: :origin | :absolute | :authority | :asterisk
+
+    @type trace_context :: %{
+            trace_id: String.t(),
+            parent_id: String.t(),
+            trace_flags: 0..255,
+            tracestate: String.t() | nil
+          }
+
+    @type peer :: %{
+            address: String.t(),
+            port: :inet.port_number() | nil,
+            protocol: :tcp4 | :tcp6 | :udp4 | :udp6 | :unix,
+            proxied: boolean()
+          }
+
     @type t :: %__MODULE__{
-            method: String.t(),
+            method: method(),
+            scheme: :http | :https,
+            host: String.t() | nil,
             path: String.t(),
             query: String.t() | nil,
-            version: String.t(),
-            headers: [{String.t(), String.t()}]
+            target: target(),
+            version: version(),
+            headers: [{String.t(), String.t()}],
+            trace_context: trace_context() | nil,
+            listener: atom(),
+            peer: peer() | nil
           }
 
-    defstruct [:method, :path, :query, :version, :headers]
+    defstruct [
+      :method,
+      :scheme,
+      :host,
+      :path,
+      :query,
+      :target,
+      :version,
+      :headers,
+      :trace_context,
+      :listener,
+      :peer
+    ]
   end
 
   @type request_handle :: reference()
 
+  @doc """
+  Get the request metadata (method, path, query, version, and headers).
+
+  The metadata is encoded once per request in Rust, so calling this
+  repeatedly is cheap even for requests with large header sets.
+
+  ## Examples
+
+      %Sparx.Request.Metadata{method: :get, path: "/"} = Sparx.Request.metadata(request)
+
+  """
+  @spec metadata(request_handle()) :: Metadata.t()
+  def metadata(request_handle) do
+    Native.request_metadata(request_handle)
+  end
+
+  @doc """
+  Get the decoded query parameters of a request.
+
+  The query string is only parsed when this is called, in Rust, with the same
+  rules as `parse_query/2`. Returns an empty map for requests without a query.
+
+  ## Examples
+
+      # GET /search?q=sparx&tags[]=http&tags[]=rust
+      %{"q" => "sparx", "tags" => ["http", "rust"]} = Sparx.Request.query_params(request)
+
+  """
+  @spec query_params(request_handle()) :: map()
+  def query_params(request_handle) do
+    case metadata(request_handle).query do
+      nil -> %{}
+      query -> parse_query(query)
+    end
+  end
+
+  @doc """
+  Decode a query string.
+
+  `+` and `%XX` escapes are decoded in keys and values. By default the result is
+  a map in which bracketed keys nest: `a[]=1&a[]=2` gives `%{"a" => ["1", "2"]}`,
+  `a[b]=1` gives `%{"a" => %{"b" => "1"}}`, and `items[][id]=1&items[][id]=2`
+  gives a list of maps. A plain key that repeats keeps its last value.
+
+  ## Options
+
+    * `:pairs` - Return the `{key, value}` pairs in order instead, keeping repeated
+      keys and leaving brackets alone (default: `false`)
+
+  ## Examples
+
+      %{"user" => %{"name" => "Ada L"}} = Sparx.Request.parse_query("user[name]=Ada+L")
+      [{"a", "1"}, {"a", "2"}] = Sparx.Request.parse_query("a=1&a=2", pairs: true)
+
+  """
+  @spec parse_query(binary(), keyword()) :: map() | [{binary(), binary()}]
+  def parse_query(query, opts \\ []) when is_binary(query) do
+    if Keyword.get(opts, :pairs, false) do
+      Native.parse_query_pairs(query)
+    else
+      Native.parse_query(query)
+    end
+  end
+
+  @doc """
+  Get the per-phase timestamps of a request.
+
+  Each value is the number of microseconds since the request's connection was
+  accepted, or `nil` if the phase hasn't been reached yet:
+
+    * `:received` - request head parsed
+    * `:enqueued` - placed on the request queue
+    * `:dequeued` - picked up by an Elixir process
+    * `:first_byte` - first response message sent by the handler
+    * `:finished` - response finished by the handler
+
+  Comparing `:enqueued` and `:dequeued` gives the time spent waiting in the queue.
+  Server-wide aggregates are reported under `:timings` in `Sparx.stats/1`.
+
+  ## Examples
+
+      %{enqueued: enqueued, dequeued: dequeued} = Sparx.Request.timings(request)
+      queue_wait_us = dequeued - enqueued
+
+  """
+  @spec timings(request_handle()) :: %{atom() => non_neg_integer() | nil}
+  def timings(request_handle) do
+    Native.request_timings(request_handle)
+  end
+
+  @doc """
+  Get the lifecycle event log of a request, oldest first.
+
+  Each entry is a map with `:at_us` (microseconds since the connection was
+  accepted), `:event`, and `:reason`. Events are:
+
+    * `:received` - request head parsed
+    * `:queued` - placed on the request queue
+    * `:dequeued` - picked up by an Elixir process
+    * `:first_chunk_read` - first body chunk handed to Elixir
+    * `:response_started` - first response message sent by the handler
+    * `:flushed` - last response body frame handed to the socket
+    * `:finished` - response finished by the handler
+    * `:error` - something failed; `:reason` is an error atom such as
+      `:connection_reset` or `:timeout`
+
+  Every event but `:error` appears at most once. Only the last 32 entries
+  are kept. The request handle keeps the log alive, so it can be read after
+  the response has been sent to find out where a slow request spent its time.
+
+  ## Examples
+
+      [%{event: :received} | _] = Sparx.Request.events(request)
+
+  """
+  @spec events(request_handle()) :: [
+          %{at_us: non_neg_integer(), event: atom(), reason: atom() | nil}
+        ]
+  def events(request_handle) do
+    Native.request_events(request_handle)
+  end
+
+  @doc """
+  Get notified if the client goes away before the response is done.
+
+  Returns a reference. If the connection is closed (or the HTTP/2 stream reset)
+  while the handler is still working or the response is still streaming, `pid`
+  receives `{:sparx_request_closed, ref}`, so the handler can abort expensive
+  work instead of finding out when a write fails. Nothing is sent for requests
+  whose response completes.
+
+  ## Examples
+
+      ref = Sparx.Request.monitor(request)
+
+      receive do
+        {:sparx_request_closed, ^ref} -> :aborted
+      after
+        0 -> compute_report(request)
+      end
+
+  """
+  @spec monitor(request_handle(), pid()) :: reference()
+  def monitor(request_handle, pid \\ self()) do
+    Native.request_monitor(request_handle, pid)
+  end
+
   @doc """
   Read a chunk from the request body.
 
   Returns `{:ok, binary()}` if a chunk is available, `:eof` when the body is fully consumed,
-  or `{:error, reason}` if an error occurs.
+  or `{:error, reason}` if an error occurs. `reason` is one of:
+
+    * `:connection_reset` - the client went away mid-body
+    * `:timeout` - the body did not arrive in time
+    * `:parse_error` - the body framing was malformed
+    * `:body_too_large` - the body went over `:max_body_size`; unless a response was
+      already started, the client has been sent `413 Payload Too Large`
+    * `:h2_protocol_error` - the HTTP/2 stream was reset or violated the protocol
+    * `:closed` - the body was already consumed
+    * `:connection_error` - any other connection failure
 
   This is a low-level async function. For most use cases, consider using `body_stream/2` instead.
 
@@ -45,7 +270,7 @@ defmodule Sparx.Request do
       :eof = Sparx.Request.read_chunk(request)
 
   """
-  @spec read_chunk(request_handle()) :: {:ok, binary()} | :eof | {:error, term()}
+  @spec read_chunk(request_handle()) :: {:ok, binary()} | :eof | {:error, atom()}
   def read_chunk(request_handle) do
     case Native.read_chunk(request_handle) do
       {:ok, <<>>} -> :eof
@@ -54,6 +279,39 @@ defmodule Sparx.Request do
     end
   end
 
+  @doc """
+  Read several chunks from the request body in a single call.
+
+  Waits for at least one chunk, then returns whatever else is already available,
+  up to `:max_chunks` chunks or until `:max_bytes` have been collected. This
+  amortizes the cost of the NIF call when bodies arrive as many medium-size frames.
+
+  Returns `{:ok, [binary()]}`, `:eof` when the body is fully consumed, or
+  `{:error, reason}` with the same reasons as `read_chunk/1`.
+
+  ## Options
+
+    * `:max_chunks` - Maximum number of chunks to return (default: 64)
+    * `:max_bytes` - Stop collecting once this many bytes are read (default: 1MB)
+
+  ## Examples
+
+      {:ok, chunks} = Sparx.Request.read_chunks(request, max_chunks: 16)
+      :eof = Sparx.Request.read_chunks(request)
+
+  """
+  @spec read_chunks(request_handle(), keyword()) :: {:ok, [binary()]} | :eof | {:error, atom()}
+  def read_chunks(request_handle, opts \\ []) do
+    max_chunks = Keyword.get(opts, :max_chunks, 64)
+    max_bytes = Keyword.get(opts, :max_bytes, 1024 * 1024)
+
+    case Native.read_chunks(request_handle, max_chunks, max_bytes) do
+      {:ok, []} -> :eof
+      {:ok, chunks} -> {:ok, chunks}
+      {:error, _} = error -> error
+    end
+  end
+
   @doc """
   Create a stream for reading the request body.
 
@@ -102,12 +360,17 @@ defmodule Sparx.Request do
   @doc """
   Read the entire request body into a binary.
 
-  This function reads all chunks from the request body and concatenates them.
-  Use this for small requests. For large requests, use `body_stream/2` instead.
+  The body is collected in Rust and returned in a single call, so there is no
+  round trip or intermediate binary per chunk. Use this for bodies that fit in
+  memory, such as JSON; stream larger ones with `body_stream/2` or
+  `stream_body_to_file/3`.
+
+  Returns `{:error, :too_large}` as soon as the body is known to be over
+  `:max_size`, from its `content-length` or from the bytes read so far, and
+  `{:error, reason}` with the reasons of `read_chunk/1`.
 
   ## Options
 
-    * `:timeout` - Timeout for reading the entire body in milliseconds (default: 30,000)
     * `:max_size` - Maximum body size in bytes (default: 10MB)
 
   ## Examples
@@ -119,32 +382,103 @@ defmodule Sparx.Request do
   @spec read_body(request_handle(), keyword()) :: {:ok, binary()} | {:error, term()}
   def read_body(request_handle, opts \\ []) do
     max_size = Keyword.get(opts, :max_size, 10 * 1024 * 1024)
-    timeout = Keyword.get(opts, :timeout, 30_000)
-
-    chunks =
-      request_handle
-      |> body_stream(timeout: timeout)
-      |> Enum.reduce_while({[], 0}, fn chunk, {acc, size} ->
-        new_size = size + byte_size(chunk)
-
-        if new_size > max_size do
-          {:halt, {:error, :too_large}}
-        else
-          {:cont, {[chunk | acc], new_size}}
-        end
-      end)
+    Native.read_body(request_handle, max_size)
+  end
+
+  @doc """
+  Write the rest of the request body to a file.
+
+  The body is copied from the socket to the file inside the server, so large
+  uploads never pass through the BEAM. Any file at `path` is replaced.
 
-    case chunks do
-      {:error, reason} ->
-        {:error, reason}
+  Returns `{:ok, bytes_written}`, or `{:error, reason}` with the reasons of
+  `read_chunk/1`; `:body_too_large` also covers a body over `:max_bytes`. The
+  partially written file is removed on failure.
 
-      {chunk_list, _size} ->
-        body =
-          chunk_list
-          |> Enum.reverse()
-          |> IO.iodata_to_binary()
+  ## Options
+
+    * `:max_bytes` - Fail once the body goes over this many bytes (default: `nil`,
+      no limit beyond the server's `:max_body_size`)
+
+  ## Examples
+
+      path = Path.join(System.tmp_dir!(), "upload-#{System.unique_integer([:positive])}")
+      {:ok, size} = Sparx.Request.stream_body_to_file(request, path, max_bytes: 1_000_000_000)
+
+  """
+  @spec stream_body_to_file(request_handle(), Path.t(), keyword()) ::
+          {:ok, non_neg_integer()} | {:error, term()}
+  def stream_body_to_file(request_handle, path, opts \\ []) do
+    max_bytes = Keyword.get(opts, :max_bytes)
+    Native.stream_body_to_file(request_handle, to_string(path), max_bytes)
+  end
 
-        {:ok, body}
+  @doc """
+  Get the trailer fields the client sent after the request body.
+
+  Trailers are only known once the body has been read to the end, e.g. with
+  `read_body/2` or until `read_chunk/1` returns `:eof`; before that this returns
+  `{:error, :pending}`. Returns an empty list if the client sent none.
+
+  ## Examples
+
+      {:ok, body} = Sparx.Request.read_body(request)
+      {:ok, [{"grpc-status", "0"}]} = Sparx.Request.read_trailers(request)
+
+  """
+  @spec read_trailers(request_handle()) :: {:ok, [{String.t(), String.t()}]} | {:error, :pending}
+  def read_trailers(request_handle) do
+    Native.read_trailers(request_handle)
+  end
+
+  @doc """
+  Read the headers of the next part of a `multipart/*` body, such as a
+  `multipart/form-data` upload.
+
+  The body is parsed in Rust as it is read, so part bodies are streamed with
+  `read_part_chunk/1` rather than buffered. Whatever is left of the current
+  part is skipped. Header names are lowercase.
+
+  Returns `{:ok, headers}`, `:eof` after the last part, `{:error, :invalid_request}`
+  if the request is not multipart or its body is malformed, or `{:error, reason}`
+  with the same reasons as `read_chunk/1`.
+
+  ## Examples
+
+      {:ok, headers} = Sparx.Request.next_part(request)
+      {"content-disposition", ~s(form-data; name="file"; filename="a.png")} =
+        List.keyfind(headers, "content-disposition", 0)
+
+  """
+  @spec next_part(request_handle()) ::
+          {:ok, [{String.t(), String.t()}]} | :eof | {:error, atom()}
+  def next_part(request_handle) do
+    case Native.multipart_next_part(request_handle) do
+      {:ok, nil} -> :eof
+      {:ok, headers} -> {:ok, headers}
+      {:error, _} = error -> error
+    end
+  end
+
+  @doc """
+  Read a chunk of the body of the current multipart part.
+
+  Returns `{:ok, binary()}`, `:eof` at the end of the part, or `{:error, reason}`
+  as for `next_part/1`.
+
+  ## Examples
+
+      {:ok, _headers} = Sparx.Request.next_part(request)
+      {:ok, chunk} = Sparx.Request.read_part_chunk(request)
+      :eof = Sparx.Request.read_part_chunk(request)
+
+  """
+  @spec read_part_chunk(request_handle()) :: {:ok, binary()} | :eof | {:error, atom()}
+  def read_part_chunk(request_handle) do
+    case Native.multipart_read_chunk(request_handle) do
+      {:ok, <<>>} -> :eof
+      {:ok, chunk} -> {:ok, chunk}
+      {:error, _} = error -> error
     end
   end
 end
@@ -3,6 +3,10 @@ defmodule Sparx.Response do
   HTTP response streaming API.
 
   Provides functions for building and sending HTTP responses.
+
+  Responses to `HEAD` requests are sent without their body, so handlers can
+  answer `HEAD` and `GET` with the same code: the body is written as usual and
+  discarded by the server, and `content-length` still reflects its size.
   """
 
   alias Sparx.Native
@@ -26,6 +30,32 @@ defmodule Sparx.Response do
     Native.send_status(request_handle, status)
   end
 
+  @doc """
+  Send a 1xx informational response ahead of the final response.
+
+  The typical use is `103 Early Hints`, letting the browser start fetching
+  assets named in `link` headers while the handler is still working. Can be
+  called several times, but only before `send_status/2` or anything else of
+  the final response; afterwards it returns `{:error, :already_sent}`.
+
+  Only HTTP/1.1 requests support it; others return `{:error, :not_supported}`.
+  `101` is reserved for protocol upgrades and is rejected.
+
+  ## Examples
+
+      :ok =
+        Sparx.Response.send_informational(request, 103, [
+          {"link", "</app.css>; rel=preload; as=style"}
+        ])
+
+  """
+  @spec send_informational(request_handle(), 100..199, [{String.t(), String.t()}]) ::
+          :ok | {:error, term()}
+  def send_informational(request_handle, status, headers \\ [])
+      when is_integer(status) and status >= 100 and status <= 199 and is_list(headers) do
+    Native.send_informational(request_handle, status, headers)
+  end
+
   @doc """
   Send a response header.
 
@@ -43,21 +73,100 @@ defmodule Sparx.Response do
     Native.send_header(request_handle, name, value)
   end
 
+  @doc """
+  Send several response headers in one call.
+
+  Headers are added in order, as if sent one by one with `send_header/3`. If any
+  name or value is invalid, none are sent and the error names the first one.
+
+  ## Examples
+
+      :ok =
+        Sparx.Response.send_headers(request, [
+          {"content-type", "application/json"},
+          {"cache-control", "no-store"}
+        ])
+
+  """
+  @spec send_headers(request_handle(), [{String.t(), String.t()}]) :: :ok | {:error, term()}
+  def send_headers(request_handle, headers) when is_list(headers) do
+    Native.send_headers(request_handle, headers)
+  end
+
+  @doc """
+  Send a trailer field, written after the last body chunk.
+
+  Trailers carry values only known once the body is done, such as a checksum
+  or a gRPC status. Over HTTP/2 they follow the final data frame. Over HTTP/1.1
+  they need a chunked response and are only sent for names declared in the
+  `trailer` header: trailers sent before the body starts streaming are
+  declared automatically, later ones must be listed with `send_header/3`.
+
+  ## Examples
+
+      :ok = Sparx.Response.send_header(request, "trailer", "x-checksum")
+      :ok = Sparx.Response.write_chunk(request, body)
+      :ok = Sparx.Response.send_trailer(request, "x-checksum", checksum)
+      :ok = Sparx.Response.finish(request)
+
+  """
+  @spec send_trailer(request_handle(), String.t(), String.t()) :: :ok | {:error, term()}
+  def send_trailer(request_handle, name, value) when is_binary(name) and is_binary(value) do
+    Native.send_trailer(request_handle, name, value)
+  end
+
   @doc """
   Write a chunk of the response body.
 
-  Can be called multiple times to stream the response.
+  Can be called multiple times to stream the response. The status and headers
+  go out with the first chunk and each chunk is sent as it is written, so this
+  works for server-sent events and long downloads. Only a few chunks are held
+  by the server at a time: when the client reads slowly, calls block until it
+  catches up (see `try_write_chunk/2` to avoid blocking). Status and headers
+  sent after the first chunk are ignored.
+
+  `data` may be any iodata; iolists are flattened by the server, so there is
+  no need to call `IO.iodata_to_binary/1` first.
+
+  A streamed body is sent with chunked transfer encoding, unless a
+  `content-length` header was sent first: then it goes out with that length,
+  and bytes written past it are dropped. Bodies written in full before the
+  response starts (and `send/4`) always get a `content-length` of the bytes
+  actually written; a different declared length is only kept for a 304.
+
+  Returns `{:error, :overloaded}` when the server's `:memory_budget` has no room
+  for the chunk.
 
   ## Examples
 
       :ok = Sparx.Response.write_chunk(request, "Hello ")
-      :ok = Sparx.Response.write_chunk(request, "World!")
+      :ok = Sparx.Response.write_chunk(request, ["Wor", ?l, ["d" | "!"]])
 
   """
   @spec write_chunk(request_handle(), iodata()) :: :ok | {:error, term()}
   def write_chunk(request_handle, data) do
-    binary_data = IO.iodata_to_binary(data)
-    Native.write_chunk(request_handle, binary_data)
+    Native.write_chunk(request_handle, data)
+  end
+
+  @doc """
+  Write a chunk of the response body without waiting for a slow client.
+
+  Like `write_chunk/2`, except that when the client has not yet taken the
+  chunks already written it returns `{:error, :wait}` right away instead of
+  blocking. The chunk is not sent in that case; write it again later. Useful in
+  processes that must stay responsive to other messages while streaming.
+
+  ## Examples
+
+      case Sparx.Response.try_write_chunk(request, event) do
+        :ok -> :sent
+        {:error, :wait} -> :retry_later
+      end
+
+  """
+  @spec try_write_chunk(request_handle(), iodata()) :: :ok | {:error, term()}
+  def try_write_chunk(request_handle, data) do
+    Native.try_write_chunk(request_handle, data)
   end
 
   @doc """
@@ -79,7 +188,13 @@ defmodule Sparx.Response do
   @doc """
   Send a complete response (status, headers, and body) in one call.
 
-  This is a convenience function for simple responses.
+  The whole response is handed to the server in a single NIF call, so this is
+  the fastest way to answer a request whose body is already in memory. Use
+  `send_status/2`, `send_header/3`, and `write_chunk/2` to stream instead.
+
+  Returns `{:error, :overloaded}` when the server's `:memory_budget` has no room
+  for the body, and `{:error, reason}` naming the first invalid header without
+  sending anything.
 
   ## Examples
 
@@ -90,23 +205,96 @@ defmodule Sparx.Response do
   """
   @spec send(request_handle(), 100..599, [{String.t(), String.t()}], iodata()) ::
           :ok | {:error, term()}
-  def send(request_handle, status, headers \\ [], body \\ "") do
+  def send(request_handle, status, headers \\ [], body \\ "")
+      when is_integer(status) and status >= 100 and status <= 599 do
     require Logger
-    Logger.debug("Sending response: status=#{status}")
-
-    with :ok <- send_status(request_handle, status),
-         :ok <- send_headers(request_handle, headers),
-         :ok <- write_chunk(request_handle, body),
-         :ok <- finish(request_handle) do
-      Logger.debug("Response sent successfully")
-      :ok
-    else
+
+    case Native.send_response(request_handle, status, headers, body) do
+      :ok ->
+        :ok
+
       error ->
         Logger.error("Failed to send response: #{inspect(error)}")
         error
     end
   end
 
+  @doc """
+  Send a file as the rest of the response and finish it.
+
+  The file is read and streamed by the server, so its contents never pass
+  through the BEAM. A `content-length` header is added, and a `content-type`
+  inferred from the file's extension unless one was already sent (see the
+  `:mime_types` server option); send the status and any other headers first.
+
+  ## Options
+
+    * `:offset` - Byte to start from (default: 0)
+    * `:length` - Number of bytes to send, capped at the end of the file
+      (default: `nil`, up to the end)
+
+  Returns `{:error, :not_found}` if there is no regular file at `path` and
+  `{:error, :invalid_request}` if `:offset` lies past its end.
+
+  ## Examples
+
+      :ok = Sparx.Response.send_status(request, 200)
+      :ok = Sparx.Response.send_file(request, "priv/static/logo.png")
+
+  """
+  @spec send_file(request_handle(), Path.t(), keyword()) :: :ok | {:error, term()}
+  def send_file(request_handle, path, opts \\ []) do
+    offset = Keyword.get(opts, :offset, 0)
+    length = Keyword.get(opts, :length)
+    Native.send_file(request_handle, to_string(path), offset, length)
+  end
+
+  @doc """
+  Answer with a file, letting the server pick the status from the request.
+
+  Unlike `send_file/3`, the status is sent too:
+
+    * `304` if a `GET` or `HEAD` carries `If-None-Match` with the file's entity
+      tag, or `If-Modified-Since` no older than the file; the file is not opened
+    * `206` with a single byte range asked for in `Range` (with `content-range`),
+      unless `If-Range` names another version of the file
+    * `416` if the range lies past the end of the file
+    * `200` with the whole file otherwise
+
+  `etag`, `last-modified`, `accept-ranges`, and `content-length` are sent by
+  the server, as is `content-type` from the file's extension unless the
+  handler already sent one; send any other headers first.
+
+  ## Options
+
+    * `:etag` - `:weak` for a tag from the file's size and modification time,
+      `:strong` for one from a hash of its contents (read in full on every
+      request), or `:none` (default: `:weak`)
+    * `:precompressed` - Send a `.br` or `.gz` file next to `path` in its place,
+      with a `content-encoding`, to clients that accept it; Brotli is tried first
+      and the original is sent if neither exists (default: `false`)
+
+  ## Examples
+
+      :ok = Sparx.Response.serve_file(request, "priv/videos/intro.mp4")
+
+      :ok = Sparx.Response.send_headers(request, [{"content-type", "text/plain"}])
+      :ok = Sparx.Response.serve_file(request, "priv/docs/README")
+
+      # Sends priv/static/app.js.br or .gz when present and accepted
+      :ok = Sparx.Response.serve_file(request, "priv/static/app.js", precompressed: true)
+
+  """
+  @spec serve_file(request_handle(), Path.t(), keyword()) :: :ok | {:error, term()}
+  def serve_file(request_handle, path, opts \\ []) do
+    options = %{
+      etag: Keyword.get(opts, :etag, :weak),
+      precompressed: Keyword.get(opts, :precompressed, false)
+    }
+
+    Native.serve_file(request_handle, to_string(path), options)
+  end
+
   @doc """
   Send a JSON response.
 
@@ -159,13 +347,23 @@ defmodule Sparx.Response do
     send(request_handle, status, [{"content-type", "text/html; charset=utf-8"}], html)
   end
 
-  # Private helper to send multiple headers
-  defp send_headers(request_handle, headers) do
-    Enum.reduce_while(headers, :ok, fn {name, value}, :ok ->
-      case send_header(request_handle, name, value) do
-        :ok -> {:cont, :ok}
-        error -> {:halt, error}
-      end
-    end)
+  @doc """
+  Decline a WebSocket upgrade with an ordinary HTTP response.
+
+  Use this instead of `send/4` to refuse an upgrade request, e.g. after
+  checking its credentials: the connection stays in HTTP mode and the request
+  can no longer be upgraded. Returns `{:error, :not_supported}` if the request
+  is not an upgrade or has already been upgraded.
+
+  ## Examples
+
+      Sparx.Response.reject_upgrade(request, 401, [{"www-authenticate", "Bearer"}], "")
+
+  """
+  @spec reject_upgrade(request_handle(), 200..599, [{String.t(), String.t()}], iodata()) ::
+          :ok | {:error, term()}
+  def reject_upgrade(request_handle, status, headers \\ [], body \\ "")
+      when is_integer(status) and status >= 200 and status <= 599 do
+    Native.reject_upgrade(request_handle, status, headers, body)
   end
 end
@@ -0,0 +1,74 @@
+defmodule Sparx.SSE do
+  @moduledoc """
+  Server-Sent Events.
+
+  `init/2` answers the request with a `text/event-stream` response and sends
+  its head straight away; events are then sent one by one with `send_event/2`
+  and the stream is ended with `Sparx.Response.finish/1`. Events are formatted
+  in Rust, with multi-line data split into one `data:` field per line.
+
+  ## Examples
+
+      :ok = Sparx.SSE.init(request, keepalive: 15_000)
+      :ok = Sparx.SSE.send_event(request, event: "price", data: ~s({"usd": 42}))
+      :ok = Sparx.SSE.send_event(request, id: "7", data: "line one\\nline two")
+      :ok = Sparx.Response.finish(request)
+
+  """
+
+  alias Sparx.Native
+
+  @type request_handle :: reference()
+
+  @type event ::
+          [id: String.t(), event: String.t(), data: String.t(), retry: non_neg_integer()]
+          | %{
+              optional(:id) => String.t(),
+              optional(:event) => String.t(),
+              optional(:data) => String.t(),
+              optional(:retry) => non_neg_integer()
+            }
+
+  @doc """
+  Start an event stream.
+
+  Sends status 200 with `content-type: text/event-stream` and
+  `cache-control: no-cache`, unless `:headers` set those.
+
+  ## Options
+
+    * `:headers` - Additional response headers (default: `[]`)
+    * `:keepalive` - Write a `:keepalive` comment this often, in milliseconds, so
+      proxies do not close a quiet stream; comments are ignored by clients
+      (default: `nil`, none)
+  """
+  @spec init(request_handle(), keyword()) :: :ok | {:error, term()}
+  def init(request_handle, opts \\ []) do
+    headers = Keyword.get(opts, :headers, [])
+    keepalive = Keyword.get(opts, :keepalive)
+    Native.sse_init(request_handle, headers, keepalive)
+  end
+
+  @doc """
+  Send an event on a stream started with `init/2`.
+
+  Every field is optional: `:id` sets the client's last event ID, `:event`
+  its type (`"message"` if unset), `:data` its payload, and `:retry` the
+  client's reconnection delay in milliseconds. `:id` and `:event` may not
+  contain line breaks.
+
+  Returns `{:error, :overloaded}` when the server's `:memory_budget` has no room
+  for the event.
+  """
+  @spec send_event(request_handle(), event()) :: :ok | {:error, term()}
+  def send_event(request_handle, event) do
+    event = Map.new(event)
+
+    Native.sse_send_event(request_handle, %{
+      id: Map.get(event, :id),
+      event: Map.get(event, :event),
+      data: Map.get(event, :data),
+      retry: Map.get(event, :retry)
+    })
+  end
+end
@@ -0,0 +1,85 @@
+defmodule Sparx.Telemetry do
+  @moduledoc """
+  `:telemetry` events for requests and connections.
+
+  Start a server with `telemetry: true` and the native side reports each
+  request and connection to a forwarder process, which emits:
+
+    * `[:sparx, :request, :start]` - A request head has arrived.
+      Measurements: `:system_time`, `:monotonic_time`.
+      Metadata: `:server`, `:method`, `:path`.
+    * `[:sparx, :request, :stop]` - The response head is ready.
+      Measurements: `:duration`, plus `:accept_to_queue`, `:queue_to_pickup`, and
+      `:pickup_to_first_byte` for the phases the request went through.
+      Metadata: `:server`, `:method`, `:path`, `:status`.
+    * `[:sparx, :request, :exception]` - The server answered with a 5xx of its own,
+      e.g. because the handler did not respond within `:request_timeout_ms`. Same
+      measurements as `:stop`.
+      Metadata: as for `:stop`, plus `:kind` (always `:error`) and `:reason`, the
+      error kind as reported in `Sparx.stats/1`.
+    * `[:sparx, :connection, :start]` - A connection was accepted.
+      Measurements: `:system_time`, `:monotonic_time`.
+      Metadata: `:server`, `:peer`.
+    * `[:sparx, :connection, :stop]` - The connection closed.
+      Measurements: `:duration`. Metadata: `:server`, `:peer`.
+
+  Durations are in `:native` time units, as is conventional for `:telemetry`;
+  convert them with `System.convert_time_unit/3`.
+
+  ## Examples
+
+      :telemetry.attach(
+        "log-slow-requests",
+        [:sparx, :request, :stop],
+        fn _event, %{duration: duration}, %{path: path}, _config ->
+          if System.convert_time_unit(duration, :native, :millisecond) > 500 do
+            Logger.warning("Slow request to \#{path}")
+          end
+        end,
+        nil
+      )
+
+  """
+
+  @doc false
+  def start_link(server) do
+    spawn_link(fn -> loop(server) end)
+  end
+
+  defp loop(server) do
+    receive do
+      {:sparx_telemetry, event, measurements, metadata} ->
+        emit(server, event, measurements, metadata)
+        loop(server)
+    end
+  end
+
+  defp emit(server, event, measurements, metadata) do
+    measurements =
+      case event do
+        event when event in [:request_start, :connection_start] ->
+          %{system_time: System.system_time(), monotonic_time: System.monotonic_time()}
+
+        _ ->
+          for {key, micros} <- measurements, micros != nil, into: %{} do
+            {key, System.convert_time_unit(micros, :microsecond, :native)}
+          end
+      end
+
+    metadata =
+      for {key, value} <- metadata, value != nil, into: %{server: server} do
+        {key, value}
+      end
+
+    metadata =
+      if event == :request_exception, do: Map.put(metadata, :kind, :error), else: metadata
+
+    :telemetry.execute(event_name(event), measurements, metadata)
+  end
+
+  defp event_name(:request_start), do: [:sparx, :request, :start]
+  defp event_name(:request_stop), do: [:sparx, :request, :stop]
+  defp event_name(:request_exception), do: [:sparx, :request, :exception]
+  defp event_name(:connection_start), do: [:sparx, :connection, :start]
+  defp event_name(:connection_stop), do: [:sparx, :connection, :stop]
+end
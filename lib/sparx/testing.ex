@@ -0,0 +1,216 @@
+defmodule Sparx.Testing do
+  @moduledoc """
+  In-memory connections for exercising a server from tests.
+
+  A server started with `transport: :memory` binds no socket. Instead, tests
+  open connections with `connect/1` and drive them with raw HTTP bytes; the
+  bytes go through the same hyper, queue, and response pipeline as a TCP
+  connection.
+
+  ## Examples
+
+      {:ok, server} = Sparx.start_link(handler: handler, transport: :memory)
+      {:ok, conn} = Sparx.Testing.connect(server)
+
+      request = "GET / HTTP/1.1\\r\\nhost: test\\r\\nconnection: close\\r\\n\\r\\n"
+      :ok = Sparx.Testing.write(conn, request)
+      {:ok, response} = Sparx.Testing.read_all(conn)
+
+  ## Simulated time
+
+  With `runtime_profile: :simulation` as well, the server's clock stands still
+  until `advance/2` moves it, so request timeouts and other deadlines can be
+  tested without sleeping:
+
+      {:ok, server} =
+        Sparx.start_link(
+          handler: slow_handler,
+          transport: :memory,
+          runtime_profile: :simulation,
+          request_timeout_ms: 1_000
+        )
+
+      {:ok, conn} = Sparx.Testing.connect(server)
+      :ok = Sparx.Testing.write(conn, request)
+      :ok = Sparx.Testing.advance(server, 1_000)
+      {:ok, "HTTP/1.1 503" <> _} = Sparx.Testing.read(conn)
+
+  ## Injected requests
+
+  `inject/5` skips HTTP parsing altogether: it queues a request built from
+  its arguments exactly as if it had been read from a socket, and
+  `await_response/2` returns the response the handler sent for it. Injection
+  works with either transport.
+
+      {:ok, capture} = Sparx.Testing.inject(server, "POST", "/echo", [], "ping")
+      {:ok, %{status: 200, body: "ping"}} = Sparx.Testing.await_response(capture)
+
+  ## Captured responses
+
+  Inside a handler, `capture/1` takes the response away from the socket:
+  everything the handler sends afterwards is built by the Rust layer exactly
+  as it would be for the client, then kept for inspection with
+  `captured_status/1`, `captured_headers/1`, and `captured_body/1`. The
+  client itself receives an empty response.
+
+      handler = fn request ->
+        {:ok, capture} = Sparx.Testing.capture(request)
+        send(test_pid, {:capture, capture})
+        MyApp.handle_request(request)
+      end
+
+      assert_receive {:capture, capture}
+      {:ok, _} = Sparx.Testing.await_response(capture)
+      assert {:ok, 200} = Sparx.Testing.captured_status(capture)
+
+  The simulation profile needs the native crate built with its `simulation` feature:
+
+      config :sparx, Sparx.Native, features: ["simulation"]
+
+  """
+
+  alias Sparx.Native
+
+  @type connection :: reference()
+  @type capture :: reference()
+
+  @type captured_response :: %{
+          status: non_neg_integer(),
+          headers: [{String.t(), String.t()}],
+          body: binary()
+        }
+
+  @doc """
+  Open an in-memory connection to `server`.
+
+  Returns `{:error, :not_supported}` unless the server runs with `transport: :memory`,
+  `{:error, :paused}` while the server is paused with `Sparx.pause/1`, and
+  `{:error, :closed}` once it is draining.
+  """
+  @spec connect(Sparx.server_ref()) ::
+          {:ok, connection()} | {:error, :not_supported | :paused | :closed}
+  def connect(server) do
+    GenServer.call(server, :test_connect)
+  end
+
+  @doc """
+  Write raw client bytes to the connection.
+  """
+  @spec write(connection(), iodata()) :: :ok | {:error, :closed | :connection_closed}
+  def write(conn, data) do
+    Native.test_write(conn, data)
+  end
+
+  @doc """
+  Read the bytes the server has written, waiting up to `timeout` ms for some.
+
+  Returns `:eof` once the server has closed the connection.
+  """
+  @spec read(connection(), non_neg_integer()) ::
+          {:ok, binary()} | :eof | {:error, :timeout | :connection_closed}
+  def read(conn, timeout \\ 5_000) do
+    case Native.test_read(conn, timeout) do
+      {:ok, ""} -> :eof
+      other -> other
+    end
+  end
+
+  @doc """
+  Read until the server closes the connection and return everything it wrote.
+  """
+  @spec read_all(connection(), non_neg_integer()) ::
+          {:ok, binary()} | {:error, :timeout | :connection_closed}
+  def read_all(conn, timeout \\ 5_000) do
+    read_all(conn, timeout, [])
+  end
+
+  defp read_all(conn, timeout, acc) do
+    case read(conn, timeout) do
+      {:ok, data} -> read_all(conn, timeout, [acc | data])
+      :eof -> {:ok, IO.iodata_to_binary(acc)}
+      error -> error
+    end
+  end
+
+  @doc """
+  Move the clock of a server started with `runtime_profile: :simulation` forward
+  by `ms` milliseconds.
+
+  Timers that come due fire before this returns. Returns `{:error, :not_supported}`
+  for servers running in real time.
+  """
+  @spec advance(Sparx.server_ref(), non_neg_integer()) :: :ok | {:error, :not_supported}
+  def advance(server, ms) when is_integer(ms) and ms >= 0 do
+    GenServer.call(server, {:sim_advance, ms})
+  end
+
+  @doc """
+  Queue a request on `server` as if it had arrived over the network.
+
+  The request goes through priority classification, queueing, and the
+  handler like any other. Returns a capture to read the response from with
+  `await_response/2`.
+  """
+  @spec inject(
+          Sparx.server_ref(),
+          String.t(),
+          String.t(),
+          [{String.t(), String.t()}],
+          iodata()
+        ) :: {:ok, capture()} | {:error, :invalid_request | :closed}
+  def inject(server, method, path, headers \\ [], body \\ "") do
+    GenServer.call(server, {:inject_request, method, path, headers, IO.iodata_to_binary(body)})
+  end
+
+  @doc """
+  Wait up to `timeout` ms for the response to an injected request.
+  """
+  @spec await_response(capture(), non_neg_integer()) ::
+          {:ok, captured_response()} | {:error, :timeout | :server_error}
+  def await_response(capture, timeout \\ 5_000) do
+    Native.await_response(capture, timeout)
+  end
+
+  @doc """
+  Capture the rest of `request`'s response instead of writing it to the socket.
+
+  Call it before the handler sends anything. Returns `{:error, :already_sent}`
+  once any part of the response has been sent.
+  """
+  @spec capture(Sparx.Request.request_handle()) :: {:ok, capture()} | {:error, :already_sent}
+  def capture(request) do
+    Native.capture_response(request)
+  end
+
+  @doc """
+  Status code of a captured response.
+
+  Returns `{:error, :pending}` until the handler has finished the response.
+  """
+  @spec captured_status(capture()) :: {:ok, non_neg_integer()} | {:error, atom()}
+  def captured_status(capture), do: Native.captured_status(capture)
+
+  @doc """
+  Headers of a captured response, as emitted by the Rust layer.
+
+  Returns `{:error, :pending}` until the handler has finished the response.
+  """
+  @spec captured_headers(capture()) :: {:ok, [{String.t(), String.t()}]} | {:error, atom()}
+  def captured_headers(capture), do: Native.captured_headers(capture)
+
+  @doc """
+  Body of a captured response.
+
+  Returns `{:error, :pending}` until the handler has finished the response.
+  """
+  @spec captured_body(capture()) :: {:ok, binary()} | {:error, atom()}
+  def captured_body(capture), do: Native.captured_body(capture)
+
+  @doc """
+  Half-close the client side, as a client shutting down its socket for writing.
+  """
+  @spec close(connection()) :: :ok
+  def close(conn) do
+    Native.test_close(conn)
+  end
+end
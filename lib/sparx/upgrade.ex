@@ -0,0 +1,60 @@
+defmodule Sparx.Upgrade do
+  @moduledoc """
+  Raw protocol upgrades.
+
+  For protocols other than WebSocket (tunnels, custom RPC), a request carrying
+  an `Upgrade` header can be answered with `101 Switching Protocols`, after
+  which the connection is read and written as a plain byte stream. WebSocket
+  upgrades go through `Sparx.WebSocket.upgrade/2` instead.
+
+  ## Examples
+
+      {:ok, stream} = Sparx.Upgrade.upgrade(request)
+      {:ok, data} = Sparx.Upgrade.read(stream)
+      :ok = Sparx.Upgrade.write(stream, data)
+
+  """
+
+  alias Sparx.Native
+
+  @type request_handle :: reference()
+  @type raw_stream :: reference()
+
+  @doc """
+  Switch protocols, echoing the request's `Upgrade` header in the 101 response.
+
+  Returns `{:error, reason}` if the request is not an upgrade or the response
+  has already started.
+  """
+  @spec upgrade(request_handle()) :: {:ok, raw_stream()} | {:error, String.t()}
+  def upgrade(request_handle) do
+    Native.upgrade_raw(request_handle)
+  end
+
+  @doc """
+  Read up to `max_bytes` from the client, waiting for at least one byte.
+
+  A single read returns at most 64KB, however large `max_bytes` is. Returns
+  an empty binary once the client has closed its side.
+  """
+  @spec read(raw_stream(), pos_integer()) :: {:ok, binary()} | {:error, atom()}
+  def read(stream, max_bytes \\ 65_536) when is_integer(max_bytes) and max_bytes > 0 do
+    Native.raw_read(stream, max_bytes)
+  end
+
+  @doc """
+  Write data to the client.
+  """
+  @spec write(raw_stream(), iodata()) :: :ok | {:error, atom()}
+  def write(stream, data) do
+    Native.raw_write(stream, data)
+  end
+
+  @doc """
+  Close the server's side of the connection.
+  """
+  @spec close(raw_stream()) :: :ok
+  def close(stream) do
+    Native.raw_close(stream)
+  end
+end
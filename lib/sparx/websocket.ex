@@ -0,0 +1,134 @@
+defmodule Sparx.WebSocket do
+  @moduledoc """
+  WebSocket upgrades and broadcast topics.
+
+  Frames are sent and received on the returned handle with the
+  `Sparx.Native.ws_*` functions.
+
+  Sockets can join topics, and a frame broadcast to a topic is sent to every
+  socket in it from Rust, without a message per socket on the BEAM. Sockets
+  leave their topics when they close or their handle is garbage collected.
+  """
+
+  alias Sparx.Native
+
+  @type request_handle :: reference()
+  @type ws_handle :: reference()
+
+  @doc """
+  Upgrade a request to a WebSocket connection.
+
+  Returns the WebSocket handle together with the negotiated subprotocol, or
+  `nil` if none was agreed on.
+
+  ## Options
+
+    * `:protocols` - Subprotocols the server speaks; the first one offered by the
+      client is chosen and echoed in `Sec-WebSocket-Protocol` (default: `[]`)
+    * `:max_message_size` - Largest message accepted, in bytes; bigger ones close the
+      connection with status 1009 and fail receives with `:body_too_large`
+      (default: `nil`, 64MB)
+    * `:max_frame_size` - Largest single frame accepted, in bytes (default: `nil`, 16MB)
+    * `:write_buffer_size` - Outgoing bytes buffered before they are written to the
+      socket (default: `nil`, 128KB)
+    * `:max_write_buffer_size` - Outgoing bytes buffered before sends fail, which must
+      be larger than `:write_buffer_size` (default: `nil`, unlimited)
+    * `:ping_interval_ms` - Ping the client this often (default: `nil`, no pings)
+    * `:pong_timeout_ms` - With pings on, close the connection once nothing has been
+      received for this long; receives then fail with `:timeout` (default: the ping
+      interval). Frames are only noticed while they are being received, with `ws_recv`
+      or an owner set with `set_owner/2`
+    * `:send_queue_size` - Outgoing frames queued for the client before
+      `:send_overflow` applies (default: 64)
+    * `:send_overflow` - What a send does when the queue is full: `:block` waits for
+      room, `:drop` discards the frame and returns `{:error, :dropped}`, and `:close`
+      closes the connection and returns `{:error, :closed}` (default: `:block`)
+
+  ## Examples
+
+      {:ok, {ws, "graphql-ws"}} =
+        Sparx.WebSocket.upgrade(request, protocols: ["graphql-ws"], max_message_size: 1_048_576)
+
+  """
+  @spec upgrade(request_handle(), keyword()) ::
+          {:ok, {ws_handle(), String.t() | nil}} | {:error, term()}
+  def upgrade(request_handle, opts \\ []) do
+    options = %{
+      protocols: Keyword.get(opts, :protocols, []),
+      max_message_size: Keyword.get(opts, :max_message_size),
+      max_frame_size: Keyword.get(opts, :max_frame_size),
+      write_buffer_size: Keyword.get(opts, :write_buffer_size),
+      max_write_buffer_size: Keyword.get(opts, :max_write_buffer_size),
+      ping_interval_ms: Keyword.get(opts, :ping_interval_ms),
+      pong_timeout_ms: Keyword.get(opts, :pong_timeout_ms),
+      send_queue_size: Keyword.get(opts, :send_queue_size, 64),
+      send_overflow: Keyword.get(opts, :send_overflow, :block)
+    }
+
+    Native.upgrade_websocket(request_handle, options)
+  end
+
+  @doc """
+  Push incoming frames to `pid` instead of receiving them with `Sparx.Native.ws_recv/1`.
+
+  `pid` receives `{:sparx_ws, ref, {kind, data}}` for each frame, then
+  `{:sparx_ws, ref, :close}` or `{:sparx_ws, ref, {:error, reason}}` when the
+  connection ends. A socket has one owner for good: later calls, and
+  `Sparx.Native.ws_recv/1`, return `{:error, :owned}`.
+
+  ## Examples
+
+      {:ok, ref} = Sparx.WebSocket.set_owner(ws, channel_pid)
+
+  """
+  @spec set_owner(ws_handle(), pid()) :: {:ok, reference()} | {:error, :owned}
+  def set_owner(ws_handle, pid \\ self()) when is_pid(pid) do
+    Native.ws_set_owner(ws_handle, pid)
+  end
+
+  @doc """
+  Number of frames queued for the client and not yet written.
+
+  Sends return once a frame is queued, so a growing queue is the sign of a
+  client that cannot keep up.
+  """
+  @spec queue_depth(ws_handle()) :: non_neg_integer()
+  def queue_depth(ws_handle) do
+    Native.ws_queue_depth(ws_handle)
+  end
+
+  @doc """
+  Subscribe a socket to a broadcast topic.
+  """
+  @spec join(ws_handle(), String.t()) :: :ok
+  def join(ws_handle, topic) when is_binary(topic) do
+    Native.ws_join(ws_handle, topic)
+  end
+
+  @doc """
+  Unsubscribe a socket from a broadcast topic.
+  """
+  @spec leave(ws_handle(), String.t()) :: :ok
+  def leave(ws_handle, topic) when is_binary(topic) do
+    Native.ws_leave(ws_handle, topic)
+  end
+
+  @doc """
+  Send a frame to every socket subscribed to `topic` on `server`.
+
+  Returns the number of sockets the frame is sent to. The sends run
+  concurrently and are not waited for, so a slow client holds up neither the
+  caller nor other sockets; sockets found closed leave their topics.
+
+  ## Examples
+
+      {:ok, 1200} = Sparx.WebSocket.broadcast(server, "room:lobby", {:text, ~s({"event":"ping"})})
+
+  """
+  @spec broadcast(GenServer.server(), String.t(), {:text | :binary, iodata()}) ::
+          {:ok, non_neg_integer()} | {:error, term()}
+  def broadcast(server, topic, {kind, _data} = frame)
+      when is_binary(topic) and kind in [:text, :binary] do
+    GenServer.call(server, {:ws_broadcast, topic, frame})
+  end
+end
@@ -81,7 +81,21 @@ defmodule Sparx.MixProject do
           Sparx.WebSocket
         ],
         Configuration: [
-          Sparx.Config
+          Sparx.Config,
+          Sparx.Config.AccessLog,
+          Sparx.Config.Faults,
+          Sparx.Config.Listener,
+          Sparx.Config.Tls
+        ],
+        Diagnostics: [
+          Sparx.Profiler,
+          Sparx.Telemetry,
+          Sparx.Logger
+        ],
+        Testing: [
+          Sparx.Testing,
+          Sparx.DevCert,
+          Sparx.Bench
         ]
       ]
     ]
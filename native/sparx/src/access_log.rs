@@ -0,0 +1,448 @@
+use crate::atoms;
+use crate::inspector::push_json_str;
+use crate::server::ServerContext;
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::{Method, Request, Version};
+use rustler::{Encoder, LocalPid, NifStruct, NifUnitEnum, OwnedEnv};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
+
+/// Lines waiting for the writer; past this, new entries are dropped
+const QUEUE_CAPACITY: usize = 8192;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Layout of each access log line
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// NCSA Common Log Format
+    Common,
+    /// Common Log Format plus the Referer and User-Agent
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where and how a server writes its access log
+///
+/// Exactly one of `path` and `pid` must be set.
+#[derive(NifStruct, Clone, Debug)]
+#[module = "Sparx.Config.AccessLog"]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    /// File lines are appended to, created if missing
+    pub path: Option<String>,
+    /// Process each line is sent to as `{:sparx_access_log, line}`
+    pub pid: Option<LocalPid>,
+}
+
+impl AccessLogConfig {
+    /// Check the settings without touching the file system
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.path, &self.pid) {
+            (Some(_), Some(_)) => Err("set either a path or a pid, not both".to_string()),
+            (None, None) => Err("needs a path or a pid to write to".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Destination the writer task owns
+enum Sink {
+    File(tokio::io::BufWriter<tokio::fs::File>),
+    Pid(LocalPid),
+}
+
+/// Access log for one server
+///
+/// Entries are formatted where the request finishes and handed to a writer
+/// task, so a slow disk or a busy log process never holds up a response.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    tx: mpsc::Sender<String>,
+    /// Taken by `run_writer` when the server starts
+    writer: Mutex<Option<(mpsc::Receiver<String>, Sink)>>,
+}
+
+/// One request as it appears in the log
+pub struct Entry {
+    pub peer: String,
+    /// Method, target, and headers; None if the request never parsed
+    pub request: Option<RequestLine>,
+    pub status: u16,
+    /// Response body bytes sent; None if no response was written by us
+    pub bytes: Option<u64>,
+    pub duration: Duration,
+}
+
+/// The parts of a request the log needs, taken before hyper hands the
+/// request over
+pub struct RequestLine {
+    method: Method,
+    target: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestLine {
+    pub fn of<B>(req: &Request<B>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        Self {
+            method: req.method().clone(),
+            target: req.uri().to_string(),
+            version: req.version(),
+            referer: header(hyper::header::REFERER),
+            user_agent: header(hyper::header::USER_AGENT),
+        }
+    }
+}
+
+impl AccessLog {
+    /// Open the log's destination, so a bad path stops `server_start`
+    pub fn open(config: &AccessLogConfig) -> Result<Self, String> {
+        config.validate()?;
+        let sink = match (&config.path, &config.pid) {
+            (Some(path), _) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Failed to open access log {}: {}", path, e))?;
+                Sink::File(tokio::io::BufWriter::new(tokio::fs::File::from_std(file)))
+            }
+            (None, Some(pid)) => Sink::Pid(*pid),
+            (None, None) => unreachable!("validated above"),
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        Ok(Self {
+            format: config.format,
+            tx,
+            writer: Mutex::new(Some((rx, sink))),
+        })
+    }
+
+    /// Queue an entry for the writer
+    pub fn log(&self, entry: Entry) {
+        let line = match self.format {
+            AccessLogFormat::Common => entry.common(false),
+            AccessLogFormat::Combined => entry.common(true),
+            AccessLogFormat::Json => entry.json(),
+        };
+        // A writer that cannot keep up loses lines rather than slowing
+        // requests down
+        let _ = self.tx.try_send(line);
+    }
+
+    /// Wrap a response body so the entry is logged once the body is done
+    ///
+    /// Dropping the body early (a client that goes away) logs what was sent.
+    pub fn wrap<B>(
+        self: &Arc<Self>,
+        body: B,
+        peer: &str,
+        request: RequestLine,
+        status: u16,
+        started: Instant,
+    ) -> LoggedBody<B> {
+        LoggedBody {
+            inner: body,
+            log: self.clone(),
+            pending: Some(Entry {
+                peer: peer_ip(peer),
+                request: Some(request),
+                status,
+                bytes: None,
+                duration: Duration::ZERO,
+            }),
+            sent: 0,
+            started,
+        }
+    }
+}
+
+/// Write queued lines until the server stops, flushing whenever the queue
+/// runs dry
+pub async fn run_writer(context: Arc<ServerContext>, mut shutdown_rx: watch::Receiver<bool>) {
+    let Some(log) = &context.access_log else {
+        return;
+    };
+    let Some((mut rx, mut sink)) = log.writer.lock().ok().and_then(|mut w| w.take()) else {
+        return;
+    };
+    loop {
+        tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => sink.write(line).await,
+                None => break,
+            },
+            _ = shutdown_rx.wait_for(|stop| *stop) => break,
+        }
+        while let Ok(line) = rx.try_recv() {
+            sink.write(line).await;
+        }
+        sink.flush().await;
+    }
+    // Requests answered just before the stop still get their lines
+    while let Ok(line) = rx.try_recv() {
+        sink.write(line).await;
+    }
+    sink.flush().await;
+}
+
+impl Sink {
+    async fn write(&mut self, mut line: String) {
+        match self {
+            Sink::File(file) => {
+                line.push('\n');
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    tracing::error!("Failed to write access log: {}", e);
+                }
+            }
+            Sink::Pid(pid) => {
+                let mut env = OwnedEnv::new();
+                let _ =
+                    env.send_and_clear(pid, |env| (atoms::sparx_access_log(), line).encode(env));
+            }
+        }
+    }
+
+    async fn flush(&mut self) {
+        if let Sink::File(file) = self {
+            if let Err(e) = file.flush().await {
+                tracing::error!("Failed to flush access log: {}", e);
+            }
+        }
+    }
+}
+
+impl Entry {
+    /// Common Log Format, with the Referer and User-Agent if `combined`
+    fn common(&self, combined: bool) -> String {
+        let mut out = String::with_capacity(128);
+        let _ = write!(out, "{} - - [", self.peer);
+        push_clf_time(&mut out, SystemTime::now());
+        out.push_str("] ");
+        match &self.request {
+            Some(request) => {
+                out.push('"');
+                push_clf_raw(&mut out, request.method.as_str());
+                out.push(' ');
+                push_clf_raw(&mut out, &request.target);
+                let _ = write!(out, " {:?}\"", request.version);
+            }
+            None => out.push_str("\"-\""),
+        }
+        let _ = write!(out, " {} ", self.status);
+        match self.bytes {
+            Some(bytes) => {
+                let _ = write!(out, "{}", bytes);
+            }
+            None => out.push('-'),
+        }
+        if combined {
+            let (referer, user_agent) = match &self.request {
+                Some(request) => (request.referer.as_deref(), request.user_agent.as_deref()),
+                None => (None, None),
+            };
+            out.push(' ');
+            push_clf_str(&mut out, referer);
+            out.push(' ');
+            push_clf_str(&mut out, user_agent);
+        }
+        out
+    }
+
+    fn json(&self) -> String {
+        let mut out = String::with_capacity(256);
+        out.push_str("{\"time\":");
+        let (year, month, day, hour, minute, second) = utc(SystemTime::now());
+        let _ = write!(
+            out,
+            "\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\"",
+            year, month, day, hour, minute, second
+        );
+        out.push_str(",\"peer\":");
+        push_json_str(&mut out, &self.peer);
+        let request = self.request.as_ref();
+        let fields = [
+            ("method", request.map(|r| r.method.as_str().to_string())),
+            ("target", request.map(|r| r.target.clone())),
+            ("version", request.map(|r| format!("{:?}", r.version))),
+            ("referer", request.and_then(|r| r.referer.clone())),
+            ("user_agent", request.and_then(|r| r.user_agent.clone())),
+        ];
+        for (name, value) in fields {
+            let _ = write!(out, ",\"{}\":", name);
+            match value {
+                Some(value) => push_json_str(&mut out, &value),
+                None => out.push_str("null"),
+            }
+        }
+        let _ = write!(out, ",\"status\":{},\"bytes\":", self.status);
+        match self.bytes {
+            Some(bytes) => {
+                let _ = write!(out, "{}", bytes);
+            }
+            None => out.push_str("null"),
+        }
+        let _ = write!(
+            out,
+            ",\"duration_us\":{}}}",
+            self.duration.as_micros() as u64
+        );
+        out
+    }
+}
+
+/// The address part of a peer, without the port
+pub fn peer_ip(peer: &str) -> String {
+    match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC
+fn push_clf_time(out: &mut String, time: SystemTime) {
+    let (year, month, day, hour, minute, second) = utc(time);
+    let _ = write!(
+        out,
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    );
+}
+
+/// Calendar date and time of day in UTC
+fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds = secs % 86_400;
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+/// Year, month, and day of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// A quoted field, or `"-"` if missing
+fn push_clf_str(out: &mut String, value: Option<&str>) {
+    out.push('"');
+    match value {
+        Some(value) => push_clf_raw(out, value),
+        None => out.push('-'),
+    }
+    out.push('"');
+}
+
+/// Escape quotes, backslashes, and control characters so a client cannot
+/// forge log lines
+fn push_clf_raw(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// Response body that logs its request once the body ends or is dropped
+pub struct LoggedBody<B> {
+    inner: B,
+    log: Arc<AccessLog>,
+    /// Taken when the entry is logged
+    pending: Option<Entry>,
+    sent: u64,
+    started: Instant,
+}
+
+impl<B> LoggedBody<B> {
+    fn finish(&mut self) {
+        if let Some(mut entry) = self.pending.take() {
+            entry.bytes = Some(self.sent);
+            entry.duration = self.started.elapsed();
+            self.log.log(entry);
+        }
+    }
+}
+
+impl<B> Body for LoggedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.sent += data.len() as u64;
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -10,8 +10,24 @@ rustler::atoms! {
     invalid_request,
     server_error,
     already_started,
+    already_sent,
     not_started,
     connection_closed,
+    not_supported,
+    overloaded,
+    wait,
+    parse_error,
+    connection_reset,
+    body_too_large,
+    too_large,
+    h2_protocol_error,
+    connection_error,
+    paused,
+    eaddrinuse,
+    eacces,
+    eaddrnotavail,
+    einval,
+    eafnosupport,
 
     // HTTP methods
     get,
@@ -23,11 +39,14 @@ rustler::atoms! {
     options,
     connect,
     trace,
+    other,
 
     // HTTP versions
+    http_0_9,
     http_1_0,
     http_1_1,
     http_2,
+    http_3,
 
     // WebSocket
     text,
@@ -36,4 +55,41 @@ rustler::atoms! {
     pong,
     close,
     closed,
+    owned,
+    dropped,
+    sparx_ws,
+
+    // Listeners
+    default,
+    tcp4,
+    tcp6,
+    udp4,
+    udp6,
+    unix,
+
+    // Response capture
+    pending,
+
+    // Request monitors
+    sparx_request_closed,
+
+    // Request dispatch
+    sparx_request,
+
+    // Telemetry
+    sparx_telemetry,
+    request_start,
+    request_stop,
+    request_exception,
+    connection_start,
+    connection_stop,
+
+    // Access log
+    sparx_access_log,
+
+    // Log forwarding
+    sparx_log,
+    warning,
+    info,
+    debug,
 }
@@ -0,0 +1,198 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::client::conn::http1::SendRequest;
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use rustler::NifMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// Pause after a failed connect so a dead server doesn't spin the workers
+const RECONNECT_DELAY: Duration = Duration::from_millis(10);
+
+/// Result of a load test
+#[derive(NifMap)]
+pub struct BenchReport {
+    /// Responses received, whatever their status
+    pub requests: u64,
+    /// Responses with a status outside 2xx
+    pub non_2xx: u64,
+    /// Failed connects and requests
+    pub errors: u64,
+    /// Response body bytes received
+    pub bytes: u64,
+    pub duration_ms: u64,
+    pub requests_per_sec: f64,
+    pub latency_us: LatencySummary,
+}
+
+/// Response latency distribution, in microseconds
+#[derive(NifMap, Default)]
+pub struct LatencySummary {
+    pub min: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[derive(Default)]
+struct WorkerStats {
+    non_2xx: u64,
+    errors: u64,
+    bytes: u64,
+    latencies: Vec<u64>,
+}
+
+/// Load-test `url` with `concurrency` keep-alive connections for `duration`
+///
+/// Like wrk, each connection sends GET requests back to back, waiting for
+/// each response before sending the next. Runs on the caller's runtime, so
+/// it can share one with the server it measures. Only `http://` URLs are
+/// supported.
+pub async fn run(url: &str, concurrency: usize, duration: Duration) -> Result<BenchReport, String> {
+    let uri: Uri = url.parse().map_err(|e| format!("Invalid URL: {}", e))?;
+    if uri.scheme_str() != Some("http") {
+        return Err("Only http:// URLs are supported".to_string());
+    }
+    let host = uri.host().ok_or("URL has no host")?.to_string();
+    let port = uri.port_u16().unwrap_or(80);
+    let authority = uri
+        .authority()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| host.clone());
+    let path: Uri = uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/")
+        .parse()
+        .map_err(|e| format!("Invalid URL: {}", e))?;
+
+    let started = Instant::now();
+    let deadline = started + duration;
+    let workers: Vec<_> = (0..concurrency.max(1))
+        .map(|_| {
+            tokio::spawn(worker(
+                (host.clone(), port),
+                authority.clone(),
+                path.clone(),
+                deadline,
+            ))
+        })
+        .collect();
+
+    let mut total = WorkerStats::default();
+    for worker in workers {
+        let stats = worker
+            .await
+            .map_err(|e| format!("Bench worker failed: {}", e))?;
+        total.non_2xx += stats.non_2xx;
+        total.errors += stats.errors;
+        total.bytes += stats.bytes;
+        total.latencies.extend(stats.latencies);
+    }
+    let elapsed = started.elapsed();
+
+    Ok(BenchReport {
+        requests: total.latencies.len() as u64,
+        non_2xx: total.non_2xx,
+        errors: total.errors,
+        bytes: total.bytes,
+        duration_ms: elapsed.as_millis() as u64,
+        requests_per_sec: total.latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        latency_us: summarize(&mut total.latencies),
+    })
+}
+
+async fn worker(
+    addr: (String, u16),
+    authority: String,
+    path: Uri,
+    deadline: Instant,
+) -> WorkerStats {
+    let mut stats = WorkerStats::default();
+    let mut sender: Option<SendRequest<Empty<Bytes>>> = None;
+
+    while Instant::now() < deadline {
+        let ready = match sender.as_mut() {
+            Some(sender) => sender.ready().await.is_ok(),
+            None => false,
+        };
+        if !ready {
+            match connect(&addr).await {
+                Some(connected) => sender = Some(connected),
+                None => {
+                    stats.errors += 1;
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            }
+        }
+        let Some(conn) = sender.as_mut() else {
+            continue;
+        };
+
+        let request = Request::get(path.clone())
+            .header(hyper::header::HOST, authority.as_str())
+            .body(Empty::<Bytes>::new())
+            .expect("request parts are valid");
+
+        let sent = Instant::now();
+        let response = match tokio::time::timeout_at(deadline, conn.send_request(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => {
+                stats.errors += 1;
+                sender = None;
+                continue;
+            }
+            // Cut off by the deadline: not counted
+            Err(_) => break,
+        };
+
+        let status = response.status();
+        match response.into_body().collect().await {
+            Ok(body) => {
+                stats.bytes += body.to_bytes().len() as u64;
+                stats.latencies.push(sent.elapsed().as_micros() as u64);
+                if !status.is_success() {
+                    stats.non_2xx += 1;
+                }
+            }
+            Err(_) => {
+                stats.errors += 1;
+                sender = None;
+            }
+        }
+    }
+
+    stats
+}
+
+/// Open a keep-alive HTTP/1.1 connection
+async fn connect(addr: &(String, u16)) -> Option<SendRequest<Empty<Bytes>>> {
+    let stream = TcpStream::connect((addr.0.as_str(), addr.1)).await.ok()?;
+    let _ = stream.set_nodelay(true);
+    let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .ok()?;
+    tokio::spawn(connection);
+    Some(sender)
+}
+
+fn summarize(latencies: &mut [u64]) -> LatencySummary {
+    if latencies.is_empty() {
+        return LatencySummary::default();
+    }
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    LatencySummary {
+        min: latencies[0],
+        mean: latencies.iter().sum::<u64>() / latencies.len() as u64,
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: latencies[latencies.len() - 1],
+    }
+}
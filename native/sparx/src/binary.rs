@@ -0,0 +1,113 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use rustler::env::SavedTerm;
+use rustler::{Binary, Decoder, Encoder, Env, OwnedBinary, OwnedEnv, Term};
+
+/// Binaries smaller than this are copied; larger ones are shared
+///
+/// Keeping a binary alive needs its own process-independent env, which
+/// costs more than copying a few kilobytes.
+const ZERO_COPY_THRESHOLD: usize = 4096;
+
+/// Owned bytes that cross the NIF boundary as an Elixir binary
+///
+/// Async NIFs can't hold env-bound `Binary` terms across an await point, so
+/// arguments are decoded into `Bytes` up front and results are only turned
+/// back into a binary when the return value is encoded. Large arguments are
+/// not copied: the `Bytes` borrows the Elixir binary and keeps it alive.
+/// iolists are accepted too and flattened into a single copy.
+#[derive(Clone)]
+pub struct NifBytes(pub Bytes);
+
+/// Keeps an Elixir binary alive after the NIF call that received it returns
+///
+/// The term is copied into an owned env, which for refc binaries only bumps
+/// the reference count. The data pointer stays valid as long as that env.
+struct BinaryOwner {
+    _env: OwnedEnv,
+    _term: SavedTerm,
+    ptr: *const u8,
+    len: usize,
+}
+
+impl BinaryOwner {
+    fn new(term: Term) -> rustler::NifResult<Self> {
+        let env = OwnedEnv::new();
+        let saved = env.save(term);
+        let (ptr, len) = env.run(|owned| -> rustler::NifResult<_> {
+            let binary: Binary = saved.load(owned).decode()?;
+            Ok((binary.as_slice().as_ptr(), binary.len()))
+        })?;
+
+        Ok(Self {
+            _env: env,
+            _term: saved,
+            ptr,
+            len,
+        })
+    }
+}
+
+impl AsRef<[u8]> for BinaryOwner {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: `ptr` points into a binary referenced from `_env`, which is
+        // never cleared and lives exactly as long as `self`.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+// SAFETY: the owned env is only used to keep the binary alive; the bytes it
+// points at are immutable.
+unsafe impl Send for BinaryOwner {}
+
+impl NifBytes {
+    pub fn empty() -> Self {
+        NifBytes(Bytes::new())
+    }
+}
+
+impl<'a> Decoder<'a> for NifBytes {
+    fn decode(term: Term<'a>) -> rustler::NifResult<Self> {
+        if term.is_list() {
+            return flatten_iolist(term).map(NifBytes);
+        }
+        let binary: Binary = term.decode()?;
+        if binary.len() < ZERO_COPY_THRESHOLD {
+            return Ok(NifBytes(Bytes::copy_from_slice(binary.as_slice())));
+        }
+        Ok(NifBytes(Bytes::from_owner(BinaryOwner::new(term)?)))
+    }
+}
+
+/// Copy the bytes of an iolist into one buffer
+///
+/// Accepts what `IO.iodata_to_binary/1` does (nested lists of binaries and
+/// byte integers, with binary tails) without building the binary in Elixir
+/// first. Walks the list with an explicit stack, so deep nesting is fine.
+fn flatten_iolist(term: Term) -> rustler::NifResult<Bytes> {
+    let mut out = BytesMut::new();
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        if term.is_binary() {
+            let binary: Binary = term.decode()?;
+            out.extend_from_slice(binary.as_slice());
+        } else if term.is_empty_list() {
+            continue;
+        } else if term.is_list() {
+            let (head, tail) = term.list_get_cell()?;
+            stack.push(tail);
+            stack.push(head);
+        } else {
+            let byte: u8 = term.decode()?;
+            out.put_u8(byte);
+        }
+    }
+    Ok(out.freeze())
+}
+
+impl Encoder for NifBytes {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut binary = OwnedBinary::new(self.0.len()).unwrap();
+        binary.as_mut_slice().copy_from_slice(&self.0);
+        binary.release(env).encode(env)
+    }
+}
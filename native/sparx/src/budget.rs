@@ -0,0 +1,124 @@
+use rustler::NifMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Server-wide limit on bytes held in memory on behalf of Elixir
+///
+/// Response chunks waiting to be written and WebSocket frames waiting to be
+/// sent take a `Reservation` for their size. Once the budget is used up new
+/// reservations fail (surfacing as `{:error, :overloaded}`) and request
+/// bodies stop being read from their sockets until memory is released.
+pub struct MemoryBudget {
+    /// Maximum reserved bytes, `None` for unlimited
+    limit: Option<usize>,
+    used: AtomicUsize,
+    rejected: AtomicU64,
+    released: Notify,
+}
+
+/// The budget has no room for a reservation
+#[derive(Debug)]
+pub struct Overloaded;
+
+/// Bytes held against a budget until dropped
+#[derive(Default)]
+pub struct Reservation {
+    budget: Option<Arc<MemoryBudget>>,
+    bytes: usize,
+}
+
+/// Budget counters returned in `server_stats`
+#[derive(NifMap)]
+pub struct BudgetStats {
+    pub limit: Option<usize>,
+    pub used: usize,
+    pub rejected: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Maximum reserved bytes, `None` for unlimited
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Reserve `bytes`, failing if that would go over the limit
+    pub fn try_reserve(self: &Arc<Self>, bytes: usize) -> Result<Reservation, Overloaded> {
+        if self.claim(bytes) {
+            Ok(Reservation {
+                budget: Some(self.clone()),
+                bytes,
+            })
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            Err(Overloaded)
+        }
+    }
+
+    /// Reserve `bytes`, waiting until they fit instead of failing
+    ///
+    /// Waiting is not counted as a rejection. `bytes` must not be over the
+    /// limit, or this never returns.
+    pub async fn reserve(self: &Arc<Self>, bytes: usize) -> Reservation {
+        loop {
+            let released = self.released.notified();
+            if self.claim(bytes) {
+                return Reservation {
+                    budget: Some(self.clone()),
+                    bytes,
+                };
+            }
+            released.await;
+        }
+    }
+
+    /// Add `bytes` to the used total if they fit under the limit
+    fn claim(&self, bytes: usize) -> bool {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .is_ok()
+    }
+
+    /// Wait until the budget is below its limit
+    pub async fn wait_for_room(&self) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        loop {
+            let released = self.released.notified();
+            if self.used.load(Ordering::Acquire) < limit {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    pub fn stats(&self) -> BudgetStats {
+        BudgetStats {
+            limit: self.limit,
+            used: self.used.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget.take() {
+            budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+            budget.released.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::atoms;
+use crate::binary::NifBytes;
+use crate::headers::HeaderList;
+use crate::response::BodyError;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::Response;
+use rustler::{Atom, NifMap};
+use std::time::Duration;
+use tokio::sync::watch;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// Response produced for an injected or captured request
+#[derive(NifMap, Clone)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: HeaderList,
+    pub body: NifBytes,
+}
+
+/// Receives the response to an injected or captured request
+///
+/// Injected requests have no socket to write to, and `capture_response`
+/// takes a request's response away from its socket; either way the response
+/// Elixir sends is built exactly as for a network request and then buffered
+/// here, to be read with `await_response` or the `captured_*` accessors.
+pub struct ResponseCapture {
+    result: watch::Sender<Option<Result<CapturedResponse, Atom>>>,
+}
+
+impl Default for ResponseCapture {
+    fn default() -> Self {
+        Self {
+            result: watch::Sender::new(None),
+        }
+    }
+}
+
+impl ResponseCapture {
+    /// Buffer a built response, or record that building it failed
+    pub async fn complete(&self, response: Result<Response<BoxBody>, String>) {
+        let captured = match response {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                let mut headers = HeaderList::default();
+                headers.extend_from_map(&parts.headers);
+                match body.collect().await {
+                    Ok(body) => Ok(CapturedResponse {
+                        status: parts.status.as_u16(),
+                        headers,
+                        body: NifBytes(body.to_bytes()),
+                    }),
+                    Err(e) => {
+                        tracing::error!("Captured response was cut off: {}", e);
+                        Err(atoms::server_error())
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to build captured response: {}", e);
+                Err(atoms::server_error())
+            }
+        };
+        self.result.send_replace(Some(captured));
+    }
+
+    /// The response if it has been built, without waiting
+    pub fn peek(&self) -> Result<CapturedResponse, Atom> {
+        self.result
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| Err(atoms::pending()))
+    }
+
+    /// Wait up to `timeout` for the response
+    pub async fn wait(&self, timeout: Duration) -> Result<CapturedResponse, Atom> {
+        let mut rx = self.result.subscribe();
+        match tokio::time::timeout(timeout, rx.wait_for(Option::is_some)).await {
+            Ok(Ok(result)) => result.clone().unwrap_or_else(|| Err(atoms::server_error())),
+            Ok(Err(_)) => Err(atoms::closed()),
+            Err(_) => Err(atoms::timeout()),
+        }
+    }
+}
+
+impl std::panic::RefUnwindSafe for ResponseCapture {}
+
+#[rustler::resource_impl]
+impl rustler::Resource for ResponseCapture {}
@@ -0,0 +1,242 @@
+use crate::pool::{Recycle, Shared};
+use crate::request::ResponseMessage;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+/// Messages a response channel holds before `send` waits for the receiver
+const CAPACITY: usize = 16;
+
+/// Queue shared by the two ends of a response channel
+///
+/// A tokio channel is freed once both of its ends are dropped; this one goes
+/// back to the server's pool instead, keeping its queue's capacity, so a
+/// request allocates nothing for its response once the pool is warm.
+#[derive(Default)]
+pub struct ChannelState {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    queue: VecDeque<ResponseMessage>,
+    /// Live senders; the receiver sees the end of the channel at zero
+    senders: usize,
+    /// Cleared once the receiver is dropped
+    receiving: bool,
+    /// Receiver waiting for a message
+    receiver: Option<Waker>,
+    /// Senders waiting for room in the queue
+    waiting: Vec<Waker>,
+}
+
+impl ChannelState {
+    /// Set up a fresh or recycled state for one sender and the receiver
+    pub fn open(&mut self) {
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+        inner.senders = 1;
+        inner.receiving = true;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Recycle for ChannelState {
+    fn recycle(&mut self) {
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+        inner.queue.clear();
+        inner.senders = 0;
+        inner.receiving = false;
+        inner.receiver = None;
+        inner.waiting.clear();
+    }
+}
+
+impl Inner {
+    /// Wake every sender waiting for room
+    fn wake_senders(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// The receiver is gone, so the message was not sent
+pub struct SendError;
+
+/// Why `try_send` did not queue a message
+pub enum TrySendError {
+    /// The queue is full; the message was not sent
+    Full,
+    /// The receiver is gone
+    Closed,
+}
+
+/// Why `try_recv` returned no message
+pub enum TryRecvError {
+    /// Nothing is queued yet
+    Empty,
+    /// Every sender is gone and the queue is empty
+    Disconnected,
+}
+
+/// Both ends of a response channel on `state`, opened with `ChannelState::open`
+pub fn pair(state: Shared<ChannelState>) -> (Sender, Receiver) {
+    let sender = Sender {
+        state: state.clone(),
+    };
+    (sender, Receiver { state })
+}
+
+/// Sending end of a response channel, held by the request handle
+pub struct Sender {
+    state: Shared<ChannelState>,
+}
+
+impl Sender {
+    /// Queue a message, waiting while the queue is full
+    pub async fn send(&self, message: ResponseMessage) -> Result<(), SendError> {
+        let mut message = Some(message);
+        poll_fn(|cx| {
+            let mut inner = self.state.lock();
+            if !inner.receiving {
+                return Poll::Ready(Err(SendError));
+            }
+            if inner.queue.len() >= CAPACITY {
+                if !inner.waiting.iter().any(|w| w.will_wake(cx.waker())) {
+                    inner.waiting.push(cx.waker().clone());
+                }
+                return Poll::Pending;
+            }
+            if let Some(message) = message.take() {
+                inner.queue.push_back(message);
+            }
+            if let Some(waker) = inner.receiver.take() {
+                waker.wake();
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Queue a message unless the queue is full
+    pub fn try_send(&self, message: ResponseMessage) -> Result<(), TrySendError> {
+        let mut inner = self.state.lock();
+        if !inner.receiving {
+            return Err(TrySendError::Closed);
+        }
+        if inner.queue.len() >= CAPACITY {
+            return Err(TrySendError::Full);
+        }
+        inner.queue.push_back(message);
+        if let Some(waker) = inner.receiver.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Whether every message sent so far has been received
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().queue.is_empty()
+    }
+
+    /// Handle that does not keep the channel open
+    pub fn downgrade(&self) -> WeakSender {
+        WeakSender {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.state.lock().senders += 1;
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut inner = self.state.lock();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            if let Some(waker) = inner.receiver.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Sender that does not keep the channel open on its own
+pub struct WeakSender {
+    state: Shared<ChannelState>,
+}
+
+impl WeakSender {
+    /// A sender, unless every other sender is already gone
+    pub fn upgrade(&self) -> Option<Sender> {
+        let mut inner = self.state.lock();
+        if inner.senders == 0 {
+            return None;
+        }
+        inner.senders += 1;
+        Some(Sender {
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// Receiving end of a response channel, held by the connection task
+pub struct Receiver {
+    state: Shared<ChannelState>,
+}
+
+impl Receiver {
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<ResponseMessage>> {
+        let mut inner = self.state.lock();
+        if let Some(message) = inner.queue.pop_front() {
+            inner.wake_senders();
+            return Poll::Ready(Some(message));
+        }
+        if inner.senders == 0 {
+            return Poll::Ready(None);
+        }
+        inner.receiver = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Wait for the next message; `None` once every sender is gone
+    pub async fn recv(&mut self) -> Option<ResponseMessage> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Take the next message if one is queued
+    pub fn try_recv(&mut self) -> Result<ResponseMessage, TryRecvError> {
+        let mut inner = self.state.lock();
+        match inner.queue.pop_front() {
+            Some(message) => {
+                inner.wake_senders();
+                Ok(message)
+            }
+            None if inner.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut inner = self.state.lock();
+        inner.receiving = false;
+        inner.receiver = None;
+        // Unsent chunks give their memory budget back now, not when the
+        // last sender goes
+        inner.queue.clear();
+        inner.wake_senders();
+    }
+}
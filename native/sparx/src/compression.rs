@@ -0,0 +1,402 @@
+use crate::response::BodyError;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{
+    HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, VARY,
+};
+use hyper::{HeaderMap, Response, StatusCode};
+use rustler::{NifStruct, NifUnitEnum};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// gzip level, flate2's default trade-off between speed and size
+const GZIP_LEVEL: u32 = 6;
+/// Brotli quality; the higher levels are too slow to run per chunk
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli window size, as log2 of the bytes
+const BROTLI_WINDOW: u32 = 22;
+/// Internal buffer of the Brotli encoder
+const BROTLI_BUFFER: usize = 4096;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Content codings the server can apply
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    /// Token used in `Accept-Encoding` and `Content-Encoding`
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Extension of files precompressed with this encoding
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+        }
+    }
+}
+
+/// Which responses to compress, and with what
+#[derive(NifStruct, Clone, Debug)]
+#[module = "Sparx.Config.Compression"]
+pub struct CompressionConfig {
+    /// Encodings in order of preference, for clients that accept several
+    /// equally
+    pub encodings: Vec<Encoding>,
+    /// Bodies of a known length below this are sent as is
+    pub min_size: u64,
+    /// Media types to compress; entries ending in `/` match a whole type
+    pub content_types: Vec<String>,
+}
+
+impl CompressionConfig {
+    fn compresses(&self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.content_types.iter().any(|pattern| {
+            if pattern.ends_with('/') {
+                media_type.starts_with(pattern.as_str())
+            } else {
+                media_type == *pattern
+            }
+        })
+    }
+}
+
+/// Compress a response body if the client accepts one of the configured
+/// encodings
+///
+/// Responses that are already encoded, partial, marked `no-transform`,
+/// empty, smaller than `min_size`, or of a content type not listed are
+/// left alone. Compressed responses lose their `content-length`, and a
+/// strong entity tag is weakened since the bytes no longer match it.
+pub fn compress(
+    response: Response<BoxBody>,
+    request_headers: &HeaderMap,
+    config: &CompressionConfig,
+) -> Response<BoxBody> {
+    if !is_compressible(&response, config) {
+        return response;
+    }
+    let accept = request_headers
+        .get_all(hyper::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let Some(encoding) = negotiate(&accept, &config.encodings) else {
+        return vary(response);
+    };
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            tracing::warn!("Failed to start {} encoder: {}", encoding.token(), e);
+            return response;
+        }
+    };
+
+    let (mut parts, inner) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+    if let Some(etag) = parts.headers.get(ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                parts.headers.insert(ETAG, weak);
+            }
+        }
+    }
+    let body = CompressedBody {
+        inner,
+        encoder: Some(encoder),
+        trailers: None,
+    };
+    vary(Response::from_parts(parts, body.boxed()))
+}
+
+fn is_compressible(response: &Response<BoxBody>, config: &CompressionConfig) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+    let headers = response.headers();
+    if headers.contains_key(CONTENT_ENCODING) || headers.contains_key(CONTENT_RANGE) {
+        return false;
+    }
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if !content_type.is_some_and(|content_type| config.compresses(content_type)) {
+        return false;
+    }
+    let body = response.body();
+    if body.is_end_stream() {
+        return false;
+    }
+    // Streamed bodies of unknown length are assumed to be worth it
+    body.size_hint()
+        .exact()
+        .is_none_or(|len| len >= config.min_size)
+}
+
+/// Add `Vary: Accept-Encoding` to a compressible response
+///
+/// Added whether or not this client got it compressed, so caches do not
+/// hand an identity body to a client that asked for a compressed one, or
+/// the reverse.
+fn vary(mut response: Response<BoxBody>) -> Response<BoxBody> {
+    let headers = response.headers_mut();
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !listed {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response
+}
+
+/// The encoding to use for an `Accept-Encoding` value
+///
+/// Picks the offered encoding with the highest quality value, falling back
+/// to the order of `offered` between equals. `None` if the client accepts
+/// none of them.
+fn negotiate(accept: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in offered {
+        let q = quality(accept, encoding.token());
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Quality value an `Accept-Encoding` value gives a coding, 0 if none
+pub fn quality(accept: &str, token: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse().unwrap_or(0.0))
+            })
+            .unwrap_or(1.0);
+        if coding.eq_ignore_ascii_case(token) {
+            return q;
+        }
+        if coding == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+/// Compressed output, shared between an encoder and the body draining it
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> Bytes {
+        match self.0.lock() {
+            Ok(mut buf) => Bytes::from(std::mem::take(&mut *buf)),
+            Err(_) => Bytes::new(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::other("poisoned"))?
+            .extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Codec {
+    Gzip(flate2::write::GzEncoder<Output>),
+    Brotli(Box<brotli::CompressorWriter<Output>>),
+    Zstd(zstd::stream::write::Encoder<'static, Output>),
+}
+
+/// Streaming encoder for one response body
+struct Encoder {
+    codec: Codec,
+    output: Output,
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        let output = Output::default();
+        let sink = output.clone();
+        let codec = match encoding {
+            Encoding::Gzip => Codec::Gzip(flate2::write::GzEncoder::new(
+                sink,
+                flate2::Compression::new(GZIP_LEVEL),
+            )),
+            Encoding::Brotli => Codec::Brotli(Box::new(brotli::CompressorWriter::new(
+                sink,
+                BROTLI_BUFFER,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Zstd => Codec::Zstd(zstd::stream::write::Encoder::new(sink, ZSTD_LEVEL)?),
+        };
+        Ok(Self { codec, output })
+    }
+
+    /// Compress a chunk and flush it, so a streamed chunk reaches the client
+    /// without waiting for the next one
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let writer: &mut dyn Write = match &mut self.codec {
+            Codec::Gzip(encoder) => encoder,
+            Codec::Brotli(encoder) => encoder.as_mut(),
+            Codec::Zstd(encoder) => encoder,
+        };
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(self.output.take())
+    }
+
+    /// End the compressed stream, returning its last bytes
+    fn finish(self) -> io::Result<Bytes> {
+        match self.codec {
+            Codec::Gzip(encoder) => {
+                encoder.finish()?;
+            }
+            Codec::Brotli(encoder) => {
+                encoder.into_inner();
+            }
+            Codec::Zstd(encoder) => {
+                encoder.finish()?;
+            }
+        }
+        Ok(self.output.take())
+    }
+}
+
+/// Response body compressed chunk by chunk as hyper polls it
+struct CompressedBody {
+    inner: BoxBody,
+    /// `None` once the compressed stream has ended
+    encoder: Option<Encoder>,
+    /// Trailers of the inner body, sent after the last compressed bytes
+    trailers: Option<HeaderMap>,
+}
+
+impl CompressedBody {
+    /// End the compressed stream, returning its last bytes
+    fn finish(&mut self) -> Result<Bytes, BodyError> {
+        let Some(encoder) = self.encoder.take() else {
+            return Ok(Bytes::new());
+        };
+        encoder.finish().map_err(|e| {
+            tracing::error!("Failed to finish compressed response: {}", e);
+            BodyError::Io(e)
+        })
+    }
+}
+
+impl Body for CompressedBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        let this = &mut *self;
+        while let Some(encoder) = this.encoder.as_mut() {
+            let data = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) if data.is_empty() => continue,
+                    Ok(data) => encoder.write(&data).map_err(|e| {
+                        tracing::error!("Failed to compress response: {}", e);
+                        BodyError::Io(e)
+                    }),
+                    Err(frame) => {
+                        this.trailers = frame.into_trailers().ok();
+                        this.finish()
+                    }
+                },
+                Some(Err(e)) => Err(e),
+                None => this.finish(),
+            };
+            // The head is already out, so a failure cuts the response off
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    this.encoder = None;
+                    this.trailers = None;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+        }
+        Poll::Ready(
+            this.trailers
+                .take()
+                .map(|trailers| Ok(Frame::trailers(trailers))),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.encoder.is_none() && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
@@ -1,32 +1,354 @@
-use rustler::NifStruct;
+use crate::access_log::AccessLogConfig;
+use crate::atoms;
+use crate::compression::CompressionConfig;
+use crate::faults::FaultConfig;
+use crate::listener::ListenerConfig;
+use crate::tls::TlsConfig;
+use rustler::{LocalPid, NifStruct, NifUnitEnum};
+use std::collections::HashMap;
+
+/// Runtime tuning profile for a server
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeProfile {
+    /// Run on the shared NIF runtime
+    Shared,
+    /// Run on a dedicated runtime that polls for I/O more often, trading CPU
+    /// for lower wakeup latency
+    LowLatency,
+    /// Run on a single-threaded runtime with a paused clock that only moves
+    /// through `sim_advance` (requires the `simulation` feature)
+    Simulation,
+}
+
+/// Smallest `http1_max_buf_size` hyper accepts
+pub const MIN_HTTP1_BUF_SIZE: usize = 8192;
+
+/// Where a server's connections come from
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Accept TCP connections on `host` and `port`
+    Tcp,
+    /// Bind nothing; connections are in-memory pipes opened with
+    /// `test_connect`
+    Memory,
+}
+
+/// What happens to connections beyond `max_connections`
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionOverflow {
+    /// Accept them and answer every request with a 503
+    Shed,
+    /// Stop accepting until a connection closes; new ones wait in the
+    /// kernel backlog
+    Wait,
+}
+
+/// What happens to requests arriving while their queue lane is full
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Hold the request on its connection until there is room
+    Wait,
+    /// Answer the new request with a 503
+    Reject,
+    /// Answer the oldest queued request with a 503 and queue the new one
+    DropOldest,
+}
 
 #[derive(NifStruct, Clone)]
 #[module = "Sparx.Config"]
 pub struct ServerConfig {
-    /// Host to bind to (e.g., "127.0.0.1", "0.0.0.0")
+    /// Host to bind to (e.g., "127.0.0.1", "0.0.0.0", "::")
     pub host: String,
 
+    /// `IPV6_V6ONLY` for IPv6 hosts: true accepts IPv6 only, false IPv4 as
+    /// well (None keeps the OS default)
+    pub ipv6_only: Option<bool>,
+
     /// Port to listen on
     pub port: u16,
 
     /// Maximum number of concurrent connections
     pub max_connections: usize,
 
-    /// Request timeout in milliseconds
+    /// How connections beyond `max_connections` are handled
+    pub connection_overflow: ConnectionOverflow,
+
+    /// Request timeout in milliseconds, from arrival until the response
+    /// starts (0 disables it)
     pub request_timeout_ms: u64,
 
-    /// Keep-alive timeout in milliseconds
+    /// Close connections with no request in flight for this many
+    /// milliseconds (0 turns keep-alive off)
     pub keep_alive_timeout_ms: u64,
+
+    /// Close connections that take longer than this many milliseconds to
+    /// send a complete request head (0 disables it)
+    pub header_read_timeout_ms: u64,
+
+    /// Most headers a request may carry; more get a 431
+    pub max_header_count: usize,
+
+    /// Most bytes of header names and values a request may carry; more get
+    /// a 431
+    pub max_header_size: usize,
+
+    /// Longest request target, in bytes; longer ones get a 414
+    pub max_uri_length: usize,
+
+    /// Size of hyper's HTTP/1 read buffer, which bounds a request head
+    /// before it is parsed (None keeps hyper's default of about 400KB)
+    pub http1_max_buf_size: Option<usize>,
+
+    /// Origins allowed to open WebSocket connections (empty allows any)
+    pub ws_allowed_origins: Vec<String>,
+
+    /// Runtime tuning profile
+    pub runtime_profile: RuntimeProfile,
+
+    /// Scheduler ticks between I/O driver polls (dedicated runtime only)
+    pub event_interval: Option<u32>,
+
+    /// Scheduler ticks between global queue checks (dedicated runtime only)
+    pub global_queue_interval: Option<u32>,
+
+    /// Worker threads for a dedicated runtime (defaults to the CPU count)
+    pub worker_threads: Option<usize>,
+
+    /// Number of reusable per-request buffers kept by each pool
+    pub pool_capacity: usize,
+
+    /// Merge buffered request body frames smaller than this many bytes
+    /// into a single chunk (0 disables coalescing)
+    pub min_chunk_size: usize,
+
+    /// Run one current-thread runtime and `SO_REUSEPORT` listener per core
+    /// (`worker_threads` cores, defaulting to the CPU count)
+    pub thread_per_core: bool,
+
+    /// Accept loops per listening address; ignored in thread-per-core mode,
+    /// which runs one per core
+    pub acceptors: usize,
+
+    /// Give each accept loop its own `SO_REUSEPORT` listener so the kernel
+    /// spreads connections between them, instead of sharing one socket
+    pub reuse_port: bool,
+
+    /// Run each accept loop on a dedicated thread, handing accepted
+    /// connections to the server's runtime
+    pub acceptor_threads: bool,
+
+    /// Pending connections the kernel queues per listener
+    pub listen_backlog: u32,
+
+    /// `TCP_NODELAY` on accepted connections, so small writes such as
+    /// streamed chunks go out without waiting on Nagle's algorithm
+    pub tcp_nodelay: bool,
+
+    /// `SO_SNDBUF` for accepted connections, in bytes (None keeps the OS
+    /// default)
+    pub send_buffer_size: Option<usize>,
+
+    /// `SO_RCVBUF` for accepted connections, in bytes (None keeps the OS
+    /// default)
+    pub recv_buffer_size: Option<usize>,
+
+    /// `SO_LINGER` on accepted connections: only 0, which resets the
+    /// connection on close, since any other value makes closing block (None
+    /// keeps the OS default of a graceful close in the background)
+    pub linger_secs: Option<u64>,
+
+    /// Bytes of a buffered response body kept in memory; the rest is spilled
+    /// to a temp file and streamed from there
+    pub response_buffer_limit: usize,
+
+    /// Prefill pools and wake runtime workers before accepting connections
+    pub warmup: bool,
+
+    /// Paths queued ahead of regular requests (exact, or prefix ending in `*`)
+    pub priority_paths: Vec<String>,
+
+    /// Requests carrying this header are queued ahead of regular requests
+    pub priority_header: Option<String>,
+
+    /// Answer requests that waited longer than this in the queue with a 503
+    /// instead of delivering them (None never sheds)
+    pub max_queue_wait_ms: Option<u64>,
+
+    /// `Retry-After` seconds sent with shed requests and connections
+    pub shed_retry_after_secs: u64,
+
+    /// Requests each priority lane of the queue holds before
+    /// `queue_overflow` applies
+    pub queue_capacity: usize,
+
+    /// How requests arriving at a full queue lane are handled
+    pub queue_overflow: QueueOverflow,
+
+    /// Spread runtime threads across NUMA nodes and give each node its own
+    /// buffer pools
+    pub numa_aware: bool,
+
+    /// NUMA nodes to run on (empty uses every node); implies `numa_aware`
+    pub numa_nodes: Vec<usize>,
+
+    /// Pin each runtime thread to a single CPU; implies `numa_aware`
+    pub pin_threads: bool,
+
+    /// Close keep-alive connections idle this long and trim buffer pools to
+    /// the connections still open (None disables the sweeper). Each
+    /// connection also closes itself after `keep_alive_timeout_ms`, so only
+    /// a shorter value here closes connections any sooner.
+    pub idle_reclaim_ms: Option<u64>,
+
+    /// Bytes of response chunks and WebSocket frames the server may buffer
+    /// at once (None for unlimited)
+    pub memory_budget: Option<usize>,
+
+    /// Largest request body accepted, in bytes; larger ones get a 413
+    /// (None for unlimited)
+    pub max_body_size: Option<usize>,
+
+    /// Connection source (`Memory` is meant for tests)
+    pub transport: Transport,
+
+    /// Network failures to inject for testing error handling (None injects
+    /// nothing)
+    pub faults: Option<FaultConfig>,
+
+    /// Compress responses the client accepts an encoding for (None
+    /// disables compression)
+    pub compression: Option<CompressionConfig>,
+
+    /// Content types for file responses by lowercase extension, on top of
+    /// the built-in table
+    pub mime_types: HashMap<String, String>,
+
+    /// Path of the development request inspector (None disables it)
+    pub inspector_path: Option<String>,
+
+    /// Finished requests kept for the inspector
+    pub inspector_history: usize,
+
+    /// Path Prometheus metrics are served at (None disables them)
+    pub metrics_path: Option<String>,
+
+    /// Process lifecycle events are sent to for `:telemetry` (None disables
+    /// them)
+    pub telemetry_pid: Option<LocalPid>,
+
+    /// Log every response, including ones Elixir never saw (None disables
+    /// the access log)
+    pub access_log: Option<AccessLogConfig>,
+
+    /// Default time `server_drain` waits for open connections to finish
+    pub drain_timeout_ms: u64,
+
+    /// Accept HTTP/2 (prior knowledge, or ALPN `h2` over TLS) as well as
+    /// HTTP/1.1
+    pub http2: bool,
+
+    /// Serve HTTPS with this certificate and key (None serves plain HTTP)
+    pub tls: Option<TlsConfig>,
+
+    /// Addresses to accept connections on; when empty, `host`, `port`, and
+    /// `tls` describe the only listener
+    pub listeners: Vec<ListenerConfig>,
+
+    /// Listening socket exported by another server, taken over instead of
+    /// binding `host` and `port`
+    pub listener_fd: Option<i32>,
+
+    /// Expect a PROXY protocol header on connections to the `:default`
+    /// listener (configured listeners opt in individually)
+    pub proxy_protocol: bool,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             host: "127.0.0.1".to_string(),
+            ipv6_only: None,
             port: 4000,
             max_connections: 100_000,
+            connection_overflow: ConnectionOverflow::Shed,
             request_timeout_ms: 30_000,
             keep_alive_timeout_ms: 60_000,
+            header_read_timeout_ms: 10_000,
+            max_header_count: 100,
+            max_header_size: 32_768,
+            max_uri_length: 8_192,
+            http1_max_buf_size: None,
+            ws_allowed_origins: Vec::new(),
+            runtime_profile: RuntimeProfile::Shared,
+            event_interval: None,
+            global_queue_interval: None,
+            worker_threads: None,
+            pool_capacity: 1024,
+            min_chunk_size: 0,
+            thread_per_core: false,
+            acceptors: 1,
+            reuse_port: false,
+            acceptor_threads: false,
+            listen_backlog: 1024,
+            tcp_nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger_secs: None,
+            response_buffer_limit: 8 * 1024 * 1024,
+            warmup: false,
+            priority_paths: Vec::new(),
+            priority_header: None,
+            max_queue_wait_ms: None,
+            shed_retry_after_secs: 1,
+            queue_capacity: 1024,
+            queue_overflow: QueueOverflow::Wait,
+            numa_aware: false,
+            numa_nodes: Vec::new(),
+            pin_threads: false,
+            idle_reclaim_ms: None,
+            memory_budget: None,
+            max_body_size: None,
+            transport: Transport::Tcp,
+            faults: None,
+            compression: None,
+            mime_types: HashMap::new(),
+            inspector_path: None,
+            metrics_path: None,
+            telemetry_pid: None,
+            access_log: None,
+            inspector_history: 50,
+            drain_timeout_ms: 30_000,
+            http2: true,
+            tls: None,
+            listeners: Vec::new(),
+            listener_fd: None,
+            proxy_protocol: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Every listener to bind, with the top-level address as the `:default`
+    /// listener when `listeners` is empty, and `ipv6_only` filled in
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if self.listeners.is_empty() {
+            return vec![ListenerConfig {
+                name: atoms::default(),
+                host: self.host.clone(),
+                port: self.listener_fd.is_none().then_some(self.port),
+                path: None,
+                tls: self.tls.clone(),
+                ipv6_only: self.ipv6_only,
+                fd: self.listener_fd,
+                proxy_protocol: self.proxy_protocol,
+            }];
         }
+        self.listeners
+            .iter()
+            .map(|listener| ListenerConfig {
+                ipv6_only: listener.ipv6_only.or(self.ipv6_only),
+                ..listener.clone()
+            })
+            .collect()
     }
 }
@@ -0,0 +1,282 @@
+use crate::proxy_protocol::Peer;
+use crate::server::ServerContext;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+/// Shortest interval between two idle sweeps
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Activity of one open connection
+pub struct ConnectionState {
+    /// Milliseconds since the registry's epoch of the last request boundary
+    last_active_ms: AtomicU64,
+    /// Requests currently being handled on this connection
+    in_flight: AtomicUsize,
+    /// Signalled by the sweeper to close the connection
+    reclaim: Notify,
+    /// Set once the connection has started closing, so the sweeper neither
+    /// signals nor counts it again
+    closing: AtomicBool,
+    /// Signalled to drop the connection without finishing in-flight requests
+    abort: Notify,
+    /// Client the connection is from, when known
+    peer: Option<Peer>,
+}
+
+impl ConnectionState {
+    /// Client the connection is from: the socket's peer, or the client a
+    /// PROXY protocol header relayed
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// Note that the connection is closing for a reason of its own
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+    }
+
+    /// Wait until the sweeper asks for this connection to be closed
+    pub async fn reclaimed(&self) {
+        self.reclaim.notified().await;
+    }
+
+    /// Drop the connection immediately, as if the network failed
+    pub fn abort(&self) {
+        self.abort.notify_one();
+    }
+
+    /// Wait until the connection is aborted
+    pub async fn aborted(&self) {
+        self.abort.notified().await;
+    }
+}
+
+/// Every open connection of a server
+pub struct ConnectionRegistry {
+    epoch: Instant,
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<ConnectionState>>>,
+    reclaimed: AtomicU64,
+    /// Connections closed so far, for any reason
+    closed_total: AtomicU64,
+    /// Requests in progress across every connection
+    in_flight: AtomicUsize,
+    /// Signalled whenever a connection closes
+    closed: Notify,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            next_id: AtomicU64::new(0),
+            connections: Mutex::new(HashMap::new()),
+            reclaimed: AtomicU64::new(0),
+            closed_total: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            closed: Notify::new(),
+        }
+    }
+}
+
+/// Registration of an accepted connection, removed again on drop
+pub struct ConnectionGuard {
+    id: u64,
+    state: Arc<ConnectionState>,
+    context: Arc<ServerContext>,
+}
+
+impl ConnectionGuard {
+    pub fn state(&self) -> &Arc<ConnectionState> {
+        &self.state
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let registry = &self.context.connections;
+        if let Ok(mut connections) = registry.connections.lock() {
+            connections.remove(&self.id);
+        }
+        registry.closed_total.fetch_add(1, Ordering::Relaxed);
+        registry.closed.notify_waiters();
+    }
+}
+
+/// A request in progress on a connection; the connection counts as busy
+/// until it is dropped
+pub struct ActiveRequest<'a> {
+    registry: &'a ConnectionRegistry,
+    state: &'a ConnectionState,
+}
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.registry.touch(self.state);
+        self.state.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.registry.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionRegistry {
+    /// Track a newly accepted connection
+    pub fn register(context: &Arc<ServerContext>, peer: Option<Peer>) -> ConnectionGuard {
+        let registry = &context.connections;
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(ConnectionState {
+            last_active_ms: AtomicU64::new(registry.now_ms()),
+            in_flight: AtomicUsize::new(0),
+            reclaim: Notify::new(),
+            closing: AtomicBool::new(false),
+            abort: Notify::new(),
+            peer,
+        });
+        if let Ok(mut connections) = registry.connections.lock() {
+            connections.insert(id, state.clone());
+        }
+        ConnectionGuard {
+            id,
+            state,
+            context: context.clone(),
+        }
+    }
+
+    /// Mark a request as started on `state`'s connection
+    pub fn begin<'a>(&'a self, state: &'a ConnectionState) -> ActiveRequest<'a> {
+        state.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.touch(state);
+        ActiveRequest {
+            registry: self,
+            state,
+        }
+    }
+
+    /// Number of open connections
+    pub fn open(&self) -> usize {
+        self.connections.lock().map(|c| c.len()).unwrap_or(0)
+    }
+
+    /// How long a connection has had no request in flight (None while busy)
+    pub fn idle_time(&self, state: &ConnectionState) -> Option<Duration> {
+        if state.in_flight.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+        let last_active = state.last_active_ms.load(Ordering::Relaxed);
+        Some(Duration::from_millis(
+            self.now_ms().saturating_sub(last_active),
+        ))
+    }
+
+    /// Wait until no connection is open
+    pub async fn all_closed(&self) {
+        loop {
+            // Created before the check so a close in between is not missed
+            let closed = self.closed.notified();
+            if self.open() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    /// Wait until fewer than `limit` connections are open
+    pub async fn below(&self, limit: usize) {
+        loop {
+            let closed = self.closed.notified();
+            if self.open() < limit {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    /// Connections closed by the sweeper so far
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed.load(Ordering::Relaxed)
+    }
+
+    /// Connections accepted so far
+    pub fn accepted(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed so far
+    pub fn closed(&self) -> u64 {
+        self.closed_total.load(Ordering::Relaxed)
+    }
+
+    /// Requests in progress on open connections
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    fn touch(&self, state: &ConnectionState) {
+        state.last_active_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    /// Ask every connection idle for longer than `idle` to close
+    fn sweep(&self, idle: Duration) -> usize {
+        let now = self.now_ms();
+        let idle_ms = idle.as_millis() as u64;
+        let Ok(connections) = self.connections.lock() else {
+            return 0;
+        };
+
+        let mut closed = 0;
+        for state in connections.values() {
+            let last_active = state.last_active_ms.load(Ordering::Relaxed);
+            if state.in_flight.load(Ordering::Relaxed) == 0
+                && now.saturating_sub(last_active) >= idle_ms
+                && !state.closing.swap(true, Ordering::Relaxed)
+            {
+                // notify_one stores a permit, so a connection that is not
+                // waiting yet still sees it
+                state.reclaim.notify_one();
+                closed += 1;
+            }
+        }
+        self.reclaimed.fetch_add(closed as u64, Ordering::Relaxed);
+        closed
+    }
+}
+
+/// Periodically close idle keep-alive connections and trim buffer pools
+///
+/// Closing a connection frees hyper's read and write buffers for it, and
+/// pools are shrunk to the number of connections still open, so a burst of
+/// connections does not pin its memory after the burst is over. Runs until
+/// `shutdown_rx` fires.
+pub async fn run_sweeper(context: Arc<ServerContext>, mut shutdown_rx: watch::Receiver<bool>) {
+    let Some(idle_ms) = context.config.idle_reclaim_ms else {
+        return;
+    };
+    let idle = Duration::from_millis(idle_ms);
+    let mut interval = tokio::time::interval((idle / 2).max(MIN_SWEEP_INTERVAL));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown_rx.wait_for(|stop| *stop) => return,
+        }
+
+        let closed = context.connections.sweep(idle);
+        let trimmed = context.pools.trim(context.connections.open());
+        if closed > 0 || trimmed > 0 {
+            tracing::debug!(
+                "Reclaimed {} idle connections and {} pooled buffers",
+                closed,
+                trimmed
+            );
+        }
+    }
+}
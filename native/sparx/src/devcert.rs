@@ -0,0 +1,31 @@
+use rustler::NifMap;
+
+/// Names a development certificate covers when none are given
+const DEFAULT_HOSTNAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// Self-signed certificate and its private key, both PEM encoded
+#[derive(NifMap)]
+pub struct DevCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+/// Generate a throwaway self-signed certificate for local HTTPS
+///
+/// Each hostname becomes a subject alternative name; entries that parse as
+/// IP addresses are added as IP SANs. The key is a fresh ECDSA P-256 key.
+pub fn generate(hostnames: Vec<String>) -> Result<DevCert, String> {
+    let hostnames = if hostnames.is_empty() {
+        DEFAULT_HOSTNAMES.iter().map(|h| h.to_string()).collect()
+    } else {
+        hostnames
+    };
+
+    let certified = rcgen::generate_simple_self_signed(hostnames)
+        .map_err(|e| format!("Failed to generate certificate: {}", e))?;
+
+    Ok(DevCert {
+        cert_pem: certified.cert.pem(),
+        key_pem: certified.key_pair.serialize_pem(),
+    })
+}
@@ -0,0 +1,110 @@
+use crate::pool::{Recycle, Shared};
+use crate::response::BodyError;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::Response;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Context, Poll};
+use tokio::sync::watch;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// Fires when a request's client goes away before its response is done
+#[derive(Default)]
+pub struct Disconnect {
+    closed: watch::Sender<bool>,
+}
+
+impl Disconnect {
+    /// Receiver that sees `true` once the client is gone
+    ///
+    /// The sender is dropped along with the request, so a receiver waiting
+    /// on a request that completed normally sees the channel close instead.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.closed.subscribe()
+    }
+}
+
+impl Recycle for Disconnect {
+    fn recycle(&mut self) {
+        // Receivers of the finished request must see its sender go, not the
+        // next request's disconnect
+        if self.closed.receiver_count() > 0 {
+            self.closed = watch::Sender::default();
+        } else {
+            self.closed.send_replace(false);
+        }
+    }
+}
+
+/// Fires the request's `Disconnect` if dropped before the response ends
+///
+/// hyper drops the service future when the connection closes while the
+/// handler is still working, and drops the response body when it closes
+/// mid-stream; the guard lives in one and then the other.
+pub struct DisconnectGuard {
+    signal: Shared<Disconnect>,
+    armed: AtomicBool,
+}
+
+impl DisconnectGuard {
+    pub fn new(signal: Shared<Disconnect>) -> Self {
+        Self {
+            signal,
+            armed: AtomicBool::new(true),
+        }
+    }
+
+    fn disarm(&self) {
+        self.armed.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if self.armed.load(Ordering::Relaxed) {
+            self.signal.closed.send_replace(true);
+        }
+    }
+}
+
+/// Keep `guard` armed until hyper has taken the whole response body
+pub fn guard_response(response: Response<BoxBody>, guard: DisconnectGuard) -> Response<BoxBody> {
+    response.map(|inner| GuardedBody { inner, guard }.boxed())
+}
+
+struct GuardedBody {
+    inner: BoxBody,
+    guard: DisconnectGuard,
+}
+
+impl Body for GuardedBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if frame.is_none() || self.inner.is_end_stream() {
+            self.guard.disarm();
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // hyper never polls a body that reports its end up front
+        let end = self.inner.is_end_stream();
+        if end {
+            self.guard.disarm();
+        }
+        end
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,528 @@
+use crate::config::{RuntimeProfile, ServerConfig, Transport, MIN_HTTP1_BUF_SIZE};
+use crate::listener;
+use crate::numa;
+use crate::tls;
+use rustler::{NifMap, NifUnitEnum};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+
+/// How serious a diagnostic is
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// `server_start` would fail, or the server could not serve anything
+    Error,
+    /// The server starts, but probably not the way it was meant to
+    Warning,
+}
+
+/// One problem found in a configuration
+#[derive(NifMap, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Config field the problem is about
+    pub field: String,
+    /// What is wrong and how to fix it
+    pub message: String,
+}
+
+/// Check a configuration for conflicting or nonsensical settings
+///
+/// Runs every check rather than stopping at the first problem, so one call
+/// reports everything that needs fixing. The bind address is checked by
+/// binding an ephemeral port on it, never the configured one.
+pub fn check(config: &ServerConfig) -> Vec<Diagnostic> {
+    let mut doctor = Doctor::default();
+    doctor.bind_address(config);
+    doctor.listeners(config);
+    doctor.ip_stack(config);
+    doctor.sockets(config);
+    doctor.proxy_protocol(config);
+    doctor.limits(config);
+    doctor.runtime(config);
+    doctor.routing(config);
+    doctor.faults(config);
+    doctor.tls(config);
+    doctor.access_log(config);
+    doctor.diagnostics
+}
+
+#[derive(Default)]
+struct Doctor {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Doctor {
+    fn error(&mut self, field: &'static str, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, field: &'static str, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn bind_address(&mut self, config: &ServerConfig) {
+        if config.transport == Transport::Memory || !config.listeners.is_empty() {
+            return;
+        }
+        match config.listener_fd {
+            Some(fd) if fd < 0 => self.error("listener_fd", "must be a file descriptor"),
+            // A taken-over socket is already bound
+            Some(_) => {}
+            None => self.host("host", &config.host),
+        }
+    }
+
+    fn host(&mut self, field: &'static str, host: &str) {
+        let Some(ip) = listener::parse_host(host) else {
+            self.error(
+                field,
+                format!(
+                    "{:?} is not an IP address; use e.g. \"127.0.0.1\", \"0.0.0.0\", or \"::\"",
+                    host
+                ),
+            );
+            return;
+        };
+
+        if let Err(e) = TcpListener::bind(SocketAddr::new(ip, 0)) {
+            self.error(
+                field,
+                format!(
+                    "cannot bind to {} on this machine ({}); use an address of a local interface",
+                    ip, e
+                ),
+            );
+        }
+    }
+
+    fn ip_stack(&mut self, config: &ServerConfig) {
+        if config.transport == Transport::Memory {
+            return;
+        }
+        let tcp = config
+            .listeners()
+            .into_iter()
+            .filter(|listener| listener.path.is_none())
+            .filter_map(|listener| {
+                let ip = listener::parse_host(&listener.host)?;
+                Some((ip, listener.port.unwrap_or(0), listener.ipv6_only))
+            })
+            .collect::<Vec<_>>();
+
+        for &(ip, port, ipv6_only) in &tcp {
+            if ip.is_ipv4() && ipv6_only.is_some() {
+                self.warning("ipv6_only", format!("ignored for the IPv4 host {}", ip));
+            }
+            // `::` takes IPv4 connections too unless it is IPv6-only, which
+            // leaves no room for `0.0.0.0` on the same port
+            let v6_any = ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && port != 0;
+            let v4_any = tcp.iter().any(|&(other, other_port, _)| {
+                other == IpAddr::V4(Ipv4Addr::UNSPECIFIED) && other_port == port
+            });
+            if !(v6_any && v4_any) {
+                continue;
+            }
+            match ipv6_only {
+                Some(true) => {}
+                Some(false) => self.error(
+                    "ipv6_only",
+                    format!(
+                        "a dual-stack :: on port {} also takes IPv4 and clashes with \
+                         0.0.0.0; drop the IPv4 listener or set ipv6_only: true",
+                        port
+                    ),
+                ),
+                None => self.warning(
+                    "ipv6_only",
+                    format!(
+                        "whether :: on port {} also takes IPv4, and so conflicts with 0.0.0.0, \
+                         is up to the OS; set ipv6_only: true to bind both",
+                        port
+                    ),
+                ),
+            }
+        }
+    }
+
+    fn sockets(&mut self, config: &ServerConfig) {
+        if config.listen_backlog == 0 {
+            self.error("listen_backlog", "must be at least 1");
+        }
+        for (field, size) in [
+            ("send_buffer_size", config.send_buffer_size),
+            ("recv_buffer_size", config.recv_buffer_size),
+        ] {
+            if size == Some(0) {
+                self.error(field, "must be at least 1; use nil for the OS default");
+            }
+        }
+        match config.linger_secs {
+            Some(0) => self.warning(
+                "linger_secs",
+                "0 resets connections on close, so clients may lose the end of a response",
+            ),
+            Some(_) => self.error(
+                "linger_secs",
+                "closing would block a runtime worker until the data is sent; only 0 is supported",
+            ),
+            None => {}
+        }
+        if config.transport == Transport::Memory
+            && (config.send_buffer_size.is_some()
+                || config.recv_buffer_size.is_some()
+                || config.linger_secs.is_some())
+        {
+            self.warning(
+                "transport",
+                "socket options are ignored by the memory transport, which has no sockets",
+            );
+        }
+    }
+
+    fn proxy_protocol(&mut self, config: &ServerConfig) {
+        let listeners = config.listeners();
+        if config.proxy_protocol && !config.listeners.is_empty() {
+            self.warning(
+                "proxy_protocol",
+                "ignored when listeners are set; set proxy_protocol on each listener",
+            );
+        }
+        if config.transport == Transport::Memory
+            && listeners.iter().any(|listener| listener.proxy_protocol)
+        {
+            self.warning(
+                "proxy_protocol",
+                "ignored by the memory transport, whose connections have no load balancer",
+            );
+        }
+    }
+
+    fn listeners(&mut self, config: &ServerConfig) {
+        if config.listeners.is_empty() {
+            return;
+        }
+        if config.tls.is_some() {
+            self.warning(
+                "tls",
+                "ignored when listeners are set; give each listener its own tls",
+            );
+        }
+        if config.transport == Transport::Memory {
+            self.warning(
+                "listeners",
+                "ignored by the memory transport, whose requests all come from :default",
+            );
+            return;
+        }
+
+        for (index, listener) in config.listeners.iter().enumerate() {
+            if let Err(e) = listener.validate() {
+                self.error("listeners", format!("listener {}: {}", index, e));
+                continue;
+            }
+            if config.listeners[..index]
+                .iter()
+                .any(|other| other.name == listener.name)
+            {
+                self.error(
+                    "listeners",
+                    format!("listener {} reuses the name of an earlier one", index),
+                );
+            }
+            match &listener.path {
+                Some(path) => {
+                    if !cfg!(unix) {
+                        self.error(
+                            "listeners",
+                            "Unix domain sockets are not supported on this platform",
+                        );
+                    }
+                    let dir = std::path::Path::new(path).parent();
+                    if dir.is_some_and(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+                        self.error(
+                            "listeners",
+                            format!("{:?} is in a directory that does not exist", path),
+                        );
+                    }
+                }
+                None if listener.fd.is_none() => self.host("listeners", &listener.host),
+                None => {}
+            }
+            if let Some(tls_config) = &listener.tls {
+                if let Err(e) = tls::acceptor(tls_config, config.http2) {
+                    self.error("listeners", format!("listener {}: {}", index, e));
+                }
+            }
+            if config.thread_per_core && listener.port == Some(0) {
+                self.error(
+                    "thread_per_core",
+                    "each core binds its own SO_REUSEPORT listener, so port 0 would give \
+                     every core a different port; set a fixed port on every listener",
+                );
+            }
+        }
+    }
+
+    fn limits(&mut self, config: &ServerConfig) {
+        if config.max_connections == 0 {
+            self.error("max_connections", "0 would refuse every connection");
+        }
+        if config.keep_alive_timeout_ms == 0 {
+            self.warning(
+                "keep_alive_timeout_ms",
+                "0 closes connections after every request",
+            );
+        }
+        if config.max_header_count == 0 {
+            self.error("max_header_count", "0 would refuse every request");
+        }
+        if config.max_header_size == 0 {
+            self.error("max_header_size", "0 would refuse every request");
+        }
+        if config.max_uri_length == 0 {
+            self.error("max_uri_length", "0 would refuse every request");
+        }
+        if let Some(size) = config.http1_max_buf_size {
+            if size < MIN_HTTP1_BUF_SIZE {
+                self.error(
+                    "http1_max_buf_size",
+                    format!("must be at least {} bytes", MIN_HTTP1_BUF_SIZE),
+                );
+            }
+        }
+        if config.header_read_timeout_ms == 0 {
+            self.warning(
+                "header_read_timeout_ms",
+                "0 lets clients hold connections open by sending request heads slowly",
+            );
+        }
+        if config.pool_capacity == 0 {
+            self.warning(
+                "pool_capacity",
+                "0 disables buffer reuse; every request allocates its headers",
+            );
+        }
+        if config.response_buffer_limit == 0 {
+            self.warning(
+                "response_buffer_limit",
+                "0 spills every buffered response body to a temp file",
+            );
+        }
+        if config.memory_budget == Some(0) {
+            self.error(
+                "memory_budget",
+                "0 rejects every response chunk; use nil for no limit",
+            );
+        }
+        if config.max_body_size == Some(0) {
+            self.warning(
+                "max_body_size",
+                "0 rejects every request with a body; use nil for no limit",
+            );
+        }
+        if config.queue_capacity == 0 {
+            self.error(
+                "queue_capacity",
+                "each queue lane needs room for at least one request",
+            );
+        }
+        if config.max_queue_wait_ms == Some(0) {
+            self.warning(
+                "max_queue_wait_ms",
+                "0 sheds nearly every request; use nil to never shed",
+            );
+        }
+        if config.idle_reclaim_ms == Some(0) {
+            self.error(
+                "idle_reclaim_ms",
+                "0 closes connections as soon as they are idle; use nil to disable the sweeper",
+            );
+        }
+        if let Some(idle_ms) = config.idle_reclaim_ms {
+            if config.keep_alive_timeout_ms > 0 && idle_ms >= config.keep_alive_timeout_ms {
+                self.warning(
+                    "idle_reclaim_ms",
+                    "keep_alive_timeout_ms closes idle connections first, so the sweeper \
+                     only trims pools; set it below keep_alive_timeout_ms",
+                );
+            }
+        }
+        if config.drain_timeout_ms == 0 {
+            self.warning(
+                "drain_timeout_ms",
+                "0 abandons in-flight requests as soon as a drain starts",
+            );
+        }
+        if config.inspector_path.is_some() && config.inspector_history == 0 {
+            self.warning(
+                "inspector_history",
+                "0 keeps no requests, so the inspector is always empty",
+            );
+        }
+    }
+
+    fn runtime(&mut self, config: &ServerConfig) {
+        if config.thread_per_core
+            && config.port == 0
+            && config.transport == Transport::Tcp
+            && config.listeners.is_empty()
+        {
+            self.error(
+                "thread_per_core",
+                "each core binds its own SO_REUSEPORT listener, so port 0 would give every \
+                 core a different port; set a fixed port",
+            );
+        }
+        if config.acceptors == 0 {
+            self.error("acceptors", "at least one accept loop is needed");
+        }
+        if config.transport == Transport::Memory
+            && (config.acceptors > 1 || config.reuse_port || config.acceptor_threads)
+        {
+            self.warning(
+                "acceptors",
+                "ignored by the memory transport, which has no listeners",
+            );
+        }
+        if config.thread_per_core && (config.acceptors > 1 || config.acceptor_threads) {
+            self.warning(
+                "acceptors",
+                "ignored with thread_per_core, which runs one accept loop per core",
+            );
+        }
+        if config.worker_threads == Some(0) {
+            self.error(
+                "worker_threads",
+                "0 worker threads cannot run anything; use nil for the CPU count",
+            );
+        }
+        if config.event_interval == Some(0) {
+            self.error("event_interval", "must be at least 1");
+        }
+        if config.global_queue_interval == Some(0) {
+            self.error("global_queue_interval", "must be at least 1");
+        }
+
+        if config.runtime_profile == RuntimeProfile::Simulation {
+            if !cfg!(feature = "simulation") {
+                self.error(
+                    "runtime_profile",
+                    "the simulation profile needs the native crate's `simulation` feature",
+                );
+            }
+            if config.thread_per_core {
+                self.warning(
+                    "thread_per_core",
+                    "ignored by the simulation profile, which runs on a single thread",
+                );
+            }
+            if config.transport == Transport::Tcp {
+                self.warning(
+                    "transport",
+                    "real sockets do not follow the simulated clock; use :memory",
+                );
+            }
+        }
+
+        if let Err(e) = numa::Placement::from_config(config) {
+            self.error("numa_nodes", e.to_string());
+        }
+    }
+
+    fn routing(&mut self, config: &ServerConfig) {
+        for (field, path) in [
+            ("inspector_path", &config.inspector_path),
+            ("metrics_path", &config.metrics_path),
+        ] {
+            if let Some(path) = path {
+                if !path.starts_with('/') {
+                    self.error(
+                        field,
+                        format!(
+                            "{:?} never matches a request path; it must start with /",
+                            path
+                        ),
+                    );
+                }
+            }
+        }
+        if config.metrics_path.is_some() && config.metrics_path == config.inspector_path {
+            self.error(
+                "metrics_path",
+                "is the same as inspector_path, which takes precedence",
+            );
+        }
+        for path in &config.priority_paths {
+            if !path.starts_with('/') {
+                self.warning(
+                    "priority_paths",
+                    format!(
+                        "{:?} never matches a request path; it must start with /",
+                        path
+                    ),
+                );
+            }
+        }
+        if config.priority_header.as_deref() == Some("") {
+            self.error("priority_header", "an empty header name never matches");
+        }
+    }
+
+    fn faults(&mut self, config: &ServerConfig) {
+        let Some(faults) = &config.faults else {
+            return;
+        };
+        let rates = [
+            faults.accept_failure_rate,
+            faults.body_reset_rate,
+            faults.chunk_delay_rate,
+            faults.truncate_rate,
+        ];
+        if rates.iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+            self.error(
+                "faults",
+                "fault rates are probabilities between 0.0 and 1.0",
+            );
+        }
+    }
+
+    fn tls(&mut self, config: &ServerConfig) {
+        let Some(tls_config) = &config.tls else {
+            return;
+        };
+        if let Err(e) = tls::acceptor(tls_config, config.http2) {
+            self.error("tls", e);
+        }
+        if config.transport == Transport::Memory {
+            self.warning(
+                "tls",
+                "ignored by the memory transport, which serves plain HTTP",
+            );
+        }
+    }
+
+    fn access_log(&mut self, config: &ServerConfig) {
+        let Some(access_log) = &config.access_log else {
+            return;
+        };
+        if let Err(e) = access_log.validate() {
+            self.error("access_log", e);
+        }
+        if let Some(path) = &access_log.path {
+            let dir = std::path::Path::new(path).parent();
+            if dir.is_some_and(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+                self.error(
+                    "access_log",
+                    format!("{:?} is in a directory that does not exist", path),
+                );
+            }
+        }
+    }
+}
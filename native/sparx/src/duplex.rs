@@ -0,0 +1,69 @@
+use crate::atoms;
+use bytes::{Bytes, BytesMut};
+use rustler::Atom;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+
+/// Bytes buffered in each direction of an in-memory connection
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Largest chunk returned by one `read`
+const READ_SIZE: usize = 16 * 1024;
+
+/// Client end of an in-memory connection
+///
+/// With `transport: :memory` the server has no listener; tests open
+/// connections with `test_connect` and drive them with raw HTTP bytes.
+/// The server end is served exactly like an accepted TCP socket.
+pub struct TestConnection {
+    reader: Mutex<ReadHalf<DuplexStream>>,
+    /// Taken when the client closes its side
+    writer: Mutex<Option<WriteHalf<DuplexStream>>>,
+}
+
+impl TestConnection {
+    pub fn new(client: DuplexStream) -> Self {
+        let (reader, writer) = tokio::io::split(client);
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    /// Write raw client bytes to the server
+    pub async fn write(&self, data: &[u8]) -> Result<(), Atom> {
+        let mut writer = self.writer.lock().await;
+        let writer = writer.as_mut().ok_or_else(atoms::closed)?;
+        writer
+            .write_all(data)
+            .await
+            .map_err(|_| atoms::connection_closed())
+    }
+
+    /// Read the bytes the server has written so far
+    ///
+    /// Waits up to `timeout` for at least one byte. An empty result means
+    /// the server closed the connection.
+    pub async fn read(&self, timeout: Duration) -> Result<Bytes, Atom> {
+        let mut reader = self.reader.lock().await;
+        let mut buf = BytesMut::with_capacity(READ_SIZE);
+        match tokio::time::timeout(timeout, reader.read_buf(&mut buf)).await {
+            Ok(Ok(_)) => Ok(buf.freeze()),
+            Ok(Err(_)) => Err(atoms::connection_closed()),
+            Err(_) => Err(atoms::timeout()),
+        }
+    }
+
+    /// Close the client's write side, as a client half-closing its socket
+    pub async fn close(&self) {
+        if let Some(mut writer) = self.writer.lock().await.take() {
+            let _ = writer.shutdown().await;
+        }
+    }
+}
+
+impl std::panic::RefUnwindSafe for TestConnection {}
+
+#[rustler::resource_impl]
+impl rustler::Resource for TestConnection {}
@@ -0,0 +1,168 @@
+use crate::atoms;
+use rustler::{Atom, NifMap};
+use std::error::Error as StdError;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_tungstenite::tungstenite;
+
+/// Classified cause of a connection, request body, or WebSocket failure
+///
+/// Errors from hyper, h2, tungstenite, and the socket are folded into a few
+/// kinds that are counted in `server_stats` and returned to Elixir as atoms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The peer sent malformed HTTP or WebSocket framing
+    ParseError,
+    /// A read or write deadline passed
+    Timeout,
+    /// The peer reset or closed the connection mid-exchange
+    ConnectionReset,
+    /// A message exceeded a configured size limit
+    BodyTooLarge,
+    /// The HTTP/2 peer violated the protocol or sent a stream/connection error
+    H2ProtocolError,
+    /// The connection, body, or WebSocket was already closed
+    Closed,
+    /// Anything not covered above
+    Other,
+}
+
+const KINDS: usize = 7;
+
+impl ErrorKind {
+    pub fn atom(self) -> Atom {
+        match self {
+            ErrorKind::ParseError => atoms::parse_error(),
+            ErrorKind::Timeout => atoms::timeout(),
+            ErrorKind::ConnectionReset => atoms::connection_reset(),
+            ErrorKind::BodyTooLarge => atoms::body_too_large(),
+            ErrorKind::H2ProtocolError => atoms::h2_protocol_error(),
+            ErrorKind::Closed => atoms::closed(),
+            ErrorKind::Other => atoms::connection_error(),
+        }
+    }
+
+    /// Classify an error by walking its source chain
+    ///
+    /// The first error in the chain that is recognized decides the kind, so
+    /// an I/O error wrapped by hyper or h2 is classified by its I/O kind.
+    pub fn of(error: &(dyn StdError + 'static)) -> Self {
+        let mut current = Some(error);
+        while let Some(error) = current {
+            if let Some(kind) = Self::classify_one(error) {
+                return kind;
+            }
+            current = error.source();
+        }
+        ErrorKind::Other
+    }
+
+    fn classify_one(error: &(dyn StdError + 'static)) -> Option<Self> {
+        if let Some(e) = error.downcast_ref::<io::Error>() {
+            return Self::of_io(e);
+        }
+        if let Some(e) = error.downcast_ref::<h2::Error>() {
+            // I/O failures surface through the source chain
+            return (!e.is_io()).then_some(ErrorKind::H2ProtocolError);
+        }
+        if error
+            .downcast_ref::<http_body_util::LengthLimitError>()
+            .is_some()
+        {
+            return Some(ErrorKind::BodyTooLarge);
+        }
+        if let Some(e) = error.downcast_ref::<tungstenite::Error>() {
+            return Self::of_ws(e);
+        }
+        if let Some(e) = error.downcast_ref::<hyper::Error>() {
+            return Self::of_hyper(e);
+        }
+        None
+    }
+
+    fn of_io(error: &io::Error) -> Option<Self> {
+        match error.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => Some(ErrorKind::ConnectionReset),
+            io::ErrorKind::TimedOut => Some(ErrorKind::Timeout),
+            io::ErrorKind::InvalidData => Some(ErrorKind::ParseError),
+            // Wrappers such as hyper's may carry the real cause inside
+            _ => match error.get_ref() {
+                Some(inner) => Self::classify_one(inner).or(Some(ErrorKind::Other)),
+                None => Some(ErrorKind::Other),
+            },
+        }
+    }
+
+    fn of_hyper(error: &hyper::Error) -> Option<Self> {
+        if error.is_timeout() {
+            Some(ErrorKind::Timeout)
+        } else if error.is_parse() || error.is_parse_status() {
+            Some(ErrorKind::ParseError)
+        } else if error.is_incomplete_message() || error.is_canceled() {
+            Some(ErrorKind::ConnectionReset)
+        } else if error.is_closed() || error.is_body_write_aborted() {
+            Some(ErrorKind::Closed)
+        } else {
+            // Let the source (I/O, h2) decide
+            None
+        }
+    }
+
+    fn of_ws(error: &tungstenite::Error) -> Option<Self> {
+        match error {
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                Some(ErrorKind::Closed)
+            }
+            tungstenite::Error::Io(e) => Self::of_io(e),
+            tungstenite::Error::Protocol(_) | tungstenite::Error::Utf8 => {
+                Some(ErrorKind::ParseError)
+            }
+            tungstenite::Error::Capacity(_) | tungstenite::Error::WriteBufferFull(_) => {
+                Some(ErrorKind::BodyTooLarge)
+            }
+            _ => Some(ErrorKind::Other),
+        }
+    }
+}
+
+/// Per-kind error counts for a server
+#[derive(Default)]
+pub struct ErrorCounters {
+    counts: [AtomicU64; KINDS],
+}
+
+/// Error counts returned in `server_stats`
+#[derive(NifMap)]
+pub struct ErrorStats {
+    pub parse_error: u64,
+    pub timeout: u64,
+    pub connection_reset: u64,
+    pub body_too_large: u64,
+    pub h2_protocol_error: u64,
+    pub closed: u64,
+    pub other: u64,
+}
+
+impl ErrorCounters {
+    /// Count an error and return its kind
+    pub fn record(&self, kind: ErrorKind) -> ErrorKind {
+        self.counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+        kind
+    }
+
+    pub fn snapshot(&self) -> ErrorStats {
+        let count = |kind: ErrorKind| self.counts[kind as usize].load(Ordering::Relaxed);
+        ErrorStats {
+            parse_error: count(ErrorKind::ParseError),
+            timeout: count(ErrorKind::Timeout),
+            connection_reset: count(ErrorKind::ConnectionReset),
+            body_too_large: count(ErrorKind::BodyTooLarge),
+            h2_protocol_error: count(ErrorKind::H2ProtocolError),
+            closed: count(ErrorKind::Closed),
+            other: count(ErrorKind::Other),
+        }
+    }
+}
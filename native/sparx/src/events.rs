@@ -0,0 +1,154 @@
+use crate::errors::ErrorKind;
+use crate::pool::Shared;
+use crate::response::BodyError;
+use crate::timing::RequestTimings;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::Response;
+use rustler::{Atom, NifMap, NifUnitEnum};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// Events kept per request; the oldest are dropped beyond this
+const CAPACITY: usize = 32;
+
+/// Steps in a request's life recorded in its event log
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Request head parsed by hyper
+    Received,
+    /// Placed on the request queue
+    Queued,
+    /// Picked up by an Elixir process
+    Dequeued,
+    /// First request body chunk handed to Elixir
+    FirstChunkRead,
+    /// First response message sent by the handler
+    ResponseStarted,
+    /// Last response body frame handed to hyper for writing
+    Flushed,
+    /// Response finished by the handler
+    Finished,
+    /// Something went wrong; the entry's `reason` says what
+    Error,
+}
+
+/// One entry of a request's event log, as returned by `request_events`
+#[derive(NifMap)]
+pub struct EventEntry {
+    /// Microseconds since the connection was accepted
+    pub at_us: u64,
+    pub event: Event,
+    /// Error kind atom for `:error` entries
+    pub reason: Option<Atom>,
+}
+
+/// Bounded log of lifecycle events for one request
+///
+/// Every event except `Error` is recorded at most once, so callers on hot
+/// paths (each body chunk, each response message) can record freely.
+#[derive(Default)]
+pub struct EventLog {
+    entries: Mutex<VecDeque<(u64, Event, Option<ErrorKind>)>>,
+    /// Bit per `Event` already recorded
+    seen: AtomicU16,
+}
+
+impl EventLog {
+    pub fn record(&self, at_us: u64, event: Event) {
+        let bit = 1 << event as u16;
+        if self.seen.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+            self.push(at_us, event, None);
+        }
+    }
+
+    pub fn error(&self, at_us: u64, kind: ErrorKind) {
+        self.push(at_us, Event::Error, Some(kind));
+    }
+
+    fn push(&self, at_us: u64, event: Event, kind: Option<ErrorKind>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == CAPACITY {
+                entries.pop_front();
+            }
+            entries.push_back((at_us, event, kind));
+        }
+    }
+
+    /// Forget every entry, keeping the buffer for the next request
+    pub fn clear(&mut self) {
+        if let Ok(entries) = self.entries.get_mut() {
+            entries.clear();
+        }
+        *self.seen.get_mut() = 0;
+    }
+
+    /// Kind of the most recent error, if any was logged
+    pub fn last_error(&self) -> Option<ErrorKind> {
+        let entries = self.entries.lock().ok()?;
+        entries.iter().rev().find_map(|&(_, _, kind)| kind)
+    }
+
+    /// Entries in the order they were recorded
+    pub fn snapshot(&self) -> Vec<EventEntry> {
+        let Ok(entries) = self.entries.lock() else {
+            return Vec::new();
+        };
+        entries
+            .iter()
+            .map(|&(at_us, event, kind)| EventEntry {
+                at_us,
+                event,
+                reason: kind.map(ErrorKind::atom),
+            })
+            .collect()
+    }
+}
+
+/// Record `Flushed` once hyper has taken the last frame of the response body
+pub fn track_flush(
+    response: Response<BoxBody>,
+    timings: Shared<RequestTimings>,
+) -> Response<BoxBody> {
+    response.map(|inner| FlushTracker { inner, timings }.boxed())
+}
+
+struct FlushTracker {
+    inner: BoxBody,
+    timings: Shared<RequestTimings>,
+}
+
+impl Body for FlushTracker {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if matches!(frame, Poll::Ready(None)) || self.inner.is_end_stream() {
+            self.timings.event(Event::Flushed);
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // hyper never polls a body that reports its end up front
+        let end = self.inner.is_end_stream();
+        if end {
+            self.timings.event(Event::Flushed);
+        }
+        end
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,200 @@
+use crate::connection::ConnectionState;
+use crate::response::BodyError;
+use crate::server::ServerContext;
+use crate::timer::Sleep;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::Response;
+use rustler::{NifMap, NifStruct};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// Network failures to inject, each as a probability between 0 and 1
+#[derive(NifStruct, Clone, Debug, Default)]
+#[module = "Sparx.Config.Faults"]
+pub struct FaultConfig {
+    /// Close accepted connections before serving them
+    pub accept_failure_rate: f64,
+    /// Fail a request body read as if the client reset the connection
+    pub body_reset_rate: f64,
+    /// Hold back a response chunk for `chunk_delay_ms`
+    pub chunk_delay_rate: f64,
+    pub chunk_delay_ms: u64,
+    /// Cut a response off partway through its body and drop the connection
+    pub truncate_rate: f64,
+}
+
+/// Fault injector for one server
+///
+/// Only built when the server is started with `faults`, so servers without
+/// it pay nothing beyond an `Option` check.
+pub struct Faults {
+    config: FaultConfig,
+    accept_failures: AtomicU64,
+    body_resets: AtomicU64,
+    delayed_chunks: AtomicU64,
+    truncated_responses: AtomicU64,
+}
+
+/// Faults injected so far, returned in `server_stats`
+#[derive(NifMap)]
+pub struct FaultStats {
+    pub accept_failures: u64,
+    pub body_resets: u64,
+    pub delayed_chunks: u64,
+    pub truncated_responses: u64,
+}
+
+impl Faults {
+    pub fn new(config: FaultConfig) -> Self {
+        Self {
+            config,
+            accept_failures: AtomicU64::new(0),
+            body_resets: AtomicU64::new(0),
+            delayed_chunks: AtomicU64::new(0),
+            truncated_responses: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether to drop a freshly accepted connection
+    pub fn fail_accept(&self) -> bool {
+        roll(self.config.accept_failure_rate, &self.accept_failures)
+    }
+
+    /// Whether to fail the next request body read
+    pub fn reset_body(&self) -> bool {
+        roll(self.config.body_reset_rate, &self.body_resets)
+    }
+
+    fn delay_chunk(&self) -> Option<Duration> {
+        roll(self.config.chunk_delay_rate, &self.delayed_chunks)
+            .then(|| Duration::from_millis(self.config.chunk_delay_ms))
+    }
+
+    fn truncate(&self) -> bool {
+        roll(self.config.truncate_rate, &self.truncated_responses)
+    }
+
+    pub fn stats(&self) -> FaultStats {
+        FaultStats {
+            accept_failures: self.accept_failures.load(Ordering::Relaxed),
+            body_resets: self.body_resets.load(Ordering::Relaxed),
+            delayed_chunks: self.delayed_chunks.load(Ordering::Relaxed),
+            truncated_responses: self.truncated_responses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Draw against `rate`, counting hits
+fn roll(rate: f64, hits: &AtomicU64) -> bool {
+    let hit = rate > 0.0 && fastrand::f64() < rate;
+    if hit {
+        hits.fetch_add(1, Ordering::Relaxed);
+    }
+    hit
+}
+
+/// Wrap a response body so chunk delays and truncation can be injected
+///
+/// Returns the response unchanged when the server has no faults configured.
+pub fn wrap_response(
+    context: &Arc<ServerContext>,
+    connection: &Arc<ConnectionState>,
+    response: Response<BoxBody>,
+) -> Response<BoxBody> {
+    let Some(faults) = context.faults.as_ref() else {
+        return response;
+    };
+    let truncate = faults.truncate();
+    response.map(|inner| {
+        FaultyBody {
+            inner,
+            context: context.clone(),
+            connection: connection.clone(),
+            truncate,
+            delay: None,
+            cut: false,
+        }
+        .boxed()
+    })
+}
+
+/// Response body that misbehaves like a flaky network
+struct FaultyBody {
+    inner: BoxBody,
+    context: Arc<ServerContext>,
+    connection: Arc<ConnectionState>,
+    truncate: bool,
+    /// Pending chunk delay, drawn before each frame
+    delay: Option<Sleep>,
+    /// Set once the truncated part of the body has been sent
+    cut: bool,
+}
+
+impl Body for FaultyBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        if self.cut {
+            // The partial body has been handed to hyper; drop the connection
+            // so the client sees it end mid-response
+            self.connection.abort();
+            return Poll::Pending;
+        }
+
+        if self.delay.is_none() {
+            let delay = self.context.faults.as_ref().and_then(Faults::delay_chunk);
+            let sleep = delay.map(|delay| self.context.timers.sleep(delay));
+            self.delay = sleep;
+        }
+        if let Some(delay) = self.delay.as_mut() {
+            if Pin::new(delay).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(_)) = frame {
+            self.delay = None;
+        }
+        if !self.truncate {
+            return frame;
+        }
+
+        match frame {
+            Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                Ok(data) => {
+                    self.cut = true;
+                    Poll::Ready(Some(Ok(Frame::data(data.slice(..data.len() / 2)))))
+                }
+                Err(frame) => Poll::Ready(Some(Ok(frame))),
+            },
+            Poll::Ready(None) => {
+                // Nothing left to cut: drop the connection before the end
+                self.connection.abort();
+                Poll::Pending
+            }
+            other => other,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        !self.truncate && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // Advertise the full length so truncation is visible to the client
+        self.inner.size_hint()
+    }
+}
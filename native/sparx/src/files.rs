@@ -0,0 +1,520 @@
+use crate::atoms;
+use crate::budget::MemoryBudget;
+use crate::compression::{self, Encoding};
+use crate::errors::ErrorKind;
+use crate::headers::HeaderList;
+use crate::request::{RequestHandle, ResponseMessage, ResponseSender};
+use crate::response::NifResult;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::BytesMut;
+use rustler::{Atom, Encoder, Env, NifMap, NifUnitEnum, Term};
+use sha1::{Digest, Sha1};
+use std::fs::Metadata;
+use std::io::{self, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Bytes read from a file for each body chunk
+///
+/// Large reads keep the number of channel messages and syscalls per file
+/// low; the memory budget still caps how many are buffered at once.
+const FILE_CHUNK: usize = 256 * 1024;
+
+/// Why a file could not be sent or received
+#[derive(Debug)]
+pub enum FileError {
+    NotFound,
+    /// `offset` lies past the end of the file
+    InvalidRange,
+    /// The memory budget has no room for the next chunk
+    Overloaded,
+    /// The client went away or the response was already finished
+    Closed,
+    /// Reading the request body failed, or it went over the size limit
+    Body(ErrorKind),
+    Io(io::Error),
+}
+
+impl FileError {
+    fn reason(&self) -> Result<Atom, String> {
+        match self {
+            FileError::NotFound => Ok(atoms::not_found()),
+            FileError::InvalidRange => Ok(atoms::invalid_request()),
+            FileError::Overloaded => Ok(atoms::overloaded()),
+            FileError::Closed => Err("Failed to send file".to_string()),
+            FileError::Body(kind) => Ok(kind.atom()),
+            FileError::Io(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl Encoder for FileError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self.reason() {
+            Ok(reason) => reason.encode(env),
+            Err(message) => message.encode(env),
+        }
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => FileError::NotFound,
+            _ => FileError::Io(error),
+        }
+    }
+}
+
+impl From<FileError> for NifResult {
+    fn from(error: FileError) -> Self {
+        match error.reason() {
+            Ok(reason) => NifResult::Reason(reason),
+            Err(message) => NifResult::Error(message),
+        }
+    }
+}
+
+/// Open a regular file, failing with `NotFound` for anything else
+async fn open_file(path: &str) -> Result<(File, Metadata), FileError> {
+    let file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(FileError::NotFound);
+    }
+    Ok((file, metadata))
+}
+
+/// A regular file opened at `offset`, with the number of bytes to send
+pub struct FileSlice {
+    file: File,
+    pub length: u64,
+}
+
+impl FileSlice {
+    /// Open `length` bytes of `path` from `offset`, or up to the end of
+    /// the file if `length` is unset or runs past it
+    pub async fn open(path: &str, offset: u64, length: Option<u64>) -> Result<Self, FileError> {
+        let (file, metadata) = open_file(path).await?;
+        let available = metadata
+            .len()
+            .checked_sub(offset)
+            .ok_or(FileError::InvalidRange)?;
+        Self::at(
+            file,
+            offset,
+            length.map_or(available, |length| length.min(available)),
+        )
+        .await
+    }
+
+    async fn at(mut file: File, offset: u64, length: u64) -> Result<Self, FileError> {
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(Self { file, length })
+    }
+
+    /// Read the slice into body chunks on `tx`
+    ///
+    /// Each chunk waits until it fits in the memory budget instead of failing
+    /// halfway through a response that has already started, so chunks are
+    /// never larger than the whole budget.
+    pub async fn stream(
+        mut self,
+        tx: &ResponseSender,
+        budget: &Arc<MemoryBudget>,
+    ) -> Result<(), FileError> {
+        let chunk_size = match budget.limit() {
+            Some(0) => return Err(FileError::Overloaded),
+            Some(limit) => limit.min(FILE_CHUNK),
+            None => FILE_CHUNK,
+        };
+        let mut remaining = self.length;
+        while remaining > 0 {
+            let size = remaining.min(chunk_size as u64) as usize;
+            let reservation = budget.reserve(size).await;
+
+            let mut chunk = BytesMut::with_capacity(size);
+            while chunk.len() < size {
+                // The buffer's spare capacity caps the read at `size`
+                if self.file.read_buf(&mut chunk).await? == 0 {
+                    // The file shrank since it was opened
+                    return Err(FileError::Io(io::ErrorKind::UnexpectedEof.into()));
+                }
+            }
+            remaining -= size as u64;
+            tx.send(ResponseMessage::BodyChunk(chunk.freeze(), reservation))
+                .await
+                .map_err(|_| FileError::Closed)?;
+        }
+        Ok(())
+    }
+}
+
+/// How entity tags are computed for file responses
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EtagKind {
+    /// Weak tag from the file's size and modification time; costs nothing
+    Weak,
+    /// Strong tag from a hash of the contents, read in full on every request
+    Strong,
+    /// No `etag` header
+    None,
+}
+
+/// How `serve` answers, from the options of `Sparx.Response.serve_file/3`
+#[derive(NifMap)]
+pub struct ServeOptions {
+    pub etag: EtagKind,
+    /// Look for `.br` and `.gz` siblings of the file for clients that accept
+    /// those encodings
+    pub precompressed: bool,
+}
+
+/// Encodings of precompressed siblings, in the order they are looked for
+const PRECOMPRESSED: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip];
+
+/// A precompressed sibling of a requested file
+struct Variant {
+    path: String,
+    metadata: Metadata,
+    encoding: Encoding,
+}
+
+/// The first precompressed sibling of `path` that exists and whose
+/// encoding the client accepts
+async fn precompressed(path: &str, request_headers: &HeaderList) -> Option<Variant> {
+    let accept = request_headers
+        .get_all("accept-encoding")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    for &encoding in PRECOMPRESSED {
+        if compression::quality(&accept, encoding.token()) <= 0.0 {
+            continue;
+        }
+        let path = format!("{}.{}", path, encoding.extension());
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => {
+                return Some(Variant {
+                    path,
+                    metadata,
+                    encoding,
+                })
+            }
+            _ => continue,
+        }
+    }
+    None
+}
+
+/// What identifies the current version of a file
+pub struct Validators {
+    /// Quoted entity tag, `W/`-prefixed if weak
+    pub etag: Option<String>,
+    /// Modification time, truncated to whole seconds like HTTP dates
+    pub modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub async fn of(path: &str, metadata: &Metadata, kind: EtagKind) -> Result<Self, FileError> {
+        let modified = metadata.modified().ok().map(truncate_to_secs);
+        let etag = match kind {
+            EtagKind::Weak => {
+                let secs = modified
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_secs());
+                Some(format!("W/\"{:x}-{:x}\"", metadata.len(), secs))
+            }
+            EtagKind::Strong => Some(format!("\"{}\"", content_hash(path).await?)),
+            EtagKind::None => None,
+        };
+        Ok(Self { etag, modified })
+    }
+
+    /// Whether an `If-Range` validator names this version
+    ///
+    /// Entity tags must match strongly, so weak tags never do.
+    fn is_current(&self, validator: &str) -> bool {
+        if validator.starts_with('"') {
+            return self.etag.as_deref() == Some(validator);
+        }
+        if validator.starts_with("W/") {
+            return false;
+        }
+        httpdate::parse_http_date(validator)
+            .ok()
+            .zip(self.modified)
+            .is_some_and(|(date, modified)| date == modified)
+    }
+
+    /// Whether a conditional GET or HEAD can be answered with a 304
+    ///
+    /// `If-None-Match` is checked with weak comparison and, when present,
+    /// overrides `If-Modified-Since` (RFC 9110, section 13.2.2).
+    pub fn not_modified(&self, headers: &HeaderList) -> bool {
+        let if_none_match: Vec<&str> = headers
+            .get_all("if-none-match")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        if !if_none_match.is_empty() {
+            let Some(etag) = &self.etag else {
+                return false;
+            };
+            let opaque = |tag: &str| tag.trim_start_matches("W/").to_string();
+            return if_none_match
+                .iter()
+                .any(|tag| *tag == "*" || opaque(tag) == opaque(etag));
+        }
+        headers
+            .get("if-modified-since")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .zip(self.modified)
+            .is_some_and(|(since, modified)| modified <= since)
+    }
+
+    fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push(("etag".to_string(), etag.clone()));
+        }
+        if let Some(modified) = self.modified {
+            headers.push((
+                "last-modified".to_string(),
+                httpdate::fmt_http_date(modified),
+            ));
+        }
+        headers
+    }
+}
+
+/// Hash of a file's contents, for strong entity tags
+async fn content_hash(path: &str) -> Result<String, FileError> {
+    let (mut file, _) = open_file(path).await?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; FILE_CHUNK];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(URL_SAFE_NO_PAD.encode(hasher.finalize()))
+}
+
+/// The part of a file a request asks for
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selection {
+    Full,
+    /// One byte range, as `(start, length)`
+    Partial(u64, u64),
+    /// A range that lies entirely past the end of the file
+    Unsatisfiable,
+}
+
+/// Pick the part of a file of `size` bytes to send for a `Range` header
+///
+/// Only single ranges are honoured; malformed headers, other units, and
+/// multiple ranges get the whole file, as RFC 9110 allows. A range is also
+/// ignored when `If-Range` names another version of the file.
+pub fn select_range(headers: &HeaderList, size: u64, validators: &Validators) -> Selection {
+    let Some(range) = headers.get("range").and_then(|value| value.to_str().ok()) else {
+        return Selection::Full;
+    };
+    if let Some(if_range) = headers.get("if-range") {
+        let current = if_range
+            .to_str()
+            .is_ok_and(|value| validators.is_current(value.trim()));
+        if !current {
+            return Selection::Full;
+        }
+    }
+    parse_range(range, size)
+}
+
+fn parse_range(range: &str, size: u64) -> Selection {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Selection::Full;
+    };
+    if spec.contains(',') {
+        return Selection::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Selection::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // A suffix: the last `end` bytes
+        return match end.parse::<u64>() {
+            Ok(suffix) if suffix > 0 && size > 0 => {
+                let length = suffix.min(size);
+                Selection::Partial(size - length, length)
+            }
+            Ok(_) => Selection::Unsatisfiable,
+            Err(_) => Selection::Full,
+        };
+    }
+    let Ok(start) = start.parse::<u64>() else {
+        return Selection::Full;
+    };
+    let last = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(last) if last >= start => last,
+            _ => return Selection::Full,
+        }
+    };
+    if start >= size {
+        return Selection::Unsatisfiable;
+    }
+    Selection::Partial(start, last.min(size - 1) - start + 1)
+}
+
+/// HTTP dates have whole seconds, so modification times are compared at
+/// that precision
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH + Duration::from_secs(since.as_secs()),
+        Err(_) => time,
+    }
+}
+
+/// Answer a request with a file, honouring conditional and range headers
+///
+/// Sends the status (200, 206, 304, or 416), the validators, and for
+/// anything but a 304 `accept-ranges`, `content-length`, `content-range`
+/// for partial responses, `content-type` unless the handler sent one, and
+/// the body; then finishes the response. A 304 is decided on metadata
+/// alone, without opening the file unless the etag needs its contents
+/// hashed, and a HEAD response is sent without reading the file.
+///
+/// With `precompressed` set, a `.br` or `.gz` sibling the client accepts is
+/// sent in place of the file, with its own validators and a
+/// `content-encoding`; `content_type` still describes the original.
+pub async fn serve(
+    path: &str,
+    method: &str,
+    request_headers: &HeaderList,
+    options: &ServeOptions,
+    content_type: &str,
+    tx: &ResponseSender,
+    budget: &Arc<MemoryBudget>,
+) -> Result<(), FileError> {
+    let variant = match options.precompressed {
+        true => precompressed(path, request_headers).await,
+        false => None,
+    };
+    let (path, metadata) = match &variant {
+        Some(variant) => (variant.path.as_str(), variant.metadata.clone()),
+        None => (path, tokio::fs::metadata(path).await?),
+    };
+    if !metadata.is_file() {
+        return Err(FileError::NotFound);
+    }
+    let validators = Validators::of(path, &metadata, options.etag).await?;
+    let send = |message| async move { tx.send(message).await.map_err(|_| FileError::Closed) };
+
+    let mut headers = validators.headers();
+    // The body depends on Accept-Encoding whichever file is picked
+    if options.precompressed {
+        headers.push(("vary".to_string(), "accept-encoding".to_string()));
+    }
+
+    let conditional = method.eq_ignore_ascii_case("GET") || method.eq_ignore_ascii_case("HEAD");
+    if conditional && validators.not_modified(request_headers) {
+        send(ResponseMessage::Status(304)).await?;
+        send(ResponseMessage::Headers(headers)).await?;
+        return send(ResponseMessage::Finish).await;
+    }
+
+    let (file, metadata) = open_file(path).await?;
+    let size = metadata.len();
+    let selection = select_range(request_headers, size, &validators);
+    let (status, slice) = match selection {
+        Selection::Full => (200, Some((0, size))),
+        Selection::Partial(start, length) => (206, Some((start, length))),
+        Selection::Unsatisfiable => (416, None),
+    };
+
+    if let Some(variant) = &variant {
+        headers.push((
+            "content-encoding".to_string(),
+            variant.encoding.token().to_string(),
+        ));
+    }
+    headers.push(("accept-ranges".to_string(), "bytes".to_string()));
+    match selection {
+        Selection::Full => {}
+        Selection::Partial(start, length) => headers.push((
+            "content-range".to_string(),
+            format!("bytes {}-{}/{}", start, start + length - 1, size),
+        )),
+        Selection::Unsatisfiable => {
+            headers.push(("content-range".to_string(), format!("bytes */{}", size)))
+        }
+    }
+    let length = slice.map_or(0, |(_, length)| length);
+    headers.push(("content-length".to_string(), length.to_string()));
+
+    send(ResponseMessage::Status(status)).await?;
+    send(ResponseMessage::Headers(headers)).await?;
+    send(ResponseMessage::DefaultHeader(
+        "content-type".to_string(),
+        content_type.to_string(),
+    ))
+    .await?;
+    // A HEAD response gets the headers a GET would, without reading the file
+    let head = method.eq_ignore_ascii_case("HEAD");
+    if let Some((start, length)) = slice.filter(|_| !head) {
+        FileSlice::at(file, start, length)
+            .await?
+            .stream(tx, budget)
+            .await?;
+    }
+    send(ResponseMessage::Finish).await
+}
+
+/// Write the rest of a request body to a new file at `path`
+///
+/// The body goes from the socket to the file without passing through the
+/// BEAM. Returns the number of bytes written; on failure, including a body
+/// longer than `max_bytes`, the partial file is removed.
+pub async fn receive(
+    request: &RequestHandle,
+    path: &str,
+    max_bytes: Option<u64>,
+) -> Result<u64, FileError> {
+    let mut file = File::create(path).await.map_err(FileError::Io)?;
+    let written = write_body(request, &mut file, max_bytes).await;
+    drop(file);
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    written
+}
+
+async fn write_body(
+    request: &RequestHandle,
+    file: &mut File,
+    max_bytes: Option<u64>,
+) -> Result<u64, FileError> {
+    let mut written: u64 = 0;
+    while let Some(chunk) = request.read_body_chunk().await.map_err(FileError::Body)? {
+        written += chunk.len() as u64;
+        if max_bytes.is_some_and(|max| written > max) {
+            return Err(FileError::Body(ErrorKind::BodyTooLarge));
+        }
+        file.write_all(&chunk).await.map_err(FileError::Io)?;
+    }
+    file.flush().await.map_err(FileError::Io)?;
+    Ok(written)
+}
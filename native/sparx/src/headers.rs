@@ -0,0 +1,118 @@
+use crate::pool::Recycle;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use rustler::{Binary, Decoder, Encoder, Env, Error, OwnedBinary, Term};
+use smallvec::SmallVec;
+
+/// Headers kept inline before spilling to the heap
+///
+/// Typical browser and API requests carry fewer than 16 headers.
+const INLINE_HEADERS: usize = 16;
+
+/// Compact, ordered list of header name/value pairs
+///
+/// Names are `HeaderName`s, so standard headers are interned constants and
+/// values are `HeaderValue`s sharing hyper's buffers: copying a request's
+/// headers out of a `HeaderMap` allocates nothing for the common case.
+///
+/// Values cross to Elixir as raw binaries, byte for byte as received, even
+/// when they are not valid UTF-8. Names are always lowercase: hyper
+/// normalizes them while parsing and does not expose the original casing.
+#[derive(Clone, Default)]
+pub struct HeaderList(SmallVec<[(HeaderName, HeaderValue); INLINE_HEADERS]>);
+
+impl HeaderList {
+    /// Copy every header out of a hyper `HeaderMap`
+    pub fn extend_from_map(&mut self, headers: &HeaderMap) {
+        self.0.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+    }
+
+    pub fn push(&mut self, name: HeaderName, value: HeaderValue) {
+        self.0.push((name, value));
+    }
+
+    /// Parse and append a header received from Elixir
+    pub fn push_str(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let (name, value) = parse(name, value)?;
+        self.push(name, value);
+        Ok(())
+    }
+
+    /// First value for `name` (case-insensitive)
+    pub fn get(&self, name: &str) -> Option<&HeaderValue> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.as_str().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Every value for `name` (case-insensitive), in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a HeaderValue> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k.as_str().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Remove every value for `name` (case-insensitive)
+    pub fn remove(&mut self, name: &str) {
+        self.0
+            .retain(|(k, _)| !k.as_str().eq_ignore_ascii_case(name));
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = (HeaderName, HeaderValue)> + '_ {
+        self.0.drain(..)
+    }
+}
+
+impl Recycle for HeaderList {
+    fn recycle(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl Encoder for HeaderList {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let pairs: Vec<Term<'a>> = self
+            .0
+            .iter()
+            .map(|(name, value)| (name.as_str(), binary(value.as_bytes(), env)).encode(env))
+            .collect();
+        pairs.encode(env)
+    }
+}
+
+fn binary<'a>(bytes: &[u8], env: Env<'a>) -> Term<'a> {
+    let mut binary = OwnedBinary::new(bytes.len()).unwrap();
+    binary.as_mut_slice().copy_from_slice(bytes);
+    binary.release(env).encode(env)
+}
+
+impl<'a> Decoder<'a> for HeaderList {
+    fn decode(term: Term<'a>) -> rustler::NifResult<Self> {
+        let pairs: Vec<(String, Binary)> = term.decode()?;
+        let mut list = HeaderList::default();
+        for (name, value) in pairs {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| Error::BadArg)?;
+            let value = HeaderValue::from_bytes(value.as_slice()).map_err(|_| Error::BadArg)?;
+            list.push(name, value);
+        }
+        Ok(list)
+    }
+}
+
+/// Parse a header received from Elixir, describing what is wrong with it
+///
+/// Values may hold any bytes but control characters, so non-ASCII text
+/// passes through as its UTF-8 encoding.
+pub fn parse(name: &str, value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("Invalid header name: {}", name))?;
+    let value = HeaderValue::from_bytes(value.as_bytes())
+        .map_err(|_| format!("Invalid header value for {}", name))?;
+    Ok((name, value))
+}
@@ -0,0 +1,202 @@
+use crate::response::BodyError;
+use crate::timing::{Phase, RequestTimings};
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use hyper::{HeaderMap, Method, Response, StatusCode, Uri, Version};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// One finished request as shown by the inspector
+struct Entry {
+    /// Unix time the request finished, in milliseconds
+    at_ms: u64,
+    method: Method,
+    target: String,
+    version: Version,
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    /// Microseconds since accept for each phase reached
+    phases: [(&'static str, Option<u64>); 5],
+    queue_wait_us: Option<u64>,
+}
+
+/// Development endpoint listing the most recent requests
+///
+/// Served straight from Rust at `inspector_path`, so it works even while
+/// the Elixir handler is stuck. Requests to the endpoint itself are not
+/// recorded.
+pub struct Inspector {
+    path: String,
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Inspector {
+    pub fn new(path: String, capacity: usize) -> Self {
+        Self {
+            path,
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Whether a request path addresses the inspector
+    pub fn is_endpoint(&self, path: &str) -> bool {
+        path == self.path
+    }
+
+    /// Remember a finished request, evicting the oldest beyond capacity
+    ///
+    /// Credential headers are kept by name only, with `[redacted]` for the
+    /// value, so the listing can be shared without leaking secrets.
+    pub fn record(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        version: Version,
+        headers: &HeaderMap,
+        status: StatusCode,
+        timings: &RequestTimings,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = Entry {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            method: method.clone(),
+            target: uri
+                .path_and_query()
+                .map(|pq| pq.as_str().to_string())
+                .unwrap_or_else(|| uri.path().to_string()),
+            version,
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = if is_credential(name) {
+                        "[redacted]".to_string()
+                    } else {
+                        String::from_utf8_lossy(value.as_bytes()).into_owned()
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+            phases: [
+                ("received", timings.get(Phase::Received)),
+                ("enqueued", timings.get(Phase::Enqueued)),
+                ("dequeued", timings.get(Phase::Dequeued)),
+                ("first_byte", timings.get(Phase::FirstByte)),
+                ("finished", timings.get(Phase::Finished)),
+            ],
+            queue_wait_us: timings.between(Phase::Enqueued, Phase::Dequeued),
+        };
+
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == self.capacity {
+                entries.pop_back();
+            }
+            entries.push_front(entry);
+        }
+    }
+
+    /// The endpoint's response: recorded requests as JSON, newest first
+    pub fn response(&self) -> Response<BoxBody> {
+        let body = http_body_util::Full::new(Bytes::from(self.render()))
+            .map_err(|never| match never {})
+            .boxed();
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .header("cache-control", "no-store")
+            .body(body)
+            .unwrap()
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::from("{\"requests\":[");
+        if let Ok(entries) = self.entries.lock() {
+            for (i, entry) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                entry.render(&mut out);
+            }
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl Entry {
+    fn render(&self, out: &mut String) {
+        let _ = write!(out, "{{\"at_ms\":{},\"method\":", self.at_ms);
+        push_json_str(out, self.method.as_str());
+        out.push_str(",\"target\":");
+        push_json_str(out, &self.target);
+        out.push_str(",\"version\":");
+        push_json_str(out, &format!("{:?}", self.version));
+        let _ = write!(out, ",\"status\":{},\"headers\":[", self.status.as_u16());
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push('[');
+            push_json_str(out, name);
+            out.push(',');
+            push_json_str(out, value);
+            out.push(']');
+        }
+        out.push_str("],\"timings_us\":{");
+        for (i, (phase, micros)) in self.phases.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":", phase);
+            push_json_opt(out, *micros);
+        }
+        out.push_str("},\"queue_wait_us\":");
+        push_json_opt(out, self.queue_wait_us);
+        out.push('}');
+    }
+}
+
+/// Whether a header's value carries credentials the inspector must not keep
+fn is_credential(name: &HeaderName) -> bool {
+    *name == AUTHORIZATION || *name == COOKIE || *name == PROXY_AUTHORIZATION
+}
+
+fn push_json_opt(out: &mut String, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            let _ = write!(out, "{}", value);
+        }
+        None => out.push_str("null"),
+    }
+}
+
+pub fn push_json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,122 @@
+use bytes::{Buf, BytesMut};
+use futures::task::AtomicWaker;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// 1xx responses waiting to be written ahead of a final HTTP/1.1 response
+///
+/// hyper has no server API for interim responses, so they are encoded here
+/// and written straight to the socket by `InterimIo` the next time the
+/// connection task runs, before hyper writes anything else.
+#[derive(Default)]
+pub struct Interim {
+    pending: Mutex<BytesMut>,
+    /// Connection task to wake when a response is queued
+    waker: AtomicWaker,
+}
+
+impl Interim {
+    /// Queue an interim response and wake the connection to write it
+    pub fn send(&self, status: StatusCode, headers: &[(HeaderName, HeaderValue)]) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.extend_from_slice(b"HTTP/1.1 ");
+            pending.extend_from_slice(status.as_str().as_bytes());
+            pending.extend_from_slice(b" ");
+            pending.extend_from_slice(status.canonical_reason().unwrap_or("").as_bytes());
+            pending.extend_from_slice(b"\r\n");
+            for (name, value) in headers {
+                pending.extend_from_slice(name.as_str().as_bytes());
+                pending.extend_from_slice(b": ");
+                pending.extend_from_slice(value.as_bytes());
+                pending.extend_from_slice(b"\r\n");
+            }
+            pending.extend_from_slice(b"\r\n");
+        }
+        self.waker.wake();
+    }
+}
+
+/// Connection I/O that writes queued interim responses first
+///
+/// hyper flushes its connection every time the task is woken, even with
+/// nothing of its own to write, which is when queued responses go out.
+pub struct InterimIo<I> {
+    inner: I,
+    interim: Arc<Interim>,
+}
+
+impl<I> InterimIo<I> {
+    pub fn new(inner: I, interim: Arc<Interim>) -> Self {
+        Self { inner, interim }
+    }
+}
+
+impl<I: AsyncWrite + Unpin> InterimIo<I> {
+    /// Write out every queued interim response
+    fn poll_interim(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.interim.waker.register(cx.waker());
+        let Ok(mut pending) = self.interim.pending.lock() else {
+            return Poll::Ready(Ok(()));
+        };
+        if pending.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        while !pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            pending.advance(written);
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for InterimIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.interim.waker.register(cx.waker());
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for InterimIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_interim(cx))?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_interim(cx))?;
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_interim(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
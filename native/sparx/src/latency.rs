@@ -0,0 +1,183 @@
+use crate::atoms;
+use crate::request::HttpMethod;
+use crate::timing::{Phase, RequestTimings};
+use hyper::{Method, StatusCode};
+use rustler::{Encoder, Env, NifMap, Term};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in microseconds
+pub const BUCKETS_US: [u64; 12] = [
+    1_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+    5_000_000, 10_000_000,
+];
+
+/// Methods with a series of their own; the rest share one
+const METHODS: [Method; 9] = [
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+    Method::HEAD,
+    Method::OPTIONS,
+    Method::CONNECT,
+    Method::TRACE,
+];
+
+/// Status classes 1xx through 5xx
+const CLASSES: usize = 5;
+
+/// Fixed-bucket latency histogram, safe to record into from any thread
+#[derive(Default)]
+pub struct Histogram {
+    /// Samples per bucket; the last one is past every bound
+    counts: [AtomicU64; BUCKETS_US.len() + 1],
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, micros: u64) {
+        let bucket = BUCKETS_US
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(BUCKETS_US.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Samples per bucket, not cumulative
+    pub fn counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn sum_us(&self) -> u64 {
+        self.sum_us.load(Ordering::Relaxed)
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let counts = self.counts();
+        HistogramSnapshot {
+            count: counts.iter().sum(),
+            sum_us: self.sum_us(),
+            counts,
+        }
+    }
+}
+
+/// Time in queue, in the handler, and in total, by method and status class
+///
+/// Aggregated natively so that per-request numbers never have to cross into
+/// Elixir; `metrics_snapshot` reads them all at once.
+pub struct LatencyHistograms {
+    /// `(METHODS.len() + 1) * CLASSES` series, by method, then class
+    series: Vec<Series>,
+}
+
+#[derive(Default)]
+struct Series {
+    /// Enqueued until a handler picked the request up
+    queue: Histogram,
+    /// Picked up until the response head was ready
+    handler: Histogram,
+    /// Arrival until the response head was ready
+    total: Histogram,
+}
+
+/// Every series with at least one request, plus the bucket bounds
+#[derive(NifMap)]
+pub struct LatencySnapshot {
+    bounds_us: Vec<u64>,
+    histograms: Vec<SeriesSnapshot>,
+}
+
+#[derive(NifMap)]
+struct SeriesSnapshot {
+    method: MethodKey,
+    status_class: u8,
+    queue: HistogramSnapshot,
+    handler: HistogramSnapshot,
+    total: HistogramSnapshot,
+}
+
+#[derive(NifMap)]
+struct HistogramSnapshot {
+    count: u64,
+    sum_us: u64,
+    /// One more than `bounds_us`: the last counts samples past every bound
+    counts: Vec<u64>,
+}
+
+/// A method as in request metadata, or `:other` for the shared series
+struct MethodKey(Option<Method>);
+
+impl Encoder for MethodKey {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match &self.0 {
+            Some(method) => HttpMethod(method.clone()).encode(env),
+            None => atoms::other().encode(env),
+        }
+    }
+}
+
+impl Default for LatencyHistograms {
+    fn default() -> Self {
+        let len = (METHODS.len() + 1) * CLASSES;
+        Self {
+            series: (0..len).map(|_| Series::default()).collect(),
+        }
+    }
+}
+
+impl LatencyHistograms {
+    /// Record a request whose response head is ready
+    ///
+    /// Requests answered without reaching a handler (shed, rejected, or
+    /// served natively) only count toward the total.
+    pub fn record(
+        &self,
+        method: &Method,
+        status: StatusCode,
+        timings: &RequestTimings,
+        total: Duration,
+    ) {
+        let method = METHODS
+            .iter()
+            .position(|known| known == method)
+            .unwrap_or(METHODS.len());
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize - 1;
+        let series = &self.series[method * CLASSES + class];
+
+        if let Some(micros) = timings.between(Phase::Enqueued, Phase::Dequeued) {
+            series.queue.record(micros);
+        }
+        if let Some(micros) = timings.between(Phase::Dequeued, Phase::FirstByte) {
+            series.handler.record(micros);
+        }
+        series.total.record(total.as_micros() as u64);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let histograms = self
+            .series
+            .iter()
+            .enumerate()
+            .map(|(i, series)| (i, series.total.snapshot(), series))
+            .filter(|(_, total, _)| total.count > 0)
+            .map(|(i, total, series)| SeriesSnapshot {
+                method: MethodKey(METHODS.get(i / CLASSES).cloned()),
+                status_class: (i % CLASSES + 1) as u8,
+                queue: series.queue.snapshot(),
+                handler: series.handler.snapshot(),
+                total,
+            })
+            .collect();
+        LatencySnapshot {
+            bounds_us: BUCKETS_US.to_vec(),
+            histograms,
+        }
+    }
+}
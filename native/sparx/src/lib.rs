@@ -2,133 +2,567 @@
 
 use base64::Engine;
 use bytes::Bytes;
-use rustler::{Env, ResourceArc, Term};
-use tokio::sync::mpsc;
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use rustler::{Encoder, Env, LocalPid, OwnedEnv, Reference, ResourceArc, Term};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 
+mod access_log;
 mod atoms;
+mod bench;
+mod binary;
+mod budget;
+mod capture;
+mod channel;
+mod compression;
 mod config;
+mod connection;
+mod devcert;
+mod disconnect;
+mod doctor;
+mod duplex;
+mod errors;
+mod events;
+mod faults;
+mod files;
+mod headers;
+mod inspector;
+mod interim;
+mod latency;
+mod library;
+mod listener;
+mod logger;
+mod metrics;
+mod mime;
+mod multipart;
+mod numa;
+mod pool;
+mod profiler;
+mod proxy_protocol;
+mod query;
+mod queue;
+mod raw;
 mod request;
 mod response;
+mod runtime;
 mod server;
+mod sse;
+mod stats;
+mod telemetry;
+mod timer;
+mod timing;
+mod tls;
+mod topics;
+mod trace_context;
 mod websocket;
 
-use config::ServerConfig;
+use access_log::AccessLog;
+use binary::NifBytes;
+use budget::Reservation;
+use capture::{CapturedResponse, ResponseCapture};
+use channel::TrySendError;
+use config::{ServerConfig, Transport};
+use duplex::TestConnection;
+use listener::{Bound, Listener, ListenerInfo, SocketOptions, StartError};
+use raw::RawStream;
 use request::{RequestHandle, ResponseMessage};
 use response::NifResult;
-use server::{QueuedRequest, ServerHandle};
+use runtime::{CoreRuntime, ServerRuntime};
+use server::{ServerContext, ServerHandle};
+use stats::ServerStats;
+use timing::TimingsSnapshot;
 use websocket::{Frame, WebSocketHandle};
 
 fn load(_env: Env, load_info: Term) -> bool {
     // Configure tracing with SPARX_LOG env variable
-    let env_filter = std::env::var("SPARX_LOG")
-        .map(|s| EnvFilter::new(&s))
-        .unwrap_or_else(|_| EnvFilter::new("warn"));
-
-    tracing_subscriber::registry()
-        .with(fmt::layer().with_filter(env_filter))
-        .init();
+    logger::init();
 
     // Configure Tokio runtime for async tasks
     if let Ok(config) = load_info.decode::<rustler::runtime::RuntimeConfig>() {
         rustler::runtime::configure(config).ok();
     }
 
+    // Servers started by this version must survive a hot upgrade
+    library::pin();
+
     true
 }
 
+/// Load this library for a new version of `Sparx.Native` while the old
+/// version's servers, WebSockets, and requests are still alive
+fn upgrade(env: Env, load_info: Term) -> bool {
+    library::take_over(env) && load(env, load_info)
+}
+
 // ============================================================================
 // Server Management NIFs
 // ============================================================================
 
 /// Start the HTTP server
-/// Returns {:ok, server_ref} or {:error, reason}
-#[rustler::nif]
-fn server_start(config: ServerConfig) -> Result<ResourceArc<ServerHandle>, String> {
+/// Returns {:ok, server_ref} | {:error, :eaddrinuse | :eacces | :eaddrnotavail | reason}
+#[rustler::nif(schedule = "DirtyIo")]
+fn server_start(config: ServerConfig) -> Result<ResourceArc<ServerHandle>, StartError> {
     // Create request queue
-    let (request_tx, request_rx) = mpsc::channel::<QueuedRequest>(1024);
-    let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+    let (request_tx, request_rx) = queue::channel(config.queue_capacity, config.queue_overflow);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let listener_configs = config.listeners();
+    for listener in &listener_configs {
+        listener
+            .validate()
+            .map_err(|e| format!("Invalid listener: {}", e))?;
+    }
+    if config.thread_per_core
+        && listener_configs
+            .iter()
+            .any(|listener| listener.port == Some(0))
+    {
+        // Each core binds its own listener, so port 0 would give every core
+        // a different ephemeral port
+        return Err("thread_per_core requires a fixed port".to_string().into());
+    }
 
-    let server_handle = ServerHandle::new(request_rx, shutdown_tx);
-    let server_arc = ResourceArc::new(server_handle);
+    ServerRuntime::validate(&config)?;
+    SocketOptions::validate(&config)?;
 
-    // Spawn server task
-    let config_clone = config.clone();
-    rustler::spawn(async move {
-        tokio::select! {
-            result = server::start_server(config_clone, request_tx) => {
-                if let Err(e) = result {
-                    tracing::error!("Server error: {}", e);
-                }
+    let placement = numa::Placement::from_config(&config)
+        .map_err(|e| format!("Invalid NUMA placement: {}", e))?
+        .map(Arc::new);
+
+    let tls = listener_configs
+        .iter()
+        .map(|listener| {
+            listener
+                .tls
+                .as_ref()
+                .map(|tls_config| tls::acceptor(tls_config, config.http2))
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let access_log = config
+        .access_log
+        .as_ref()
+        .map(AccessLog::open)
+        .transpose()?;
+
+    let runtime = ServerRuntime::from_config(&config, placement.clone())
+        .map_err(|e| format!("Failed to build runtime: {}", e))?;
+
+    // Build the context inside the server's runtime so clocks started here
+    // (the timer wheel's and the connection registry's) read that runtime's
+    // time, simulated or not
+    let runtime_handle = runtime.handle();
+    let enter = runtime_handle.as_ref().map(|handle| handle.enter());
+    let context = Arc::new(ServerContext::new(config, placement.as_deref(), access_log));
+    drop(enter);
+
+    if context.config.warmup {
+        // This does not reach hyper's Date header cache, which is private and
+        // per-thread; it is filled by the first response each worker writes
+        context.pools.prefill(placement.as_deref());
+        runtime.warm_up();
+    }
+
+    // Bind every listener before returning, so address problems reach the
+    // caller. In-memory servers get their connections from `test_connect`.
+    let mut accept_loops = Vec::new();
+    let mut bound_listeners = Vec::new();
+    let mut local_addr = None;
+    let mut exports = Vec::new();
+    if context.config.transport == Transport::Tcp {
+        // Acceptors get SO_REUSEPORT listeners of their own, or share one
+        // socket and take turns accepting from it
+        let options = SocketOptions::from_config(&context.config);
+        for (listener_config, tls) in listener_configs.iter().zip(tls) {
+            let mut bound = vec![Bound::open(listener_config, &options).map_err(StartError::bind)?];
+            for _ in 1..runtime.acceptors(&context.config) {
+                let another = bound[0].another(listener_config, &options);
+                bound.push(another.map_err(StartError::bind)?);
             }
-            _ = shutdown_rx.recv() => {
-                tracing::info!("Server shutdown requested");
+            // Port 0 picks an ephemeral port; further acceptors share it
+            let addr = bound[0].local_addr().map_err(StartError::bind)?;
+            local_addr = local_addr.or(addr);
+            let info = ListenerInfo::new(listener_config, &bound[0]).map_err(StartError::bind)?;
+            bound_listeners.push(info);
+            // One descriptor cannot hand over several SO_REUSEPORT sockets
+            let export = if bound.len() > 1 && bound[0].splits(listener_config, &options) {
+                None
+            } else {
+                Some(bound[0].try_clone().map_err(StartError::bind)?)
+            };
+            exports.push((listener_config.name, export));
+
+            let listener = Listener::of(listener_config);
+            for (core, bound) in bound.into_iter().enumerate() {
+                accept_loops.push((core, listener, tls.clone(), bound));
+            }
+        }
+    }
+
+    // With `acceptor_threads`, accept loops get threads of their own and
+    // hand connections to the server's runtime
+    let per_core = matches!(runtime, ServerRuntime::PerCore(_));
+    let acceptor_threads = if context.config.acceptor_threads && !per_core {
+        (0..accept_loops.len())
+            .map(CoreRuntime::start_acceptor)
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|e| format!("Failed to start acceptor threads: {}", e))?
+    } else {
+        Vec::new()
+    };
+    let connections = if acceptor_threads.is_empty() {
+        None
+    } else {
+        let handle = runtime
+            .connection_handle()
+            .ok_or_else(|| "Failed to reach the server runtime".to_string())?;
+        Some(handle)
+    };
+
+    // Spawn `acceptors` accept loops per listener (one per core in
+    // thread-per-core mode)
+    for (index, (core, listener, tls, bound)) in accept_loops.into_iter().enumerate() {
+        let server_context = context.clone();
+        let request_tx = request_tx.clone();
+        let connections = connections.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        let accept_loop = async move {
+            let serving = server::start_server(
+                server_context,
+                request_tx,
+                listener,
+                tls,
+                bound,
+                connections,
+            );
+            tokio::select! {
+                result = serving => {
+                    if let Err(e) = result {
+                        tracing::error!("Server error: {}", e);
+                    }
+                }
+                _ = shutdown_rx.wait_for(|stop| *stop) => {
+                    tracing::info!("Server shutdown requested");
+                }
             }
+        };
+        match acceptor_threads.get(index) {
+            Some(thread) => thread.spawn(accept_loop),
+            None => runtime.spawn_on(core, accept_loop),
         }
+    }
+
+    runtime.spawn_on(
+        0,
+        connection::run_sweeper(context.clone(), shutdown_rx.clone()),
+    );
+
+    if context.access_log.is_some() {
+        runtime.spawn_on(
+            0,
+            access_log::run_writer(context.clone(), shutdown_rx.clone()),
+        );
+    }
+
+    let timer_context = context.clone();
+    let timer_shutdown_rx = shutdown_rx.clone();
+    runtime.spawn_on(0, async move {
+        timer_context.timers.run(timer_shutdown_rx).await;
     });
 
-    Ok(server_arc)
+    let server_handle = ServerHandle::new(
+        request_rx,
+        shutdown_tx,
+        runtime,
+        acceptor_threads,
+        context,
+        request_tx,
+        local_addr,
+        bound_listeners,
+        exports,
+    );
+    Ok(ResourceArc::new(server_handle))
 }
 
 /// Stop the HTTP server
 #[rustler::nif(schedule = "DirtyCpu")]
 fn server_stop(server: ResourceArc<ServerHandle>) -> rustler::Atom {
-    rustler::spawn(async move {
-        server.shutdown().await;
-    });
+    server.shutdown();
     atoms::ok()
 }
 
+/// Stop accepting new connections, keeping open ones alive
+/// Returns :ok
+#[rustler::nif]
+fn server_pause(server: ResourceArc<ServerHandle>) -> rustler::Atom {
+    server.set_paused(true);
+    atoms::ok()
+}
+
+/// Start accepting new connections again after `server_pause`
+/// Returns :ok
+#[rustler::nif]
+fn server_resume(server: ResourceArc<ServerHandle>) -> rustler::Atom {
+    server.set_paused(false);
+    atoms::ok()
+}
+
+/// Stop accepting and wait for open connections to finish their requests
+/// Returns :ok | {:error, :timeout}
+#[rustler::nif]
+async fn server_drain(server: ResourceArc<ServerHandle>, timeout_ms: Option<u64>) -> NifResult {
+    let timeout_ms = timeout_ms.unwrap_or(server.context.config.drain_timeout_ms);
+    if server.drain(Duration::from_millis(timeout_ms)).await {
+        NifResult::Ok
+    } else {
+        NifResult::Reason(atoms::timeout())
+    }
+}
+
+/// Check a configuration before starting a server with it
+/// Returns a list of %{severity: :error | :warning, field, message}
+#[rustler::nif(schedule = "DirtyIo")]
+fn validate_config(config: ServerConfig) -> Vec<doctor::Diagnostic> {
+    doctor::check(&config)
+}
+
+/// Duplicate a server's listening sockets for another server to take over
+/// Returns {:ok, [{name, fd}]} | {:error, :not_supported | :server_error}
+#[rustler::nif]
+fn server_export_listener(
+    server: ResourceArc<ServerHandle>,
+) -> Result<Vec<(rustler::Atom, i32)>, rustler::Atom> {
+    server.export_listeners()
+}
+
+/// Get the address a server is listening on
+/// Returns %{host, port, tls, transport}
+#[rustler::nif]
+fn server_info(server: ResourceArc<ServerHandle>) -> server::ServerInfo {
+    server.info()
+}
+
+/// Get server statistics
+/// Returns a map of counters
+#[rustler::nif]
+fn server_stats(server: ResourceArc<ServerHandle>) -> ServerStats {
+    server.stats()
+}
+
+/// Get latency histograms by method and status class
+/// Returns %{bounds_us: [...], histograms: [...]}
+#[rustler::nif]
+fn metrics_snapshot(server: ResourceArc<ServerHandle>) -> latency::LatencySnapshot {
+    server.context.latency.snapshot()
+}
+
 /// Receive a request from the server (demand-driven, async)
-/// Returns {:ok, request_handle} or {:error, reason}
+/// Returns {:ok, request_handle}, {:error, :timeout} once `timeout_ms`
+/// passes without one (nil waits forever), or {:error, :closed}
 #[rustler::nif]
 async fn receive_request(
     server: ResourceArc<ServerHandle>,
+    timeout_ms: Option<u64>,
 ) -> Result<ResourceArc<RequestHandle>, rustler::Atom> {
-    match server.receive_request().await {
-        Some(handle) => {
-            let handle_arc = ResourceArc::new(handle);
-            Ok(handle_arc)
-        }
-        None => {
-            // Server shut down or queue closed
-            Err(atoms::error())
-        }
+    server
+        .receive_request(timeout_ms.map(Duration::from_millis))
+        .await
+        .map(ResourceArc::new)
+}
+
+/// Push requests to `pids`, round-robin, as {:sparx_request, request_handle}
+/// instead of waiting for `receive_request`; an empty list goes back to
+/// pulling
+/// Returns :ok
+#[rustler::nif]
+fn server_set_dispatchers(server: ResourceArc<ServerHandle>, pids: Vec<LocalPid>) -> rustler::Atom {
+    if server.set_dispatchers(pids) {
+        rustler::spawn(async move { server.dispatch().await });
     }
+    atoms::ok()
+}
+
+/// Receive up to `max_requests` requests in one call
+/// Returns {:ok, [request_handle]} with at least one handle, or the errors
+/// of `receive_request`
+#[rustler::nif]
+async fn receive_requests(
+    server: ResourceArc<ServerHandle>,
+    max_requests: usize,
+    timeout_ms: Option<u64>,
+) -> Result<Vec<ResourceArc<RequestHandle>>, rustler::Atom> {
+    let batch = server
+        .receive_requests(max_requests.max(1), timeout_ms.map(Duration::from_millis))
+        .await?;
+    Ok(batch.into_iter().map(ResourceArc::new).collect())
 }
 
 // ============================================================================
 // Request Streaming NIFs
 // ============================================================================
 
-/// Read a chunk from the request body
-/// Returns {:ok, binary} | {:error, reason}
+/// Get the request metadata
+/// Returns %Sparx.Request.Metadata{}
 #[rustler::nif]
-fn read_chunk(
-    env: rustler::Env,
-    request: ResourceArc<RequestHandle>,
-) -> Result<rustler::Binary, rustler::Atom> {
-    // Use oneshot channel to wait for async result
-    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+fn request_metadata<'a>(env: Env<'a>, request: ResourceArc<RequestHandle>) -> Term<'a> {
+    request.metadata_term(env)
+}
+
+/// Get the per-phase timestamps of a request
+/// Returns a map of microseconds since the connection was accepted
+#[rustler::nif]
+fn request_timings(request: ResourceArc<RequestHandle>) -> TimingsSnapshot {
+    request.timings.snapshot()
+}
+
+/// Get the lifecycle event log of a request
+/// Returns a list of %{at_us, event, reason}, oldest first
+#[rustler::nif]
+fn request_events(request: ResourceArc<RequestHandle>) -> Vec<events::EventEntry> {
+    request.timings.events()
+}
+
+/// Decode a query string into a map, nesting bracketed keys
+/// Returns %{binary => binary | list | map}
+#[rustler::nif]
+fn parse_query(query: rustler::Binary) -> query::Value {
+    query::parse(query.as_slice())
+}
+
+/// Decode a query string into key/value pairs, in order
+/// Returns [{binary, binary}]
+#[rustler::nif]
+fn parse_query_pairs(query: rustler::Binary) -> Vec<(NifBytes, NifBytes)> {
+    query::pairs(query.as_slice())
+        .into_iter()
+        .map(|(key, value)| (NifBytes(key.into()), NifBytes(value.into())))
+        .collect()
+}
 
+/// Send {:sparx_request_closed, ref} to `pid` if the client goes away
+/// before the response is done
+/// Returns ref
+#[rustler::nif]
+fn request_monitor<'a>(
+    env: Env<'a>,
+    request: ResourceArc<RequestHandle>,
+    pid: LocalPid,
+) -> Reference<'a> {
+    let reference = env.make_ref();
+    let mut owned_env = OwnedEnv::new();
+    let saved = owned_env.run(|owned| owned_env.save(reference.in_env(owned)));
+    let mut closed = request.disconnect.subscribe();
     rustler::spawn(async move {
-        let result = match request.read_body_chunk().await {
-            Ok(Some(chunk)) => Ok(chunk.to_vec()),
-            Ok(None) => Ok(Vec::new()), // Empty vec signals EOF
-            Err(_e) => Err(atoms::error()),
-        };
-        let _ = result_tx.send(result);
+        // Fails once the request is dropped without the client leaving
+        if closed.wait_for(|closed| *closed).await.is_ok() {
+            let _ = owned_env.send_and_clear(&pid, |env| {
+                (atoms::sparx_request_closed(), saved.load(env)).encode(env)
+            });
+        }
     });
+    reference
+}
 
-    match result_rx.blocking_recv() {
-        Ok(Ok(vec)) => {
-            // Convert Vec<u8> to Binary
-            let mut binary = rustler::OwnedBinary::new(vec.len()).unwrap();
-            binary.as_mut_slice().copy_from_slice(&vec);
-            Ok(binary.release(env))
-        }
-        Ok(Err(e)) => Err(e),
-        Err(_) => Err(atoms::error()),
+/// Read a chunk from the request body
+/// Returns {:ok, binary} | {:error, reason}, where reason is an error kind
+/// atom such as :connection_reset or :timeout
+#[rustler::nif]
+async fn read_chunk(request: ResourceArc<RequestHandle>) -> Result<NifBytes, rustler::Atom> {
+    match request.read_body_chunk().await {
+        Ok(Some(chunk)) => Ok(NifBytes(chunk)),
+        Ok(None) => Ok(NifBytes::empty()), // Empty binary signals EOF
+        Err(kind) => Err(kind.atom()),
+    }
+}
+
+/// Read several chunks from the request body in one call
+/// Returns {:ok, [binary]} | {:error, reason}, with an empty list at EOF
+#[rustler::nif]
+async fn read_chunks(
+    request: ResourceArc<RequestHandle>,
+    max_chunks: usize,
+    max_bytes: usize,
+) -> Result<Vec<NifBytes>, rustler::Atom> {
+    match request.read_body_chunks(max_chunks, max_bytes).await {
+        Ok(chunks) => Ok(chunks.into_iter().map(NifBytes).collect()),
+        Err(kind) => Err(kind.atom()),
+    }
+}
+
+/// Write the rest of the request body to a file, replacing any file at
+/// `path`, and fail once it goes over `max_bytes` (if set)
+/// Returns {:ok, bytes_written} | {:error, :body_too_large | reason}
+#[rustler::nif]
+async fn stream_body_to_file(
+    request: ResourceArc<RequestHandle>,
+    path: String,
+    max_bytes: Option<u64>,
+) -> Result<u64, files::FileError> {
+    files::receive(&request, &path, max_bytes).await
+}
+
+/// Read the whole request body into one binary
+/// Returns {:ok, binary} | {:error, :too_large} for a body over `max_bytes` |
+/// {:error, reason}
+#[rustler::nif]
+async fn read_body(
+    request: ResourceArc<RequestHandle>,
+    max_bytes: usize,
+) -> Result<NifBytes, rustler::Atom> {
+    match request.read_full_body(max_bytes).await {
+        Ok(Some(body)) => Ok(NifBytes(body)),
+        Ok(None) => Err(atoms::too_large()),
+        Err(kind) => Err(kind.atom()),
+    }
+}
+
+/// Get the trailer fields sent after the request body
+/// Returns {:ok, [{name, value}]} | {:error, :pending} before the body has
+/// been read to the end
+#[rustler::nif]
+fn read_trailers(
+    request: ResourceArc<RequestHandle>,
+) -> Result<headers::HeaderList, rustler::Atom> {
+    request.trailers().ok_or_else(atoms::pending)
+}
+
+/// Read the headers of the next part of a multipart body, skipping whatever
+/// is left of the current part
+/// Returns {:ok, [{name, value}]} | {:ok, nil} after the last part |
+/// {:error, :invalid_request | reason}
+#[rustler::nif]
+async fn multipart_next_part(
+    request: ResourceArc<RequestHandle>,
+) -> Result<Option<Vec<(String, String)>>, rustler::Atom> {
+    let mut guard = request.multipart.lock().await;
+    if guard.is_none() {
+        *guard = multipart::Multipart::for_request(&request.metadata.headers);
+    }
+    let Some(parser) = guard.as_mut() else {
+        return Err(atoms::invalid_request());
+    };
+    parser
+        .next_part(&request)
+        .await
+        .map_err(multipart::MultipartError::atom)
+}
+
+/// Read a chunk of the current multipart part's body
+/// Returns {:ok, binary} | {:error, reason}, with an empty binary at the end
+/// of the part
+#[rustler::nif]
+async fn multipart_read_chunk(
+    request: ResourceArc<RequestHandle>,
+) -> Result<NifBytes, rustler::Atom> {
+    let mut guard = request.multipart.lock().await;
+    let Some(parser) = guard.as_mut() else {
+        return Ok(NifBytes::empty());
+    };
+    match parser.read_chunk(&request).await {
+        Ok(Some(chunk)) => Ok(NifBytes(chunk)),
+        Ok(None) => Ok(NifBytes::empty()),
+        Err(e) => Err(e.atom()),
     }
 }
 
@@ -150,6 +584,19 @@ async fn send_status(request: ResourceArc<RequestHandle>, status: u16) -> NifRes
     }
 }
 
+/// Send a 1xx informational response, such as 103 Early Hints, ahead of
+/// the final response
+/// Returns :ok | {:error, :not_supported | :already_sent} | {:error, reason},
+/// with :not_supported for anything but HTTP/1.1
+#[rustler::nif]
+async fn send_informational(
+    request: ResourceArc<RequestHandle>,
+    status: u16,
+    headers: Vec<(String, String)>,
+) -> NifResult {
+    request.send_informational(status, headers).await
+}
+
 /// Send response header
 /// Returns :ok | {:error, reason}
 #[rustler::nif]
@@ -168,37 +615,227 @@ async fn send_header(
     }
 }
 
+/// Send several response headers in one call
+/// Returns :ok | {:error, reason}, naming the first invalid header
+#[rustler::nif]
+async fn send_headers(
+    request: ResourceArc<RequestHandle>,
+    headers: Vec<(String, String)>,
+) -> NifResult {
+    if let Some(e) = headers
+        .iter()
+        .find_map(|(name, value)| headers::parse(name, value).err())
+    {
+        return NifResult::Error(e);
+    }
+
+    if let Some(tx) = request.get_response_sender().await {
+        match tx.send(ResponseMessage::Headers(headers)).await {
+            Ok(_) => NifResult::Ok,
+            Err(_) => NifResult::Error("Failed to send headers".to_string()),
+        }
+    } else {
+        NifResult::Error("Response already sent".to_string())
+    }
+}
+
+/// Send a response trailer field, written after the last body chunk
+/// Returns :ok | {:error, reason}
+#[rustler::nif]
+async fn send_trailer(
+    request: ResourceArc<RequestHandle>,
+    name: String,
+    value: String,
+) -> NifResult {
+    if let Some(tx) = request.get_response_sender().await {
+        match tx.send(ResponseMessage::Trailer(name, value)).await {
+            Ok(_) => NifResult::Ok,
+            Err(_) => NifResult::Error("Failed to send trailer".to_string()),
+        }
+    } else {
+        NifResult::Error("Response already sent".to_string())
+    }
+}
+
 /// Write a chunk to the response body
+/// Returns :ok | {:error, :overloaded} | {:error, reason}
+#[rustler::nif]
+async fn write_chunk(request: ResourceArc<RequestHandle>, data: NifBytes) -> NifResult {
+    let reservation = match request.context.budget.try_reserve(data.0.len()) {
+        Ok(reservation) => reservation,
+        Err(_) => return NifResult::Reason(atoms::overloaded()),
+    };
+
+    if let Some(tx) = request.get_response_sender().await {
+        match tx
+            .send(ResponseMessage::BodyChunk(data.0, reservation))
+            .await
+        {
+            Ok(_) => NifResult::Ok,
+            Err(_) => NifResult::Error("Failed to write chunk".to_string()),
+        }
+    } else {
+        NifResult::Error("Response already sent".to_string())
+    }
+}
+
+/// Write a chunk to the response body without waiting for the client
+/// Returns :ok | {:error, :wait} | {:error, :overloaded} | {:error, reason},
+/// with :wait while earlier chunks are still waiting to be written
+#[rustler::nif]
+async fn try_write_chunk(request: ResourceArc<RequestHandle>, data: NifBytes) -> NifResult {
+    let reservation = match request.context.budget.try_reserve(data.0.len()) {
+        Ok(reservation) => reservation,
+        Err(_) => return NifResult::Reason(atoms::overloaded()),
+    };
+
+    if let Some(tx) = request.get_response_sender().await {
+        match tx.try_send(ResponseMessage::BodyChunk(data.0, reservation)) {
+            Ok(_) => NifResult::Ok,
+            Err(TrySendError::Full) => NifResult::Reason(atoms::wait()),
+            Err(TrySendError::Closed) => NifResult::Error("Failed to write chunk".to_string()),
+        }
+    } else {
+        NifResult::Error("Response already sent".to_string())
+    }
+}
+
+/// Start a Server-Sent Events stream: status 200, `text/event-stream`
+/// headers along with the handler's, and the head sent right away. With
+/// `keepalive_ms`, a comment is written that often until the stream ends.
 /// Returns :ok | {:error, reason}
 #[rustler::nif]
-fn write_chunk(request: ResourceArc<RequestHandle>, data_term: Term) -> NifResult {
-    // Decode binary synchronously
-    let binary: rustler::Binary = match data_term.decode() {
-        Ok(b) => b,
-        Err(_) => return NifResult::Error("Invalid binary data".to_string()),
+async fn sse_init(
+    request: ResourceArc<RequestHandle>,
+    headers: Vec<(String, String)>,
+    keepalive_ms: Option<u64>,
+) -> NifResult {
+    if let Some(e) = headers
+        .iter()
+        .find_map(|(name, value)| headers::parse(name, value).err())
+    {
+        return NifResult::Error(e);
+    }
+    if keepalive_ms == Some(0) {
+        return NifResult::Error("keepalive must be positive".to_string());
+    }
+    let Some(tx) = request.get_response_sender().await else {
+        return NifResult::Error("Response already sent".to_string());
     };
-    let bytes = Bytes::copy_from_slice(binary.as_slice());
 
-    // Create oneshot channel for result
-    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+    let default_header = |name: &str, value: &str| {
+        ResponseMessage::DefaultHeader(name.to_string(), value.to_string())
+    };
+    let messages = [
+        ResponseMessage::Status(200),
+        ResponseMessage::Headers(headers),
+        default_header("content-type", "text/event-stream"),
+        default_header("cache-control", "no-cache"),
+        // An empty chunk starts the body without waiting for an event
+        ResponseMessage::BodyChunk(Bytes::new(), Reservation::default()),
+    ];
+    for message in messages {
+        if tx.send(message).await.is_err() {
+            return NifResult::Error("Failed to start event stream".to_string());
+        }
+    }
+    if let Some(ms) = keepalive_ms {
+        // Only the weak sender goes into the task, so it never holds the
+        // response open on its own
+        let tx = tx.downgrade();
+        let context = request.context.clone();
+        let interval = Duration::from_millis(ms);
+        rustler::spawn(async move { sse::keepalive(tx, context, interval).await });
+    }
+    NifResult::Ok
+}
 
-    // Spawn async task
-    rustler::spawn(async move {
-        let result = if let Some(tx) = request.get_response_sender().await {
-            match tx.send(ResponseMessage::BodyChunk(bytes)).await {
-                Ok(_) => NifResult::Ok,
-                Err(_) => NifResult::Error("Failed to write chunk".to_string()),
-            }
-        } else {
-            NifResult::Error("Response already sent".to_string())
-        };
-        let _ = result_tx.send(result);
-    });
+/// Send an event on a stream started with `sse_init`
+/// Returns :ok | {:error, :overloaded} | {:error, reason}
+#[rustler::nif]
+async fn sse_send_event(request: ResourceArc<RequestHandle>, event: sse::SseEvent) -> NifResult {
+    let data = match event.encode() {
+        Ok(data) => data,
+        Err(e) => return NifResult::Error(e),
+    };
+    let reservation = match request.context.budget.try_reserve(data.len()) {
+        Ok(reservation) => reservation,
+        Err(_) => return NifResult::Reason(atoms::overloaded()),
+    };
 
-    // Wait for result (blocks the NIF, but that's okay for now)
-    match result_rx.blocking_recv() {
-        Ok(result) => result,
-        Err(_) => NifResult::Error("Internal error".to_string()),
+    if let Some(tx) = request.get_response_sender().await {
+        match tx.send(ResponseMessage::BodyChunk(data, reservation)).await {
+            Ok(_) => NifResult::Ok,
+            Err(_) => NifResult::Error("Failed to send event".to_string()),
+        }
+    } else {
+        NifResult::Error("Response already sent".to_string())
+    }
+}
+
+/// Send `length` bytes of a file (up to its end if nil) from `offset` as the
+/// rest of the response body, with a content-length, and finish the response
+/// Returns :ok | {:error, :not_found | :invalid_request | :overloaded} |
+/// {:error, reason}
+#[rustler::nif]
+async fn send_file(
+    request: ResourceArc<RequestHandle>,
+    path: String,
+    offset: u64,
+    length: Option<u64>,
+) -> NifResult {
+    let file = match files::FileSlice::open(&path, offset, length).await {
+        Ok(file) => file,
+        Err(e) => return e.into(),
+    };
+    let Some(tx) = request.get_response_sender().await else {
+        return NifResult::Error("Response already sent".to_string());
+    };
+    let content_type = mime::content_type(&path, &request.context.config.mime_types);
+    let headers = [
+        ResponseMessage::Header("content-length".to_string(), file.length.to_string()),
+        ResponseMessage::DefaultHeader("content-type".to_string(), content_type.to_string()),
+    ];
+    for header in headers {
+        if tx.send(header).await.is_err() {
+            return NifResult::Error("Failed to send file".to_string());
+        }
+    }
+    if let Err(e) = file.stream(&tx, &request.context.budget).await {
+        return e.into();
+    }
+    match tx.send(ResponseMessage::Finish).await {
+        Ok(_) => NifResult::Ok,
+        Err(_) => NifResult::Error("Failed to finish response".to_string()),
+    }
+}
+
+/// Answer with a file, sending the status and headers as well as the body:
+/// 200 with the whole file, 206 with the byte range asked for in `Range`,
+/// 304 when the client's copy is current, or 416 for a range past its end
+/// Returns :ok | {:error, :not_found} | {:error, reason}
+#[rustler::nif]
+async fn serve_file(
+    request: ResourceArc<RequestHandle>,
+    path: String,
+    options: files::ServeOptions,
+) -> NifResult {
+    let Some(tx) = request.get_response_sender().await else {
+        return NifResult::Error("Response already sent".to_string());
+    };
+    let metadata = &request.metadata;
+    let served = files::serve(
+        &path,
+        metadata.method.0.as_str(),
+        &metadata.headers,
+        &options,
+        mime::content_type(&path, &request.context.config.mime_types),
+        &tx,
+        &request.context.budget,
+    );
+    match served.await {
+        Ok(()) => NifResult::Ok,
+        Err(e) => e.into(),
     }
 }
 
@@ -216,63 +853,87 @@ async fn finish(request: ResourceArc<RequestHandle>) -> NifResult {
     }
 }
 
-// ============================================================================
-// WebSocket NIFs
-// ============================================================================
-
-/// Upgrade an HTTP request to a WebSocket connection
-/// Returns {:ok, websocket_handle} or {:error, reason}
+/// Send a whole response (status, headers, and body) in one call
+/// Returns :ok | {:error, :overloaded} | {:error, reason}, naming the first
+/// invalid header
 #[rustler::nif]
-async fn upgrade_websocket(
+async fn send_response(
     request: ResourceArc<RequestHandle>,
-) -> Result<ResourceArc<WebSocketHandle>, String> {
-    use sha1::{Digest, Sha1};
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: NifBytes,
+) -> NifResult {
+    complete_response(&request, status, headers, body).await
+}
+
+async fn complete_response(
+    request: &RequestHandle,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: NifBytes,
+) -> NifResult {
+    if let Some(e) = headers
+        .iter()
+        .find_map(|(name, value)| headers::parse(name, value).err())
+    {
+        return NifResult::Error(e);
+    }
+
+    let reservation = match request.context.budget.try_reserve(body.0.len()) {
+        Ok(reservation) => reservation,
+        Err(_) => return NifResult::Reason(atoms::overloaded()),
+    };
+
+    if let Some(tx) = request.get_response_sender().await {
+        match tx
+            .send(ResponseMessage::Complete(
+                status,
+                headers,
+                body.0,
+                reservation,
+            ))
+            .await
+        {
+            Ok(_) => NifResult::Ok,
+            Err(_) => NifResult::Error("Failed to send response".to_string()),
+        }
+    } else {
+        NifResult::Error("Response already sent".to_string())
+    }
+}
+
+// ============================================================================
+// Upgrade and WebSocket NIFs
+// ============================================================================
 
+/// Answer an upgrade request with 101 Switching Protocols and wait for
+/// hyper to hand the connection over
+///
+/// `headers` come on top of `Connection: Upgrade`.
+async fn switch_protocols(
+    request: &RequestHandle,
+    headers: Vec<(String, String)>,
+) -> Result<hyper::upgrade::Upgraded, String> {
     // Take the upgrade future (can only be done once)
     let upgrade_future = request
         .take_upgrade()
         .await
         .ok_or_else(|| "Not an upgradeable request".to_string())?;
 
-    // Get the Sec-WebSocket-Key from request metadata
-    let ws_key = request
-        .metadata
-        .headers
-        .iter()
-        .find(|(k, _)| k.eq_ignore_ascii_case("sec-websocket-key"))
-        .map(|(_, v)| v.clone())
-        .ok_or_else(|| "Missing Sec-WebSocket-Key header".to_string())?;
-
-    // Compute the Sec-WebSocket-Accept value
-    const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-    let mut sha1 = Sha1::new();
-    sha1.update(ws_key.as_bytes());
-    sha1.update(WS_GUID.as_bytes());
-    let accept = base64::engine::general_purpose::STANDARD.encode(sha1.finalize());
-
     // Send the 101 Switching Protocols response
     if let Some(tx) = request.get_response_sender().await {
         tx.send(ResponseMessage::Status(101))
             .await
             .map_err(|_| "Failed to send status")?;
-        tx.send(ResponseMessage::Header(
-            "Upgrade".to_string(),
-            "websocket".to_string(),
-        ))
-        .await
-        .map_err(|_| "Failed to send Upgrade header")?;
         tx.send(ResponseMessage::Header(
             "Connection".to_string(),
             "Upgrade".to_string(),
         ))
         .await
         .map_err(|_| "Failed to send Connection header")?;
-        tx.send(ResponseMessage::Header(
-            "Sec-WebSocket-Accept".to_string(),
-            accept,
-        ))
-        .await
-        .map_err(|_| "Failed to send Sec-WebSocket-Accept header")?;
+        tx.send(ResponseMessage::Headers(headers))
+            .await
+            .map_err(|_| "Failed to send headers")?;
         tx.send(ResponseMessage::Finish)
             .await
             .map_err(|_| "Failed to finish response")?;
@@ -281,9 +942,48 @@ async fn upgrade_websocket(
     }
 
     // Wait for the upgrade to complete
-    let upgraded = upgrade_future
+    upgrade_future
         .await
-        .map_err(|e| format!("Upgrade failed: {}", e))?;
+        .map_err(|e| format!("Upgrade failed: {}", e))
+}
+
+/// Upgrade an HTTP request to a WebSocket connection, speaking the first
+/// subprotocol offered by the client that is also in `options.protocols`
+/// Returns {:ok, {websocket_handle, protocol | nil}} or {:error, reason}
+#[rustler::nif]
+async fn upgrade_websocket(
+    request: ResourceArc<RequestHandle>,
+    options: websocket::UpgradeOptions,
+) -> Result<(ResourceArc<WebSocketHandle>, Option<String>), String> {
+    use sha1::{Digest, Sha1};
+
+    let ws_config = options.ws_config()?;
+    let heartbeat = options.heartbeat()?;
+
+    // Get the Sec-WebSocket-Key from request metadata
+    let ws_key = request
+        .metadata
+        .headers
+        .get("sec-websocket-key")
+        .cloned()
+        .ok_or_else(|| "Missing Sec-WebSocket-Key header".to_string())?;
+
+    // Compute the Sec-WebSocket-Accept value
+    const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let mut sha1 = Sha1::new();
+    sha1.update(ws_key.as_bytes());
+    sha1.update(WS_GUID.as_bytes());
+    let accept = base64::engine::general_purpose::STANDARD.encode(sha1.finalize());
+    let protocol = websocket::select_protocol(&request.metadata.headers, &options.protocols);
+
+    let mut headers = vec![
+        ("Upgrade".to_string(), "websocket".to_string()),
+        ("Sec-WebSocket-Accept".to_string(), accept),
+    ];
+    if let Some(protocol) = &protocol {
+        headers.push(("Sec-WebSocket-Protocol".to_string(), protocol.clone()));
+    }
+    let upgraded = switch_protocols(&request, headers).await?;
 
     // Wrap in TokioIo
     let io = hyper_util::rt::TokioIo::new(upgraded);
@@ -292,13 +992,96 @@ async fn upgrade_websocket(
     let ws_stream = tokio_tungstenite::WebSocketStream::from_raw_socket(
         io,
         tokio_tungstenite::tungstenite::protocol::Role::Server,
-        None,
+        Some(ws_config),
     )
     .await;
 
     // Create and return WebSocketHandle
-    let ws_handle = WebSocketHandle::new(ws_stream);
-    Ok(ResourceArc::new(ws_handle))
+    let ws_handle = ResourceArc::new(WebSocketHandle::new(
+        ws_stream,
+        request.context.clone(),
+        options.send_queue_size,
+        options.send_overflow,
+    ));
+    if let Some((interval, timeout)) = heartbeat {
+        let ws = ws_handle.clone();
+        rustler::spawn(async move { ws.heartbeat(interval, timeout).await });
+    }
+    Ok((ws_handle, protocol))
+}
+
+/// Upgrade an HTTP request to the protocol named in its `Upgrade` header,
+/// handing the raw connection to Elixir
+/// Returns {:ok, raw_stream} or {:error, reason}
+#[rustler::nif]
+async fn upgrade_raw(
+    request: ResourceArc<RequestHandle>,
+) -> Result<ResourceArc<RawStream>, String> {
+    let protocol = request
+        .metadata
+        .headers
+        .get("upgrade")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| "Missing Upgrade header".to_string())?;
+    let upgraded = switch_protocols(&request, vec![("Upgrade".to_string(), protocol)]).await?;
+    Ok(ResourceArc::new(RawStream::new(
+        upgraded,
+        request.context.clone(),
+    )))
+}
+
+/// Read up to `max_bytes` from an upgraded connection
+/// Returns {:ok, binary} | {:error, reason}, with an empty binary once the
+/// client has closed its side
+#[rustler::nif]
+async fn raw_read(
+    stream: ResourceArc<RawStream>,
+    max_bytes: usize,
+) -> Result<NifBytes, rustler::Atom> {
+    stream
+        .read(max_bytes)
+        .await
+        .map(NifBytes)
+        .map_err(|kind| kind.atom())
+}
+
+/// Write to an upgraded connection
+/// Returns :ok | {:error, reason}
+#[rustler::nif]
+async fn raw_write(stream: ResourceArc<RawStream>, data: NifBytes) -> NifResult {
+    match stream.write(&data.0).await {
+        Ok(()) => NifResult::Ok,
+        Err(kind) => NifResult::Reason(kind.atom()),
+    }
+}
+
+/// Shut down the server's side of an upgraded connection
+#[rustler::nif]
+async fn raw_close(stream: ResourceArc<RawStream>) -> rustler::Atom {
+    stream.close().await;
+    atoms::ok()
+}
+
+/// Decline a WebSocket upgrade with an ordinary response, keeping the
+/// connection in HTTP mode
+/// Returns :ok | {:error, :not_supported} if the request is not (or no longer)
+/// upgradeable | {:error, reason}
+#[rustler::nif]
+async fn reject_upgrade(
+    request: ResourceArc<RequestHandle>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: NifBytes,
+) -> NifResult {
+    if status < 200 {
+        return NifResult::Error("Upgrades must be rejected with a final status".to_string());
+    }
+    // Dropping the upgrade future gives up on the upgrade for good
+    if request.take_upgrade().await.is_none() {
+        return NifResult::Reason(atoms::not_supported());
+    }
+    complete_response(&request, status, headers, body).await
 }
 
 /// Send a text frame over the WebSocket
@@ -307,61 +1090,125 @@ async fn ws_send_text(ws: ResourceArc<WebSocketHandle>, text: String) -> NifResu
     ws.send_frame(Frame::Text(text))
         .await
         .map(|_| NifResult::Ok)
-        .unwrap_or_else(NifResult::Error)
+        .unwrap_or_else(NifResult::from)
 }
 
 /// Send a binary frame over the WebSocket
 #[rustler::nif]
-fn ws_send_binary(ws: ResourceArc<WebSocketHandle>, data: rustler::Binary) -> NifResult {
-    let bytes = data.as_slice().to_vec();
-    let (tx, rx) = tokio::sync::oneshot::channel();
-
-    rustler::spawn(async move {
-        let result = ws
-            .send_frame(Frame::Binary(bytes))
-            .await
-            .map(|_| NifResult::Ok)
-            .unwrap_or_else(NifResult::Error);
-        let _ = tx.send(result);
-    });
+async fn ws_send_binary(ws: ResourceArc<WebSocketHandle>, data: NifBytes) -> NifResult {
+    ws.send_frame(Frame::Binary(data.0.to_vec()))
+        .await
+        .map(|_| NifResult::Ok)
+        .unwrap_or_else(NifResult::from)
+}
 
-    match rx.blocking_recv() {
-        Ok(result) => result,
-        Err(_) => NifResult::Error("Internal error".to_string()),
+/// Convert a received frame into the `{kind, data}` tuple handed to Elixir
+fn frame_tuple(
+    received: Result<Frame, errors::ErrorKind>,
+) -> Result<(rustler::Atom, NifBytes), rustler::Atom> {
+    match received {
+        Ok(Frame::Text(text)) => Ok((atoms::text(), NifBytes(text.into()))),
+        Ok(Frame::Binary(data)) => Ok((atoms::binary(), NifBytes(data.into()))),
+        Ok(Frame::Ping(data)) => Ok((atoms::ping(), NifBytes(data.into()))),
+        Ok(Frame::Pong(data)) => Ok((atoms::pong(), NifBytes(data.into()))),
+        Ok(Frame::Close) => Err(atoms::close()),
+        Err(kind) => Err(kind.atom()),
     }
 }
 
 /// Receive a frame from the WebSocket
-/// Returns {:text, data} | {:binary, data} | {:ping, data} | {:pong, data} | :close | :closed
+/// Returns {:ok, {:text | :binary | :ping | :pong, data}} | {:error, :close | :closed | :owned | reason}
 #[rustler::nif]
-fn ws_recv(
-    env: rustler::Env,
+async fn ws_recv(
     ws: ResourceArc<WebSocketHandle>,
-) -> Result<(rustler::Atom, rustler::Binary), rustler::Atom> {
-    // Use oneshot channel to wait for async result
-    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+) -> Result<(rustler::Atom, NifBytes), rustler::Atom> {
+    if ws.is_owned() {
+        return Err(atoms::owned());
+    }
+    frame_tuple(ws.recv_frame().await)
+}
 
+/// Push incoming frames to `pid` instead of waiting for `ws_recv`
+///
+/// `pid` receives {:sparx_ws, ref, {:text | :binary | :ping | :pong, data}}
+/// for each frame, then {:sparx_ws, ref, :close} or
+/// {:sparx_ws, ref, {:error, reason}} when the connection ends. Delivery
+/// stops early if `pid` exits.
+/// Returns {:ok, ref} | {:error, :owned}
+#[rustler::nif]
+fn ws_set_owner<'a>(
+    env: Env<'a>,
+    ws: ResourceArc<WebSocketHandle>,
+    pid: LocalPid,
+) -> Result<Reference<'a>, rustler::Atom> {
+    if !ws.claim() {
+        return Err(atoms::owned());
+    }
+    let reference = env.make_ref();
+    // The reference lives in its own env, as sending clears the message env
+    let ref_env = OwnedEnv::new();
+    let saved = ref_env.run(|owned| ref_env.save(reference.in_env(owned)));
     rustler::spawn(async move {
-        let result = match ws.recv_frame().await {
-            Some(Frame::Text(text)) => Ok((atoms::text(), text.into_bytes())),
-            Some(Frame::Binary(data)) => Ok((atoms::binary(), data)),
-            Some(Frame::Ping(data)) => Ok((atoms::ping(), data)),
-            Some(Frame::Pong(data)) => Ok((atoms::pong(), data)),
-            Some(Frame::Close) => Err(atoms::close()),
-            None => Err(atoms::closed()),
-        };
-        let _ = result_tx.send(result);
+        let mut msg_env = OwnedEnv::new();
+        loop {
+            let received = frame_tuple(ws.recv_frame().await);
+            let done = received.is_err();
+            let sent = msg_env.send_and_clear(&pid, |env| {
+                let reference = ref_env.run(|owned| saved.load(owned).in_env(env));
+                let message = match received {
+                    Ok(frame) => frame.encode(env),
+                    Err(reason) if reason == atoms::close() => reason.encode(env),
+                    Err(reason) => (atoms::error(), reason).encode(env),
+                };
+                (atoms::sparx_ws(), reference, message).encode(env)
+            });
+            if done || sent.is_err() {
+                break;
+            }
+        }
     });
+    Ok(reference)
+}
 
-    match result_rx.blocking_recv() {
-        Ok(Ok((frame_type, data))) => {
-            let mut binary = rustler::OwnedBinary::new(data.len()).unwrap();
-            binary.as_mut_slice().copy_from_slice(&data);
-            Ok((frame_type, binary.release(env)))
-        }
-        Ok(Err(atom)) => Err(atom),
-        Err(_) => Err(atoms::error()),
-    }
+/// Number of frames queued on the WebSocket and not yet written
+#[rustler::nif]
+fn ws_queue_depth(ws: ResourceArc<WebSocketHandle>) -> usize {
+    ws.queue_depth()
+}
+
+/// Subscribe the WebSocket to a broadcast topic
+#[rustler::nif]
+fn ws_join(ws: ResourceArc<WebSocketHandle>, topic: String) -> rustler::Atom {
+    ws.context.topics.join(&ws, topic);
+    atoms::ok()
+}
+
+/// Unsubscribe the WebSocket from a broadcast topic
+#[rustler::nif]
+fn ws_leave(ws: ResourceArc<WebSocketHandle>, topic: String) -> rustler::Atom {
+    ws.context.topics.leave(&ws, &topic);
+    atoms::ok()
+}
+
+/// Send a {:text | :binary, data} frame to every WebSocket subscribed to
+/// `topic`, without waiting for the sends to complete
+/// Returns {:ok, count} | {:error, :invalid_request}
+#[rustler::nif]
+fn ws_broadcast(
+    server: ResourceArc<ServerHandle>,
+    topic: String,
+    frame: (rustler::Atom, NifBytes),
+) -> Result<usize, rustler::Atom> {
+    let (kind, data) = frame;
+    let frame = if kind == atoms::text() {
+        let text = std::str::from_utf8(&data.0).map_err(|_| atoms::invalid_request())?;
+        Frame::Text(text.to_string())
+    } else if kind == atoms::binary() {
+        Frame::Binary(data.0.to_vec())
+    } else {
+        return Err(atoms::invalid_request());
+    };
+    Ok(server.context.topics.broadcast(&topic, frame))
 }
 
 /// Close the WebSocket connection
@@ -370,11 +1217,204 @@ async fn ws_close(ws: ResourceArc<WebSocketHandle>) -> NifResult {
     ws.send_frame(Frame::Close)
         .await
         .map(|_| NifResult::Ok)
-        .unwrap_or_else(NifResult::Error)
+        .unwrap_or_else(NifResult::from)
+}
+
+// ============================================================================
+// Test Transport NIFs
+// ============================================================================
+
+/// Open an in-memory connection to a server started with `transport: :memory`
+/// Returns {:ok, connection} | {:error, :not_supported | :paused}
+#[rustler::nif]
+fn test_connect(
+    server: ResourceArc<ServerHandle>,
+) -> Result<ResourceArc<TestConnection>, rustler::Atom> {
+    server.connect_in_memory().map(ResourceArc::new)
+}
+
+/// Write raw client bytes to an in-memory connection
+/// Returns :ok | {:error, :closed | :connection_closed}
+#[rustler::nif]
+async fn test_write(
+    conn: ResourceArc<TestConnection>,
+    data: NifBytes,
+) -> Result<rustler::Atom, rustler::Atom> {
+    conn.write(&data.0).await.map(|_| atoms::ok())
+}
+
+/// Read raw server bytes from an in-memory connection
+/// Returns {:ok, binary} | {:error, :timeout | :connection_closed}, with an
+/// empty binary once the server has closed the connection
+#[rustler::nif]
+async fn test_read(
+    conn: ResourceArc<TestConnection>,
+    timeout_ms: u64,
+) -> Result<NifBytes, rustler::Atom> {
+    conn.read(Duration::from_millis(timeout_ms))
+        .await
+        .map(NifBytes)
+}
+
+/// Advance the clock of a server started with `runtime_profile: :simulation`
+/// Returns :ok | {:error, :not_supported}
+#[rustler::nif]
+async fn sim_advance(
+    server: ResourceArc<ServerHandle>,
+    ms: u64,
+) -> Result<rustler::Atom, rustler::Atom> {
+    server
+        .runtime
+        .advance(Duration::from_millis(ms))
+        .await
+        .map(|_| atoms::ok())
+        .ok_or_else(atoms::not_supported)
+}
+
+/// Queue a synthetic request as if it had arrived over the network
+/// Returns {:ok, capture} | {:error, :invalid_request | :closed}
+#[rustler::nif]
+async fn inject_request(
+    server: ResourceArc<ServerHandle>,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: NifBytes,
+) -> Result<ResourceArc<ResponseCapture>, rustler::Atom> {
+    let capture = ResourceArc::new(ResponseCapture::default());
+    server
+        .inject(&method, &path, headers, body.0, capture.clone())
+        .await?;
+    Ok(capture)
+}
+
+/// Wait for the response to an injected request
+/// Returns {:ok, %{status, headers, body}} | {:error, :timeout | :server_error}
+#[rustler::nif]
+async fn await_response(
+    capture: ResourceArc<ResponseCapture>,
+    timeout_ms: u64,
+) -> Result<CapturedResponse, rustler::Atom> {
+    capture.wait(Duration::from_millis(timeout_ms)).await
+}
+
+/// Capture the rest of a request's response instead of writing it to the socket
+/// Returns {:ok, capture} | {:error, :already_sent}
+#[rustler::nif]
+async fn capture_response(
+    request: ResourceArc<RequestHandle>,
+) -> Result<ResourceArc<ResponseCapture>, rustler::Atom> {
+    let rx = request
+        .redirect_response()
+        .await
+        .ok_or_else(atoms::already_sent)?;
+    let capture = ResourceArc::new(ResponseCapture::default());
+    let completed = capture.clone();
+    let method = request.metadata.method.0.clone();
+    let context = request.context.clone();
+    let timings = request.timings.clone();
+    // Holding the request would keep its response sender open, so a handler
+    // that dies without responding would leave the capture pending forever
+    drop(request);
+    rustler::spawn(async move {
+        let response = response::build_response_from_channel(rx, &method, &context, &timings).await;
+        completed.complete(response).await;
+    });
+    Ok(capture)
+}
+
+/// Status of a captured response
+/// Returns {:ok, status} | {:error, :pending | reason}
+#[rustler::nif]
+fn captured_status(capture: ResourceArc<ResponseCapture>) -> Result<u16, rustler::Atom> {
+    capture.peek().map(|response| response.status)
+}
+
+/// Headers of a captured response
+/// Returns {:ok, [{name, value}]} | {:error, :pending | reason}
+#[rustler::nif]
+fn captured_headers(
+    capture: ResourceArc<ResponseCapture>,
+) -> Result<headers::HeaderList, rustler::Atom> {
+    capture.peek().map(|response| response.headers)
+}
+
+/// Body of a captured response
+/// Returns {:ok, binary} | {:error, :pending | reason}
+#[rustler::nif]
+fn captured_body(capture: ResourceArc<ResponseCapture>) -> Result<NifBytes, rustler::Atom> {
+    capture.peek().map(|response| response.body)
+}
+
+/// Half-close the client side of an in-memory connection
+#[rustler::nif]
+async fn test_close(conn: ResourceArc<TestConnection>) -> rustler::Atom {
+    conn.close().await;
+    atoms::ok()
+}
+
+// ============================================================================
+// Development NIFs
+// ============================================================================
+
+/// Generate a self-signed certificate for local HTTPS
+/// Returns {:ok, %{cert_pem, key_pem}} | {:error, reason}
+#[rustler::nif(schedule = "DirtyCpu")]
+fn generate_dev_cert(hostnames: Vec<String>) -> Result<devcert::DevCert, String> {
+    devcert::generate(hostnames)
+}
+
+/// Load-test an http:// URL with keep-alive connections
+/// Returns {:ok, report} | {:error, reason}
+#[rustler::nif]
+async fn bench(
+    url: String,
+    concurrency: usize,
+    duration_ms: u64,
+) -> Result<bench::BenchReport, String> {
+    bench::run(&url, concurrency, Duration::from_millis(duration_ms)).await
+}
+
+// ============================================================================
+// Logging NIFs
+// ============================================================================
+
+/// Forward native log events to `pid` as `{:sparx_log, level, target, message}`
+/// instead of stderr; `nil` restores stderr
+/// Returns :ok
+#[rustler::nif]
+fn set_log_handler(pid: Option<LocalPid>) -> rustler::Atom {
+    logger::set_handler(pid);
+    atoms::ok()
+}
+
+/// Replace the `SPARX_LOG` filter, e.g. `"sparx=debug,hyper=warn"`
+/// Returns :ok | {:error, reason}
+#[rustler::nif]
+fn set_log_filter(filter: String) -> Result<rustler::Atom, String> {
+    logger::set_filter(&filter).map(|_| atoms::ok())
+}
+
+// ============================================================================
+// Profiling NIFs
+// ============================================================================
+
+/// Start the in-process CPU profiler
+/// Returns :ok | {:error, :already_started | :not_supported}
+#[rustler::nif]
+fn profiler_start(frequency: i32) -> Result<rustler::Atom, rustler::Atom> {
+    profiler::start(frequency).map(|_| atoms::ok())
+}
+
+/// Stop the CPU profiler
+/// Returns {:ok, pprof_binary} | {:error, :not_started | :not_supported}
+#[rustler::nif(schedule = "DirtyCpu")]
+fn profiler_stop() -> Result<NifBytes, rustler::Atom> {
+    profiler::stop().map(|profile| NifBytes(profile.into()))
 }
 
 // ============================================================================
 // NIF Registration
 // ============================================================================
 
-rustler::init!("Elixir.Sparx.Native", load = load);
+rustler::init!("Elixir.Sparx.Native", load = load, upgrade = upgrade);
@@ -0,0 +1,63 @@
+use crate::capture::ResponseCapture;
+use crate::duplex::TestConnection;
+use crate::raw::RawStream;
+use crate::request::RequestHandle;
+use crate::server::ServerHandle;
+use crate::websocket::WebSocketHandle;
+use rustler::Env;
+
+/// Keep this NIF library mapped for the life of the VM
+///
+/// Servers outlive the module version that started them: runtime threads
+/// keep executing this library's code and resources hold pointers to its
+/// destructors. When `Sparx.Native` is hot-upgraded and the old version is
+/// purged, the VM closes the old library; an extra handle opened with
+/// `RTLD_NODELETE` keeps the code in place so running servers, WebSockets,
+/// and in-flight requests carry on.
+pub fn pin() {
+    #[cfg(unix)]
+    // SAFETY: `dladdr` only reads the address of a function in this library,
+    // and `dlopen` of an already loaded library just takes a reference.
+    unsafe {
+        let mut info: libc::Dl_info = std::mem::zeroed();
+        if libc::dladdr(pin as *const libc::c_void, &mut info) == 0 || info.dli_fname.is_null() {
+            tracing::warn!("Could not locate the NIF library; hot upgrades may unload it");
+            return;
+        }
+
+        // The handle is leaked on purpose: it is what keeps the library loaded
+        let handle = libc::dlopen(info.dli_fname, libc::RTLD_NOW | libc::RTLD_NODELETE);
+        if handle.is_null() {
+            tracing::warn!("Could not pin the NIF library; hot upgrades may unload it");
+        }
+    }
+}
+
+/// Take over the resource types of the library version being upgraded from
+///
+/// Each type is opened with `ERL_NIF_RT_TAKEOVER`, which hands every live
+/// instance, and the call to its destructor, to this version. Without it
+/// the VM refuses the upgrade, since the old version's resources would
+/// outlive the code that frees them.
+pub fn take_over(env: Env) -> bool {
+    let taken = [
+        ("ServerHandle", env.register_takeover::<ServerHandle>()),
+        ("RequestHandle", env.register_takeover::<RequestHandle>()),
+        (
+            "WebSocketHandle",
+            env.register_takeover::<WebSocketHandle>(),
+        ),
+        ("RawStream", env.register_takeover::<RawStream>()),
+        (
+            "ResponseCapture",
+            env.register_takeover::<ResponseCapture>(),
+        ),
+        ("TestConnection", env.register_takeover::<TestConnection>()),
+    ];
+    taken.into_iter().all(|(name, result)| {
+        if result.is_err() {
+            tracing::warn!("Could not take over the {} resource type", name);
+        }
+        result.is_ok()
+    })
+}
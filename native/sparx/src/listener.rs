@@ -0,0 +1,471 @@
+use crate::atoms;
+use crate::config::ServerConfig;
+use crate::request::Scheme;
+use crate::tls::TlsConfig;
+use rustler::{Atom, Encoder, Env, NifMap, NifStruct, Term};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::time::Duration;
+
+/// One address a server accepts connections on (`listeners` config)
+#[derive(NifStruct, Clone, Debug)]
+#[module = "Sparx.Config.Listener"]
+pub struct ListenerConfig {
+    /// Reported as `listener` in the metadata of requests it accepts
+    pub name: Atom,
+    /// Host to bind to, for TCP listeners
+    pub host: String,
+    /// TCP port to listen on
+    pub port: Option<u16>,
+    /// Unix domain socket to listen on instead of a TCP port
+    pub path: Option<String>,
+    /// Serve HTTPS with this certificate and key (None serves plain HTTP)
+    pub tls: Option<TlsConfig>,
+    /// `IPV6_V6ONLY` for an IPv6 host: true accepts IPv6 only, false IPv4
+    /// as well (None keeps the server's `ipv6_only`)
+    pub ipv6_only: Option<bool>,
+    /// Listening socket exported by another server with
+    /// `server_export_listener`, taken over instead of binding
+    pub fd: Option<i32>,
+    /// Read a PROXY protocol (v1 or v2) header from each connection and
+    /// report the client it relays instead of the socket's peer
+    pub proxy_protocol: bool,
+}
+
+impl ListenerConfig {
+    /// Check that the listener has exactly one address
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.port, &self.path, self.fd) {
+            (None, None, None) => Err("set a port, a Unix socket path, or an fd".to_string()),
+            (_, _, Some(fd)) if fd < 0 => Err(format!("{} is not a file descriptor", fd)),
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => {
+                Err("set only one of port, path, and fd".to_string())
+            }
+            (None, Some(path), None) if path.is_empty() => Err("path is empty".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Scheme of the requests it accepts
+    pub fn scheme(&self) -> Scheme {
+        if self.tls.is_some() {
+            Scheme::Https
+        } else {
+            Scheme::Http
+        }
+    }
+}
+
+/// The listener a connection arrived on, as seen by its requests
+#[derive(Clone, Copy, Debug)]
+pub struct Listener {
+    pub name: Atom,
+    pub scheme: Scheme,
+    pub proxy_protocol: bool,
+}
+
+impl Listener {
+    pub fn of(config: &ListenerConfig) -> Self {
+        Self {
+            name: config.name,
+            scheme: config.scheme(),
+            proxy_protocol: config.proxy_protocol,
+        }
+    }
+}
+
+impl Default for Listener {
+    /// In-memory connections and injected requests, which skip TLS
+    fn default() -> Self {
+        Self {
+            name: atoms::default(),
+            scheme: Scheme::Http,
+            proxy_protocol: false,
+        }
+    }
+}
+
+/// Socket options from the server config, for listeners and the
+/// connections they accept
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    /// `SO_REUSEPORT`, so accept loops can each bind the address
+    pub reuse_port: bool,
+    /// Pending connections the kernel queues per listener
+    pub backlog: i32,
+    /// `SO_SNDBUF` and `SO_RCVBUF`, set on listeners so accepted sockets
+    /// inherit them (and the receive window is scaled for them)
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// `TCP_NODELAY` on accepted sockets
+    pub nodelay: bool,
+    /// `SO_LINGER` of 0 on accepted sockets, resetting them on close
+    pub reset_on_close: bool,
+}
+
+impl SocketOptions {
+    /// Refuse a nonzero `linger_secs`: with `SO_LINGER` set, closing a
+    /// socket blocks until its data is sent, which would stall a runtime
+    /// worker and every connection on it
+    pub fn validate(config: &ServerConfig) -> Result<(), String> {
+        match config.linger_secs {
+            Some(secs) if secs > 0 => Err(format!(
+                "linger_secs {} would block a runtime worker on close; only 0 is supported",
+                secs
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            reuse_port: config.thread_per_core || config.reuse_port,
+            backlog: config.listen_backlog.min(i32::MAX as u32) as i32,
+            send_buffer_size: config.send_buffer_size,
+            recv_buffer_size: config.recv_buffer_size,
+            nodelay: config.tcp_nodelay,
+            reset_on_close: config.linger_secs == Some(0),
+        }
+    }
+
+    /// Set the per-connection options on an accepted TCP socket, before it
+    /// is handed to hyper
+    pub fn apply(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if self.reset_on_close {
+            SockRef::from(stream).set_linger(Some(Duration::ZERO))?;
+        }
+        Ok(())
+    }
+
+    /// Set the options listeners pass on to the sockets they accept
+    fn apply_to_listener(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// A bound listening socket, not yet registered with a runtime
+pub enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Bound {
+    /// Bind the listener's address; TCP listeners may use `SO_REUSEPORT`
+    pub fn open(config: &ListenerConfig, options: &SocketOptions) -> io::Result<Self> {
+        if let Some(fd) = config.fd {
+            return inherit(fd);
+        }
+        match (&config.path, config.port) {
+            (Some(path), _) => bind_unix(path, options),
+            (None, port) => {
+                let ip = parse_host(&config.host).ok_or_else(|| {
+                    let message = format!("{:?} is not an IP address", config.host);
+                    io::Error::new(io::ErrorKind::InvalidInput, message)
+                })?;
+                let addr = SocketAddr::new(ip, port.unwrap_or(0));
+                bind(addr, config.ipv6_only, options).map(Bound::Tcp)
+            }
+        }
+    }
+
+    /// Another listener for one more accept loop on the same address
+    ///
+    /// TCP listeners bind the same address again with `SO_REUSEPORT`, or
+    /// share the socket. Unix sockets and taken-over sockets always share it.
+    pub fn another(&self, config: &ListenerConfig, options: &SocketOptions) -> io::Result<Self> {
+        match self {
+            Bound::Tcp(listener) if self.splits(config, options) => {
+                bind(listener.local_addr()?, config.ipv6_only, options).map(Bound::Tcp)
+            }
+            _ => self.try_clone(),
+        }
+    }
+
+    /// Whether further acceptors get sockets of their own rather than
+    /// sharing this one
+    pub fn splits(&self, config: &ListenerConfig, options: &SocketOptions) -> bool {
+        matches!(self, Bound::Tcp(_)) && options.reuse_port && config.fd.is_none()
+    }
+
+    /// A second handle on the same socket
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Bound::Tcp(listener) => listener.try_clone().map(Bound::Tcp),
+            #[cfg(unix)]
+            Bound::Unix(listener) => listener.try_clone().map(Bound::Unix),
+        }
+    }
+
+    /// The bound TCP address, which has the real port when 0 was asked for
+    pub fn local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match self {
+            Bound::Tcp(listener) => listener.local_addr().map(Some),
+            #[cfg(unix)]
+            Bound::Unix(_) => Ok(None),
+        }
+    }
+
+    /// The Unix socket's path
+    fn path(&self) -> Option<String> {
+        match self {
+            Bound::Tcp(_) => None,
+            #[cfg(unix)]
+            Bound::Unix(listener) => {
+                let addr = listener.local_addr().ok()?;
+                Some(addr.as_pathname()?.to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Duplicate the socket into a descriptor for another server to take
+    /// over with `ListenerConfig::fd`
+    ///
+    /// The descriptor stays open until that server takes it over, so the
+    /// socket keeps queueing connections while this one drains.
+    #[cfg(unix)]
+    pub fn export(&self) -> io::Result<i32> {
+        use std::os::fd::IntoRawFd;
+
+        Ok(match self.try_clone()? {
+            Bound::Tcp(listener) => listener.into_raw_fd(),
+            Bound::Unix(listener) => listener.into_raw_fd(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn export(&self) -> io::Result<i32> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "exporting listeners is not supported on this platform",
+        ))
+    }
+}
+
+/// Take over a listening socket exported by `Bound::export`
+///
+/// The descriptor must be open and a listening stream socket; from here on the
+/// listener owns it and closes it when dropped, so each exported descriptor
+/// can be taken over once.
+#[cfg(unix)]
+fn inherit(fd: i32) -> io::Result<Bound> {
+    use std::os::fd::{BorrowedFd, FromRawFd};
+
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    // SAFETY: F_GETFD only reads the descriptor flags, and fails on
+    // descriptors that are not open
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(invalid(format!("{} is not an open file descriptor", fd)));
+    }
+    // SAFETY: the descriptor was just checked to be open, and is only
+    // borrowed for the checks below
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&borrowed);
+    if socket.r#type().ok() != Some(Type::STREAM) {
+        return Err(invalid(format!("{} is not a stream socket", fd)));
+    }
+    if !is_listening(fd) {
+        return Err(invalid(format!("{} is not a listening socket", fd)));
+    }
+    let unix = socket.local_addr()?.is_unix();
+
+    // SAFETY: the caller hands over the exported descriptor, which nothing
+    // else in this process owns any more
+    let bound = unsafe {
+        if unix {
+            Bound::Unix(std::os::unix::net::UnixListener::from_raw_fd(fd))
+        } else {
+            Bound::Tcp(TcpListener::from_raw_fd(fd))
+        }
+    };
+    match &bound {
+        Bound::Tcp(listener) => listener.set_nonblocking(true)?,
+        Bound::Unix(listener) => listener.set_nonblocking(true)?,
+    }
+    Ok(bound)
+}
+
+/// Whether `listen` has been called on the socket, so a connected or merely
+/// bound socket is not mistaken for a listener
+#[cfg(unix)]
+fn is_listening(fd: i32) -> bool {
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: SO_ACCEPTCONN is an int, read into one of the length passed
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    result == 0 && listening != 0
+}
+
+#[cfg(not(unix))]
+fn inherit(_fd: i32) -> io::Result<Bound> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "taking over listeners is not supported on this platform",
+    ))
+}
+
+/// Bind a Unix domain socket, replacing a stale socket file left behind by a
+/// server that did not shut down cleanly
+#[cfg(unix)]
+fn bind_unix(path: &str, options: &SocketOptions) -> io::Result<Bound> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let listener = match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            // A live server still accepts on it; only a dead one refuses
+            match UnixStream::connect(path) {
+                Err(connect) if connect.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?;
+                    UnixListener::bind(path)?
+                }
+                _ => return Err(e),
+            }
+        }
+        result => result?,
+    };
+    let socket = SockRef::from(&listener);
+    options.apply_to_listener(&socket)?;
+    // std listens with a fixed backlog; listening again replaces it
+    socket.listen(options.backlog)?;
+    listener.set_nonblocking(true)?;
+    Ok(Bound::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str, _options: &SocketOptions) -> io::Result<Bound> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// Where one listener is bound, reported by `server_info`
+#[derive(NifMap, Clone)]
+pub struct ListenerInfo {
+    pub name: Atom,
+    /// Bound IP address (None for Unix sockets)
+    pub host: Option<String>,
+    /// Bound port, also when the configured port was 0
+    pub port: Option<u16>,
+    /// Unix socket path
+    pub path: Option<String>,
+    pub tls: bool,
+}
+
+impl ListenerInfo {
+    pub fn new(config: &ListenerConfig, bound: &Bound) -> io::Result<Self> {
+        let addr = bound.local_addr()?;
+        Ok(Self {
+            name: config.name,
+            host: addr.map(|addr| addr.ip().to_string()),
+            port: addr.map(|addr| addr.port()),
+            path: bound.path(),
+            tls: config.tls.is_some(),
+        })
+    }
+}
+
+/// Parse a host to bind to: an IPv4 address, or an IPv6 address with or
+/// without brackets (`::1` or `[::1]`)
+pub fn parse_host(host: &str) -> Option<IpAddr> {
+    let host = host.trim();
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'));
+    match unbracketed {
+        Some(host) => host.parse().ok().filter(IpAddr::is_ipv6),
+        None => host.parse().ok(),
+    }
+}
+
+/// Bind a listening socket, optionally with `SO_REUSEPORT`
+///
+/// With `options.reuse_port` several listeners can bind the same address and
+/// the kernel load-balances incoming connections between them. For IPv6
+/// addresses, `ipv6_only` sets `IPV6_V6ONLY`; None leaves the OS default
+/// (`net.ipv6.bindv6only` on Linux). The socket is non-blocking, ready for
+/// `tokio::net::TcpListener::from_std` on the runtime that will accept on it.
+fn bind(
+    addr: SocketAddr,
+    ipv6_only: Option<bool>,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if let (true, Some(only)) = (addr.is_ipv6(), ipv6_only) {
+        socket.set_only_v6(only)?;
+    }
+    #[cfg(unix)]
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    if options.reuse_port {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    options.apply_to_listener(&socket)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog)?;
+    Ok(socket.into())
+}
+
+/// Why `server_start` failed
+#[derive(Debug)]
+pub enum StartError {
+    /// The listening socket could not be bound; encoded as a POSIX-style
+    /// atom such as `:eaddrinuse`
+    Bind(Atom),
+    Other(String),
+}
+
+impl StartError {
+    pub fn bind(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::AddrInUse => StartError::Bind(atoms::eaddrinuse()),
+            io::ErrorKind::PermissionDenied => StartError::Bind(atoms::eacces()),
+            io::ErrorKind::AddrNotAvailable => StartError::Bind(atoms::eaddrnotavail()),
+            io::ErrorKind::InvalidInput => StartError::Bind(atoms::einval()),
+            #[cfg(unix)]
+            _ if error.raw_os_error() == Some(libc::EAFNOSUPPORT) => {
+                // IPv6 is disabled or missing on this machine
+                StartError::Bind(atoms::eafnosupport())
+            }
+            _ => StartError::Other(format!("Failed to bind: {}", error)),
+        }
+    }
+}
+
+impl From<String> for StartError {
+    fn from(message: String) -> Self {
+        StartError::Other(message)
+    }
+}
+
+impl Encoder for StartError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            StartError::Bind(reason) => reason.encode(env),
+            StartError::Other(message) => message.encode(env),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::atoms;
+use crate::trace_context;
+use rustler::{Atom, Encoder, LocalPid, OwnedEnv};
+use std::fmt::Write;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{OnceLock, RwLock};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::{filter, fmt, prelude::*, reload, EnvFilter, Layer, Registry};
+
+/// Events waiting for the forwarder thread; past this, new ones are dropped
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Process log events are forwarded to, if any
+static HANDLER: RwLock<Option<LocalPid>> = RwLock::new(None);
+
+/// Queue to the forwarder thread, started the first time a handler is set
+static FORWARDER: OnceLock<SyncSender<Record>> = OnceLock::new();
+
+/// Swaps the filter of the subscriber installed by `init`
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// One tracing event, ready to send
+struct Record {
+    level: Atom,
+    target: String,
+    message: String,
+}
+
+/// Install the global subscriber, filtered by `SPARX_LOG` (`warn` if unset)
+///
+/// Events go to stderr, or to the process set with `set_handler`. If a
+/// subscriber is already installed, by the host or by this library before a
+/// reload in place (statics survive those), it is left alone.
+pub fn init() {
+    let initial = std::env::var("SPARX_LOG")
+        .map(|s| EnvFilter::new(&s))
+        .unwrap_or_else(|_| EnvFilter::new("warn"));
+    let (env_filter, handle) = reload::Layer::new(initial);
+    let stderr = fmt::layer().with_filter(filter::filter_fn(|_| !forwarding()));
+    let (otlp, otlp_error) = match trace_context::otlp_layer() {
+        Ok(otlp) => (otlp, None),
+        Err(e) => (None, Some(e)),
+    };
+    let installed = tracing_subscriber::registry()
+        .with(env_filter)
+        .with(stderr)
+        .with(ElixirLayer)
+        .with(otlp)
+        .try_init();
+    if installed.is_ok() {
+        let _ = FILTER.set(handle);
+    }
+    // Logged only now, so it goes through the subscriber just installed
+    if let Some(e) = otlp_error {
+        tracing::warn!("{}", e);
+    }
+}
+
+/// Replace the filter set by `SPARX_LOG`, using the same directive syntax
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let env_filter = EnvFilter::try_new(directives)
+        .map_err(|e| format!("Invalid log filter {:?}: {}", directives, e))?;
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| "another tracing subscriber is installed".to_string())?;
+    handle
+        .reload(env_filter)
+        .map_err(|e| format!("Failed to set log filter: {}", e))
+}
+
+/// Forward events to `pid` as `{:sparx_log, level, target, message}`, or
+/// back to stderr when `pid` is None
+pub fn set_handler(pid: Option<LocalPid>) {
+    if pid.is_some() {
+        FORWARDER.get_or_init(|| {
+            let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+            std::thread::Builder::new()
+                .name("sparx-logger".to_string())
+                .spawn(move || forward(rx))
+                .expect("failed to spawn the log forwarder");
+            tx
+        });
+    }
+    if let Ok(mut handler) = HANDLER.write() {
+        *handler = pid;
+    }
+}
+
+/// Whether events go to an Elixir process instead of stderr
+pub fn forwarding() -> bool {
+    HANDLER
+        .read()
+        .map(|handler| handler.is_some())
+        .unwrap_or(false)
+}
+
+/// Send queued events from a thread of its own, since events are also
+/// emitted on BEAM scheduler threads, which cannot send with an `OwnedEnv`
+fn forward(rx: Receiver<Record>) {
+    let mut env = OwnedEnv::new();
+    while let Ok(record) = rx.recv() {
+        let Some(pid) = HANDLER.read().ok().and_then(|handler| *handler) else {
+            continue;
+        };
+        let sent = env.send_and_clear(&pid, |env| {
+            (
+                atoms::sparx_log(),
+                record.level,
+                record.target,
+                record.message,
+            )
+                .encode(env)
+        });
+        if sent.is_err() {
+            // The handler exited; stderr takes over again
+            set_handler(None);
+        }
+    }
+}
+
+/// Layer that hands events to the forwarder while a handler is set
+pub struct ElixirLayer;
+
+impl<S: Subscriber> Layer<S> for ElixirLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(tx) = FORWARDER.get() else {
+            return;
+        };
+        if !forwarding() {
+            return;
+        }
+        let metadata = event.metadata();
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let record = Record {
+            level: level(metadata.level()),
+            target: metadata.target().to_string(),
+            message: visitor.finish(),
+        };
+        // A Logger that cannot keep up loses events rather than blocking
+        // the thread that logged
+        let _ = tx.try_send(record);
+    }
+}
+
+/// Logger level for a tracing level; Logger has nothing below debug
+fn level(level: &Level) -> Atom {
+    match *level {
+        Level::ERROR => atoms::error(),
+        Level::WARN => atoms::warning(),
+        Level::INFO => atoms::info(),
+        Level::DEBUG | Level::TRACE => atoms::debug(),
+    }
+}
+
+/// Formats an event like the stderr layer: the message, then `key=value`
+/// for each other field
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl MessageVisitor {
+    fn finish(mut self) -> String {
+        self.message.push_str(&self.fields);
+        self.message
+    }
+
+    fn push_field(&mut self, field: &Field, value: std::fmt::Arguments<'_>) {
+        if field.name() == "message" {
+            let _ = self.message.write_fmt(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push_field(field, format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push_field(field, format_args!("{:?}", value));
+    }
+}
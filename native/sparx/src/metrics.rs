@@ -0,0 +1,228 @@
+use crate::latency::{Histogram, BUCKETS_US};
+use crate::response::BodyError;
+use crate::server::ServerContext;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::{Response, StatusCode};
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// Prometheus metrics for a server
+///
+/// Served straight from Rust at `metrics_path` in the text exposition
+/// format, so scrapes never wait behind the Elixir handler.
+pub struct Metrics {
+    path: String,
+    /// Responses by status class, 1xx through 5xx
+    responses: [AtomicU64; 5],
+    latency: Histogram,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            responses: Default::default(),
+            latency: Histogram::default(),
+            received_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a request path addresses the metrics endpoint
+    pub fn is_endpoint(&self, path: &str) -> bool {
+        path == self.path
+    }
+
+    /// Count a response and the time it took to start, and count its body
+    /// bytes as they are sent
+    pub fn record(
+        self: &Arc<Self>,
+        response: Response<BoxBody>,
+        latency: Duration,
+    ) -> Response<BoxBody> {
+        let class = (response.status().as_u16() / 100).clamp(1, 5) as usize;
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+
+        self.latency.record(latency.as_micros() as u64);
+
+        let metrics = self.clone();
+        response.map(|inner| {
+            CountedBody {
+                inner,
+                metrics,
+                sent: true,
+            }
+            .boxed()
+        })
+    }
+
+    /// Count the bytes of a request body as they are read
+    pub fn count_received<B>(self: &Arc<Self>, body: B) -> CountedBody<B> {
+        CountedBody {
+            inner: body,
+            metrics: self.clone(),
+            sent: false,
+        }
+    }
+
+    /// The endpoint's response: every metric in the text format
+    pub fn response(&self, context: &ServerContext, queue_depth: usize) -> Response<BoxBody> {
+        let body = http_body_util::Full::new(Bytes::from(self.render(context, queue_depth)))
+            .map_err(|never| match never {})
+            .boxed();
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain; version=0.0.4")
+            .header("cache-control", "no-store")
+            .body(body)
+            .unwrap()
+    }
+
+    fn render(&self, context: &ServerContext, queue_depth: usize) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "sparx_requests_total",
+            "counter",
+            "Requests answered, by status class",
+        );
+        for (i, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "sparx_requests_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "sparx_request_duration_seconds",
+            "histogram",
+            "Time from a request's arrival until its response head is ready",
+        );
+        let mut cumulative = 0;
+        for (i, count) in self.latency.counts().into_iter().enumerate() {
+            cumulative += count;
+            match BUCKETS_US.get(i) {
+                Some(&bound) => {
+                    let _ = writeln!(
+                        out,
+                        "sparx_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                        bound as f64 / 1e6,
+                        cumulative
+                    );
+                }
+                None => {
+                    let _ = writeln!(
+                        out,
+                        "sparx_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+                        cumulative
+                    );
+                }
+            }
+        }
+        let sum_us = self.latency.sum_us();
+        let _ = writeln!(
+            out,
+            "sparx_request_duration_seconds_sum {}",
+            sum_us as f64 / 1e6
+        );
+        let _ = writeln!(out, "sparx_request_duration_seconds_count {}", cumulative);
+
+        let gauges = [
+            (
+                "sparx_open_connections",
+                "Connections currently open",
+                context.connections.open() as u64,
+            ),
+            (
+                "sparx_queue_depth",
+                "Requests waiting in the queue for a handler",
+                queue_depth as u64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let counters = [
+            (
+                "sparx_received_bytes_total",
+                "Request body bytes read",
+                &self.received_bytes,
+            ),
+            (
+                "sparx_sent_bytes_total",
+                "Response body bytes sent",
+                &self.sent_bytes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Body that adds the size of each data frame to the byte counters
+pub struct CountedBody<B> {
+    inner: B,
+    metrics: Arc<Metrics>,
+    /// Counts toward sent bytes if set, received bytes otherwise
+    sent: bool,
+}
+
+impl<B> Body for CountedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                let counter = if self.sent {
+                    &self.metrics.sent_bytes
+                } else {
+                    &self.metrics.received_bytes
+                };
+                counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
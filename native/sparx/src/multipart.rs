@@ -0,0 +1,231 @@
+use crate::atoms;
+use crate::errors::ErrorKind;
+use crate::headers::HeaderList;
+use crate::request::RequestHandle;
+use bytes::{Buf, Bytes, BytesMut};
+use rustler::Atom;
+
+/// Largest header block accepted for a single part
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Longest boundary RFC 2046 allows
+const MAX_BOUNDARY: usize = 70;
+
+/// Why a multipart body could not be read
+pub enum MultipartError {
+    /// Not a multipart request, or a body that breaks the format
+    Malformed,
+    /// Reading the request body failed
+    Body(ErrorKind),
+}
+
+impl MultipartError {
+    pub fn atom(self) -> Atom {
+        match self {
+            MultipartError::Malformed => atoms::invalid_request(),
+            MultipartError::Body(kind) => kind.atom(),
+        }
+    }
+}
+
+enum State {
+    /// Before the first boundary
+    Preamble,
+    /// Just past a boundary, before its line ending or the closing `--`
+    Boundary,
+    Headers,
+    Body,
+    /// Past the closing boundary; the epilogue is ignored
+    Done,
+}
+
+/// Streaming parser for a `multipart/*` request body
+///
+/// Body bytes are pulled from the request as parts are read, and only the
+/// tail that may hold the start of a boundary is kept between calls, so a
+/// file upload is never buffered whole.
+pub struct Multipart {
+    /// `\r\n--` followed by the boundary
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+    state: State,
+}
+
+impl Multipart {
+    /// Parser for the body of a request, if its content type is multipart
+    /// and has a boundary
+    pub fn for_request(headers: &HeaderList) -> Option<Self> {
+        let content_type = headers.get("content-type")?.to_str().ok()?;
+        let boundary = boundary(content_type)?;
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        // A boundary opening the body has no line ending in front of it
+        let buf = BytesMut::from(&b"\r\n"[..]);
+        Some(Self {
+            delimiter,
+            buf,
+            state: State::Preamble,
+        })
+    }
+
+    /// Read the headers of the next part, skipping whatever is left of the
+    /// current one; `None` after the last part
+    pub async fn next_part(
+        &mut self,
+        request: &RequestHandle,
+    ) -> Result<Option<Vec<(String, String)>>, MultipartError> {
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buf, &self.delimiter) {
+                    Some(at) => {
+                        self.buf.advance(at + self.delimiter.len());
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        if self.buf.len() > keep {
+                            self.buf.advance(self.buf.len() - keep);
+                        }
+                        self.fill(request).await?;
+                    }
+                },
+                State::Boundary => {
+                    // Transport padding may follow the boundary
+                    let padding = self
+                        .buf
+                        .iter()
+                        .take_while(|&&byte| byte == b' ' || byte == b'\t')
+                        .count();
+                    if self.buf.len() < padding + 2 {
+                        self.fill(request).await?;
+                        continue;
+                    }
+                    match &self.buf[padding..padding + 2] {
+                        b"--" => {
+                            self.buf.clear();
+                            self.state = State::Done;
+                        }
+                        b"\r\n" => {
+                            self.buf.advance(padding + 2);
+                            self.state = State::Headers;
+                        }
+                        _ => return Err(MultipartError::Malformed),
+                    }
+                }
+                State::Headers => {
+                    let end = if self.buf.starts_with(b"\r\n") {
+                        Some((0, 2))
+                    } else {
+                        find(&self.buf, b"\r\n\r\n").map(|at| (at, at + 4))
+                    };
+                    match end {
+                        Some((end, consumed)) => {
+                            let headers = parse_headers(&self.buf[..end])?;
+                            self.buf.advance(consumed);
+                            self.state = State::Body;
+                            return Ok(Some(headers));
+                        }
+                        None if self.buf.len() > MAX_HEADER_SIZE => {
+                            return Err(MultipartError::Malformed)
+                        }
+                        None => self.fill(request).await?,
+                    }
+                }
+                State::Body => while self.read_chunk(request).await?.is_some() {},
+                State::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Read the next piece of the current part's body; `None` at its end
+    pub async fn read_chunk(
+        &mut self,
+        request: &RequestHandle,
+    ) -> Result<Option<Bytes>, MultipartError> {
+        loop {
+            if !matches!(self.state, State::Body) {
+                return Ok(None);
+            }
+            match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.buf.advance(self.delimiter.len());
+                    self.state = State::Boundary;
+                    return Ok(None);
+                }
+                Some(at) => return Ok(Some(self.buf.split_to(at).freeze())),
+                None => {
+                    // The tail may be the start of a delimiter
+                    let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+                    if safe > 0 {
+                        return Ok(Some(self.buf.split_to(safe).freeze()));
+                    }
+                    self.fill(request).await?;
+                }
+            }
+        }
+    }
+
+    /// Append the next chunk of the request body to the buffer
+    async fn fill(&mut self, request: &RequestHandle) -> Result<(), MultipartError> {
+        match request.read_body_chunk().await {
+            Ok(Some(chunk)) => {
+                self.buf.extend_from_slice(&chunk);
+                Ok(())
+            }
+            // The body ended before the closing boundary
+            Ok(None) => Err(MultipartError::Malformed),
+            Err(kind) => Err(MultipartError::Body(kind)),
+        }
+    }
+}
+
+/// The `boundary` parameter of a `multipart/*` content type
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type
+        .get(..10)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("multipart/"))
+    {
+        return None;
+    }
+    let boundary = params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })?;
+    (!boundary.is_empty() && boundary.len() <= MAX_BOUNDARY).then_some(boundary)
+}
+
+/// Header fields of a part, with lowercase names
+fn parse_headers(block: &[u8]) -> Result<Vec<(String, String)>, MultipartError> {
+    if block.is_empty() {
+        return Ok(Vec::new());
+    }
+    block
+        .split(|&byte| byte == b'\n')
+        .map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let colon = line
+                .iter()
+                .position(|&byte| byte == b':')
+                .ok_or(MultipartError::Malformed)?;
+            let name = String::from_utf8_lossy(&line[..colon])
+                .trim()
+                .to_ascii_lowercase();
+            let value = String::from_utf8_lossy(&line[colon + 1..])
+                .trim()
+                .to_string();
+            if name.is_empty() {
+                return Err(MultipartError::Malformed);
+            }
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,171 @@
+use crate::config::ServerConfig;
+use std::cell::Cell;
+use std::io;
+
+const NODE_ROOT: &str = "/sys/devices/system/node";
+
+thread_local! {
+    /// Node slot the current thread was placed on, if any
+    static LOCAL_NODE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// CPUs belonging to one NUMA node
+#[derive(Debug, Clone)]
+struct Node {
+    id: usize,
+    cpus: Vec<usize>,
+}
+
+/// Where a server's threads run
+///
+/// Built from `numa_aware`, `numa_nodes`, and `pin_threads`. Threads are
+/// assigned to the selected nodes round-robin, so acceptors and workers are
+/// spread evenly across sockets, and each node gets its own buffer pool
+/// shard that is first touched from that node.
+#[derive(Debug)]
+pub struct Placement {
+    nodes: Vec<Node>,
+    pin_threads: bool,
+}
+
+/// The CPUs a single thread is bound to
+#[derive(Debug, Clone)]
+pub struct Slot {
+    /// Index into the placement's nodes (not the kernel's node id)
+    pub node: usize,
+    pub cpus: Vec<usize>,
+}
+
+impl Placement {
+    /// Build the placement requested by the configuration, if any
+    pub fn from_config(config: &ServerConfig) -> io::Result<Option<Self>> {
+        if !config.numa_aware && config.numa_nodes.is_empty() && !config.pin_threads {
+            return Ok(None);
+        }
+
+        let mut nodes = detect_nodes();
+        if !config.numa_nodes.is_empty() {
+            if let Some(missing) = config
+                .numa_nodes
+                .iter()
+                .find(|id| !nodes.iter().any(|node| node.id == **id))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("NUMA node {} does not exist", missing),
+                ));
+            }
+            nodes.retain(|node| config.numa_nodes.contains(&node.id));
+        }
+
+        Ok(Some(Self {
+            nodes,
+            pin_threads: config.pin_threads,
+        }))
+    }
+
+    /// Number of nodes threads are spread across
+    pub fn nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// CPUs of the node in `slot`
+    pub fn node_cpus(&self, slot: usize) -> &[usize] {
+        &self.nodes[slot % self.nodes.len()].cpus
+    }
+
+    /// Pick the CPUs for the `index`th thread
+    ///
+    /// Consecutive threads alternate between nodes. With `pin_threads` each
+    /// thread gets a CPU of its own, otherwise it may run anywhere on its node.
+    pub fn assign(&self, index: usize) -> Slot {
+        let node = index % self.nodes.len();
+        let cpus = &self.nodes[node].cpus;
+        let cpus = if self.pin_threads {
+            vec![cpus[(index / self.nodes.len()) % cpus.len()]]
+        } else {
+            cpus.clone()
+        };
+        Slot { node, cpus }
+    }
+}
+
+impl Slot {
+    /// Bind the current thread to this slot
+    ///
+    /// Failing to set the affinity is not fatal; the thread keeps running
+    /// unpinned but still uses its node's pool shard.
+    pub fn apply(&self) {
+        if let Err(e) = set_affinity(&self.cpus) {
+            tracing::warn!("Failed to pin thread to CPUs {:?}: {}", self.cpus, e);
+        }
+        LOCAL_NODE.with(|node| node.set(Some(self.node)));
+    }
+}
+
+/// Node slot of the current thread, `None` for threads that were not placed
+pub fn local_node() -> Option<usize> {
+    LOCAL_NODE.with(|node| node.get())
+}
+
+/// Read the NUMA topology, treating the machine as one node if unavailable
+fn detect_nodes() -> Vec<Node> {
+    let mut nodes: Vec<Node> = std::fs::read_dir(NODE_ROOT)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let id = name.to_str()?.strip_prefix("node")?.parse().ok()?;
+            let list = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            let cpus = parse_cpu_list(&list);
+            (!cpus.is_empty()).then_some(Node { id, cpus })
+        })
+        .collect();
+
+    if nodes.is_empty() {
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        nodes.push(Node {
+            id: 0,
+            cpus: (0..cpus).collect(),
+        });
+    }
+
+    nodes.sort_by_key(|node| node.id);
+    nodes
+}
+
+/// Parse a kernel CPU list such as `0-3,8-11`
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    list.trim()
+        .split(',')
+        .filter_map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            Some(start.parse::<usize>().ok()?..=end.parse().ok()?)
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: `cpu_set_t` is a plain bitmask for which all zeroes is the
+    // empty set, and the set outlives the `sched_setaffinity` call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Ok(())
+}
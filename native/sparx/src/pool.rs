@@ -0,0 +1,326 @@
+use crate::channel::{self, ChannelState};
+use crate::disconnect::Disconnect;
+use crate::headers::HeaderList;
+use crate::numa::{self, Placement};
+use crate::timing::RequestTimings;
+use rustler::NifMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Values that can be reset and handed out again
+pub trait Recycle {
+    fn recycle(&mut self);
+}
+
+impl<T> Recycle for Vec<T> {
+    fn recycle(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: Recycle> Recycle for Arc<T> {
+    fn recycle(&mut self) {
+        // Pools only ever hold `Arc`s nothing else refers to
+        if let Some(inner) = Arc::get_mut(self) {
+            inner.recycle();
+        }
+    }
+}
+
+/// Free-list of reusable per-request allocations
+///
+/// Header lists keep their capacity between requests instead of being
+/// reallocated, and the state a request shares with its connection task is
+/// reset and handed to the next request instead of freed.
+pub struct Pool<T> {
+    items: Mutex<Vec<T>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discarded: AtomicU64,
+    trimmed: AtomicU64,
+}
+
+/// Snapshot of pool counters returned by `server_stats`
+#[derive(NifMap, Clone, Copy, Default)]
+pub struct PoolStats {
+    pub available: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub discarded: u64,
+    pub trimmed: u64,
+}
+
+impl<T: Default + Recycle> Pool<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            items: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+            trimmed: AtomicU64::new(0),
+        }
+    }
+
+    /// Fill the pool up to its capacity ahead of the first requests
+    pub fn prefill(&self) {
+        if let Ok(mut items) = self.items.lock() {
+            let missing = self.capacity.saturating_sub(items.len());
+            items.extend((0..missing).map(|_| T::default()));
+        }
+    }
+
+    /// Take a buffer from the pool, allocating a fresh one if it is empty
+    pub fn take(&self) -> T {
+        let item = self.items.lock().ok().and_then(|mut items| items.pop());
+        match item {
+            Some(item) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                item
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                T::default()
+            }
+        }
+    }
+
+    /// Return a buffer to the pool, dropping it if the pool is full
+    pub fn give(&self, mut item: T) {
+        item.recycle();
+        if let Ok(mut items) = self.items.lock() {
+            if items.len() < self.capacity {
+                items.push(item);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drop pooled buffers beyond `keep`, returning how many were freed
+    pub fn trim(&self, keep: usize) -> usize {
+        let Ok(mut items) = self.items.lock() else {
+            return 0;
+        };
+        let excess = items.len().saturating_sub(keep);
+        if excess > 0 {
+            items.truncate(keep);
+            items.shrink_to(keep);
+            self.trimmed.fetch_add(excess as u64, Ordering::Relaxed);
+        }
+        excess
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            available: self.items.lock().map(|items| items.len()).unwrap_or(0),
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            trimmed: self.trimmed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One pool per NUMA node
+///
+/// Threads take from and return to the shard of the node they were placed
+/// on, so buffers stay in memory local to the node using them. Threads that
+/// were not placed (and servers without NUMA placement) use the first shard.
+pub struct ShardedPool<T> {
+    shards: Vec<Pool<T>>,
+}
+
+impl<T: Default + Recycle> ShardedPool<T> {
+    pub fn new(shards: usize, capacity: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Pool::new(capacity)).collect(),
+        }
+    }
+
+    /// Shard used by the current thread
+    pub fn local_shard(&self) -> usize {
+        numa::local_node().unwrap_or(0) % self.shards.len()
+    }
+
+    /// Take a buffer from the current thread's shard
+    pub fn take(&self) -> T {
+        self.shards[self.local_shard()].take()
+    }
+
+    /// Return a buffer to the current thread's shard
+    pub fn give(&self, item: T) {
+        self.shards[self.local_shard()].give(item);
+    }
+
+    /// Return a buffer to the shard it was taken from
+    pub fn give_to(&self, shard: usize, item: T) {
+        self.shards[shard % self.shards.len()].give(item);
+    }
+
+    /// Preallocate every shard
+    ///
+    /// With NUMA placement each shard is filled from a thread running on its
+    /// node, so the kernel backs its buffers with that node's memory.
+    fn prefill(&self, placement: Option<&Placement>)
+    where
+        T: Send,
+    {
+        let Some(placement) = placement else {
+            self.shards.iter().for_each(Pool::prefill);
+            return;
+        };
+
+        std::thread::scope(|scope| {
+            for (index, shard) in self.shards.iter().enumerate() {
+                let cpus = placement.node_cpus(index).to_vec();
+                scope.spawn(move || {
+                    numa::Slot { node: index, cpus }.apply();
+                    shard.prefill();
+                });
+            }
+        });
+    }
+
+    /// Trim the shards to `keep` buffers between them
+    pub fn trim(&self, keep: usize) -> usize {
+        let per_shard = keep.div_ceil(self.shards.len());
+        self.shards.iter().map(|shard| shard.trim(per_shard)).sum()
+    }
+
+    /// Counters summed over every shard
+    pub fn stats(&self) -> PoolStats {
+        self.shards
+            .iter()
+            .map(Pool::stats)
+            .fold(PoolStats::default(), |total, shard| PoolStats {
+                available: total.available + shard.available,
+                capacity: total.capacity + shard.capacity,
+                hits: total.hits + shard.hits,
+                misses: total.misses + shard.misses,
+                discarded: total.discarded + shard.discarded,
+                trimmed: total.trimmed + shard.trimmed,
+            })
+    }
+}
+
+/// Request state shared with the connection task, handed back to the pool
+/// it came from by whichever holder drops it last
+pub struct Shared<T: Default + Recycle> {
+    /// Only `None` while being dropped
+    item: Option<Arc<T>>,
+    pool: Arc<ShardedPool<Arc<T>>>,
+    shard: usize,
+}
+
+impl<T: Default + Recycle> Shared<T> {
+    /// Take an item from the current thread's shard, set up by `init`
+    fn take(pool: &Arc<ShardedPool<Arc<T>>>, init: impl FnOnce(&mut T)) -> Self {
+        let shard = pool.local_shard();
+        let mut item = pool.shards[shard].take();
+        match Arc::get_mut(&mut item) {
+            Some(inner) => init(inner),
+            None => {
+                let mut fresh = T::default();
+                init(&mut fresh);
+                item = Arc::new(fresh);
+            }
+        }
+        Self {
+            item: Some(item),
+            pool: pool.clone(),
+            shard,
+        }
+    }
+}
+
+impl<T: Default + Recycle> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self {
+            item: self.item.clone(),
+            pool: self.pool.clone(),
+            shard: self.shard,
+        }
+    }
+}
+
+impl<T: Default + Recycle> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item
+            .as_deref()
+            .expect("shared item is only taken on drop")
+    }
+}
+
+impl<T: Default + Recycle> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Other holders still use it; the last of them hands it back
+        if let Some(mut item) = self.item.take() {
+            if Arc::get_mut(&mut item).is_some() {
+                self.pool.give_to(self.shard, item);
+            }
+        }
+    }
+}
+
+/// Pools shared by every request on a server
+pub struct Pools {
+    /// Header lists for request metadata and response builders
+    pub headers: ShardedPool<HeaderList>,
+    /// Phase timestamps and event logs
+    pub timings: Arc<ShardedPool<Arc<RequestTimings>>>,
+    /// Client disconnect signals
+    pub disconnects: Arc<ShardedPool<Arc<Disconnect>>>,
+    /// Response channels and their queues
+    pub channels: Arc<ShardedPool<Arc<ChannelState>>>,
+}
+
+impl Pools {
+    pub fn new(capacity: usize, placement: Option<&Placement>) -> Self {
+        let shards = placement.map(Placement::nodes).unwrap_or(1);
+        Self {
+            headers: ShardedPool::new(shards, capacity),
+            timings: Arc::new(ShardedPool::new(shards, capacity)),
+            disconnects: Arc::new(ShardedPool::new(shards, capacity)),
+            channels: Arc::new(ShardedPool::new(shards, capacity)),
+        }
+    }
+
+    /// Timings for a request on a connection accepted at `accepted`
+    pub fn take_timings(&self, accepted: Instant) -> Shared<RequestTimings> {
+        Shared::take(&self.timings, |timings| timings.restart(accepted))
+    }
+
+    /// Signal for a request's client going away
+    pub fn take_disconnect(&self) -> Shared<Disconnect> {
+        Shared::take(&self.disconnects, |_| {})
+    }
+
+    /// Both ends of a request's response channel
+    pub fn take_channel(&self) -> (channel::Sender, channel::Receiver) {
+        channel::pair(Shared::take(&self.channels, ChannelState::open))
+    }
+
+    /// Shrink every pool to what `open_connections` can use at once
+    pub fn trim(&self, open_connections: usize) -> usize {
+        self.headers.trim(open_connections)
+            + self.timings.trim(open_connections)
+            + self.disconnects.trim(open_connections)
+            + self.channels.trim(open_connections)
+    }
+
+    /// Preallocate every pool
+    pub fn prefill(&self, placement: Option<&Placement>) {
+        self.headers.prefill(placement);
+        self.timings.prefill(placement);
+        self.disconnects.prefill(placement);
+        self.channels.prefill(placement);
+    }
+}
@@ -0,0 +1,82 @@
+//! In-process CPU profiler producing pprof profiles
+//!
+//! Only available when the crate is built with the `profiling` feature;
+//! otherwise every call returns `:not_supported`.
+
+use crate::atoms;
+use rustler::Atom;
+
+#[cfg(feature = "profiling")]
+mod imp {
+    use super::*;
+    use pprof::protos::Message;
+    use std::sync::Mutex;
+
+    /// Frames from these libraries are dropped from samples to avoid
+    /// deadlocking inside the unwinder
+    const BLOCKLIST: &[&str] = &["libc", "libgcc", "pthread", "vdso"];
+
+    static PROFILER: Mutex<Option<pprof::ProfilerGuard<'static>>> = Mutex::new(None);
+
+    pub fn start(frequency: i32) -> Result<(), Atom> {
+        let mut guard = PROFILER.lock().map_err(|_| atoms::error())?;
+        if guard.is_some() {
+            return Err(atoms::already_started());
+        }
+
+        let profiler = pprof::ProfilerGuardBuilder::default()
+            .frequency(frequency)
+            .blocklist(BLOCKLIST)
+            .build()
+            .map_err(|e| {
+                tracing::error!("Failed to start profiler: {}", e);
+                atoms::error()
+            })?;
+        *guard = Some(profiler);
+        Ok(())
+    }
+
+    pub fn stop() -> Result<Vec<u8>, Atom> {
+        let profiler = PROFILER
+            .lock()
+            .map_err(|_| atoms::error())?
+            .take()
+            .ok_or_else(atoms::not_started)?;
+
+        let profile = profiler
+            .report()
+            .build()
+            .and_then(|report| report.pprof())
+            .map_err(|e| {
+                tracing::error!("Failed to build profile: {}", e);
+                atoms::error()
+            })?;
+
+        let mut encoded = Vec::new();
+        profile.encode(&mut encoded).map_err(|_| atoms::error())?;
+        Ok(encoded)
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+mod imp {
+    use super::*;
+
+    pub fn start(_frequency: i32) -> Result<(), Atom> {
+        Err(atoms::not_supported())
+    }
+
+    pub fn stop() -> Result<Vec<u8>, Atom> {
+        Err(atoms::not_supported())
+    }
+}
+
+/// Start sampling the process `frequency` times per second
+pub fn start(frequency: i32) -> Result<(), Atom> {
+    imp::start(frequency)
+}
+
+/// Stop the profiler and return the samples as an encoded pprof profile
+pub fn stop() -> Result<Vec<u8>, Atom> {
+    imp::stop()
+}
@@ -0,0 +1,280 @@
+use crate::atoms;
+use bytes::{Buf, BytesMut};
+use rustler::{Atom, NifMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// Binary header signature (PROXY protocol v2)
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Fixed part of a v2 header: signature, version and command, family, length
+const V2_HEADER_LEN: usize = 16;
+
+/// Client a connection is from, as seen in request metadata
+#[derive(NifMap, Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    /// IP address, or socket path for Unix clients relayed by PROXY v2
+    pub address: String,
+    pub port: Option<u16>,
+    /// `:tcp4`, `:tcp6`, `:udp4`, `:udp6`, or `:unix`
+    pub protocol: Atom,
+    /// Whether the address came from a PROXY protocol header rather than
+    /// the socket
+    pub proxied: bool,
+}
+
+impl Peer {
+    /// The peer of a directly accepted TCP connection
+    pub fn direct(addr: SocketAddr) -> Self {
+        Self {
+            address: addr.ip().to_string(),
+            port: Some(addr.port()),
+            protocol: if addr.is_ipv4() {
+                atoms::tcp4()
+            } else {
+                atoms::tcp6()
+            },
+            proxied: false,
+        }
+    }
+
+    fn proxied(addr: SocketAddr, stream: bool) -> Self {
+        let protocol = match (addr.is_ipv4(), stream) {
+            (true, true) => atoms::tcp4(),
+            (false, true) => atoms::tcp6(),
+            (true, false) => atoms::udp4(),
+            (false, false) => atoms::udp6(),
+        };
+        Self {
+            address: addr.ip().to_string(),
+            port: Some(addr.port()),
+            protocol,
+            proxied: true,
+        }
+    }
+
+    /// Label for logs, formatted like a socket address
+    pub fn label(&self) -> String {
+        match self.port {
+            Some(port) if self.address.contains(':') => format!("[{}]:{}", self.address, port),
+            Some(port) => format!("{}:{}", self.address, port),
+            None => self.address.clone(),
+        }
+    }
+}
+
+/// Read the PROXY protocol header (v1 or v2) a load balancer sends ahead
+/// of the client's bytes
+///
+/// Returns the original client, or None when the header does not relay one
+/// (v1 `UNKNOWN`, or a v2 `LOCAL` health check), along with the stream.
+/// Bytes read past the header are replayed by the returned stream.
+pub async fn accept<I>(mut stream: I) -> io::Result<(Option<Peer>, Rewind<I>)>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(V1_MAX_LEN);
+    let (len, peer) = loop {
+        if let Some(parsed) = parse(&buf)? {
+            break parsed;
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(invalid("connection closed before the PROXY header ended"));
+        }
+    };
+    buf.advance(len);
+    Ok((peer, Rewind::new(stream, buf)))
+}
+
+/// Parse a complete header at the start of `buf`, with its length
+///
+/// Returns None while more bytes are needed.
+fn parse(buf: &[u8]) -> io::Result<Option<(usize, Option<Peer>)>> {
+    let signature = &V2_SIGNATURE[..buf.len().min(V2_SIGNATURE.len())];
+    if buf.starts_with(signature) {
+        return parse_v2(buf);
+    }
+    let prefix = &b"PROXY "[..buf.len().min(6)];
+    if buf.starts_with(prefix) {
+        return parse_v1(buf);
+    }
+    Err(invalid("connection did not start with a PROXY header"))
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(usize, Option<Peer>)>> {
+    let Some(end) = buf.windows(2).position(|pair| pair == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header is too long"));
+        }
+        return Ok(None);
+    };
+    let line =
+        std::str::from_utf8(&buf[..end]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let mut fields = line.split(' ').skip(1);
+    let peer = match fields.next() {
+        Some("UNKNOWN") => None,
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let fields: Vec<&str> = fields.collect();
+            let [source, _destination, source_port, _destination_port] = fields[..] else {
+                return Err(invalid("PROXY v1 header has the wrong number of fields"));
+            };
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("PROXY v1 source address is invalid"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("PROXY v1 source port is invalid"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("PROXY v1 source address is of the wrong family"));
+            }
+            Some(Peer::proxied(SocketAddr::new(ip, port), true))
+        }
+        _ => return Err(invalid("PROXY v1 header has an unknown protocol")),
+    };
+    Ok(Some((end + 2, peer)))
+}
+
+/// Binary header: signature, version and command, family and transport,
+/// address length, then the addresses and any TLVs
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(usize, Option<Peer>)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13] >> 4;
+    let transport = buf[13] & 0x0f;
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[V2_HEADER_LEN..len];
+
+    let peer = match command {
+        // LOCAL: the proxy's own connection, e.g. a health check
+        0x0 => None,
+        0x1 => address_v2(family, transport, addresses)?,
+        _ => return Err(invalid("unknown PROXY v2 command")),
+    };
+    Ok(Some((len, peer)))
+}
+
+fn address_v2(family: u8, transport: u8, addresses: &[u8]) -> io::Result<Option<Peer>> {
+    let stream = match transport {
+        // UNSPEC leaves the addresses unknown
+        0x0 => return Ok(None),
+        0x1 => true,
+        0x2 => false,
+        _ => return Err(invalid("unknown PROXY v2 transport")),
+    };
+    let short = || invalid("PROXY v2 addresses are truncated");
+    match family {
+        0x0 => Ok(None),
+        // Source and destination addresses, then source and destination ports
+        0x1 => {
+            let bytes = addresses.get(..12).ok_or_else(short)?;
+            let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            let port = u16::from_be_bytes([bytes[8], bytes[9]]);
+            Ok(Some(Peer::proxied(
+                SocketAddr::new(ip.into(), port),
+                stream,
+            )))
+        }
+        0x2 => {
+            let bytes = addresses.get(..36).ok_or_else(short)?;
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes[..16]);
+            let port = u16::from_be_bytes([bytes[32], bytes[33]]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(Peer::proxied(
+                SocketAddr::new(ip.into(), port),
+                stream,
+            )))
+        }
+        // 108-byte NUL-padded source and destination paths
+        0x3 => {
+            let source = addresses.get(..108).ok_or_else(short)?;
+            let end = source.iter().position(|&b| b == 0).unwrap_or(source.len());
+            Ok(Some(Peer {
+                address: String::from_utf8_lossy(&source[..end]).into_owned(),
+                port: None,
+                protocol: atoms::unix(),
+                proxied: true,
+            }))
+        }
+        _ => Err(invalid("unknown PROXY v2 address family")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Connection I/O that replays bytes read ahead of the PROXY header's end
+/// before reading from the socket again
+pub struct Rewind<I> {
+    inner: I,
+    buffered: BytesMut,
+}
+
+impl<I> Rewind<I> {
+    fn new(inner: I, buffered: BytesMut) -> Self {
+        Self { inner, buffered }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Rewind<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let len = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..len]);
+            self.buffered.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Rewind<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::binary::NifBytes;
+use bytes::Bytes;
+use rustler::{Encoder, Env, Term};
+use std::collections::HashMap;
+
+/// Deepest bracket nesting honoured; keys nested further are kept as is
+const MAX_DEPTH: usize = 32;
+
+/// A decoded query parameter, nested according to bracketed keys
+pub enum Value {
+    Leaf(Vec<u8>),
+    /// From `key[]` segments
+    List(Vec<Value>),
+    /// From `key[field]` segments, and the query as a whole
+    Map(HashMap<Vec<u8>, Value>),
+}
+
+impl Encoder for Value {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Value::Leaf(bytes) => binary(bytes, env),
+            Value::List(items) => items.encode(env),
+            Value::Map(fields) => {
+                let keys: Vec<_> = fields.keys().map(|key| binary(key, env)).collect();
+                let values: Vec<_> = fields.values().map(|value| value.encode(env)).collect();
+                Term::map_from_arrays(env, &keys, &values)
+                    .unwrap_or_else(|_| rustler::types::map::map_new(env))
+            }
+        }
+    }
+}
+
+fn binary<'a>(bytes: &[u8], env: Env<'a>) -> Term<'a> {
+    NifBytes(Bytes::copy_from_slice(bytes)).encode(env)
+}
+
+/// Split a query string into decoded key/value pairs, in order
+///
+/// Both halves are form-decoded (`+` is a space, `%XX` a byte); malformed
+/// escapes are kept as they are. A pair without `=` has an empty value.
+pub fn pairs(query: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    query
+        .split(|&byte| byte == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.iter().position(|&byte| byte == b'=') {
+            Some(at) => (decode(&pair[..at]), decode(&pair[at + 1..])),
+            None => (decode(pair), Vec::new()),
+        })
+        .collect()
+}
+
+/// Parse a query string into a map, nesting bracketed keys
+///
+/// `a[]=1&a[]=2` gives a list under `a`, `a[b]=1` a map, and the two
+/// combine (`items[][id]=1`). A plain key that repeats keeps its last value.
+pub fn parse(query: &[u8]) -> Value {
+    let mut params = HashMap::new();
+    for (key, value) in pairs(query) {
+        match segments(&key) {
+            Some((root, path)) => insert(&mut params, root, &path, value),
+            None => {
+                params.insert(key, Value::Leaf(value));
+            }
+        }
+    }
+    Value::Map(params)
+}
+
+/// The root of a bracketed key and its segments, `None` for a plain key
+fn segments(key: &[u8]) -> Option<(&[u8], Vec<&[u8]>)> {
+    let open = key.iter().position(|&byte| byte == b'[')?;
+    if open == 0 {
+        return None;
+    }
+    let (root, mut rest) = key.split_at(open);
+    let mut path = Vec::new();
+    while let Some(inner) = rest.strip_prefix(b"[") {
+        let close = inner.iter().position(|&byte| byte == b']')?;
+        path.push(&inner[..close]);
+        rest = &inner[close + 1..];
+    }
+    (rest.is_empty() && path.len() <= MAX_DEPTH).then_some((root, path))
+}
+
+fn insert(fields: &mut HashMap<Vec<u8>, Value>, key: &[u8], path: &[&[u8]], value: Vec<u8>) {
+    match path.split_first() {
+        None => {
+            fields.insert(key.to_vec(), Value::Leaf(value));
+        }
+        Some((segment, rest)) => {
+            let slot = fields
+                .entry(key.to_vec())
+                .or_insert_with(|| Value::Map(HashMap::new()));
+            insert_into(slot, segment, rest, value);
+        }
+    }
+}
+
+/// Add a value under one segment of a bracketed key, replacing a slot of
+/// the wrong kind
+fn insert_into(slot: &mut Value, segment: &[u8], rest: &[&[u8]], value: Vec<u8>) {
+    if !segment.is_empty() {
+        if !matches!(slot, Value::Map(_)) {
+            *slot = Value::Map(HashMap::new());
+        }
+        if let Value::Map(fields) = slot {
+            insert(fields, segment, rest, value);
+        }
+        return;
+    }
+
+    if !matches!(slot, Value::List(_)) {
+        *slot = Value::List(Vec::new());
+    }
+    let Value::List(items) = slot else {
+        return;
+    };
+    let Some((next, more)) = rest.split_first() else {
+        items.push(Value::Leaf(value));
+        return;
+    };
+    // Fields of one element fill the last map until a field repeats
+    let reuse = matches!(
+        items.last(),
+        Some(Value::Map(fields)) if !next.is_empty() && !fields.contains_key(*next)
+    );
+    if !reuse {
+        items.push(Value::Map(HashMap::new()));
+    }
+    if let Some(last) = items.last_mut() {
+        insert_into(last, next, more, value);
+    }
+}
+
+/// Form-decode a query component
+fn decode(component: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(component.len());
+    let mut i = 0;
+    while i < component.len() {
+        match component[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match component.get(i + 1..i + 3).and_then(hex_byte) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    let high = (digits[0] as char).to_digit(16)?;
+    let low = (digits[1] as char).to_digit(16)?;
+    Some((high * 16 + low) as u8)
+}
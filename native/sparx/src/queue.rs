@@ -0,0 +1,189 @@
+use crate::config::{QueueOverflow, ServerConfig};
+use crate::request::RequestHandle;
+use async_channel::TrySendError;
+use hyper::HeaderMap;
+
+/// A queued request waiting to be picked up by Elixir
+pub struct QueuedRequest {
+    pub handle: RequestHandle,
+}
+
+/// Priority class a request is queued under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Health checks, admin endpoints, and anything else that must not wait
+    /// behind a backlog of regular requests
+    High,
+    Normal,
+}
+
+/// Classify a request using the server's priority rules
+///
+/// A request is high priority when its path matches one of
+/// `priority_paths` (exact, or a prefix when the pattern ends in `*`) or
+/// when it carries the configured `priority_header`.
+pub fn classify(config: &ServerConfig, path: &str, headers: &HeaderMap) -> Priority {
+    let path_match = config
+        .priority_paths
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == pattern,
+        });
+
+    let header_match = config
+        .priority_header
+        .as_deref()
+        .map(|name| headers.contains_key(name))
+        .unwrap_or(false);
+
+    if path_match || header_match {
+        Priority::High
+    } else {
+        Priority::Normal
+    }
+}
+
+/// Why a request could not be queued
+#[derive(Debug)]
+pub enum QueueError {
+    /// The receiving side of the queue is gone (the server shut down)
+    Closed,
+    /// The lane is full and the overflow policy is `Reject`
+    Full,
+}
+
+/// Sending half of the request queue, one per accept loop
+#[derive(Clone)]
+pub struct QueueSender {
+    high: Lane,
+    normal: Lane,
+    overflow: QueueOverflow,
+}
+
+#[derive(Clone)]
+struct Lane {
+    tx: async_channel::Sender<QueuedRequest>,
+    /// Used to evict the oldest request under `DropOldest`; weak so the
+    /// senders don't keep the queue open once the server is gone
+    rx: async_channel::WeakReceiver<QueuedRequest>,
+}
+
+/// Receiving half of the request queue, owned by the `ServerHandle`
+///
+/// Each lane is a multi-consumer channel, so any number of processes can
+/// wait in `receive_request` at once without taking a lock; each request is
+/// delivered to exactly one of them.
+#[derive(Clone)]
+pub struct QueueReceiver {
+    high: async_channel::Receiver<QueuedRequest>,
+    normal: async_channel::Receiver<QueuedRequest>,
+}
+
+/// Create a request queue with a lane per priority class, each holding up
+/// to `capacity` requests
+pub fn channel(capacity: usize, overflow: QueueOverflow) -> (QueueSender, QueueReceiver) {
+    let (high_tx, high_rx) = async_channel::bounded(capacity.max(1));
+    let (normal_tx, normal_rx) = async_channel::bounded(capacity.max(1));
+    (
+        QueueSender {
+            high: Lane {
+                tx: high_tx,
+                rx: high_rx.downgrade(),
+            },
+            normal: Lane {
+                tx: normal_tx,
+                rx: normal_rx.downgrade(),
+            },
+            overflow,
+        },
+        QueueReceiver {
+            high: high_rx,
+            normal: normal_rx,
+        },
+    )
+}
+
+impl QueueSender {
+    /// Requests waiting in both lanes
+    pub fn depth(&self) -> usize {
+        self.high.tx.len() + self.normal.tx.len()
+    }
+
+    /// Queue a request in the lane for its priority
+    ///
+    /// When the lane is full, `Wait` waits for room, `Reject` fails with
+    /// `Full`, and `DropOldest` takes the oldest requests out to make room
+    /// and returns them for the caller to answer.
+    pub async fn send(
+        &self,
+        request: QueuedRequest,
+        priority: Priority,
+    ) -> Result<Vec<QueuedRequest>, QueueError> {
+        let lane = match priority {
+            Priority::High => &self.high,
+            Priority::Normal => &self.normal,
+        };
+        let mut request = match self.overflow {
+            QueueOverflow::Wait => {
+                lane.tx
+                    .send(request)
+                    .await
+                    .map_err(|_| QueueError::Closed)?;
+                return Ok(Vec::new());
+            }
+            QueueOverflow::Reject => {
+                return match lane.tx.try_send(request) {
+                    Ok(()) => Ok(Vec::new()),
+                    Err(TrySendError::Full(_)) => Err(QueueError::Full),
+                    Err(TrySendError::Closed(_)) => Err(QueueError::Closed),
+                };
+            }
+            QueueOverflow::DropOldest => request,
+        };
+
+        let mut evicted = Vec::new();
+        loop {
+            match lane.tx.try_send(request) {
+                Ok(()) => return Ok(evicted),
+                Err(TrySendError::Closed(_)) => return Err(QueueError::Closed),
+                Err(TrySendError::Full(returned)) => {
+                    request = returned;
+                    let rx = lane.rx.upgrade().ok_or(QueueError::Closed)?;
+                    // Another receiver may have emptied the lane meanwhile
+                    if let Ok(oldest) = rx.try_recv() {
+                        evicted.push(oldest);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl QueueReceiver {
+    /// Receive the next request, always draining the high lane first
+    ///
+    /// Cancel-safe: a request is only taken off a lane by the branch that
+    /// returns it.
+    pub async fn recv(&self) -> Option<QueuedRequest> {
+        tokio::select! {
+            biased;
+            Ok(request) = self.high.recv() => Some(request),
+            Ok(request) = self.normal.recv() => Some(request),
+            else => None,
+        }
+    }
+
+    /// Requests waiting in both lanes
+    pub fn depth(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    /// Take the next request if one is already queued, high lane first
+    pub fn try_recv(&self) -> Option<QueuedRequest> {
+        self.high
+            .try_recv()
+            .or_else(|_| self.normal.try_recv())
+            .ok()
+    }
+}
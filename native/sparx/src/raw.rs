@@ -0,0 +1,74 @@
+use crate::errors::ErrorKind;
+use crate::server::ServerContext;
+use bytes::{Bytes, BytesMut};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+
+type RawIo = TokioIo<Upgraded>;
+
+/// Most bytes a single read returns, so a large `max_bytes` cannot make
+/// every read allocate that much up front
+const MAX_READ_SIZE: usize = 64 * 1024;
+
+/// Connection handed over by an HTTP upgrade to a protocol other than
+/// WebSocket
+///
+/// The halves have their own locks, so a process parked in `raw_read` does
+/// not hold up writers.
+pub struct RawStream {
+    reader: Mutex<ReadHalf<RawIo>>,
+    /// Taken once the server's side is shut down
+    writer: Mutex<Option<WriteHalf<RawIo>>>,
+    /// Server state, for the error counters failures are recorded in
+    context: Arc<ServerContext>,
+}
+
+impl RawStream {
+    pub fn new(upgraded: Upgraded, context: Arc<ServerContext>) -> Self {
+        let (reader, writer) = tokio::io::split(TokioIo::new(upgraded));
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(Some(writer)),
+            context,
+        }
+    }
+
+    /// Read up to `max_bytes` (capped at `MAX_READ_SIZE`), waiting for at
+    /// least one
+    ///
+    /// An empty result means the client closed its side.
+    pub async fn read(&self, max_bytes: usize) -> Result<Bytes, ErrorKind> {
+        let mut reader = self.reader.lock().await;
+        // An empty buffer would read nothing and look like the end
+        let mut buf = BytesMut::with_capacity(max_bytes.clamp(1, MAX_READ_SIZE));
+        match reader.read_buf(&mut buf).await {
+            Ok(_) => Ok(buf.freeze()),
+            Err(e) => Err(self.context.errors.record(ErrorKind::of(&e))),
+        }
+    }
+
+    pub async fn write(&self, data: &[u8]) -> Result<(), ErrorKind> {
+        let mut writer = self.writer.lock().await;
+        let writer = writer.as_mut().ok_or(ErrorKind::Closed)?;
+        let written = match writer.write_all(data).await {
+            Ok(()) => writer.flush().await,
+            Err(e) => Err(e),
+        };
+        written.map_err(|e| self.context.errors.record(ErrorKind::of(&e)))
+    }
+
+    /// Shut down the server's side; the client can still be read from
+    pub async fn close(&self) {
+        if let Some(mut writer) = self.writer.lock().await.take() {
+            let _ = writer.shutdown().await;
+        }
+    }
+}
+
+impl std::panic::RefUnwindSafe for RawStream {}
+
+#[rustler::resource_impl]
+impl rustler::Resource for RawStream {}
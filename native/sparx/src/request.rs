@@ -1,78 +1,496 @@
-use bytes::Bytes;
-use hyper::http::{HeaderMap, Method, Uri, Version};
+use crate::atoms;
+use crate::budget::Reservation;
+use crate::channel;
+use crate::disconnect::Disconnect;
+use crate::errors::ErrorKind;
+use crate::events::Event;
+use crate::faults::Faults;
+use crate::headers::{self, HeaderList};
+use crate::interim::Interim;
+use crate::listener::Listener;
+use crate::multipart::Multipart;
+use crate::pool::Shared;
+use crate::proxy_protocol::Peer;
+use crate::response::NifResult;
+use crate::server::ServerContext;
+use crate::timing::{Phase, RequestTimings};
+use crate::trace_context::TraceContext;
+use bytes::{Bytes, BytesMut};
+use futures::FutureExt;
+use http_body_util::BodyExt;
+use hyper::http::{HeaderMap, Method, StatusCode, Uri, Version};
 use hyper::upgrade::OnUpgrade;
-use rustler::NifStruct;
-use tokio::sync::{mpsc, Mutex};
+use rustler::env::SavedTerm;
+use rustler::{Atom, Decoder, Encoder, Env, NifStruct, NifUnitEnum, OwnedEnv, Term};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Request body as handed over by hyper (or an empty body for upgrades)
+pub type RequestBody = http_body_util::combinators::BoxBody<Bytes, BoxError>;
 
 /// Request metadata sent to Elixir
 #[derive(NifStruct, Clone)]
 #[module = "Sparx.Request.Metadata"]
 pub struct RequestMetadata {
-    pub method: String,
+    pub method: HttpMethod,
+    pub scheme: Scheme,
+    /// Authority of an absolute-form target, else the `Host` header
+    pub host: Option<String>,
     pub path: String,
     pub query: Option<String>,
-    pub version: String,
-    pub headers: Vec<(String, String)>,
+    pub target: TargetForm,
+    pub version: HttpVersion,
+    pub headers: HeaderList,
+    /// Caller's W3C trace context, if it sent a valid `traceparent`
+    pub trace_context: Option<TraceContext>,
+    /// Name of the listener the request arrived on
+    pub listener: Atom,
+    /// Client the request came from: the TCP peer, or the client relayed
+    /// by a PROXY protocol header (None for Unix sockets and test connections)
+    pub peer: Option<Peer>,
+}
+
+/// Scheme of the connection a request arrived on
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+/// Form of the request-target (RFC 9112 section 3.2)
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetForm {
+    /// `/path?query`, as sent to origin servers
+    Origin,
+    /// `http://host/path`, as sent to proxies
+    Absolute,
+    /// `host:port`, for `CONNECT`
+    Authority,
+    /// `*`, for server-wide `OPTIONS`
+    Asterisk,
+}
+
+impl TargetForm {
+    /// HTTP/2 requests always carry a scheme and authority, so they count as
+    /// origin-form unless they are `CONNECT` or `OPTIONS *`
+    fn of(method: &Method, uri: &Uri, version: Version) -> Self {
+        if *method == Method::CONNECT {
+            TargetForm::Authority
+        } else if uri.path() == "*" {
+            TargetForm::Asterisk
+        } else if uri.scheme().is_some() && version < Version::HTTP_2 {
+            TargetForm::Absolute
+        } else {
+            TargetForm::Origin
+        }
+    }
+}
+
+/// Request method, as a lowercase atom (`:get`) for the standard methods
+/// and `{:other, "PURGE"}` for extensions
+#[derive(Clone)]
+pub struct HttpMethod(pub Method);
+
+impl HttpMethod {
+    fn atom(&self) -> Option<Atom> {
+        let atom = match self.0 {
+            Method::GET => atoms::get(),
+            Method::POST => atoms::post(),
+            Method::PUT => atoms::put(),
+            Method::PATCH => atoms::patch(),
+            Method::DELETE => atoms::delete(),
+            Method::HEAD => atoms::head(),
+            Method::OPTIONS => atoms::options(),
+            Method::CONNECT => atoms::connect(),
+            Method::TRACE => atoms::trace(),
+            _ => return None,
+        };
+        Some(atom)
+    }
+}
+
+impl Encoder for HttpMethod {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self.atom() {
+            Some(atom) => atom.encode(env),
+            None => (atoms::other(), self.0.as_str()).encode(env),
+        }
+    }
+}
+
+impl<'a> Decoder<'a> for HttpMethod {
+    fn decode(term: Term<'a>) -> rustler::NifResult<Self> {
+        let name = match term.atom_to_string() {
+            Ok(name) => name.to_uppercase(),
+            Err(_) => term.decode::<(Atom, String)>()?.1,
+        };
+        Method::from_bytes(name.as_bytes())
+            .map(HttpMethod)
+            .map_err(|_| rustler::Error::BadArg)
+    }
+}
+
+/// HTTP version, as an atom such as `:http_1_1` or `:http_2`
+#[derive(Clone, Copy)]
+pub struct HttpVersion(pub Version);
+
+impl Encoder for HttpVersion {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let atom = match self.0 {
+            Version::HTTP_09 => atoms::http_0_9(),
+            Version::HTTP_10 => atoms::http_1_0(),
+            Version::HTTP_2 => atoms::http_2(),
+            Version::HTTP_3 => atoms::http_3(),
+            _ => atoms::http_1_1(),
+        };
+        atom.encode(env)
+    }
+}
+
+impl<'a> Decoder<'a> for HttpVersion {
+    fn decode(term: Term<'a>) -> rustler::NifResult<Self> {
+        let atom: Atom = term.decode()?;
+        let version = if atom == atoms::http_0_9() {
+            Version::HTTP_09
+        } else if atom == atoms::http_1_0() {
+            Version::HTTP_10
+        } else if atom == atoms::http_1_1() {
+            Version::HTTP_11
+        } else if atom == atoms::http_2() {
+            Version::HTTP_2
+        } else if atom == atoms::http_3() {
+            Version::HTTP_3
+        } else {
+            return Err(rustler::Error::BadArg);
+        };
+        Ok(HttpVersion(version))
+    }
 }
 
 /// Handle to an HTTP request
 /// This resource holds the state needed for streaming request body
 /// and sending the response
 pub struct RequestHandle {
-    #[allow(dead_code)]
     pub metadata: RequestMetadata,
-    /// Receiver for request body chunks
-    pub body_rx: Mutex<Option<mpsc::Receiver<Result<Bytes, String>>>>,
+    /// Metadata encoded once, on first access, and copied out from there
+    metadata_term: std::sync::Mutex<Option<(OwnedEnv, SavedTerm)>>,
+    /// Request body, polled directly by `read_chunk`
+    pub body: Mutex<Option<RequestBody>>,
     /// Sender for response parts
     pub response_tx: Mutex<Option<ResponseSender>>,
     /// Optional upgrade future for WebSocket upgrades
     pub upgrade: Mutex<Option<OnUpgrade>>,
+    /// Server the request arrived on
+    pub context: Arc<ServerContext>,
+    /// Per-phase timestamps, shared with the connection task
+    pub timings: Shared<RequestTimings>,
+    /// Fired by the connection task if the client goes away early
+    pub disconnect: Shared<Disconnect>,
+    /// Where 1xx responses go (HTTP/1.1 requests only)
+    interim: Option<Arc<Interim>>,
+    /// Trailer fields of the request body, set once the body has ended
+    trailers: std::sync::Mutex<Option<HeaderList>>,
+    /// Parser state once the body is read as multipart parts
+    pub multipart: Mutex<Option<Multipart>>,
+    /// Pool shard the header list was taken from
+    pool_shard: usize,
 }
 
 /// Types of response messages
 pub enum ResponseMessage {
     Status(u16),
     Header(String, String),
-    BodyChunk(Bytes),
+    /// Several headers at once, as sent by `send_headers`
+    Headers(Vec<(String, String)>),
+    /// A header added only if the handler has not sent one of that name,
+    /// such as the content type guessed for a file
+    DefaultHeader(String, String),
+    /// A trailer field, sent after the body
+    Trailer(String, String),
+    /// A body chunk and its hold on the server's memory budget
+    BodyChunk(Bytes, Reservation),
     Finish,
+    /// Status, headers, and the whole body at once, as sent by `send_response`
+    Complete(u16, Vec<(String, String)>, Bytes, Reservation),
 }
 
-pub type ResponseSender = mpsc::Sender<ResponseMessage>;
+pub type ResponseSender = channel::Sender;
 
 impl RequestHandle {
     pub fn new(
         metadata: RequestMetadata,
-        body_rx: mpsc::Receiver<Result<Bytes, String>>,
+        body: RequestBody,
         response_tx: ResponseSender,
         upgrade: Option<OnUpgrade>,
+        context: Arc<ServerContext>,
+        timings: Shared<RequestTimings>,
     ) -> Self {
+        let pool_shard = context.pools.headers.local_shard();
+        let disconnect = context.pools.take_disconnect();
         Self {
             metadata,
-            body_rx: Mutex::new(Some(body_rx)),
+            metadata_term: std::sync::Mutex::new(None),
+            body: Mutex::new(Some(body)),
             response_tx: Mutex::new(Some(response_tx)),
             upgrade: Mutex::new(upgrade),
+            context,
+            timings,
+            disconnect,
+            trailers: std::sync::Mutex::new(None),
+            multipart: Mutex::new(None),
+            interim: None,
+            pool_shard,
         }
     }
 
+    /// Allow 1xx responses, written through `interim`
+    pub fn with_interim(mut self, interim: Option<Arc<Interim>>) -> Self {
+        self.interim = interim;
+        self
+    }
+
+    /// Get the request metadata as an Elixir term
+    ///
+    /// The struct is encoded into a process-independent env the first time it
+    /// is requested; later calls only copy the finished term into `env`
+    /// instead of re-encoding every header.
+    pub fn metadata_term<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut guard = match self.metadata_term.lock() {
+            Ok(guard) => guard,
+            Err(_) => return self.metadata.encode(env),
+        };
+
+        let (owned_env, saved) = guard.get_or_insert_with(|| {
+            let owned_env = OwnedEnv::new();
+            let saved = owned_env.run(|owned| owned_env.save(self.metadata.encode(owned)));
+            (owned_env, saved)
+        });
+
+        owned_env.run(|owned| saved.load(owned).in_env(env))
+    }
+
     /// Read a chunk from the request body
-    pub async fn read_body_chunk(&self) -> Result<Option<Bytes>, String> {
-        let mut body_rx_guard = self.body_rx.lock().await;
-        if let Some(ref mut rx) = *body_rx_guard {
-            match rx.recv().await {
-                Some(Ok(chunk)) => {
-                    if chunk.is_empty() {
-                        // Empty chunk signals EOF
-                        Ok(None)
-                    } else {
-                        Ok(Some(chunk))
-                    }
+    ///
+    /// The body is polled lazily on the caller's task, so nothing is read from
+    /// the socket until Elixir asks for it. When `min_chunk_size` is set, small
+    /// frames that are already buffered are merged into one chunk so chatty
+    /// clients don't cost one NIF call per frame. A body going over
+    /// `max_body_size` fails with `BodyTooLarge` and is answered with a 413.
+    pub async fn read_body_chunk(&self) -> Result<Option<Bytes>, ErrorKind> {
+        let result = self.next_body_chunk().await;
+        if matches!(result, Err(ErrorKind::BodyTooLarge)) {
+            self.reject_body().await;
+        }
+        result
+    }
+
+    async fn next_body_chunk(&self) -> Result<Option<Bytes>, ErrorKind> {
+        // Over the memory budget: leave the bytes in the socket until
+        // buffered responses have drained
+        self.context.budget.wait_for_room().await;
+
+        let mut body_guard = self.body.lock().await;
+        let body = body_guard.as_mut().ok_or(ErrorKind::Closed)?;
+
+        let first = match next_data(body, &self.trailers)
+            .await
+            .map_err(|kind| self.fail(kind))?
+        {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+
+        if self.context.faults.as_ref().is_some_and(Faults::reset_body) {
+            body_guard.take();
+            self.timings.error(ErrorKind::ConnectionReset);
+            return Err(ErrorKind::ConnectionReset);
+        }
+        self.timings.event(Event::FirstChunkRead);
+
+        let min_chunk_size = self.context.config.min_chunk_size;
+        if first.len() >= min_chunk_size {
+            return Ok(Some(first));
+        }
+
+        let mut merged = BytesMut::from(&first[..]);
+        while merged.len() < min_chunk_size {
+            // Only merge frames hyper already has; never wait for more data
+            match next_data(body, &self.trailers).now_or_never() {
+                Some(Ok(Some(chunk))) => merged.extend_from_slice(&chunk),
+                Some(Ok(None)) | None => break,
+                Some(Err(kind)) => return Err(self.fail(kind)),
+            }
+        }
+
+        Ok(Some(merged.freeze()))
+    }
+
+    /// Drop an oversized body and answer with a 413
+    ///
+    /// The 413 is only sent if the handler has not started its response;
+    /// the response sender is taken along with it, so the handler's own
+    /// response calls fail from then on.
+    async fn reject_body(&self) {
+        self.body.lock().await.take();
+
+        let mut guard = self.response_tx.lock().await;
+        if !guard.as_ref().is_some_and(|tx| self.response_untouched(tx)) {
+            return;
+        }
+        let Some(tx) = guard.take() else {
+            return;
+        };
+
+        warn!(
+            "Rejecting request body to {} over {} bytes",
+            self.metadata.path,
+            self.context.config.max_body_size.unwrap_or(0)
+        );
+        let messages = [
+            ResponseMessage::Status(413),
+            ResponseMessage::Header("content-type".to_string(), "text/plain".to_string()),
+            ResponseMessage::BodyChunk(
+                Bytes::from_static(b"Payload Too Large"),
+                Reservation::default(),
+            ),
+            ResponseMessage::Finish,
+        ];
+        for message in messages {
+            if tx.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Whether the handler has yet to send any part of its final response:
+    /// nothing received by the connection, and nothing queued
+    fn response_untouched(&self, tx: &ResponseSender) -> bool {
+        tx.is_empty() && self.timings.get(Phase::FirstByte).is_none()
+    }
+
+    /// Write a 1xx response ahead of the final one
+    pub async fn send_informational(
+        &self,
+        status: u16,
+        headers: Vec<(String, String)>,
+    ) -> NifResult {
+        let status = match StatusCode::from_u16(status) {
+            // 101 is reserved for protocol upgrades
+            Ok(status)
+                if status.is_informational() && status != StatusCode::SWITCHING_PROTOCOLS =>
+            {
+                status
+            }
+            _ => return NifResult::Error(format!("Invalid informational status: {}", status)),
+        };
+        let headers = match headers
+            .iter()
+            .map(|(name, value)| headers::parse(name, value))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(headers) => headers,
+            Err(e) => return NifResult::Error(e),
+        };
+        let Some(interim) = &self.interim else {
+            return NifResult::Reason(atoms::not_supported());
+        };
+
+        // Held so no part of the final response can be sent meanwhile
+        let guard = self.response_tx.lock().await;
+        if !guard.as_ref().is_some_and(|tx| self.response_untouched(tx)) {
+            return NifResult::Reason(atoms::already_sent());
+        }
+        interim.send(status, &headers);
+        NifResult::Ok
+    }
+
+    /// Count a body error and log it against the request
+    fn fail(&self, kind: ErrorKind) -> ErrorKind {
+        self.timings.error(kind);
+        self.context.errors.record(kind)
+    }
+
+    /// Read up to `max_chunks` body chunks in one go
+    ///
+    /// Waits for the first chunk, then only takes chunks that are already
+    /// available, stopping once `max_bytes` have been collected. An empty
+    /// list means the body is fully consumed.
+    pub async fn read_body_chunks(
+        &self,
+        max_chunks: usize,
+        max_bytes: usize,
+    ) -> Result<Vec<Bytes>, ErrorKind> {
+        let mut chunks = Vec::new();
+        let mut total = match self.read_body_chunk().await? {
+            Some(chunk) => {
+                let len = chunk.len();
+                chunks.push(chunk);
+                len
+            }
+            None => return Ok(chunks),
+        };
+
+        while chunks.len() < max_chunks && total < max_bytes {
+            match self.read_body_chunk().now_or_never() {
+                Some(Ok(Some(chunk))) => {
+                    total += chunk.len();
+                    chunks.push(chunk);
                 }
-                Some(Err(e)) => Err(e),
-                None => Ok(None), // Channel closed = EOF
+                Some(Ok(None)) | None => break,
+                Some(Err(e)) => return Err(e),
             }
-        } else {
-            Err("Body stream already consumed".to_string())
         }
+
+        Ok(chunks)
+    }
+
+    /// Read the rest of the body into a single buffer
+    ///
+    /// Returns `None` as soon as the body is known to be longer than
+    /// `max_bytes`, from its `content-length` or from the bytes read so far;
+    /// the rest of it is left unread. A body that arrives in one chunk is
+    /// returned without copying.
+    pub async fn read_full_body(&self, max_bytes: usize) -> Result<Option<Bytes>, ErrorKind> {
+        let declared = self
+            .metadata
+            .headers
+            .get("content-length")
+            .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+        if declared.is_some_and(|len| len > max_bytes) {
+            return Ok(None);
+        }
+
+        let Some(first) = self.read_body_chunk().await? else {
+            return Ok(Some(Bytes::new()));
+        };
+        if first.len() > max_bytes {
+            return Ok(None);
+        }
+        let Some(second) = self.read_body_chunk().await? else {
+            return Ok(Some(first));
+        };
+
+        let mut body =
+            BytesMut::with_capacity(declared.unwrap_or(0).max(first.len() + second.len()));
+        let mut next = Some(second);
+        body.extend_from_slice(&first);
+        while let Some(chunk) = next {
+            if body.len() + chunk.len() > max_bytes {
+                return Ok(None);
+            }
+            body.extend_from_slice(&chunk);
+            next = self.read_body_chunk().await?;
+        }
+        Ok(Some(body.freeze()))
+    }
+
+    /// Trailer fields of the request body, or `None` until it has been read
+    /// to the end (an empty list if the client sent none)
+    pub fn trailers(&self) -> Option<HeaderList> {
+        self.trailers.lock().ok()?.clone()
     }
 
     /// Get a clone of the response sender (for sending multiple messages)
@@ -81,6 +499,19 @@ impl RequestHandle {
         guard.as_ref().cloned()
     }
 
+    /// Deliver the rest of the response to a new channel instead of the socket
+    ///
+    /// The connection's own channel is dropped, so the client receives an
+    /// empty response. Returns `None` once any part of the response has been
+    /// sent, since a capture could not hold the whole of it.
+    pub async fn redirect_response(&self) -> Option<channel::Receiver> {
+        let mut guard = self.response_tx.lock().await;
+        let sender = guard.as_mut().filter(|tx| self.response_untouched(tx))?;
+        let (tx, rx) = self.context.pools.take_channel();
+        *sender = tx;
+        Some(rx)
+    }
+
     /// Take the upgrade future (can only be done once)
     pub async fn take_upgrade(&self) -> Option<OnUpgrade> {
         let mut guard = self.upgrade.lock().await;
@@ -88,49 +519,92 @@ impl RequestHandle {
     }
 }
 
-/// Helper to convert hyper::Version to string
-pub fn version_to_string(version: Version) -> String {
-    match version {
-        Version::HTTP_09 => "HTTP/0.9".to_string(),
-        Version::HTTP_10 => "HTTP/1.0".to_string(),
-        Version::HTTP_11 => "HTTP/1.1".to_string(),
-        Version::HTTP_2 => "HTTP/2.0".to_string(),
-        Version::HTTP_3 => "HTTP/3.0".to_string(),
-        _ => "HTTP/1.1".to_string(),
+/// Wait for the next non-empty data frame of a request body
+///
+/// Trailer frames are collected into `trailers`, which is filled in (empty
+/// if the client sent none) once the body ends.
+async fn next_data(
+    body: &mut RequestBody,
+    trailers: &std::sync::Mutex<Option<HeaderList>>,
+) -> Result<Option<Bytes>, ErrorKind> {
+    loop {
+        match body.frame().await {
+            Some(Ok(frame)) => match frame.into_data() {
+                // Empty chunks would look like EOF to Elixir, skip them
+                Ok(chunk) if chunk.is_empty() => {}
+                Ok(chunk) => return Ok(Some(chunk)),
+                Err(frame) => {
+                    if let (Ok(fields), Ok(mut trailers)) = (frame.into_trailers(), trailers.lock())
+                    {
+                        trailers
+                            .get_or_insert_with(HeaderList::default)
+                            .extend_from_map(&fields);
+                    }
+                }
+            },
+            Some(Err(e)) => return Err(ErrorKind::of(&*e)),
+            None => {
+                if let Ok(mut trailers) = trailers.lock() {
+                    trailers.get_or_insert_with(HeaderList::default);
+                }
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl Drop for RequestHandle {
+    fn drop(&mut self) {
+        // Hand the header list back so the next request can reuse its capacity.
+        // This usually runs on a BEAM scheduler, so name the shard explicitly.
+        let headers = std::mem::take(&mut self.metadata.headers);
+        self.context.pools.headers.give_to(self.pool_shard, headers);
     }
 }
 
 /// Helper to extract request metadata from hyper request parts
+///
+/// `header_list` is a (possibly pooled) buffer the headers are collected into.
 pub fn extract_metadata(
     method: &Method,
     uri: &Uri,
     version: Version,
     headers: &HeaderMap,
+    listener: Listener,
+    peer: Option<Peer>,
+    mut header_list: HeaderList,
 ) -> RequestMetadata {
     let path = uri.path().to_string();
     let query = uri.query().map(|q| q.to_string());
+    let host = uri
+        .authority()
+        .map(|authority| authority.to_string())
+        .or_else(|| {
+            headers
+                .get(hyper::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(|host| host.to_string())
+        });
 
-    let headers_vec: Vec<(String, String)> = headers
-        .iter()
-        .map(|(name, value)| {
-            let name_str = name.as_str().to_string();
-            let value_str = value.to_str().unwrap_or("").to_string();
-            (name_str, value_str)
-        })
-        .collect();
+    header_list.extend_from_map(headers);
 
     RequestMetadata {
-        method: method.as_str().to_string(),
+        method: HttpMethod(method.clone()),
+        scheme: listener.scheme,
+        host,
         path,
         query,
-        version: version_to_string(version),
-        headers: headers_vec,
+        target: TargetForm::of(method, uri, version),
+        version: HttpVersion(version),
+        headers: header_list,
+        trace_context: TraceContext::from_headers(headers),
+        listener: listener.name,
+        peer,
     }
 }
 
-// Implement required traits for Rustler Resource
-unsafe impl Send for RequestHandle {}
-unsafe impl Sync for RequestHandle {}
+// `Resource` requires `Send + Sync`; every field is immutable, atomic, or
+// behind a lock, so both are derived rather than asserted
 impl std::panic::RefUnwindSafe for RequestHandle {}
 
 #[rustler::resource_impl]
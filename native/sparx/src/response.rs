@@ -1,18 +1,72 @@
-use bytes::Bytes;
-use futures::stream;
+use crate::budget::Reservation;
+use crate::channel::{self, TryRecvError};
+use crate::headers::{self, HeaderList};
+use crate::pool::Shared;
+use crate::request::ResponseMessage;
+use crate::server::ServerContext;
+use crate::timing::{Phase, RequestTimings};
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
 use http_body_util::{BodyExt, StreamBody};
-use hyper::body::Frame;
-use hyper::{Response, StatusCode};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, TRAILER};
+use hyper::{HeaderMap, Method, Response, StatusCode};
 use rustler::{Encoder, Env, Term};
-use std::convert::Infallible;
-use tokio::sync::mpsc;
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
-type BoxBody = http_body_util::combinators::BoxBody<Bytes, Infallible>;
+/// Read size used when streaming a spilled response back out
+const SPILL_READ_SIZE: usize = 64 * 1024;
+
+/// Buffered bodies up to this size are merged into a single chunk
+///
+/// A body with one known-length chunk lets hyper send it with a
+/// `content-length` and queue it behind the status line and headers, so a
+/// small response leaves in one vectored write instead of a head write
+/// followed by chunked-encoded frames.
+const SINGLE_WRITE_LIMIT: usize = 64 * 1024;
+
+/// Counter making spill file names unique within the process
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
+
+/// Why a response body ended before it was complete
+///
+/// Returning it from a body makes hyper reset the stream, or close an
+/// HTTP/1 connection without the final chunk, so the client can tell a
+/// cut-off response from a whole one.
+#[derive(Debug)]
+pub enum BodyError {
+    /// The handler went away without finishing the response
+    Abandoned,
+    /// Reading or encoding the body failed
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyError::Abandoned => write!(f, "handler exited before finishing the response"),
+            BodyError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
 
 /// Custom result type for NIF functions that properly encodes to Elixir
 pub enum NifResult {
     Ok,
     Error(String),
+    /// `{:error, reason}` with an atom reason
+    Reason(rustler::Atom),
 }
 
 impl Encoder for NifResult {
@@ -20,6 +74,7 @@ impl Encoder for NifResult {
         match self {
             NifResult::Ok => crate::atoms::ok().encode(env),
             NifResult::Error(msg) => (crate::atoms::error(), msg.as_str()).encode(env),
+            NifResult::Reason(reason) => (crate::atoms::error(), *reason).encode(env),
         }
     }
 }
@@ -32,53 +87,251 @@ pub fn u16_to_status(code: u16) -> StatusCode {
 /// Build a hyper Response from a stream of response messages
 pub struct ResponseBuilder {
     pub status: Option<StatusCode>,
-    pub headers: Vec<(String, String)>,
+    pub headers: HeaderList,
     pub body_chunks: Vec<Bytes>,
+    /// First header Elixir sent that could not be parsed
+    pub invalid_header: Option<String>,
+    /// Bytes held in `body_chunks`
+    buffered_bytes: usize,
+    /// Bytes of body added, in memory or spilled
+    body_len: u64,
+    /// Bytes kept in memory before the rest of the body goes to a temp file
+    spill_threshold: usize,
+    /// Temp file holding the body past `spill_threshold`
+    spill: Option<File>,
+    /// Memory budget held by the chunks in `body_chunks`
+    reservations: Vec<Reservation>,
+    /// Trailer fields sent after the body
+    trailers: HeaderMap,
+    /// Whether the response answers a HEAD request
+    head: bool,
 }
 
 impl ResponseBuilder {
     pub fn new() -> Self {
+        Self::with_headers(HeaderList::default())
+    }
+
+    /// Create a builder that collects headers into an existing (pooled) buffer
+    pub fn with_headers(headers: HeaderList) -> Self {
         Self {
             status: None,
-            headers: Vec::new(),
+            headers,
             body_chunks: Vec::new(),
+            invalid_header: None,
+            buffered_bytes: 0,
+            body_len: 0,
+            spill_threshold: usize::MAX,
+            spill: None,
+            reservations: Vec::new(),
+            trailers: HeaderMap::new(),
+            head: false,
         }
     }
 
+    /// Spill body bytes beyond `threshold` to a temp file instead of memory
+    pub fn spill_after(mut self, threshold: usize) -> Self {
+        self.spill_threshold = threshold;
+        self
+    }
+
+    /// Mark the response as answering a HEAD request
+    ///
+    /// A HEAD response keeps the `content-length` of the GET body it stands
+    /// in for, even though it carries no body itself.
+    pub fn for_head(mut self, head: bool) -> Self {
+        self.head = head;
+        self
+    }
+
     pub fn set_status(&mut self, status: u16) {
         self.status = Some(u16_to_status(status));
     }
 
     pub fn add_header(&mut self, name: String, value: String) {
-        self.headers.push((name, value));
+        if let Err(e) = self.headers.push_str(&name, &value) {
+            self.invalid_header.get_or_insert(e);
+        }
     }
 
-    pub fn add_body_chunk(&mut self, chunk: Bytes) {
-        self.body_chunks.push(chunk);
+    pub fn add_trailer(&mut self, name: String, value: String) {
+        match headers::parse(&name, &value) {
+            Ok((name, value)) => {
+                self.trailers.append(name, value);
+            }
+            Err(e) => {
+                self.invalid_header.get_or_insert(e);
+            }
+        }
+    }
+
+    /// Add a header unless one of that name was already sent
+    pub fn add_default_header(&mut self, name: String, value: String) {
+        if self.headers.get(&name).is_none() {
+            self.add_header(name, value);
+        }
+    }
+
+    /// Add headers in order; the first invalid one fails the response
+    pub fn add_headers(&mut self, headers: Vec<(String, String)>) {
+        for (name, value) in headers {
+            self.add_header(name, value);
+        }
     }
 
-    pub fn build(self) -> Result<Response<BoxBody>, String> {
-        let status = self.status.unwrap_or(StatusCode::OK);
+    /// Buffer a body chunk, keeping its reservation until the body is sent
+    ///
+    /// Chunks that go to the spill file release their reservation right away,
+    /// since they no longer take up memory.
+    pub async fn add_body_chunk(
+        &mut self,
+        chunk: Bytes,
+        reservation: Reservation,
+    ) -> Result<(), String> {
+        self.body_len += chunk.len() as u64;
+        if self.spill.is_none() && self.buffered_bytes + chunk.len() <= self.spill_threshold {
+            self.buffered_bytes += chunk.len();
+            self.body_chunks.push(chunk);
+            self.reservations.push(reservation);
+            return Ok(());
+        }
+        drop(reservation);
 
-        let mut response_builder = Response::builder().status(status);
+        if self.spill.is_none() {
+            self.spill = Some(create_spill_file().await?);
+        }
+        if let Some(file) = self.spill.as_mut() {
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| format!("Failed to spill response chunk: {}", e))?;
+        }
+        Ok(())
+    }
 
-        // Add headers
-        for (name, value) in self.headers {
+    /// `content-length` set by the handler, if any
+    fn declared_length(&self) -> Option<u64> {
+        self.headers
+            .get(CONTENT_LENGTH.as_str())?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Status line and headers, handing the header buffer back to its pool
+    fn head(&mut self, context: &ServerContext) -> Result<hyper::http::response::Builder, String> {
+        let mut headers = std::mem::take(&mut self.headers);
+        if let Some(e) = self.invalid_header.take() {
+            context.pools.headers.give(headers);
+            return Err(e);
+        }
+
+        // HTTP/1.1 only sends trailer fields declared up front
+        let declare_trailers = !self.trailers.is_empty() && headers.get(TRAILER.as_str()).is_none();
+        let mut response_builder =
+            Response::builder().status(self.status.unwrap_or(StatusCode::OK));
+        for (name, value) in headers.drain() {
             response_builder = response_builder.header(name, value);
         }
+        context.pools.headers.give(headers);
+        if declare_trailers {
+            let names = self
+                .trailers
+                .keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if let Ok(value) = HeaderValue::from_str(&names) {
+                response_builder = response_builder.header(TRAILER, value);
+            }
+        }
+        Ok(response_builder)
+    }
+
+    /// Build a response whose whole body has been buffered
+    ///
+    /// The body's length is known, so it is always sent with a
+    /// `content-length` (unless trailers require chunked encoding).
+    pub async fn build(mut self, context: &ServerContext) -> Result<Response<BoxBody>, String> {
+        // Only a 304 or a HEAD response may declare the length of a body it
+        // does not carry
+        if let Some(declared) = self.declared_length() {
+            let bodiless = self.head || self.status == Some(StatusCode::NOT_MODIFIED);
+            if declared != self.body_len && !(bodiless && self.body_len == 0) {
+                tracing::warn!(
+                    "Replacing content-length {} with the {} bytes written",
+                    declared,
+                    self.body_len
+                );
+                self.headers.remove(CONTENT_LENGTH.as_str());
+            }
+        }
+        let body_len = self.body_len;
+        let response_builder = self.head(context)?;
 
-        // Create body from chunks
-        let body = if self.body_chunks.is_empty() {
-            http_body_util::Empty::<Bytes>::new()
+        // Create body from chunks, followed by whatever was spilled to disk
+        let body = match (self.body_chunks.is_empty(), self.spill) {
+            (true, None) => http_body_util::Empty::<Bytes>::new()
                 .map_err(|never| match never {})
-                .boxed()
+                .boxed(),
+            (false, None) if self.body_chunks.len() == 1 => {
+                let chunk = self.body_chunks.into_iter().next().unwrap_or_default();
+                http_body_util::Full::new(chunk)
+                    .map_err(|never| match never {})
+                    .boxed()
+            }
+            (false, None) if self.buffered_bytes <= SINGLE_WRITE_LIMIT => {
+                let mut merged = BytesMut::with_capacity(self.buffered_bytes);
+                for chunk in &self.body_chunks {
+                    merged.extend_from_slice(chunk);
+                }
+                http_body_util::Full::new(merged.freeze())
+                    .map_err(|never| match never {})
+                    .boxed()
+            }
+            (_, None) => {
+                let stream = stream::iter(
+                    self.body_chunks
+                        .into_iter()
+                        .map(|chunk| Ok::<_, BodyError>(Frame::data(chunk))),
+                );
+                SizedBody::new(StreamBody::new(stream).boxed(), body_len).boxed()
+            }
+            (_, Some(mut file)) => {
+                file.flush()
+                    .await
+                    .map_err(|e| format!("Failed to flush spill file: {}", e))?;
+                file.seek(SeekFrom::Start(0))
+                    .await
+                    .map_err(|e| format!("Failed to rewind spill file: {}", e))?;
+                let memory = stream::iter(
+                    self.body_chunks
+                        .into_iter()
+                        .map(|chunk| Ok::<_, BodyError>(Frame::data(chunk))),
+                );
+                let body = StreamBody::new(memory.chain(spill_stream(file))).boxed();
+                SizedBody::new(body, body_len).boxed()
+            }
+        };
+
+        let body = if self.trailers.is_empty() {
+            body
         } else {
-            let stream = stream::iter(
-                self.body_chunks
-                    .into_iter()
-                    .map(|chunk| Ok::<_, Infallible>(Frame::data(chunk))),
-            );
-            StreamBody::new(stream).boxed()
+            TrailersBody {
+                inner: body,
+                trailers: Some(self.trailers),
+            }
+            .boxed()
+        };
+
+        let body = if self.reservations.is_empty() {
+            body
+        } else {
+            BudgetedBody {
+                inner: body,
+                _reservations: self.reservations,
+            }
+            .boxed()
         };
 
         response_builder
@@ -87,21 +340,351 @@ impl ResponseBuilder {
     }
 }
 
+/// Response body fed by the handler's chunks as they arrive
+///
+/// The channel is only polled when hyper asks for the next frame, so a
+/// client that reads slowly leaves messages in the channel and eventually
+/// blocks `write_chunk` in Elixir.
+struct ChannelBody {
+    /// Chunks that arrived together with the first one
+    pending: VecDeque<(Bytes, Reservation)>,
+    /// `None` once the handler has finished or gone away
+    rx: Option<channel::Receiver>,
+    /// Set when the handler went away before finishing; the body ends with
+    /// an error once `pending` is sent
+    abandoned: bool,
+    /// Trailer fields sent so far, written after the last chunk
+    trailers: HeaderMap,
+    /// Bytes left of the `content-length` the handler declared
+    remaining: Option<u64>,
+    context: Arc<ServerContext>,
+    timings: Shared<RequestTimings>,
+}
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        let this = &mut *self;
+        // The reservation is released once hyper has the chunk
+        if let Some((chunk, _reservation)) = this.pending.pop_front() {
+            return Poll::Ready(Some(Ok(Frame::data(this.limit(chunk)))));
+        }
+        let Some(rx) = this.rx.as_mut() else {
+            if std::mem::take(&mut this.abandoned) {
+                return Poll::Ready(Some(Err(BodyError::Abandoned)));
+            }
+            return Poll::Ready(this.take_trailers());
+        };
+
+        loop {
+            match ready!(rx.poll_recv(cx)) {
+                Some(ResponseMessage::BodyChunk(chunk, _reservation)) => {
+                    // An empty frame would end a chunked body early
+                    if !chunk.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(this.limit(chunk)))));
+                    }
+                }
+                Some(
+                    ResponseMessage::Status(_)
+                    | ResponseMessage::Header(..)
+                    | ResponseMessage::Headers(_)
+                    | ResponseMessage::DefaultHeader(..),
+                ) => {
+                    tracing::warn!(
+                        "Ignoring status or header sent after the response body started"
+                    );
+                }
+                Some(ResponseMessage::Trailer(name, value)) => {
+                    match headers::parse(&name, &value) {
+                        Ok((name, value)) => {
+                            this.trailers.append(name, value);
+                        }
+                        Err(e) => tracing::warn!("Ignoring trailer: {}", e),
+                    }
+                }
+                Some(ResponseMessage::Finish) => {
+                    finished(&this.context, &this.timings);
+                    this.rx = None;
+                    return Poll::Ready(this.take_trailers());
+                }
+                Some(ResponseMessage::Complete(_, _, chunk, _reservation)) => {
+                    tracing::warn!(
+                        "Ignoring status and headers of a response sent after the body started"
+                    );
+                    finished(&this.context, &this.timings);
+                    this.rx = None;
+                    if !chunk.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(this.limit(chunk)))));
+                    }
+                    return Poll::Ready(this.take_trailers());
+                }
+                None => {
+                    tracing::warn!("Handler exited before finishing its response");
+                    this.rx = None;
+                    return Poll::Ready(Some(Err(BodyError::Abandoned)));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.rx.is_none() && !self.abandoned && self.pending.is_empty() && self.trailers.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.remaining {
+            Some(remaining) => SizeHint::with_exact(remaining),
+            None => SizeHint::default(),
+        }
+    }
+}
+
+impl ChannelBody {
+    /// Count a chunk against the declared `content-length`, ending the body
+    /// at that length
+    fn limit(&mut self, mut chunk: Bytes) -> Bytes {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if chunk.len() as u64 > *remaining {
+                tracing::warn!("Dropping response bytes past the declared content-length");
+                chunk.truncate(*remaining as usize);
+                self.pending.clear();
+                self.abandoned = false;
+                if self.rx.take().is_some() {
+                    finished(&self.context, &self.timings);
+                }
+            }
+            *remaining -= chunk.len() as u64;
+        }
+        chunk
+    }
+
+    /// Trailers frame to end the body with, if any were sent
+    fn take_trailers(&mut self) -> Option<Result<Frame<Bytes>, BodyError>> {
+        if self.trailers.is_empty() {
+            return None;
+        }
+        Some(Ok(Frame::trailers(std::mem::take(&mut self.trailers))))
+    }
+}
+
+/// Buffered body of known length that is not a single chunk
+///
+/// Reporting the exact size lets hyper send a `content-length` instead of
+/// chunked encoding.
+struct SizedBody {
+    inner: BoxBody,
+    remaining: u64,
+}
+
+impl SizedBody {
+    fn new(inner: BoxBody, len: u64) -> Self {
+        Self {
+            inner,
+            remaining: len,
+        }
+    }
+}
+
+impl Body for SizedBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(data) = frame
+            .as_ref()
+            .and_then(|frame| frame.as_ref().ok()?.data_ref())
+        {
+            self.remaining = self.remaining.saturating_sub(data.len() as u64);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+/// Buffered body followed by trailer fields
+///
+/// Reports no exact size, so HTTP/1.1 responses are chunked, the only
+/// encoding that can carry trailers.
+struct TrailersBody {
+    inner: BoxBody,
+    trailers: Option<HeaderMap>,
+}
+
+impl Body for TrailersBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            Some(frame) => Poll::Ready(Some(frame)),
+            None => Poll::Ready(
+                self.trailers
+                    .take()
+                    .map(|trailers| Ok(Frame::trailers(trailers))),
+            ),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = SizeHint::new();
+        hint.set_lower(self.inner.size_hint().lower());
+        hint
+    }
+}
+
+/// Response body that holds its memory reservations until hyper drops it
+struct BudgetedBody {
+    inner: BoxBody,
+    _reservations: Vec<Reservation>,
+}
+
+impl Body for BudgetedBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Drop the body of a response to a HEAD request
+///
+/// The handler's body is drained in the background, so handlers can share
+/// code with GET without their writes failing or blocking. The empty body
+/// reports the original size, keeping the `content-length` a GET would get.
+pub fn strip_body(response: Response<BoxBody>) -> Response<BoxBody> {
+    let (parts, mut body) = response.into_parts();
+    let size_hint = body.size_hint();
+    if !body.is_end_stream() {
+        tokio::spawn(async move { while body.frame().await.is_some() {} });
+    }
+    Response::from_parts(parts, HeadBody { size_hint }.boxed())
+}
+
+/// Body of a HEAD response: the size of the GET body, and no data
+struct HeadBody {
+    size_hint: SizeHint,
+}
+
+impl Body for HeadBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        Poll::Ready(None)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.size_hint.clone()
+    }
+}
+
+/// Create an anonymous temp file for spilling a response body
+///
+/// The file is unlinked right after it is opened, so it disappears with the
+/// last handle even if the response is abandoned halfway.
+async fn create_spill_file() -> Result<File, String> {
+    let path = std::env::temp_dir().join(format!(
+        "sparx-spill-{}-{}",
+        std::process::id(),
+        SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await
+        .map_err(|e| format!("Failed to create spill file: {}", e))?;
+    let _ = tokio::fs::remove_file(&path).await;
+    Ok(file)
+}
+
+/// Stream the contents of a spill file as body frames
+fn spill_stream(
+    file: File,
+) -> impl futures::Stream<Item = Result<Frame<Bytes>, BodyError>> + Send + Sync {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = BytesMut::with_capacity(SPILL_READ_SIZE);
+        match file.read_buf(&mut buf).await {
+            Ok(0) => None,
+            Ok(_) => Some((Ok(Frame::data(buf.freeze())), Some(file))),
+            Err(e) => {
+                // The status line is already out, so cut the response off
+                tracing::error!("Failed to read spilled response: {}", e);
+                Some((Err(BodyError::Io(e)), None))
+            }
+        }
+    })
+}
+
 impl Default for ResponseBuilder {
     fn default() -> Self {
         Self::new()
     }
 }
 
-/// Spawn a task that receives response messages and builds a Response
+/// Build a Response from the handler's response messages
+///
+/// Status and headers are collected until the first body chunk. If the
+/// handler has already sent the rest of the response by then, it is
+/// buffered and sent with a `content-length`; otherwise the head is sent
+/// right away and the body streams to the client chunk by chunk. Fails if
+/// the handler goes away before it has sent any of the body.
 pub async fn build_response_from_channel(
-    mut rx: mpsc::Receiver<crate::request::ResponseMessage>,
+    mut rx: channel::Receiver,
+    method: &Method,
+    context: &Arc<ServerContext>,
+    timings: &Shared<RequestTimings>,
 ) -> Result<Response<BoxBody>, String> {
-    use crate::request::ResponseMessage;
+    let mut builder = ResponseBuilder::with_headers(context.pools.headers.take())
+        .spill_after(context.config.response_buffer_limit)
+        .for_head(method == Method::HEAD);
 
-    let mut builder = ResponseBuilder::new();
-
-    while let Some(msg) = rx.recv().await {
+    loop {
+        let Some(msg) = rx.recv().await else {
+            return Err("Handler exited without finishing the response".to_string());
+        };
+        timings.mark(Phase::FirstByte);
         match msg {
             ResponseMessage::Status(status) => {
                 builder.set_status(status);
@@ -109,14 +692,102 @@ pub async fn build_response_from_channel(
             ResponseMessage::Header(name, value) => {
                 builder.add_header(name, value);
             }
-            ResponseMessage::BodyChunk(chunk) => {
-                builder.add_body_chunk(chunk);
+            ResponseMessage::Headers(headers) => {
+                builder.add_headers(headers);
+            }
+            ResponseMessage::DefaultHeader(name, value) => {
+                builder.add_default_header(name, value);
+            }
+            ResponseMessage::Trailer(name, value) => {
+                builder.add_trailer(name, value);
+            }
+            ResponseMessage::BodyChunk(chunk, reservation) => {
+                let mut pending = VecDeque::new();
+                if !chunk.is_empty() {
+                    pending.push_back((chunk, reservation));
+                }
+                // Take whatever else the handler has already sent
+                loop {
+                    match rx.try_recv() {
+                        Ok(ResponseMessage::Status(status)) => builder.set_status(status),
+                        Ok(ResponseMessage::Header(name, value)) => builder.add_header(name, value),
+                        Ok(ResponseMessage::Headers(headers)) => builder.add_headers(headers),
+                        Ok(ResponseMessage::DefaultHeader(name, value)) => {
+                            builder.add_default_header(name, value)
+                        }
+                        Ok(ResponseMessage::Trailer(name, value)) => {
+                            builder.add_trailer(name, value)
+                        }
+                        Ok(ResponseMessage::BodyChunk(chunk, reservation)) => {
+                            if !chunk.is_empty() {
+                                pending.push_back((chunk, reservation));
+                            }
+                        }
+                        Ok(ResponseMessage::Finish) => {
+                            finished(context, timings);
+                            break;
+                        }
+                        Ok(ResponseMessage::Complete(status, headers, chunk, reservation)) => {
+                            builder.set_status(status);
+                            builder.add_headers(headers);
+                            if !chunk.is_empty() {
+                                pending.push_back((chunk, reservation));
+                            }
+                            finished(context, timings);
+                            break;
+                        }
+                        Err(error) => {
+                            // A handler that went away mid-response gets what
+                            // it sent so far, cut off the same way as when
+                            // streaming, instead of passing for a whole body
+                            let abandoned = matches!(error, TryRecvError::Disconnected);
+                            if abandoned {
+                                tracing::warn!("Handler exited before finishing its response");
+                            }
+                            // A declared length is sent as is instead of chunked
+                            let remaining = builder.declared_length();
+                            let response_builder = builder.head(context)?;
+                            let body = ChannelBody {
+                                pending,
+                                rx: (!abandoned).then_some(rx),
+                                abandoned,
+                                trailers: std::mem::take(&mut builder.trailers),
+                                remaining,
+                                context: context.clone(),
+                                timings: timings.clone(),
+                            };
+                            return response_builder
+                                .body(body.boxed())
+                                .map_err(|e| format!("Failed to build response: {}", e));
+                        }
+                    }
+                }
+                for (chunk, reservation) in pending {
+                    builder.add_body_chunk(chunk, reservation).await?;
+                }
+                return builder.build(context).await;
             }
             ResponseMessage::Finish => {
+                finished(context, timings);
+                break;
+            }
+            ResponseMessage::Complete(status, headers, chunk, reservation) => {
+                builder.set_status(status);
+                builder.add_headers(headers);
+                if !chunk.is_empty() {
+                    builder.add_body_chunk(chunk, reservation).await?;
+                }
+                finished(context, timings);
                 break;
             }
         }
     }
 
-    builder.build()
+    builder.build(context).await
+}
+
+/// Mark the handler's response finished and fold its timings into the totals
+fn finished(context: &ServerContext, timings: &RequestTimings) {
+    timings.mark(Phase::Finished);
+    context.timings.record(timings);
 }
@@ -0,0 +1,359 @@
+use crate::config::{RuntimeProfile, ServerConfig};
+use crate::numa::{Placement, Slot};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::oneshot;
+
+/// Scheduler tick interval used by the low-latency profile
+///
+/// Tokio polls the I/O driver every `event_interval` ticks (61 by default).
+/// Polling on almost every tick wakes connection tasks sooner at the cost of
+/// extra epoll syscalls when the runtime is busy.
+const LOW_LATENCY_EVENT_INTERVAL: u32 = 2;
+
+/// Global queue check interval used by the low-latency profile
+const LOW_LATENCY_GLOBAL_QUEUE_INTERVAL: u32 = 8;
+
+/// How long `warm_up` waits for the runtime's workers to check in
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Yields after a simulated clock advance, letting woken tasks settle
+const SETTLE_YIELDS: usize = 16;
+
+/// The runtime a server's accept loop and connections run on
+pub enum ServerRuntime {
+    /// The process-wide runtime shared with async NIFs
+    Shared,
+    /// A runtime owned by this server, built from its tuning options
+    Dedicated(DedicatedRuntime),
+    /// One current-thread runtime per core, each with its own accept loop
+    PerCore(Vec<CoreRuntime>),
+    /// A current-thread runtime whose clock only moves through `advance`
+    Simulation(CoreRuntime),
+}
+
+/// Owned runtime that can be dropped from any context
+///
+/// A `ServerHandle` may be released from inside a Tokio task, where dropping
+/// a runtime normally panics, so the runtime is shut down in the background.
+pub struct DedicatedRuntime(Option<Runtime>);
+
+impl Drop for DedicatedRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Current-thread runtime driven by its own OS thread
+///
+/// Everything spawned on it (the core's accept loop and the connections it
+/// accepts) stays on that thread, keeping a connection's state in one
+/// core's caches. The thread exits when the handle is dropped.
+pub struct CoreRuntime {
+    handle: Handle,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl CoreRuntime {
+    fn start(index: usize, slot: Option<Slot>) -> std::io::Result<Self> {
+        Self::start_thread(format!("sparx-core-{}", index), slot, false)
+    }
+
+    /// Start a thread of its own for one accept loop (`acceptor_threads`)
+    pub fn start_acceptor(index: usize) -> std::io::Result<Self> {
+        Self::start_thread(format!("sparx-acceptor-{}", index), None, false)
+    }
+
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.handle.spawn(future);
+    }
+
+    /// Start a runtime with a paused clock for deterministic tests
+    ///
+    /// Requires the `simulation` feature, which enables tokio's test clock.
+    fn start_simulated() -> std::io::Result<Self> {
+        if !cfg!(feature = "simulation") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the simulation profile requires the `simulation` feature",
+            ));
+        }
+        Self::start_thread("sparx-sim".to_string(), None, true)
+    }
+
+    fn start_thread(name: String, slot: Option<Slot>, paused: bool) -> std::io::Result<Self> {
+        let (handle_tx, handle_rx) = std::sync::mpsc::channel();
+        let (stop, stop_rx) = oneshot::channel::<()>();
+
+        std::thread::Builder::new().name(name).spawn(move || {
+            // Pin before building the runtime so its allocations are
+            // made from the core's node
+            if let Some(slot) = slot {
+                slot.apply();
+            }
+            let mut builder = Builder::new_current_thread();
+            builder.enable_all();
+            #[cfg(feature = "simulation")]
+            builder.start_paused(paused);
+            match builder.build() {
+                Ok(runtime) => {
+                    let _ = handle_tx.send(Ok(runtime.handle().clone()));
+                    runtime.block_on(async {
+                        if !paused {
+                            let _ = stop_rx.await;
+                            return;
+                        }
+                        // A paused clock jumps to the next timer whenever
+                        // the runtime is idle, except while a blocking task
+                        // runs. Holding one for the runtime's lifetime
+                        // leaves `advance` as the only way time moves.
+                        let (release, hold) = std::sync::mpsc::channel::<()>();
+                        let holder = tokio::task::spawn_blocking(move || {
+                            let _ = hold.recv();
+                        });
+                        let _ = stop_rx.await;
+                        drop(release);
+                        let _ = holder.await;
+                    });
+                }
+                Err(e) => {
+                    let _ = handle_tx.send(Err(e));
+                }
+            }
+        })?;
+
+        let handle = handle_rx
+            .recv()
+            .map_err(|_| std::io::Error::other("core runtime thread exited"))??;
+
+        Ok(Self {
+            handle,
+            stop: Some(stop),
+        })
+    }
+}
+
+impl Drop for CoreRuntime {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+impl ServerRuntime {
+    /// Refuse settings tokio's runtime builder, or spreading tasks across
+    /// cores, would panic on
+    pub fn validate(config: &ServerConfig) -> Result<(), String> {
+        // Otherwise there would be no core runtimes to spread tasks across
+        if config.thread_per_core && config.worker_threads == Some(0) {
+            return Err("thread_per_core needs at least one core".to_string());
+        }
+        if config.worker_threads == Some(0) {
+            return Err("worker_threads must be at least 1".to_string());
+        }
+        if config.event_interval == Some(0) {
+            return Err("event_interval must be at least 1".to_string());
+        }
+        if config.global_queue_interval == Some(0) {
+            return Err("global_queue_interval must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Build the runtime requested by the server configuration
+    ///
+    /// With a NUMA `placement`, every runtime thread is bound to a node as
+    /// it starts; this always uses an owned runtime, since the shared one's
+    /// threads belong to every server.
+    pub fn from_config(
+        config: &ServerConfig,
+        placement: Option<Arc<Placement>>,
+    ) -> std::io::Result<Self> {
+        if config.runtime_profile == RuntimeProfile::Simulation {
+            return Ok(ServerRuntime::Simulation(CoreRuntime::start_simulated()?));
+        }
+
+        if config.thread_per_core {
+            let cores = config.worker_threads.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+            let runtimes = (0..cores)
+                .map(|index| {
+                    let slot = placement.as_ref().map(|p| p.assign(index));
+                    CoreRuntime::start(index, slot)
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            return Ok(ServerRuntime::PerCore(runtimes));
+        }
+
+        let tuned = config.event_interval.is_some()
+            || config.global_queue_interval.is_some()
+            || config.worker_threads.is_some()
+            || placement.is_some();
+
+        if config.runtime_profile == RuntimeProfile::Shared && !tuned {
+            return Ok(ServerRuntime::Shared);
+        }
+
+        let mut builder = Builder::new_multi_thread();
+        builder.enable_all().thread_name("sparx-server");
+
+        if config.runtime_profile == RuntimeProfile::LowLatency {
+            builder
+                .event_interval(LOW_LATENCY_EVENT_INTERVAL)
+                .global_queue_interval(LOW_LATENCY_GLOBAL_QUEUE_INTERVAL);
+        }
+        if let Some(interval) = config.event_interval {
+            builder.event_interval(interval);
+        }
+        if let Some(interval) = config.global_queue_interval {
+            builder.global_queue_interval(interval);
+        }
+        if let Some(threads) = config.worker_threads {
+            builder.worker_threads(threads);
+        }
+        if let Some(placement) = placement {
+            let started = AtomicUsize::new(0);
+            builder.on_thread_start(move || {
+                let index = started.fetch_add(1, Ordering::Relaxed);
+                placement.assign(index).apply();
+            });
+        }
+
+        let runtime = builder.build()?;
+        Ok(ServerRuntime::Dedicated(DedicatedRuntime(Some(runtime))))
+    }
+
+    /// Number of accept loops to run (one per core in thread-per-core mode)
+    pub fn acceptors(&self, config: &ServerConfig) -> usize {
+        match self {
+            ServerRuntime::PerCore(cores) => cores.len(),
+            _ => config.acceptors.max(1),
+        }
+    }
+
+    /// Wake the runtime's workers before the first connection arrives
+    ///
+    /// The worker threads exist once the runtime is built, but sit parked
+    /// until work arrives. Spawning a batch of no-op tasks that each yield
+    /// once wakes them and runs their schedulers before any request does.
+    /// Blocks until the tasks have run or `WARM_UP_TIMEOUT` passes.
+    pub fn warm_up(&self) {
+        let tasks = match self {
+            ServerRuntime::PerCore(cores) => cores.len(),
+            _ => {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+                    * 2
+            }
+        };
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        for index in 0..tasks {
+            let done_tx = done_tx.clone();
+            self.spawn_on(index, async move {
+                tokio::task::yield_now().await;
+                let _ = done_tx.send(());
+            });
+        }
+        drop(done_tx);
+
+        let deadline = std::time::Instant::now() + WARM_UP_TIMEOUT;
+        for _ in 0..tasks {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if done_rx.recv_timeout(remaining).is_err() {
+                tracing::warn!("Runtime warm-up timed out");
+                break;
+            }
+        }
+    }
+
+    /// Spawn a task on this runtime
+    ///
+    /// In thread-per-core mode `index` picks the core to run on.
+    pub fn spawn_on<F>(&self, index: usize, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self {
+            ServerRuntime::Shared => {
+                rustler::spawn(future);
+            }
+            ServerRuntime::Dedicated(DedicatedRuntime(Some(runtime))) => {
+                runtime.spawn(future);
+            }
+            ServerRuntime::Dedicated(DedicatedRuntime(None)) => {}
+            ServerRuntime::PerCore(cores) => {
+                cores[index % cores.len()].handle.spawn(future);
+            }
+            ServerRuntime::Simulation(core) => {
+                core.handle.spawn(future);
+            }
+        }
+    }
+
+    /// Handle connections accepted on another thread are spawned onto
+    ///
+    /// For the shared runtime this asks a task running on it, so it blocks
+    /// briefly; `None` if that task never reports back.
+    pub fn connection_handle(&self) -> Option<Handle> {
+        match self {
+            ServerRuntime::Shared => {
+                let (handle_tx, handle_rx) = std::sync::mpsc::channel();
+                rustler::spawn(async move {
+                    let _ = handle_tx.send(Handle::current());
+                });
+                handle_rx.recv_timeout(WARM_UP_TIMEOUT).ok()
+            }
+            _ => self.handle(),
+        }
+    }
+
+    /// Handle of a runtime owned by this server, if it has one
+    pub fn handle(&self) -> Option<Handle> {
+        match self {
+            ServerRuntime::Shared | ServerRuntime::Dedicated(DedicatedRuntime(None)) => None,
+            ServerRuntime::Dedicated(DedicatedRuntime(Some(runtime))) => {
+                Some(runtime.handle().clone())
+            }
+            ServerRuntime::PerCore(cores) => cores.first().map(|core| core.handle.clone()),
+            ServerRuntime::Simulation(core) => Some(core.handle.clone()),
+        }
+    }
+
+    /// Move a simulation runtime's clock forward by `duration`
+    ///
+    /// Timers that come due fire, and the tasks they wake get a chance to run
+    /// before this returns. Returns `None` for every other runtime.
+    pub async fn advance(&self, duration: Duration) -> Option<()> {
+        let ServerRuntime::Simulation(core) = self else {
+            return None;
+        };
+
+        let advanced = core.handle.spawn(async move {
+            #[cfg(feature = "simulation")]
+            tokio::time::advance(duration).await;
+            #[cfg(not(feature = "simulation"))]
+            let _ = duration;
+
+            // Let woken tasks (and the tasks they wake) run; the runtime has
+            // a single thread, so each yield runs everything queued before it
+            for _ in 0..SETTLE_YIELDS {
+                tokio::task::yield_now().await;
+            }
+        });
+        advanced.await.ok()
+    }
+}
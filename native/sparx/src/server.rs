@@ -1,58 +1,681 @@
-use crate::config::ServerConfig;
-use crate::request::{extract_metadata, RequestHandle, ResponseMessage};
-use crate::response::build_response_from_channel;
+use crate::access_log::{self, AccessLog, RequestLine};
+use crate::atoms;
+use crate::budget::{MemoryBudget, Reservation};
+use crate::capture::ResponseCapture;
+use crate::compression;
+use crate::config::{ConnectionOverflow, ServerConfig, Transport, MIN_HTTP1_BUF_SIZE};
+use crate::connection::{ConnectionRegistry, ConnectionState};
+use crate::disconnect::{self, DisconnectGuard};
+use crate::duplex::{TestConnection, PIPE_CAPACITY};
+use crate::errors::{ErrorCounters, ErrorKind};
+use crate::events;
+use crate::faults::{self, Faults};
+use crate::inspector::Inspector;
+use crate::interim::{Interim, InterimIo};
+use crate::latency::LatencyHistograms;
+use crate::listener::{Bound, Listener, ListenerInfo, SocketOptions};
+use crate::metrics::Metrics;
+use crate::numa::Placement;
+use crate::pool::{Pools, Shared};
+use crate::proxy_protocol::{self, Peer};
+use crate::queue::{self, Priority, QueueError, QueueReceiver, QueueSender, QueuedRequest};
+use crate::request::{extract_metadata, BoxError, RequestBody, RequestHandle, ResponseMessage};
+use crate::response::{build_response_from_channel, strip_body, BodyError};
+use crate::runtime::{CoreRuntime, ServerRuntime};
+use crate::stats::ServerStats;
+use crate::telemetry::Telemetry;
+use crate::timer::TimerWheel;
+use crate::timing::{Phase, RequestTimings, TimingTotals};
+use crate::topics::Topics;
+use crate::trace_context::{self, TraceContext};
+use crate::websocket::{validate_handshake, HandshakeError, WS_VERSION};
 use bytes::Bytes;
 use http_body_util::BodyExt;
 use hyper::body::Incoming;
+use hyper::header::{HeaderName, HeaderValue};
 use hyper::service::service_fn;
-use hyper::{Request, Response};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
 use hyper_util::rt::TokioIo;
+use rustler::{Atom, Encoder, LocalPid, NifMap, OwnedEnv, ResourceArc};
 use std::convert::Infallible;
+use std::future::Future;
 use std::net::SocketAddr;
-use tokio::net::TcpListener;
-use tokio::sync::{mpsc, Mutex};
-use tracing::{error, info};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, warn, Instrument};
 
-type BoxBody = http_body_util::combinators::BoxBody<Bytes, Infallible>;
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, BodyError>;
 
-/// A queued request waiting to be picked up by Elixir
-pub struct QueuedRequest {
-    pub handle: RequestHandle,
+/// Time a client gets to complete the TLS handshake after connecting
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a load balancer gets to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a connection over `max_connections` is kept for its 503
+const SHED_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State shared by the accept loop, connections, and request handles
+pub struct ServerContext {
+    pub config: ServerConfig,
+    pub pools: Pools,
+    pub timings: TimingTotals,
+    /// Requests answered with a 503 because they waited too long in the queue
+    pub shed: AtomicU64,
+    /// Connections answered with a 503 because `max_connections` were open
+    pub shed_connections: AtomicU64,
+    /// Requests answered with a 503 because their queue lane was full
+    pub rejected: AtomicU64,
+    /// Open connections, watched by the idle sweeper
+    pub connections: ConnectionRegistry,
+    /// Bytes buffered for responses and WebSocket sends
+    pub budget: Arc<MemoryBudget>,
+    /// Deadlines for every request and connection on the server
+    pub timers: TimerWheel,
+    /// Injected network failures (`faults` config only)
+    pub faults: Option<Faults>,
+    /// Recent requests for the development inspector (`inspector_path` only)
+    pub inspector: Option<Inspector>,
+    /// Prometheus metrics (`metrics_path` only)
+    pub metrics: Option<Arc<Metrics>>,
+    /// Queue, handler, and total time by method and status class
+    pub latency: LatencyHistograms,
+    /// Lifecycle events for `:telemetry` (`telemetry_pid` only)
+    pub telemetry: Option<Arc<Telemetry>>,
+    /// One line per response (`access_log` only)
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Connection, body, and WebSocket failures by kind
+    pub errors: ErrorCounters,
+    /// WebSocket broadcast groups
+    pub topics: Topics,
+    /// Set while `server_pause` has the accept loops stopped
+    pub paused: watch::Sender<bool>,
+    /// Set once `server_drain` has closed the listeners
+    pub draining: watch::Sender<bool>,
+}
+
+impl ServerContext {
+    pub fn new(
+        config: ServerConfig,
+        placement: Option<&Placement>,
+        access_log: Option<AccessLog>,
+    ) -> Self {
+        let pools = Pools::new(config.pool_capacity, placement);
+        let budget = Arc::new(MemoryBudget::new(config.memory_budget));
+        let faults = config.faults.clone().map(Faults::new);
+        let inspector = config
+            .inspector_path
+            .clone()
+            .map(|path| Inspector::new(path, config.inspector_history));
+        let metrics = config
+            .metrics_path
+            .clone()
+            .map(|path| Arc::new(Metrics::new(path)));
+        let telemetry = config
+            .telemetry_pid
+            .map(|pid| Arc::new(Telemetry::new(pid)));
+        Self {
+            config,
+            pools,
+            timings: TimingTotals::default(),
+            shed: AtomicU64::new(0),
+            shed_connections: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            connections: ConnectionRegistry::default(),
+            budget,
+            timers: TimerWheel::default(),
+            faults,
+            inspector,
+            metrics,
+            latency: LatencyHistograms::default(),
+            telemetry,
+            access_log: access_log.map(Arc::new),
+            errors: ErrorCounters::default(),
+            topics: Topics::default(),
+            paused: watch::Sender::new(false),
+            draining: watch::Sender::new(false),
+        }
+    }
+
+    /// Queue a request, answering whatever the overflow policy pushes out
+    async fn enqueue(
+        &self,
+        request_tx: &QueueSender,
+        request: QueuedRequest,
+        priority: Priority,
+    ) -> Result<(), QueueError> {
+        let result = request_tx.send(request, priority).await;
+        if matches!(result, Err(QueueError::Full)) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        for evicted in result? {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Evicting request to {} from a full queue",
+                evicted.handle.metadata.path
+            );
+            shed_request(&evicted.handle, self.config.shed_retry_after_secs).await;
+        }
+        Ok(())
+    }
+
+    /// Time a request may take from arrival to a finished response
+    fn request_timeout(&self) -> Option<Duration> {
+        match self.config.request_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// How long a connection may sit idle between requests (None turns
+    /// keep-alive off)
+    fn keep_alive_timeout(&self) -> Option<Duration> {
+        match self.config.keep_alive_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Time allowed to receive a request head
+    fn header_read_timeout(&self) -> Option<Duration> {
+        match self.config.header_read_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Whether a dequeued request waited longer than `max_queue_wait_ms`
+    fn is_stale(&self, timings: &RequestTimings) -> bool {
+        let Some(limit_ms) = self.config.max_queue_wait_ms else {
+            return false;
+        };
+        timings
+            .between(Phase::Enqueued, Phase::Dequeued)
+            .map(|wait_us| wait_us > limit_ms.saturating_mul(1000))
+            .unwrap_or(false)
+    }
 }
 
 /// Server handle resource
 pub struct ServerHandle {
-    /// Queue of pending requests
-    pub request_queue: Mutex<mpsc::Receiver<QueuedRequest>>,
-    /// Shutdown signal sender
-    pub shutdown_tx: Mutex<Option<mpsc::Sender<()>>>,
+    /// Queue of pending requests, shared by every `receive_request` caller
+    pub request_queue: QueueReceiver,
+    /// Shutdown signal, observed by every accept loop
+    pub shutdown_tx: watch::Sender<bool>,
+    /// Runtime the accept loop and connections run on
+    pub runtime: ServerRuntime,
+    /// Threads running the accept loops instead (`acceptor_threads` only)
+    pub acceptor_threads: Vec<CoreRuntime>,
+    /// Queue handle for in-memory connections and injected requests, dropped
+    /// on shutdown
+    pub request_tx: Mutex<Option<QueueSender>>,
+    /// Shared server state
+    pub context: Arc<ServerContext>,
+    /// Address the first TCP listener is bound to (None for the memory
+    /// transport)
+    pub local_addr: Option<SocketAddr>,
+    /// Every bound listener, in configuration order
+    pub listeners: Vec<ListenerInfo>,
+    /// A handle on each listening socket for `server_export_listener`,
+    /// released on drain and shutdown so the sockets can close. `None` for
+    /// listeners split across `SO_REUSEPORT` sockets, which cannot be exported.
+    exports: Mutex<Vec<(Atom, Option<Bound>)>>,
+    /// Processes requests are pushed to; empty while requests are pulled
+    dispatchers: watch::Sender<Vec<LocalPid>>,
+    /// Set once the dispatch task has been started
+    dispatching: AtomicBool,
+}
+
+/// Where a server listens, returned by `server_info`
+///
+/// `host`, `port`, and `tls` describe the first TCP listener; `listeners`
+/// has every one.
+#[derive(NifMap)]
+pub struct ServerInfo {
+    pub host: String,
+    /// The bound port, also when the configured port was 0
+    pub port: u16,
+    pub tls: bool,
+    pub transport: Transport,
+    pub listeners: Vec<ListenerInfo>,
 }
 
 impl ServerHandle {
-    pub fn new(request_rx: mpsc::Receiver<QueuedRequest>, shutdown_tx: mpsc::Sender<()>) -> Self {
+    pub fn new(
+        request_rx: QueueReceiver,
+        shutdown_tx: watch::Sender<bool>,
+        runtime: ServerRuntime,
+        acceptor_threads: Vec<CoreRuntime>,
+        context: Arc<ServerContext>,
+        request_tx: QueueSender,
+        local_addr: Option<SocketAddr>,
+        listeners: Vec<ListenerInfo>,
+        exports: Vec<(Atom, Option<Bound>)>,
+    ) -> Self {
         Self {
-            request_queue: Mutex::new(request_rx),
-            shutdown_tx: Mutex::new(Some(shutdown_tx)),
+            request_queue: request_rx,
+            shutdown_tx,
+            runtime,
+            acceptor_threads,
+            request_tx: Mutex::new(Some(request_tx)),
+            context,
+            local_addr,
+            listeners,
+            exports: Mutex::new(exports),
+            dispatchers: watch::Sender::new(Vec::new()),
+            dispatching: AtomicBool::new(false),
+        }
+    }
+
+    pub fn info(&self) -> ServerInfo {
+        let config = &self.context.config;
+        ServerInfo {
+            host: self
+                .local_addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|| config.host.clone()),
+            port: self
+                .local_addr
+                .map(|addr| addr.port())
+                .unwrap_or(config.port),
+            tls: self
+                .listeners
+                .iter()
+                .find(|listener| listener.port.is_some())
+                .map(|listener| listener.tls)
+                .unwrap_or(config.tls.is_some()),
+            transport: config.transport,
+            listeners: self.listeners.clone(),
+        }
+    }
+
+    /// Duplicate every listening socket, by listener name, for a new server
+    /// to take over while this one drains
+    ///
+    /// Fails with `not_supported` for the memory transport, for listeners
+    /// split across `SO_REUSEPORT` sockets and on platforms without file
+    /// descriptors, and with `closed` once the server is draining.
+    pub fn export_listeners(&self) -> Result<Vec<(Atom, i32)>, Atom> {
+        if self.context.config.transport != Transport::Tcp {
+            return Err(atoms::not_supported());
+        }
+        let exports = self.exports.lock().map_err(|_| atoms::server_error())?;
+        if exports.is_empty() {
+            return Err(atoms::closed());
+        }
+        if exports.iter().any(|(_, bound)| bound.is_none()) {
+            return Err(atoms::not_supported());
+        }
+        exports
+            .iter()
+            .flat_map(|(name, bound)| bound.as_ref().map(|bound| (name, bound)))
+            .map(|(name, bound)| Ok((*name, bound.export()?)))
+            .collect::<std::io::Result<_>>()
+            .map_err(|e| {
+                warn!("Failed to export listener: {}", e);
+                match e.kind() {
+                    std::io::ErrorKind::Unsupported => atoms::not_supported(),
+                    _ => atoms::server_error(),
+                }
+            })
+    }
+
+    /// Open an in-memory connection to the server
+    ///
+    /// Fails with `not_supported` unless the server runs with the memory
+    /// transport, with `paused` while accepting is paused, and with `closed`
+    /// once the server is draining.
+    pub fn connect_in_memory(&self) -> Result<TestConnection, Atom> {
+        if self.context.config.transport != Transport::Memory {
+            return Err(atoms::not_supported());
+        }
+        if *self.context.paused.borrow() {
+            return Err(atoms::paused());
+        }
+        if *self.context.draining.borrow() {
+            return Err(atoms::closed());
+        }
+        let request_tx = self.sender().ok_or_else(atoms::closed)?;
+        let (client, server) = tokio::io::duplex(PIPE_CAPACITY);
+        self.runtime.spawn_on(
+            0,
+            serve_connection(
+                self.context.clone(),
+                request_tx,
+                Listener::default(),
+                server,
+                Instant::now(),
+                "memory".to_string(),
+                None,
+            ),
+        );
+        Ok(TestConnection::new(client))
+    }
+
+    /// Fabricate a request and queue it exactly like one read from a socket
+    ///
+    /// The response Elixir sends for it is delivered to `capture`.
+    pub async fn inject(
+        &self,
+        method: &str,
+        target: &str,
+        headers: Vec<(String, String)>,
+        body: Bytes,
+        capture: ResourceArc<ResponseCapture>,
+    ) -> Result<(), Atom> {
+        let method = Method::from_bytes(method.as_bytes()).map_err(|_| atoms::invalid_request())?;
+        let uri: Uri = target.parse().map_err(|_| atoms::invalid_request())?;
+        let mut header_map = HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers {
+            let name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| atoms::invalid_request())?;
+            let value = HeaderValue::from_str(&value).map_err(|_| atoms::invalid_request())?;
+            header_map.append(name, value);
+        }
+        if *self.context.draining.borrow() {
+            return Err(atoms::closed());
+        }
+        let request_tx = self.sender().ok_or_else(atoms::closed)?;
+
+        let timings = self.context.pools.take_timings(Instant::now());
+        timings.mark(Phase::Received);
+
+        let priority = queue::classify(&self.context.config, uri.path(), &header_map);
+        let metadata = extract_metadata(
+            &method,
+            &uri,
+            Version::HTTP_11,
+            &header_map,
+            Listener::default(),
+            None,
+            self.context.pools.headers.take(),
+        );
+        let body: RequestBody = http_body_util::Full::new(body)
+            .map_err(|never| match never {})
+            .boxed();
+
+        let (response_tx, response_rx) = self.context.pools.take_channel();
+        let handle = RequestHandle::new(
+            metadata,
+            body,
+            response_tx,
+            None,
+            self.context.clone(),
+            timings.clone(),
+        );
+
+        timings.mark(Phase::Enqueued);
+        self.context
+            .enqueue(&request_tx, QueuedRequest { handle }, priority)
+            .await
+            .map_err(|e| match e {
+                QueueError::Closed => atoms::closed(),
+                QueueError::Full => atoms::overloaded(),
+            })?;
+
+        let context = self.context.clone();
+        self.runtime.spawn_on(0, async move {
+            let mut response =
+                build_response_from_channel(response_rx, &method, &context, &timings).await;
+            if let Some(compression) = &context.config.compression {
+                response = response
+                    .map(|response| compression::compress(response, &header_map, compression));
+            }
+            if method == Method::HEAD {
+                response = response.map(strip_body);
+            }
+            if let Some(inspector) = &context.inspector {
+                let status = match &response {
+                    Ok(response) => response.status(),
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                inspector.record(
+                    &method,
+                    &uri,
+                    Version::HTTP_11,
+                    &header_map,
+                    status,
+                    &timings,
+                );
+            }
+            capture.complete(response).await;
+        });
+        Ok(())
+    }
+
+    /// Snapshot of the server's counters
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            header_pool: self.context.pools.headers.stats(),
+            timings_pool: self.context.pools.timings.stats(),
+            disconnect_pool: self.context.pools.disconnects.stats(),
+            channel_pool: self.context.pools.channels.stats(),
+            timings: self.context.timings.snapshot(),
+            shed_requests: self.context.shed.load(Ordering::Relaxed),
+            queue_depth: self.request_queue.depth(),
+            in_flight_requests: self.context.connections.in_flight(),
+            requests_served: self.context.timings.completed(),
+            open_connections: self.context.connections.open(),
+            accepted_connections: self.context.connections.accepted(),
+            closed_connections: self.context.connections.closed(),
+            shed_connections: self.context.shed_connections.load(Ordering::Relaxed),
+            rejected_requests: self.context.rejected.load(Ordering::Relaxed),
+            reclaimed_connections: self.context.connections.reclaimed(),
+            memory: self.context.budget.stats(),
+            faults: self.context.faults.as_ref().map(Faults::stats),
+            errors: self.context.errors.snapshot(),
+            paused: *self.context.paused.borrow(),
+            draining: *self.context.draining.borrow(),
         }
     }
 
     /// Receive a request from the queue (demand-driven)
-    pub async fn receive_request(&self) -> Option<RequestHandle> {
-        let mut queue = self.request_queue.lock().await;
-        queue.recv().await.map(|req| req.handle)
+    ///
+    /// Requests that already waited past `max_queue_wait_ms` are answered
+    /// with a 503 here and never reach Elixir. Fails with `timeout` if no
+    /// request arrives within `timeout`, and with `closed` once the server
+    /// has shut down.
+    pub async fn receive_request(&self, timeout: Option<Duration>) -> Result<RequestHandle, Atom> {
+        let mut deadline = timeout.map(|timeout| self.context.timers.sleep(timeout));
+        loop {
+            // Only the wait for the queue is cut short; shedding a stale
+            // request always runs to completion
+            let queued = match &mut deadline {
+                Some(deadline) => tokio::select! {
+                    queued = self.request_queue.recv() => queued,
+                    _ = deadline => return Err(atoms::timeout()),
+                },
+                None => self.request_queue.recv().await,
+            };
+            if let Some(handle) = self.admit(queued.ok_or_else(atoms::closed)?).await {
+                return Ok(handle);
+            }
+        }
+    }
+
+    /// Receive up to `max` requests at once
+    ///
+    /// Waits as `receive_request` does for the first one, then takes only
+    /// requests that are already queued, so a batch never waits to fill up.
+    pub async fn receive_requests(
+        &self,
+        max: usize,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<RequestHandle>, Atom> {
+        let mut batch = vec![self.receive_request(timeout).await?];
+        while batch.len() < max {
+            let Some(queued) = self.request_queue.try_recv() else {
+                break;
+            };
+            if let Some(handle) = self.admit(queued).await {
+                batch.push(handle);
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Replace the processes requests are pushed to
+    ///
+    /// Returns true the first time a dispatch task is needed; the caller
+    /// spawns it with `dispatch`.
+    pub fn set_dispatchers(&self, pids: Vec<LocalPid>) -> bool {
+        let start = !pids.is_empty();
+        self.dispatchers.send_replace(pids);
+        start && !self.dispatching.swap(true, Ordering::AcqRel)
+    }
+
+    /// Push queued requests to the dispatcher processes, round-robin, as
+    /// `{:sparx_request, request}` until the server shuts down
+    ///
+    /// Waits without taking requests while no dispatchers are set, so
+    /// `receive_request` callers get them instead. Dispatchers that have
+    /// exited are dropped from the set.
+    pub async fn dispatch(&self) {
+        let mut dispatchers = self.dispatchers.subscribe();
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        let mut env = OwnedEnv::new();
+        let mut next = 0;
+        loop {
+            // Nothing else wakes a server without dispatchers once it stops
+            let ready = tokio::select! {
+                ready = dispatchers.wait_for(|pids| !pids.is_empty()) => ready.is_ok(),
+                _ = shutdown_rx.wait_for(|stop| *stop) => false,
+            };
+            if !ready {
+                return;
+            }
+            let queued = tokio::select! {
+                biased;
+                _ = dispatchers.changed() => continue,
+                queued = self.request_queue.recv() => queued,
+            };
+            let Some(queued) = queued else {
+                return;
+            };
+            let Some(handle) = self.admit(queued).await else {
+                continue;
+            };
+
+            let pids = dispatchers.borrow_and_update().clone();
+            if pids.is_empty() {
+                // Cleared while the request was taken; put it back for the
+                // pull side
+                if let Some(request_tx) = self.sender() {
+                    let requeued = QueuedRequest { handle };
+                    let _ = self
+                        .context
+                        .enqueue(&request_tx, requeued, Priority::Normal)
+                        .await;
+                }
+                continue;
+            }
+
+            let handle = ResourceArc::new(handle);
+            let mut exited = Vec::new();
+            for offset in 0..pids.len() {
+                let pid = pids[(next + offset) % pids.len()];
+                let sent = env.send_and_clear(&pid, |env| {
+                    (atoms::sparx_request(), handle.clone()).encode(env)
+                });
+                if sent.is_ok() {
+                    next = (next + offset + 1) % pids.len();
+                    break;
+                }
+                exited.push(pid);
+            }
+            if !exited.is_empty() {
+                if exited.len() == pids.len() {
+                    warn!(
+                        "Dropping request to {}: every dispatcher has exited",
+                        handle.metadata.path
+                    );
+                }
+                self.dispatchers
+                    .send_modify(|pids| pids.retain(|pid| !exited.contains(pid)));
+            }
+        }
+    }
+
+    /// Hand a dequeued request to Elixir, unless it waited past
+    /// `max_queue_wait_ms`, in which case it is answered with a 503
+    async fn admit(&self, queued: QueuedRequest) -> Option<RequestHandle> {
+        let handle = queued.handle;
+        handle.timings.mark(Phase::Dequeued);
+
+        if !self.context.is_stale(&handle.timings) {
+            return Some(handle);
+        }
+
+        self.context.shed.fetch_add(1, Ordering::Relaxed);
+        shed_request(&handle, self.context.config.shed_retry_after_secs).await;
+        None
+    }
+
+    /// Stop or restart accepting new connections
+    ///
+    /// Open connections keep being served either way.
+    pub fn set_paused(&self, paused: bool) {
+        let changed = self.context.paused.send_if_modified(|current| {
+            let changed = *current != paused;
+            *current = paused;
+            changed
+        });
+        if changed {
+            info!(
+                "{} accepting connections",
+                if paused { "Paused" } else { "Resumed" }
+            );
+        }
+    }
+
+    /// Stop accepting and wait for open connections to finish
+    ///
+    /// Listeners are closed, idle keep-alive connections are closed right
+    /// away, and busy ones once their in-flight requests have been answered.
+    /// Returns whether every connection closed within `timeout`; whatever is
+    /// left is abandoned by `shutdown`.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.release_listeners();
+        if !self.context.draining.send_replace(true) {
+            info!(
+                "Draining {} open connections",
+                self.context.connections.open()
+            );
+        }
+        tokio::select! {
+            _ = self.context.connections.all_closed() => true,
+            _ = self.context.timers.sleep(timeout) => false,
+        }
     }
 
     /// Shutdown the server
-    pub async fn shutdown(&self) {
-        let mut guard = self.shutdown_tx.lock().await;
-        if let Some(tx) = guard.take() {
-            let _ = tx.send(()).await;
+    pub fn shutdown(&self) {
+        self.release_listeners();
+        self.shutdown_tx.send_replace(true);
+        if let Ok(mut request_tx) = self.request_tx.lock() {
+            request_tx.take();
         }
     }
-}
 
-unsafe impl Send for ServerHandle {}
-unsafe impl Sync for ServerHandle {}
+    /// Drop the handles kept for exporting, leaving the accept loops'
+    /// listeners as the only ones open
+    fn release_listeners(&self) {
+        if let Ok(mut exports) = self.exports.lock() {
+            exports.clear();
+        }
+    }
+
+    fn sender(&self) -> Option<QueueSender> {
+        self.request_tx.lock().ok()?.clone()
+    }
+}
 
 // Implement RefUnwindSafe since we need this for NIF resources
 impl std::panic::RefUnwindSafe for ServerHandle {}
@@ -60,20 +683,37 @@ impl std::panic::RefUnwindSafe for ServerHandle {}
 #[rustler::resource_impl]
 impl rustler::Resource for ServerHandle {}
 
-/// Start the HTTP server
+/// Accept connections on a listener bound by `server_start`
+///
+/// With `tls` set, every accepted connection is served over HTTPS. With
+/// `connections` set, connections are served on that runtime rather than
+/// the accept loop's own.
 pub async fn start_server(
-    config: ServerConfig,
-    request_tx: mpsc::Sender<QueuedRequest>,
+    context: Arc<ServerContext>,
+    request_tx: QueueSender,
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+    bound: Bound,
+    connections: Option<Handle>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr: SocketAddr = format!("{}:{}", config.host, config.port)
-        .parse()
-        .map_err(|e| format!("Invalid address: {}", e))?;
-
-    let listener = TcpListener::bind(addr).await?;
-    info!("Sparx server listening on http://{}", addr);
+    let accepting = Accepting::new(bound)?;
+    let options = SocketOptions::from_config(&context.config);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let addr = accepting.address()?;
+    info!("Sparx server listening on {}://{}", scheme, addr);
 
+    let mut paused = context.paused.subscribe();
+    let mut draining = context.draining.subscribe();
     loop {
-        let (stream, remote_addr) = match listener.accept().await {
+        let accepted = tokio::select! {
+            _ = draining.wait_for(|draining| *draining) => {
+                // Dropping the listener refuses new connections outright
+                info!("Stopped listening on {} to drain", addr);
+                return Ok(());
+            }
+            accepted = next_connection(&accepting, &context, &mut paused) => accepted,
+        };
+        let (stream, client) = match accepted {
             Ok(conn) => conn,
             Err(e) => {
                 error!("Failed to accept connection: {}", e);
@@ -81,33 +721,518 @@ pub async fn start_server(
             }
         };
 
-        let io = TokioIo::new(stream);
+        let accepted = Instant::now();
+        let serve = Serve {
+            context: context.clone(),
+            request_tx: request_tx.clone(),
+            listener,
+            tls: tls.clone(),
+            accepted,
+            peer: client
+                .as_ref()
+                .map_or_else(|| "unix".to_string(), Peer::label),
+            client,
+        };
+        let connection = match stream {
+            Stream::Tcp(stream) => {
+                if let Err(e) = options.apply(&stream) {
+                    warn!("Failed to set socket options for {}: {}", serve.peer, e);
+                }
+                serve.boxed(stream)
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => serve.boxed(stream),
+        };
+        match &connections {
+            Some(handle) => handle.spawn(connection),
+            None => tokio::spawn(connection),
+        };
+    }
+}
+
+/// A listener registered with the accept loop's runtime
+enum Accepting {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// A connection accepted from either kind of listener
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Accepting {
+    fn new(bound: Bound) -> std::io::Result<Self> {
+        match bound {
+            Bound::Tcp(listener) => TcpListener::from_std(listener).map(Accepting::Tcp),
+            #[cfg(unix)]
+            Bound::Unix(listener) => {
+                tokio::net::UnixListener::from_std(listener).map(Accepting::Unix)
+            }
+        }
+    }
+
+    /// Address for log messages: `host:port`, or `unix:path`
+    fn address(&self) -> std::io::Result<String> {
+        match self {
+            Accepting::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Accepting::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or(std::path::Path::new(""));
+                Ok(format!("unix:{}", path.display()))
+            }
+        }
+    }
+
+    /// Accept one connection, with its TCP peer; Unix socket peers are
+    /// unnamed
+    async fn accept(&self) -> std::io::Result<(Stream, Option<Peer>)> {
+        match self {
+            Accepting::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Some(Peer::direct(addr))))
+            }
+            #[cfg(unix)]
+            Accepting::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+}
+
+/// Everything an accepted connection is served with
+struct Serve {
+    context: Arc<ServerContext>,
+    request_tx: QueueSender,
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+    accepted: Instant,
+    /// Peer as it appears in logs; Unix socket peers appear as `unix`
+    peer: String,
+    client: Option<Peer>,
+}
+
+impl Serve {
+    /// The connection's task: the PROXY protocol header if the listener
+    /// expects one, a TLS handshake if it has a certificate, then HTTP
+    fn boxed<I>(self, stream: I) -> Pin<Box<dyn Future<Output = ()> + Send>>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if self.listener.proxy_protocol {
+            Box::pin(self.proxied(stream))
+        } else {
+            Box::pin(self.handshake(stream))
+        }
+    }
+
+    /// Read the PROXY protocol header, then serve the client it relays
+    ///
+    /// Connections without a valid header are logged, counted in the error
+    /// stats, and closed.
+    async fn proxied<I>(mut self, stream: I)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (client, stream) = tokio::select! {
+            result = proxy_protocol::accept(stream) => match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    self.context.errors.record(ErrorKind::ParseError);
+                    warn!("Invalid PROXY protocol header from {}: {}", self.peer, e);
+                    return;
+                }
+            },
+            _ = self.context.timers.sleep(PROXY_HEADER_TIMEOUT) => {
+                self.context.errors.record(ErrorKind::Timeout);
+                warn!("PROXY protocol header from {} timed out", self.peer);
+                return;
+            }
+        };
+        // LOCAL and UNKNOWN headers keep the load balancer as the peer
+        if let Some(client) = client {
+            self.peer = client.label();
+            self.client = Some(client);
+        }
+        self.handshake(stream).await;
+    }
+
+    /// Complete the TLS handshake if the listener has a certificate, then
+    /// serve the connection
+    ///
+    /// Failed and stalled handshakes are logged, counted in the error stats,
+    /// and never reach hyper.
+    async fn handshake<I>(mut self, stream: I)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Some(acceptor) = self.tls.take() else {
+            return self.serve(stream).await;
+        };
+        let stream = tokio::select! {
+            result = acceptor.accept(stream) => match result {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let kind = self.context.errors.record(ErrorKind::of(&e));
+                    warn!("TLS handshake with {} failed ({:?}): {}", self.peer, kind, e);
+                    return;
+                }
+            },
+            _ = self.context.timers.sleep(TLS_HANDSHAKE_TIMEOUT) => {
+                self.context.errors.record(ErrorKind::Timeout);
+                warn!("TLS handshake with {} timed out", self.peer);
+                return;
+            }
+        };
+        self.serve(stream).await;
+    }
+
+    async fn serve<I>(self, stream: I)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        serve_connection(
+            self.context,
+            self.request_tx,
+            self.listener,
+            stream,
+            self.accepted,
+            self.peer,
+            self.client,
+        )
+        .await;
+    }
+}
+
+/// Accept the next connection, waiting while accepting is paused
+///
+/// While paused, or at `max_connections` with `ConnectionOverflow::Wait`,
+/// the listener stays bound; new connections wait in the kernel backlog
+/// until accepting resumes.
+async fn next_connection(
+    listener: &Accepting,
+    context: &ServerContext,
+    paused: &mut watch::Receiver<bool>,
+) -> std::io::Result<(Stream, Option<Peer>)> {
+    loop {
+        // The sender lives in the server context, so this cannot fail
+        let _ = paused.wait_for(|paused| !*paused).await;
+        if context.config.connection_overflow == ConnectionOverflow::Wait {
+            context
+                .connections
+                .below(context.config.max_connections)
+                .await;
+        }
+        tokio::select! {
+            accepted = listener.accept() => return accepted,
+            _ = paused.wait_for(|paused| *paused) => {}
+        }
+    }
+}
+
+/// Serve HTTP on one accepted connection until it closes
+///
+/// Generic over the transport so TCP sockets and in-memory test pipes go
+/// through the same hyper, queue, and response pipeline.
+pub async fn serve_connection<I>(
+    context: Arc<ServerContext>,
+    request_tx: QueueSender,
+    listener: Listener,
+    stream: I,
+    accepted: Instant,
+    peer: String,
+    client: Option<Peer>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if context.faults.as_ref().is_some_and(Faults::fail_accept) {
+        warn!("Dropping connection from {} (injected fault)", peer);
+        return;
+    }
+
+    let interim = Arc::new(Interim::default());
+    let io = TokioIo::new(InterimIo::new(stream, interim.clone()));
+    let registration = ConnectionRegistry::register(&context, client);
+    if context.connections.open() > context.config.max_connections {
+        // Shed connections do not count against the limit while answered
+        drop(registration);
+        shed_connection(&context, io, &peer).await;
+        return;
+    }
+    let connection = registration.state().clone();
+    let _span = context
+        .telemetry
+        .as_ref()
+        .map(|telemetry| telemetry.connection_start(&peer));
+
+    let service_context = context.clone();
+    let service_peer = peer.clone();
+    let service = service_fn(move |req: Request<Incoming>| {
         let request_tx = request_tx.clone();
+        let context = service_context.clone();
+        let connection = connection.clone();
+        let interim = interim.clone();
+        let peer = service_peer.clone();
+        async move {
+            let started = Instant::now();
+            let timings = context.pools.take_timings(accepted);
+            timings.mark(Phase::Received);
+            let span = context
+                .telemetry
+                .as_ref()
+                .map(|telemetry| telemetry.request_start(req.method(), req.uri().path()));
+            let request_line = context.access_log.as_ref().map(|_| RequestLine::of(&req));
+            let method = req.method().clone();
+            let request_span = tracing::info_span!(
+                "request",
+                method = %req.method(),
+                path = req.uri().path(),
+                trace_id = tracing::field::Empty,
+            );
+            // Only parsed here when the span is recorded; Elixir gets its
+            // copy through the metadata
+            if !request_span.is_disabled() {
+                if let Some(trace) = TraceContext::from_headers(req.headers()) {
+                    request_span.record("trace_id", trace.trace_id.as_str());
+                    trace_context::set_parent(&request_span, &trace);
+                }
+            }
+            let response = handle_request(
+                req,
+                timings.clone(),
+                context.clone(),
+                connection.clone(),
+                interim,
+                request_tx,
+                listener,
+            )
+            .instrument(request_span)
+            .await;
+            response.map(|response| {
+                if let Some(span) = span {
+                    span.stop(response.status(), &timings);
+                }
+                context
+                    .latency
+                    .record(&method, response.status(), &timings, started.elapsed());
+                let response = faults::wrap_response(&context, &connection, response);
+                let response = match &context.metrics {
+                    Some(metrics) => metrics.record(response, started.elapsed()),
+                    None => response,
+                };
+                match (&context.access_log, request_line) {
+                    (Some(log), Some(request_line)) => {
+                        log_response(log, response, &peer, request_line, started)
+                    }
+                    _ => response,
+                }
+            })
+        }
+    });
 
-        // Spawn a task to handle this connection
-        tokio::spawn(async move {
-            let service = service_fn(move |req: Request<Incoming>| {
-                let request_tx = request_tx.clone();
-                async move { handle_request(req, request_tx).await }
-            });
-
-            // Use auto builder to support both HTTP/1.1 and HTTP/2
-            if let Err(e) =
-                hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new())
-                    .serve_connection(io, service)
-                    .await
-            {
-                error!("Error serving connection from {}: {}", remote_addr, e);
+    // The auto builder detects HTTP/2 (prior knowledge or ALPN) from the
+    // connection preface; each h2 stream becomes its own request
+    let mut builder =
+        hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    if !context.config.http2 {
+        builder = builder.http1_only();
+    }
+    let config = &context.config;
+    // Bound what a request head may hold before hyper hands it over; the
+    // exact limits are checked per request in `head_limit_response`
+    builder
+        .http2()
+        .max_header_list_size(h2_header_list_size(config));
+    let keep_alive = context.keep_alive_timeout();
+    let http1 = builder.http1();
+    http1
+        .keep_alive(keep_alive.is_some())
+        .max_headers(config.max_header_count);
+    if let Some(size) = config.http1_max_buf_size {
+        // hyper panics below its minimum
+        http1.max_buf_size(size.max(MIN_HTTP1_BUF_SIZE));
+    }
+    if let Some(timeout) = context.header_read_timeout() {
+        // Slowloris defence: a request head must arrive in one piece in time
+        http1
+            .timer(hyper_util::rt::TokioTimer::new())
+            .header_read_timeout(timeout);
+    }
+    // Upgraded connections (WebSocket or `upgrade_raw`) are handed over
+    // once their 101 response is written
+    let conn = builder.serve_connection_with_upgrades(io, service);
+    tokio::pin!(conn);
+
+    let mut draining = context.draining.subscribe();
+    let state = registration.state();
+    let finished = tokio::select! {
+        result = conn.as_mut() => Some(result),
+        // Draining: close idle keep-alive connections now and busy ones
+        // once their in-flight requests are answered
+        _ = draining.wait_for(|draining| *draining) => None,
+        // No request for `keep_alive_timeout_ms`
+        _ = idle_for(&context, state, keep_alive) => None,
+        // Idle past `idle_reclaim_ms`: finish anything in flight, then close
+        // and free the connection's buffers
+        _ = state.reclaimed() => None,
+        _ = state.aborted() => {
+            // Dropping the connection closes the socket mid-exchange
+            warn!("Aborted connection from {} (injected fault)", peer);
+            return;
+        }
+    };
+    let result = match finished {
+        Some(result) => result,
+        None => {
+            state.close();
+            conn.as_mut().graceful_shutdown();
+            // An abort still tears the connection down while it closes
+            tokio::select! {
+                result = conn.as_mut() => result,
+                _ = state.aborted() => {
+                    warn!("Aborted connection from {} (injected fault)", peer);
+                    return;
+                }
             }
-        });
+        }
+    };
+    if let Err(e) = result {
+        let kind = context.errors.record(ErrorKind::of(&*e));
+        if kind == ErrorKind::ParseError {
+            if let Some(log) = &context.access_log {
+                // hyper answers unparseable requests with a 400 by itself
+                log.log(access_log::Entry {
+                    peer: access_log::peer_ip(&peer),
+                    request: None,
+                    status: 400,
+                    bytes: None,
+                    duration: accepted.elapsed(),
+                });
+            }
+        }
+        match kind {
+            // Routine for clients that go away or send garbage
+            ErrorKind::ConnectionReset | ErrorKind::ParseError | ErrorKind::Closed => {
+                warn!("Connection from {} failed ({:?}): {}", peer, kind, e)
+            }
+            _ => error!("Error serving connection from {} ({:?}): {}", peer, kind, e),
+        }
+    }
+}
+
+/// Answer a connection over `max_connections` with a 503, then close it
+///
+/// The request is read first so clients see the response rather than a
+/// reset. With `ConnectionOverflow::Wait` this only happens to in-memory
+/// connections, which do not go through the accept loop.
+async fn shed_connection<I>(context: &ServerContext, io: TokioIo<I>, peer: &str)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    context.shed_connections.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        "Shedding connection from {}: {} connections open",
+        peer, context.config.max_connections
+    );
+
+    let retry_after = context.config.shed_retry_after_secs;
+    let access_log = context.access_log.clone();
+    let peer = peer.to_string();
+    let service = service_fn(move |req: Request<Incoming>| {
+        let started = Instant::now();
+        let request_line = access_log.as_ref().map(|_| RequestLine::of(&req));
+        let access_log = access_log.clone();
+        let peer = peer.clone();
+        async move {
+            let mut response = error_response(503, "Service Unavailable");
+            response
+                .headers_mut()
+                .insert(hyper::header::RETRY_AFTER, HeaderValue::from(retry_after));
+            let response = match (&access_log, request_line) {
+                (Some(log), Some(request_line)) => {
+                    log_response(log, response, &peer, request_line, started)
+                }
+                _ => response,
+            };
+            Ok::<_, Infallible>(response)
+        }
+    });
+    let mut builder =
+        hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    if !context.config.http2 {
+        builder = builder.http1_only();
+    }
+    builder.http1().keep_alive(false);
+
+    tokio::select! {
+        result = builder.serve_connection(io, service) => {
+            if let Err(e) = result {
+                tracing::debug!("Shed connection from {} failed: {}", peer, e);
+            }
+        }
+        _ = context.timers.sleep(SHED_CONNECTION_TIMEOUT) => {}
+    }
+}
+
+/// Log a response to the access log once its body has been sent
+fn log_response(
+    log: &Arc<AccessLog>,
+    response: Response<BoxBody>,
+    peer: &str,
+    request_line: RequestLine,
+    started: Instant,
+) -> Response<BoxBody> {
+    let status = response.status().as_u16();
+    response.map(|body| log.wrap(body, peer, request_line, status, started).boxed())
+}
+
+/// Resolve once a connection has had no request in flight for `timeout`
+///
+/// Never resolves without a timeout, when keep-alive is off and hyper closes
+/// the connection after its first response by itself.
+async fn idle_for(context: &ServerContext, state: &ConnectionState, timeout: Option<Duration>) {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+    loop {
+        // While busy, check again a full timeout later
+        let wait = match context.connections.idle_time(state) {
+            Some(idle) if idle >= timeout => return,
+            Some(idle) => timeout - idle,
+            None => timeout,
+        };
+        context.timers.sleep(wait).await;
     }
 }
 
 /// Handle a single HTTP request
 async fn handle_request(
-    req: Request<Incoming>,
-    request_tx: mpsc::Sender<QueuedRequest>,
+    mut req: Request<Incoming>,
+    timings: Shared<RequestTimings>,
+    context: Arc<ServerContext>,
+    connection: Arc<ConnectionState>,
+    interim: Arc<Interim>,
+    request_tx: QueueSender,
+    listener: Listener,
 ) -> Result<Response<BoxBody>, Infallible> {
+    let _active = context.connections.begin(&connection);
+
+    if let Some(inspector) = &context.inspector {
+        if inspector.is_endpoint(req.uri().path()) {
+            return Ok(inspector.response());
+        }
+    }
+    if let Some(metrics) = &context.metrics {
+        if metrics.is_endpoint(req.uri().path()) {
+            return Ok(metrics.response(&context, request_tx.depth()));
+        }
+    }
+
     // Check if this is a WebSocket upgrade request
     let is_upgrade = req
         .headers()
@@ -116,87 +1241,266 @@ async fn handle_request(
         .map(|v| v.eq_ignore_ascii_case("websocket"))
         .unwrap_or(false);
 
+    // Refuse bad handshakes before they ever reach Elixir
+    if is_upgrade {
+        if let Err(e) = validate_handshake(req.headers(), &context.config.ws_allowed_origins) {
+            warn!("Rejected WebSocket upgrade: {}", e.message());
+            return Ok(handshake_rejection(e));
+        }
+    }
+
+    if let Some(response) = head_limit_response(&context.config, &req) {
+        return Ok(response);
+    }
+
+    // Refuse bodies announced as too large before queueing anything
+    if let Some(limit) = context.config.max_body_size {
+        if !is_upgrade && declared_length(req.headers()).is_some_and(|len| len > limit as u64) {
+            timings.error(context.errors.record(ErrorKind::BodyTooLarge));
+            warn!(
+                "Rejected request to {} with a body over {} bytes",
+                req.uri().path(),
+                limit
+            );
+            return Ok(error_response(413, "Payload Too Large"));
+        }
+    }
+
     // Clone metadata before potentially consuming the request
     let method = req.method().clone();
     let uri = req.uri().clone();
     let version = req.version();
     let headers = req.headers().clone();
 
+    let priority = queue::classify(&context.config, uri.path(), &headers);
+
     // Extract metadata from cloned values
-    let metadata = extract_metadata(&method, &uri, version, &headers);
+    let metadata = extract_metadata(
+        &method,
+        &uri,
+        version,
+        &headers,
+        listener,
+        connection.peer().cloned(),
+        context.pools.headers.take(),
+    );
 
     //  Extract upgrade future and body
-    let (upgrade, body) = if is_upgrade {
+    let (upgrade, body): (_, RequestBody) = if is_upgrade {
         // For upgrades, get the OnUpgrade future (this consumes the request)
         let upgrade_future = hyper::upgrade::on(req);
         // WebSocket upgrades don't have a request body, use empty
         let empty = http_body_util::Empty::<Bytes>::new()
-            .map_err(|never: std::convert::Infallible| match never {})
+            .map_err(|never| match never {})
             .boxed();
         (Some(upgrade_future), empty)
     } else {
-        // Normal flow - extract the body and box it
+        // Upgrades to other protocols keep their body; the connection is
+        // only handed over if the handler calls `upgrade_raw`
+        let upgrade = req
+            .headers()
+            .contains_key(hyper::header::UPGRADE)
+            .then(|| hyper::upgrade::on(&mut req));
+        // Normal flow - hand the body to the request handle, which polls it
+        // on demand from `read_chunk`
         let (_, incoming_body) = req.into_parts();
-        let boxed_body = incoming_body.boxed();
-        (None, boxed_body)
+        let boxed_body = match context.config.max_body_size {
+            // Chunked bodies have no declared length; count as they arrive
+            Some(limit) => http_body_util::Limited::new(incoming_body, limit).boxed(),
+            None => incoming_body.map_err(BoxError::from).boxed(),
+        };
+        let boxed_body = match &context.metrics {
+            Some(metrics) => metrics.count_received(boxed_body).boxed(),
+            None => boxed_body,
+        };
+        (upgrade, boxed_body)
     };
 
-    // Create channels for body streaming and response
-    let (body_tx, body_rx) = mpsc::channel::<Result<Bytes, String>>(16);
-    let (response_tx, response_rx) = mpsc::channel::<ResponseMessage>(16);
+    // Create channel for the response
+    let (response_tx, response_rx) = context.pools.take_channel();
 
     // Create request handle with optional upgrade
-    let request_handle = RequestHandle::new(metadata, body_rx, response_tx.clone(), upgrade);
+    let request_handle = RequestHandle::new(
+        metadata,
+        body,
+        response_tx,
+        upgrade,
+        context.clone(),
+        timings.clone(),
+    )
+    // Interim responses are written as raw HTTP/1.1 on the socket
+    .with_interim((version == Version::HTTP_11).then_some(interim));
 
-    // Spawn task to stream request body into channel
-    tokio::spawn(async move {
-        let mut body = body;
-
-        loop {
-            match body.frame().await {
-                Some(Ok(frame)) => {
-                    if let Some(chunk) = frame.data_ref() {
-                        let bytes = chunk.to_vec();
-                        if body_tx.send(Ok(Bytes::from(bytes))).await.is_err() {
-                            // Receiver dropped, stop streaming
-                            break;
-                        }
-                    }
-                    // If frame has no data (trailers), continue
-                }
-                Some(Err(e)) => {
-                    let _ = body_tx.send(Err(format!("Body read error: {}", e))).await;
-                    break;
-                }
-                None => {
-                    // Send empty chunk to signal EOF
-                    let _ = body_tx.send(Ok(Bytes::new())).await;
-                    break;
-                }
-            }
-        }
-    });
+    // Dropped armed if hyper abandons the request because the client left
+    let disconnect = DisconnectGuard::new(request_handle.disconnect.clone());
 
     // Queue the request for Elixir to pick up
     let queued = QueuedRequest {
         handle: request_handle,
     };
 
-    if request_tx.send(queued).await.is_err() {
-        error!("Failed to queue request - server may be shutting down");
-        return Ok(error_response(500, "Server Error"));
+    timings.mark(Phase::Enqueued);
+    match context.enqueue(&request_tx, queued, priority).await {
+        Ok(()) => {}
+        Err(QueueError::Full) => {
+            warn!("Rejecting request to {}: queue is full", uri.path());
+            let mut response = error_response(503, "Service Unavailable");
+            response.headers_mut().insert(
+                hyper::header::RETRY_AFTER,
+                HeaderValue::from(context.config.shed_retry_after_secs),
+            );
+            return Ok(response);
+        }
+        Err(QueueError::Closed) => {
+            timings.error(ErrorKind::Closed);
+            error!("Failed to queue request - server may be shutting down");
+            return Ok(error_response(500, "Server Error"));
+        }
     }
 
     // Wait for Elixir to build and send the response
-    match build_response_from_channel(response_rx).await {
-        Ok(response) => Ok(response),
-        Err(e) => {
+    let response = build_response_from_channel(response_rx, &method, &context, &timings);
+    let result = match context.request_timeout() {
+        Some(timeout) => {
+            tokio::select! {
+                result = response => Some(result),
+                _ = context.timers.sleep(timeout) => None,
+            }
+        }
+        None => Some(response.await),
+    };
+
+    let response = match result {
+        Some(Ok(response)) => response,
+        Some(Err(e)) => {
+            timings.error(ErrorKind::Other);
             error!("Failed to build response: {}", e);
-            Ok(error_response(500, "Internal Server Error"))
+            error_response(500, "Internal Server Error")
+        }
+        None => {
+            timings.error(ErrorKind::Timeout);
+            warn!(
+                "Request to {} timed out after {}ms",
+                uri.path(),
+                context.config.request_timeout_ms
+            );
+            error_response(503, "Service Unavailable")
+        }
+    };
+
+    if let Some(inspector) = &context.inspector {
+        inspector.record(
+            &method,
+            &uri,
+            version,
+            &headers,
+            response.status(),
+            &timings,
+        );
+    }
+    let response = match &context.config.compression {
+        Some(compression) => compression::compress(response, &headers, compression),
+        None => response,
+    };
+    let response = disconnect::guard_response(response, disconnect);
+    let response = events::track_flush(response, timings);
+    if method == Method::HEAD {
+        return Ok(strip_body(response));
+    }
+    Ok(response)
+}
+
+/// Answer a stale request with `503 Service Unavailable` and `Retry-After`
+async fn shed_request(handle: &RequestHandle, retry_after_secs: u64) {
+    let Some(tx) = handle.get_response_sender().await else {
+        return;
+    };
+
+    warn!(
+        "Shedding request to {} after {}us in queue",
+        handle.metadata.path,
+        handle
+            .timings
+            .between(Phase::Enqueued, Phase::Dequeued)
+            .unwrap_or(0)
+    );
+
+    let messages = [
+        ResponseMessage::Status(503),
+        ResponseMessage::Header("retry-after".to_string(), retry_after_secs.to_string()),
+        ResponseMessage::Header("content-type".to_string(), "text/plain".to_string()),
+        ResponseMessage::BodyChunk(
+            Bytes::from_static(b"Service Unavailable"),
+            Reservation::default(),
+        ),
+        ResponseMessage::Finish,
+    ];
+    for message in messages {
+        if tx.send(message).await.is_err() {
+            // The client went away while the request was queued
+            return;
         }
     }
 }
 
+/// A 431 or 414 for a request head over the configured limits
+fn head_limit_response(
+    config: &ServerConfig,
+    req: &Request<Incoming>,
+) -> Option<Response<BoxBody>> {
+    let uri_length = uri_length(req.uri());
+    if uri_length > config.max_uri_length {
+        warn!("Rejected request with a {} byte URI", uri_length);
+        return Some(error_response(414, "URI Too Long"));
+    }
+
+    let headers = req.headers();
+    let header_size: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if headers.len() > config.max_header_count || header_size > config.max_header_size {
+        warn!(
+            "Rejected request to {} with {} headers of {} bytes",
+            req.uri().path(),
+            headers.len(),
+            header_size
+        );
+        return Some(error_response(431, "Request Header Fields Too Large"));
+    }
+    None
+}
+
+/// Length of the request target as the client sent it
+fn uri_length(uri: &Uri) -> usize {
+    let scheme = uri
+        .scheme_str()
+        .map_or(0, |scheme| scheme.len() + "://".len());
+    let authority = uri
+        .authority()
+        .map_or(0, |authority| authority.as_str().len());
+    let path_and_query = uri.path_and_query().map_or(0, |pq| pq.as_str().len());
+    scheme + authority + path_and_query
+}
+
+/// HTTP/2 header list limit matching `max_header_size` and `max_header_count`
+///
+/// HTTP/2 counts 32 bytes of overhead per header on top of its name and value.
+fn h2_header_list_size(config: &ServerConfig) -> u32 {
+    let overhead = config.max_header_count.saturating_mul(32);
+    u32::try_from(config.max_header_size.saturating_add(overhead)).unwrap_or(u32::MAX)
+}
+
+/// The request's `content-length`, if present and valid
+fn declared_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(hyper::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
 /// Create an error response
 fn error_response(status: u16, message: &str) -> Response<BoxBody> {
     use http_body_util::BodyExt;
@@ -212,3 +1516,15 @@ fn error_response(status: u16, message: &str) -> Response<BoxBody> {
         .body(body)
         .unwrap()
 }
+
+/// Create the response for a refused WebSocket handshake
+fn handshake_rejection(error: HandshakeError) -> Response<BoxBody> {
+    let mut response = error_response(error.status(), error.message());
+    if error == HandshakeError::UnsupportedVersion {
+        response.headers_mut().insert(
+            "sec-websocket-version",
+            hyper::header::HeaderValue::from_static(WS_VERSION),
+        );
+    }
+    response
+}
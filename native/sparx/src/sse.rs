@@ -0,0 +1,76 @@
+use crate::budget::Reservation;
+use crate::channel::WeakSender;
+use crate::request::ResponseMessage;
+use crate::server::ServerContext;
+use bytes::{BufMut, Bytes, BytesMut};
+use rustler::NifMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Comment line sent on quiet streams; clients ignore it
+const KEEPALIVE: &[u8] = b":keepalive\n\n";
+
+/// One Server-Sent Event, as given to `sse_send_event`
+#[derive(NifMap)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: Option<String>,
+    /// Reconnection delay for the client, in milliseconds
+    pub retry: Option<u64>,
+}
+
+impl SseEvent {
+    /// The event in the `text/event-stream` format
+    ///
+    /// Multi-line data becomes one `data:` field per line. Fails if `id` or
+    /// `event` contain a line break, which would end the field early.
+    pub fn encode(&self) -> Result<Bytes, String> {
+        let mut buf = BytesMut::new();
+        for (field, value) in [("id", &self.id), ("event", &self.event)] {
+            let Some(value) = value else {
+                continue;
+            };
+            if value.contains(['\r', '\n']) {
+                return Err(format!("Invalid SSE {}: contains a line break", field));
+            }
+            put_field(&mut buf, field, value);
+        }
+        if let Some(retry) = self.retry {
+            put_field(&mut buf, "retry", &retry.to_string());
+        }
+        if let Some(data) = &self.data {
+            for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+                put_field(&mut buf, "data", line);
+            }
+        }
+        buf.put_u8(b'\n');
+        Ok(buf.freeze())
+    }
+}
+
+fn put_field(buf: &mut BytesMut, name: &str, value: &str) {
+    buf.put_slice(name.as_bytes());
+    buf.put_slice(b": ");
+    buf.put_slice(value.as_bytes());
+    buf.put_u8(b'\n');
+}
+
+/// Write a keepalive comment every `interval` until the stream ends
+///
+/// Only a weak handle on the response is kept, so the stream still ends
+/// when the request is dropped without being finished. The interval is
+/// timed on the server's timer wheel.
+pub async fn keepalive(tx: WeakSender, context: Arc<ServerContext>, interval: Duration) {
+    loop {
+        context.timers.sleep(interval).await;
+        let Some(tx) = tx.upgrade() else {
+            return;
+        };
+        let comment =
+            ResponseMessage::BodyChunk(Bytes::from_static(KEEPALIVE), Reservation::default());
+        if tx.send(comment).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::budget::BudgetStats;
+use crate::errors::ErrorStats;
+use crate::faults::FaultStats;
+use crate::pool::PoolStats;
+use crate::timing::TimingTotalsSnapshot;
+use rustler::NifMap;
+
+/// Server statistics returned by `server_stats`
+#[derive(NifMap)]
+pub struct ServerStats {
+    pub header_pool: PoolStats,
+    pub timings_pool: PoolStats,
+    pub disconnect_pool: PoolStats,
+    pub channel_pool: PoolStats,
+    pub timings: TimingTotalsSnapshot,
+    pub shed_requests: u64,
+    /// Requests waiting in the queue for Elixir
+    pub queue_depth: usize,
+    /// Requests on open connections, queued or being handled, whose
+    /// response has not finished
+    pub in_flight_requests: usize,
+    /// Requests whose response has finished
+    pub requests_served: u64,
+    pub open_connections: usize,
+    pub accepted_connections: u64,
+    pub closed_connections: u64,
+    /// Connections answered with a 503 for exceeding `max_connections`
+    pub shed_connections: u64,
+    /// Requests answered with a 503 because their queue lane was full
+    pub rejected_requests: u64,
+    pub reclaimed_connections: u64,
+    pub memory: BudgetStats,
+    pub faults: Option<FaultStats>,
+    pub errors: ErrorStats,
+    /// Whether accepting new connections is paused
+    pub paused: bool,
+    /// Whether the server is draining for shutdown
+    pub draining: bool,
+}
@@ -0,0 +1,152 @@
+use crate::atoms;
+use crate::request::HttpMethod;
+use crate::timing::{Phase, RequestTimings};
+use hyper::{Method, StatusCode};
+use rustler::{Atom, Encoder, Env, LocalPid, NifMap, OwnedEnv, Term};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Forwards request and connection lifecycle events to an Elixir process
+///
+/// Each event is a `{:sparx_telemetry, event, measurements, metadata}`
+/// message; `Sparx.Telemetry` turns them into `:telemetry` events. Durations
+/// are in microseconds.
+pub struct Telemetry {
+    pid: LocalPid,
+}
+
+#[derive(NifMap)]
+struct RequestInfo {
+    method: HttpMethod,
+    path: String,
+}
+
+#[derive(NifMap)]
+struct RequestStop {
+    duration: u64,
+    accept_to_queue: Option<u64>,
+    queue_to_pickup: Option<u64>,
+    pickup_to_first_byte: Option<u64>,
+}
+
+#[derive(NifMap)]
+struct RequestResult {
+    method: HttpMethod,
+    path: String,
+    status: u16,
+    /// Error kind for `:request_exception`
+    reason: Option<Atom>,
+}
+
+#[derive(NifMap)]
+struct ConnectionInfo {
+    peer: String,
+}
+
+#[derive(NifMap)]
+struct ConnectionStop {
+    duration: u64,
+}
+
+/// Start events carry no measurements of their own; `Sparx.Telemetry` adds
+/// the timestamps
+struct NoMeasurements;
+
+impl Encoder for NoMeasurements {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        rustler::types::map::map_new(env)
+    }
+}
+
+impl Telemetry {
+    pub fn new(pid: LocalPid) -> Self {
+        Self { pid }
+    }
+
+    fn send(&self, event: Atom, measurements: impl Encoder, metadata: impl Encoder) {
+        let mut env = OwnedEnv::new();
+        // A forwarder that has exited just misses the event
+        let _ = env.send_and_clear(&self.pid, |env| {
+            (atoms::sparx_telemetry(), event, measurements, metadata).encode(env)
+        });
+    }
+
+    /// Emit `:request_start`; the span emits the matching stop
+    pub fn request_start(self: &Arc<Self>, method: &Method, path: &str) -> RequestSpan {
+        let info = RequestInfo {
+            method: HttpMethod(method.clone()),
+            path: path.to_string(),
+        };
+        self.send(atoms::request_start(), NoMeasurements, &info);
+        RequestSpan {
+            telemetry: self.clone(),
+            info,
+            started: Instant::now(),
+        }
+    }
+
+    /// Emit `:connection_start`; dropping the span emits the stop
+    pub fn connection_start(self: &Arc<Self>, peer: &str) -> ConnectionSpan {
+        let info = ConnectionInfo {
+            peer: peer.to_string(),
+        };
+        self.send(atoms::connection_start(), NoMeasurements, &info);
+        ConnectionSpan {
+            telemetry: self.clone(),
+            info,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// A request between its start and stop events
+pub struct RequestSpan {
+    telemetry: Arc<Telemetry>,
+    info: RequestInfo,
+    started: Instant,
+}
+
+impl RequestSpan {
+    /// Emit `:request_stop` once the response head is ready, or
+    /// `:request_exception` if the server answered with a 5xx of its own
+    pub fn stop(self, status: StatusCode, timings: &RequestTimings) {
+        let failure = timings
+            .last_error()
+            .filter(|_| status.is_server_error())
+            .map(|kind| kind.atom());
+        let measurements = RequestStop {
+            duration: self.started.elapsed().as_micros() as u64,
+            accept_to_queue: timings.get(Phase::Enqueued),
+            queue_to_pickup: timings.between(Phase::Enqueued, Phase::Dequeued),
+            pickup_to_first_byte: timings.between(Phase::Dequeued, Phase::FirstByte),
+        };
+        let metadata = RequestResult {
+            method: self.info.method,
+            path: self.info.path,
+            status: status.as_u16(),
+            reason: failure,
+        };
+        let event = match failure {
+            Some(_) => atoms::request_exception(),
+            None => atoms::request_stop(),
+        };
+        self.telemetry.send(event, measurements, metadata);
+    }
+}
+
+/// An open connection; emits `:connection_stop` when dropped
+pub struct ConnectionSpan {
+    telemetry: Arc<Telemetry>,
+    info: ConnectionInfo,
+    started: Instant,
+}
+
+impl Drop for ConnectionSpan {
+    fn drop(&mut self) {
+        let measurements = ConnectionStop {
+            duration: self.started.elapsed().as_micros() as u64,
+        };
+        self.telemetry
+            .send(atoms::connection_stop(), measurements, &self.info);
+    }
+}
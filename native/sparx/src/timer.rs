@@ -0,0 +1,277 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+/// Resolution of the wheel
+const TICK: Duration = Duration::from_millis(10);
+
+/// Slots per level, as a power of two
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+
+/// Levels of the wheel; with 10ms ticks four levels span about 46 hours
+const LEVELS: usize = 4;
+
+/// Shared timer wheel for per-request deadlines
+///
+/// Every deadline on a server (request timeouts, idle and keep-alive timers,
+/// WebSocket pings) is a slot entry here instead of its own runtime timer.
+/// A single driver task advances the wheel once per tick; level 0 holds
+/// timers due within 64 ticks, and each higher level covers 64 times the
+/// span of the one below, cascading its entries down as their slot comes up.
+///
+/// Time is read from tokio's clock, so on a simulation runtime deadlines
+/// only pass when the clock is advanced.
+pub struct TimerWheel {
+    start: Instant,
+    /// Runtime the wheel was created in, whose clock every reading comes
+    /// from; otherwise a sleep created from a NIF would read the real clock
+    /// while the driver reads a paused one
+    clock: Option<Handle>,
+    wheel: Mutex<Wheel>,
+    /// Wakes the driver when the first timer is added to an empty wheel
+    armed: Notify,
+}
+
+struct Wheel {
+    /// Ticks processed so far
+    tick: u64,
+    levels: Vec<Vec<Vec<Entry>>>,
+    /// Entries in the wheel, including cancelled ones not yet swept
+    len: usize,
+}
+
+struct Entry {
+    deadline: u64,
+    timer: Weak<TimerState>,
+}
+
+#[derive(Default)]
+struct TimerState {
+    fired: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Future returned by `TimerWheel::sleep`
+///
+/// Dropping it cancels the timer; its entry is discarded when its slot
+/// comes up.
+pub struct Sleep {
+    state: Arc<TimerState>,
+}
+
+impl TimerState {
+    fn fire(&self) {
+        self.fired.store(true, Ordering::Release);
+        if let Some(waker) = self.waker.lock().ok().and_then(|mut w| w.take()) {
+            waker.wake();
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.state.fired.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        if let Ok(mut waker) = self.state.waker.lock() {
+            *waker = Some(cx.waker().clone());
+        }
+        // The timer may have fired between the check and storing the waker
+        if self.state.fired.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            clock: Handle::try_current().ok(),
+            wheel: Mutex::new(Wheel {
+                tick: 0,
+                levels: (0..LEVELS)
+                    .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                    .collect(),
+                len: 0,
+            }),
+            armed: Notify::new(),
+        }
+    }
+}
+
+impl TimerWheel {
+    /// Sleep for `duration`, rounded up to the next tick
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        let state = Arc::new(TimerState::default());
+        let deadline = (self.elapsed() + duration)
+            .as_nanos()
+            .div_ceil(TICK.as_nanos()) as u64;
+
+        let entry = Entry {
+            deadline,
+            timer: Arc::downgrade(&state),
+        };
+        if let Ok(mut wheel) = self.wheel.lock() {
+            let was_empty = wheel.len == 0;
+            if was_empty {
+                // Skip the ticks that passed while idle instead of having
+                // the driver walk through every one of them
+                wheel.tick = wheel.tick.max(self.now());
+            }
+            wheel.insert(entry);
+            if was_empty {
+                self.armed.notify_one();
+            }
+        }
+
+        Sleep { state }
+    }
+
+    /// Time since the wheel was created, on its runtime's clock
+    fn elapsed(&self) -> Duration {
+        let _clock = self.clock.as_ref().map(Handle::enter);
+        self.start.elapsed()
+    }
+
+    /// Ticks elapsed since the wheel was created
+    fn now(&self) -> u64 {
+        (self.elapsed().as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    /// Number of timers in the wheel, including cancelled ones not yet swept
+    pub fn len(&self) -> usize {
+        self.wheel.lock().map(|wheel| wheel.len).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drive the wheel until `shutdown_rx` fires
+    pub async fn run(&self, mut shutdown_rx: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(TICK);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        loop {
+            if self.is_empty() {
+                // Nothing to expire: park instead of ticking
+                tokio::select! {
+                    _ = self.armed.notified() => interval.reset(),
+                    _ = shutdown_rx.wait_for(|stop| *stop) => return,
+                }
+            }
+
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.wait_for(|stop| *stop) => return,
+            }
+
+            let now = self.now();
+            let expired = match self.wheel.lock() {
+                Ok(mut wheel) => wheel.advance_to(now),
+                Err(_) => return,
+            };
+            // Wake tasks outside the lock
+            for timer in expired {
+                timer.fire();
+            }
+        }
+    }
+}
+
+impl Wheel {
+    fn insert(&mut self, entry: Entry) {
+        if entry.timer.strong_count() == 0 {
+            return;
+        }
+        if entry.deadline <= self.tick {
+            // Already due; fires on the next advance
+            self.push(0, self.tick as usize % SLOTS, entry);
+            return;
+        }
+
+        let delta = entry.deadline - self.tick;
+        let mut level = 0;
+        while level + 1 < LEVELS && delta >= 1 << (SLOT_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        let slot = (entry.deadline >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.push(level, slot, entry);
+    }
+
+    /// Add an entry to a slot
+    ///
+    /// Most timers are cancelled long before they are due (a request timeout
+    /// rarely fires), so before a slot grows its cancelled entries are
+    /// dropped, keeping memory proportional to the live timers.
+    fn push(&mut self, level: usize, slot: usize, entry: Entry) {
+        let entries = &mut self.levels[level][slot];
+        if entries.len() == entries.capacity() {
+            let before = entries.len();
+            entries.retain(|entry| entry.timer.strong_count() > 0);
+            self.len -= before - entries.len();
+        }
+        entries.push(entry);
+        self.len += 1;
+    }
+
+    /// Process every tick up to `now`, returning the timers that expired
+    fn advance_to(&mut self, now: u64) -> Vec<Arc<TimerState>> {
+        let mut expired = Vec::new();
+
+        // Entries put in the current slot after it was processed
+        expired.extend(self.take_due(self.tick as usize % SLOTS));
+
+        while self.tick < now {
+            self.tick += 1;
+
+            // Cascade higher levels whose slot boundary was just crossed
+            for level in 1..LEVELS {
+                let span = 1u64 << (SLOT_BITS * level as u32);
+                if !self.tick.is_multiple_of(span) {
+                    break;
+                }
+                let slot = (self.tick >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+                let entries = std::mem::take(&mut self.levels[level][slot]);
+                self.len -= entries.len();
+                for entry in entries {
+                    self.insert(entry);
+                }
+            }
+
+            expired.extend(self.take_due(self.tick as usize % SLOTS));
+        }
+
+        expired
+    }
+
+    /// Remove the due entries of a level-0 slot
+    fn take_due(&mut self, slot: usize) -> Vec<Arc<TimerState>> {
+        let tick = self.tick;
+        let entries = std::mem::take(&mut self.levels[0][slot]);
+        let mut due = Vec::new();
+        for entry in entries {
+            if entry.deadline <= tick {
+                self.len -= 1;
+                if let Some(timer) = entry.timer.upgrade() {
+                    due.push(timer);
+                }
+            } else {
+                self.levels[0][slot].push(entry);
+            }
+        }
+        due
+    }
+}
@@ -0,0 +1,195 @@
+use crate::errors::ErrorKind;
+use crate::events::{Event, EventEntry, EventLog};
+use crate::pool::Recycle;
+use rustler::NifMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Phases of a request's life that are timestamped
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// Request head parsed by hyper
+    Received = 0,
+    /// Placed on the request queue
+    Enqueued = 1,
+    /// Picked up by an Elixir process
+    Dequeued = 2,
+    /// First response message sent by the handler
+    FirstByte = 3,
+    /// Response finished by the handler
+    Finished = 4,
+}
+
+const PHASES: usize = 5;
+
+impl Phase {
+    /// Event logged when the phase is reached
+    fn event(self) -> Event {
+        match self {
+            Phase::Received => Event::Received,
+            Phase::Enqueued => Event::Queued,
+            Phase::Dequeued => Event::Dequeued,
+            Phase::FirstByte => Event::ResponseStarted,
+            Phase::Finished => Event::Finished,
+        }
+    }
+}
+
+/// Timestamps for one request, relative to when its connection was accepted
+///
+/// Each phase is stored as microseconds since `accepted` plus one, so zero
+/// means "not reached yet" and the marks can be set from any thread. Phases
+/// and other lifecycle events also go to the request's event log.
+pub struct RequestTimings {
+    accepted: Instant,
+    marks: [AtomicU64; PHASES],
+    events: EventLog,
+}
+
+/// Timings returned by `request_timings`, in microseconds since accept
+#[derive(NifMap)]
+pub struct TimingsSnapshot {
+    pub received: Option<u64>,
+    pub enqueued: Option<u64>,
+    pub dequeued: Option<u64>,
+    pub first_byte: Option<u64>,
+    pub finished: Option<u64>,
+}
+
+impl RequestTimings {
+    pub fn new(accepted: Instant) -> Self {
+        Self {
+            accepted,
+            marks: Default::default(),
+            events: EventLog::default(),
+        }
+    }
+
+    /// Reuse pooled timings for a request on a connection accepted at
+    /// `accepted`
+    pub fn restart(&mut self, accepted: Instant) {
+        self.accepted = accepted;
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.accepted.elapsed().as_micros() as u64
+    }
+
+    /// Record that the request reached `phase` (only the first mark counts)
+    pub fn mark(&self, phase: Phase) {
+        let micros = self.elapsed_us() + 1;
+        let first = self.marks[phase as usize]
+            .compare_exchange(0, micros, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok();
+        if first {
+            self.events.record(micros - 1, phase.event());
+        }
+    }
+
+    /// Log a lifecycle event that is not a timed phase
+    pub fn event(&self, event: Event) {
+        self.events.record(self.elapsed_us(), event);
+    }
+
+    /// Log an error in the request's life
+    pub fn error(&self, kind: ErrorKind) {
+        self.events.error(self.elapsed_us(), kind);
+    }
+
+    /// Kind of the most recent error logged for the request
+    pub fn last_error(&self) -> Option<ErrorKind> {
+        self.events.last_error()
+    }
+
+    /// The request's event log, oldest first
+    pub fn events(&self) -> Vec<EventEntry> {
+        self.events.snapshot()
+    }
+
+    pub fn get(&self, phase: Phase) -> Option<u64> {
+        match self.marks[phase as usize].load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(micros - 1),
+        }
+    }
+
+    /// Microseconds between two phases, if both were reached
+    pub fn between(&self, from: Phase, to: Phase) -> Option<u64> {
+        Some(self.get(to)?.saturating_sub(self.get(from)?))
+    }
+
+    pub fn snapshot(&self) -> TimingsSnapshot {
+        TimingsSnapshot {
+            received: self.get(Phase::Received),
+            enqueued: self.get(Phase::Enqueued),
+            dequeued: self.get(Phase::Dequeued),
+            first_byte: self.get(Phase::FirstByte),
+            finished: self.get(Phase::Finished),
+        }
+    }
+}
+
+impl Default for RequestTimings {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl Recycle for RequestTimings {
+    fn recycle(&mut self) {
+        for mark in &mut self.marks {
+            *mark.get_mut() = 0;
+        }
+        self.events.clear();
+    }
+}
+
+/// Server-wide aggregates of request timings
+#[derive(Default)]
+pub struct TimingTotals {
+    completed: AtomicU64,
+    queue_wait_us: AtomicU64,
+    queue_wait_max_us: AtomicU64,
+    handler_us: AtomicU64,
+    handler_max_us: AtomicU64,
+}
+
+/// Aggregated timings returned in `server_stats`
+#[derive(NifMap)]
+pub struct TimingTotalsSnapshot {
+    pub completed: u64,
+    pub queue_wait_us: u64,
+    pub queue_wait_max_us: u64,
+    pub handler_us: u64,
+    pub handler_max_us: u64,
+}
+
+impl TimingTotals {
+    /// Requests whose response has finished
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Fold a finished request into the totals
+    pub fn record(&self, timings: &RequestTimings) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        if let Some(wait) = timings.between(Phase::Enqueued, Phase::Dequeued) {
+            self.queue_wait_us.fetch_add(wait, Ordering::Relaxed);
+            self.queue_wait_max_us.fetch_max(wait, Ordering::Relaxed);
+        }
+        if let Some(handler) = timings.between(Phase::Dequeued, Phase::Finished) {
+            self.handler_us.fetch_add(handler, Ordering::Relaxed);
+            self.handler_max_us.fetch_max(handler, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> TimingTotalsSnapshot {
+        TimingTotalsSnapshot {
+            completed: self.completed.load(Ordering::Relaxed),
+            queue_wait_us: self.queue_wait_us.load(Ordering::Relaxed),
+            queue_wait_max_us: self.queue_wait_max_us.load(Ordering::Relaxed),
+            handler_us: self.handler_us.load(Ordering::Relaxed),
+            handler_max_us: self.handler_max_us.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use rustler::NifStruct;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// ALPN protocol ids
+const ALPN_H2: &[u8] = b"h2";
+const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+/// Certificate chain and private key for HTTPS
+///
+/// Each of the two can be given either as a path to a PEM file or as the
+/// PEM text itself, but not both.
+#[derive(NifStruct, Clone, Debug, Default)]
+#[module = "Sparx.Config.Tls"]
+pub struct TlsConfig {
+    /// Path to the PEM certificate chain, leaf first
+    pub certfile: Option<String>,
+    /// Path to the PEM private key
+    pub keyfile: Option<String>,
+    /// PEM certificate chain, leaf first
+    pub cert_pem: Option<String>,
+    /// PEM private key (PKCS#8, PKCS#1, or SEC1)
+    pub key_pem: Option<String>,
+}
+
+/// Build the acceptor that terminates TLS on accepted connections
+///
+/// Fails with a readable message if the certificate or key is missing,
+/// unreadable, or does not parse, so `server_start` can refuse to start.
+/// `h2` is offered through ALPN only when `http2` is enabled.
+pub fn acceptor(config: &TlsConfig, http2: bool) -> Result<TlsAcceptor, String> {
+    let cert_pem = load("certificate", &config.certfile, &config.cert_pem)?;
+    let key_pem = load("private key", &config.keyfile, &config.key_pem)?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_bytes()))
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+        .map_err(|e| format!("Invalid TLS certificate: {}", e))?;
+    if certs.is_empty() {
+        return Err("No certificates found in the TLS certificate PEM".to_string());
+    }
+    let key: PrivateKeyDer<'static> =
+        rustls_pemfile::private_key(&mut BufReader::new(key_pem.as_bytes()))
+            .map_err(|e| format!("Invalid TLS private key: {}", e))?
+            .ok_or("No private key found in the TLS key PEM")?;
+
+    // Name the provider explicitly rather than relying on a process default
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Failed to configure TLS: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("TLS certificate and key do not match: {}", e))?;
+    if http2 {
+        server_config.alpn_protocols.push(ALPN_H2.to_vec());
+    }
+    server_config.alpn_protocols.push(ALPN_HTTP11.to_vec());
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// PEM text given inline or read from a file
+fn load(what: &str, path: &Option<String>, pem: &Option<String>) -> Result<String, String> {
+    match (path, pem) {
+        (Some(_), Some(_)) => Err(format!(
+            "TLS {} given both as a file and as PEM; use one",
+            what
+        )),
+        (Some(path), None) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read TLS {} from {}: {}", what, path, e)),
+        (None, Some(pem)) => Ok(pem.clone()),
+        (None, None) => Err(format!("TLS {} is missing", what)),
+    }
+}
@@ -0,0 +1,107 @@
+use crate::websocket::{Frame, Outbox, SendError, WebSocketHandle};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Identifies a socket by the address of its outbox, which cannot be
+/// reused while the registry holds a weak reference to it
+type SocketId = usize;
+
+fn socket_id(outbox: &Outbox) -> SocketId {
+    outbox as *const Outbox as SocketId
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Sockets subscribed to each topic, held weakly so that membership
+    /// does not keep a socket, and through it the server, alive
+    members: HashMap<String, HashMap<SocketId, Weak<Outbox>>>,
+    /// Topics each socket is subscribed to, so closed sockets can be removed
+    /// from all of them
+    joined: HashMap<SocketId, HashSet<String>>,
+}
+
+/// WebSocket broadcast groups of a server
+///
+/// Sockets leave their topics when their handle is dropped, or when they
+/// are found closed, either by a receive or by a failed broadcast send.
+#[derive(Default)]
+pub struct Topics {
+    registry: Mutex<Registry>,
+}
+
+impl Topics {
+    pub fn join(&self, ws: &WebSocketHandle, topic: String) {
+        let Ok(mut registry) = self.registry.lock() else {
+            return;
+        };
+        let id = socket_id(ws.outbox());
+        registry.joined.entry(id).or_default().insert(topic.clone());
+        registry
+            .members
+            .entry(topic)
+            .or_default()
+            .insert(id, Arc::downgrade(ws.outbox()));
+    }
+
+    pub fn leave(&self, ws: &WebSocketHandle, topic: &str) {
+        let Ok(mut registry) = self.registry.lock() else {
+            return;
+        };
+        let id = socket_id(ws.outbox());
+        if let Some(topics) = registry.joined.get_mut(&id) {
+            topics.remove(topic);
+            if topics.is_empty() {
+                registry.joined.remove(&id);
+            }
+        }
+        registry.remove_member(topic, id);
+    }
+
+    /// Remove a socket from every topic it joined
+    pub fn leave_all(&self, outbox: &Outbox) {
+        let Ok(mut registry) = self.registry.lock() else {
+            return;
+        };
+        let id = socket_id(outbox);
+        for topic in registry.joined.remove(&id).unwrap_or_default() {
+            registry.remove_member(&topic, id);
+        }
+    }
+
+    /// Send a frame to every socket subscribed to `topic`
+    ///
+    /// Each send runs in its own task, so a slow client holds up neither the
+    /// caller nor the other sockets. Returns the number of sockets the frame
+    /// went out to.
+    pub fn broadcast(&self, topic: &str, frame: Frame) -> usize {
+        let sockets: Vec<_> = match self.registry.lock() {
+            Ok(registry) => registry
+                .members
+                .get(topic)
+                .map(|members| members.values().filter_map(Weak::upgrade).collect())
+                .unwrap_or_default(),
+            Err(_) => return 0,
+        };
+        for outbox in &sockets {
+            let outbox = outbox.clone();
+            let frame = frame.clone();
+            rustler::spawn(async move {
+                if let Err(SendError::Failed(_)) = outbox.send(frame).await {
+                    outbox.context.topics.leave_all(&outbox);
+                }
+            });
+        }
+        sockets.len()
+    }
+}
+
+impl Registry {
+    fn remove_member(&mut self, topic: &str, id: SocketId) {
+        if let Some(members) = self.members.get_mut(topic) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.members.remove(topic);
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use hyper::http::HeaderMap;
+use rustler::NifMap;
+use tracing::Span;
+
+/// W3C Trace Context propagated by the caller (`traceparent` and
+/// `tracestate` headers)
+#[derive(NifMap, Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits
+    pub trace_id: String,
+    /// Caller's span id, 16 lowercase hex digits
+    pub parent_id: String,
+    /// Bit 0 is `sampled`
+    pub trace_flags: u8,
+    /// Vendor entries, passed along unparsed
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Read the context from request headers
+    ///
+    /// Returns None if `traceparent` is missing or malformed; `tracestate`
+    /// is only kept alongside a valid `traceparent`, as the spec requires.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut parents = headers.get_all("traceparent").iter();
+        let traceparent = parents.next()?.to_str().ok()?;
+        if parents.next().is_some() {
+            // Several traceparents are ambiguous; start a new trace
+            return None;
+        }
+        let mut context = Self::parse_traceparent(traceparent)?;
+
+        let tracestate = headers
+            .get_all("tracestate")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+            .join(",");
+        if !tracestate.is_empty() {
+            context.tracestate = Some(tracestate);
+        }
+        Some(context)
+    }
+
+    /// `version-trace_id-parent_id-flags`, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+    fn parse_traceparent(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let mut parts = value.splitn(5, '-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if !is_hex(version, 2) || version == "ff" {
+            return None;
+        }
+        // Version 00 has exactly four fields; later versions may append more
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || is_zero(trace_id) {
+            return None;
+        }
+        if !is_hex(parent_id, 16) || is_zero(parent_id) {
+            return None;
+        }
+        if !is_hex(flags, 2) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            trace_flags: u8::from_str_radix(flags, 16).ok()?,
+            tracestate: None,
+        })
+    }
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|b| b == b'0')
+}
+
+/// Make `context` the remote parent of `span` in exported traces
+#[cfg(feature = "otlp")]
+pub fn set_parent(span: &Span, context: &TraceContext) {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let (Ok(trace_id), Ok(span_id)) = (
+        TraceId::from_hex(&context.trace_id),
+        SpanId::from_hex(&context.parent_id),
+    ) else {
+        return;
+    };
+    let state = context
+        .tracestate
+        .as_deref()
+        .and_then(|state| state.parse::<TraceState>().ok())
+        .unwrap_or_default();
+    let remote = SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::new(context.trace_flags),
+        true,
+        state,
+    );
+    span.set_parent(opentelemetry::Context::new().with_remote_span_context(remote));
+}
+
+/// Without the `otlp` feature spans are not exported, so there is nothing
+/// to link them to
+#[cfg(not(feature = "otlp"))]
+pub fn set_parent(_span: &Span, _context: &TraceContext) {}
+
+/// Layer exporting request spans over OTLP, when built with the `otlp`
+/// feature and `OTEL_EXPORTER_OTLP_ENDPOINT` (or the traces-specific
+/// variant) is set
+///
+/// Fails if the exporter cannot be built; the caller reports it once a
+/// subscriber is there to log it.
+#[cfg(feature = "otlp")]
+pub fn otlp_layer<S>() -> Result<Option<impl tracing_subscriber::Layer<S>>, String>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider as _;
+    use std::sync::OnceLock;
+
+    // Kept so the batch processor lives as long as the library
+    static PROVIDER: OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> = OnceLock::new();
+
+    let configured = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|name| std::env::var_os(name).is_some());
+    if !configured {
+        return Ok(None);
+    }
+    // Endpoint, headers, and service name come from the standard OTEL_*
+    // environment variables
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| format!("Failed to build the OTLP exporter: {}", e))?;
+    let provider = PROVIDER.get_or_init(|| {
+        opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .build()
+    });
+    Ok(Some(
+        tracing_opentelemetry::layer().with_tracer(provider.tracer("sparx")),
+    ))
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn otlp_layer<S: tracing::Subscriber>(
+) -> Result<Option<tracing_subscriber::layer::Identity>, String> {
+    Ok(None)
+}
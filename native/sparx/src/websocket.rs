@@ -1,7 +1,24 @@
+use crate::atoms;
+use crate::budget::Reservation;
+use crate::errors::ErrorKind;
+use crate::headers::HeaderList;
+use crate::response::NifResult;
+use crate::server::ServerContext;
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
+use hyper::http::HeaderMap;
 use hyper_util::rt::TokioIo;
-use tokio::sync::Mutex;
+use rustler::{NifMap, NifUnitEnum};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
 use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::WebSocketStream;
 
 /// WebSocket frame types that can be sent/received
@@ -26,6 +43,15 @@ impl Frame {
         }
     }
 
+    /// Payload size, used to account the frame against the memory budget
+    pub fn payload_len(&self) -> usize {
+        match self {
+            Frame::Text(s) => s.len(),
+            Frame::Binary(b) | Frame::Ping(b) | Frame::Pong(b) => b.len(),
+            Frame::Close => 0,
+        }
+    }
+
     /// Convert from tungstenite message
     pub fn from_ws_message(msg: WsMessage) -> Option<Self> {
         match msg {
@@ -39,55 +65,462 @@ impl Frame {
     }
 }
 
+/// The only WebSocket protocol version we speak (RFC 6455)
+pub const WS_VERSION: &str = "13";
+
+/// Reasons a WebSocket handshake is refused before it reaches Elixir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    MissingKey,
+    UnsupportedVersion,
+    OriginNotAllowed,
+}
+
+impl HandshakeError {
+    /// HTTP status used to reject the upgrade
+    pub fn status(&self) -> u16 {
+        match self {
+            HandshakeError::MissingKey => 400,
+            HandshakeError::UnsupportedVersion => 426,
+            HandshakeError::OriginNotAllowed => 403,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            HandshakeError::MissingKey => "Missing Sec-WebSocket-Key header",
+            HandshakeError::UnsupportedVersion => "Unsupported Sec-WebSocket-Version",
+            HandshakeError::OriginNotAllowed => "Origin not allowed",
+        }
+    }
+}
+
+/// Validate the handshake headers of a WebSocket upgrade request
+///
+/// Requests without an `Origin` header are not made by browsers and are
+/// allowed through; cross-site hijacking is only possible from a browser.
+pub fn validate_handshake(
+    headers: &HeaderMap,
+    allowed_origins: &[String],
+) -> Result<(), HandshakeError> {
+    if !headers.contains_key("sec-websocket-key") {
+        return Err(HandshakeError::MissingKey);
+    }
+
+    let version_ok = headers
+        .get("sec-websocket-version")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim() == WS_VERSION)
+        .unwrap_or(false);
+    if !version_ok {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+
+    if allowed_origins.is_empty() {
+        return Ok(());
+    }
+
+    match headers.get(hyper::header::ORIGIN) {
+        None => Ok(()),
+        Some(origin) => {
+            let origin = origin
+                .to_str()
+                .map_err(|_| HandshakeError::OriginNotAllowed)?;
+            if allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin.trim_end_matches('/')))
+            {
+                Ok(())
+            } else {
+                Err(HandshakeError::OriginNotAllowed)
+            }
+        }
+    }
+}
+
+/// Options for `upgrade_websocket`
+#[derive(NifMap)]
+pub struct UpgradeOptions {
+    /// Subprotocols the server speaks
+    pub protocols: Vec<String>,
+    /// Limits on incoming data, tungstenite's defaults if unset
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    /// Buffering of outgoing data, tungstenite's defaults if unset
+    pub write_buffer_size: Option<usize>,
+    pub max_write_buffer_size: Option<usize>,
+    /// Send a ping this often (no heartbeat if unset)
+    pub ping_interval_ms: Option<u64>,
+    /// Close the connection once nothing has been received for this long
+    /// (`ping_interval_ms` if unset)
+    pub pong_timeout_ms: Option<u64>,
+    /// Outgoing frames queued before `send_overflow` applies
+    pub send_queue_size: usize,
+    pub send_overflow: SendOverflow,
+}
+
+impl UpgradeOptions {
+    /// tungstenite configuration for the connection
+    ///
+    /// tungstenite panics on a write buffer cap that is not larger than the
+    /// write buffer, so that is refused here.
+    pub fn ws_config(&self) -> Result<WebSocketConfig, String> {
+        let mut config = WebSocketConfig::default();
+        if self.max_message_size.is_some() {
+            config.max_message_size = self.max_message_size;
+        }
+        if self.max_frame_size.is_some() {
+            config.max_frame_size = self.max_frame_size;
+        }
+        if let Some(size) = self.write_buffer_size {
+            config.write_buffer_size = size;
+        }
+        if let Some(size) = self.max_write_buffer_size {
+            config.max_write_buffer_size = size;
+        }
+        if self.send_queue_size == 0 {
+            return Err("send_queue_size must be positive".to_string());
+        }
+        if config.max_write_buffer_size <= config.write_buffer_size {
+            return Err("max_write_buffer_size must be larger than write_buffer_size".to_string());
+        }
+        Ok(config)
+    }
+
+    /// Ping interval and liveness timeout, if a heartbeat was asked for
+    pub fn heartbeat(&self) -> Result<Option<(Duration, Duration)>, String> {
+        let Some(interval) = self.ping_interval_ms else {
+            return Ok(None);
+        };
+        if interval == 0 {
+            return Err("ping_interval_ms must be positive".to_string());
+        }
+        let timeout = self.pong_timeout_ms.unwrap_or(interval);
+        Ok(Some((
+            Duration::from_millis(interval),
+            Duration::from_millis(timeout),
+        )))
+    }
+}
+
+/// Pick the subprotocol to speak from those offered in
+/// `Sec-WebSocket-Protocol`
+///
+/// The client lists protocols in order of preference, so the first one the
+/// server supports wins. Protocol names are case-sensitive.
+pub fn select_protocol(headers: &HeaderList, supported: &[String]) -> Option<String> {
+    headers
+        .get_all("sec-websocket-protocol")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .find(|offered| supported.iter().any(|protocol| protocol == offered))
+        .map(str::to_string)
+}
+
+/// What happens to a frame sent while the socket's send queue is full
+#[derive(NifUnitEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOverflow {
+    /// Wait for room in the queue
+    Block,
+    /// Discard the frame
+    Drop,
+    /// Give up on the client and close the connection
+    Close,
+}
+
+/// Why a frame could not be sent
+#[derive(Debug)]
+pub enum SendError {
+    /// The server's memory budget has no room for the frame
+    Overloaded,
+    /// The send queue was full and the frame was discarded
+    Dropped,
+    Failed(ErrorKind),
+}
+
+impl From<SendError> for NifResult {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::Overloaded => NifResult::Reason(atoms::overloaded()),
+            SendError::Dropped => NifResult::Reason(atoms::dropped()),
+            SendError::Failed(kind) => NifResult::Reason(kind.atom()),
+        }
+    }
+}
+
+type WsStream = WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>;
+
+/// A queued frame and its reservation on the memory budget
+type Outgoing = (WsMessage, Reservation);
+
+/// How long a connection that is given up on gets to send its close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sending side of a WebSocket, shared with the broadcast topics
+///
+/// Topics hold it weakly, so a subscribed socket is still freed once Elixir
+/// drops its handle, and the writer task ends with it.
+pub struct Outbox {
+    /// Queue of frames for the writer task
+    queue: mpsc::Sender<Outgoing>,
+    /// What `send` does when the queue is full
+    overflow: SendOverflow,
+    /// Set when the server gives up on the client, closing the connection
+    /// and failing receives with the reason
+    abandoned: watch::Sender<Option<ErrorKind>>,
+    /// Server state: the memory budget frames are accounted against, and
+    /// the broadcast topics
+    pub context: Arc<ServerContext>,
+}
+
+impl Outbox {
+    /// Queue a frame for sending
+    ///
+    /// The frame holds a reservation on the memory budget from the moment it
+    /// is queued until it has been flushed. Succeeding only means the frame
+    /// was queued; write failures close the connection.
+    pub async fn send(&self, frame: Frame) -> Result<(), SendError> {
+        let reservation = self
+            .context
+            .budget
+            .try_reserve(frame.payload_len())
+            .map_err(|_| SendError::Overloaded)?;
+        let outgoing = (frame.to_ws_message(), reservation);
+
+        let queued = match self.overflow {
+            SendOverflow::Block => self.queue.send(outgoing).await.is_ok(),
+            overflow => match self.queue.try_send(outgoing) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) if overflow == SendOverflow::Drop => {
+                    return Err(SendError::Dropped)
+                }
+                Err(TrySendError::Full(_)) => {
+                    tracing::debug!("WebSocket send queue full, closing");
+                    self.abandon(ErrorKind::Closed);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+        };
+        if queued {
+            Ok(())
+        } else {
+            Err(SendError::Failed(ErrorKind::Closed))
+        }
+    }
+
+    /// Close the connection, failing receives with `reason`
+    fn abandon(&self, reason: ErrorKind) {
+        self.abandoned.send_if_modified(|abandoned| {
+            let first = abandoned.is_none();
+            if first {
+                *abandoned = Some(reason);
+            }
+            first
+        });
+    }
+}
+
 /// WebSocket connection handle
+///
+/// Frames are sent through a bounded queue drained by a writer task, so a
+/// slow client fills its own queue instead of stalling every sender, and a
+/// process parked in `ws_recv` does not hold up senders either.
 pub struct WebSocketHandle {
-    /// The underlying WebSocket stream
-    stream: Mutex<Option<WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>>>,
+    /// Sending side, also reached from the topics the socket joined
+    outbox: Arc<Outbox>,
+    /// Receiving half, `None` once the stream has ended
+    stream: Mutex<Option<SplitStream<WsStream>>>,
+    /// Set once frames are pushed to an owner process instead of `ws_recv`
+    owned: AtomicBool,
+    /// When a frame was last received, for the heartbeat
+    last_seen: std::sync::Mutex<Instant>,
+    /// Server state: the memory budget frames are accounted against, the
+    /// error counters failures are recorded in, and the broadcast topics
+    pub context: Arc<ServerContext>,
 }
 
 impl WebSocketHandle {
-    /// Create a new WebSocket handle from an upgraded connection
-    #[allow(dead_code)]
-    pub fn new(ws_stream: WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>) -> Self {
+    /// Create a new WebSocket handle from an upgraded connection, queueing
+    /// up to `queue_size` outgoing frames
+    pub fn new(
+        ws_stream: WsStream,
+        context: Arc<ServerContext>,
+        queue_size: usize,
+        overflow: SendOverflow,
+    ) -> Self {
+        let (sink, stream) = ws_stream.split();
+        let (queue, outgoing) = mpsc::channel(queue_size);
+        let abandoned = watch::Sender::new(None);
+        rustler::spawn(write_frames(
+            sink,
+            outgoing,
+            abandoned.subscribe(),
+            context.clone(),
+        ));
         Self {
-            stream: Mutex::new(Some(ws_stream)),
+            outbox: Arc::new(Outbox {
+                queue,
+                overflow,
+                abandoned,
+                context: context.clone(),
+            }),
+            stream: Mutex::new(Some(stream)),
+            owned: AtomicBool::new(false),
+            last_seen: std::sync::Mutex::new(Instant::now()),
+            context,
         }
     }
 
-    /// Send a frame to the WebSocket
-    pub async fn send_frame(&self, frame: Frame) -> Result<(), String> {
-        let mut stream_opt = self.stream.lock().await;
-        if let Some(stream) = stream_opt.as_mut() {
-            let ws_msg = frame.to_ws_message();
-            stream
-                .send(ws_msg)
-                .await
-                .map_err(|e| format!("Failed to send frame: {}", e))
-        } else {
-            Err("WebSocket closed".to_string())
+    /// Sending side of the socket
+    pub fn outbox(&self) -> &Arc<Outbox> {
+        &self.outbox
+    }
+
+    /// Queue a frame for sending, see `Outbox::send`
+    pub async fn send_frame(&self, frame: Frame) -> Result<(), SendError> {
+        self.outbox.send(frame).await
+    }
+
+    /// Frames queued and not yet written
+    pub fn queue_depth(&self) -> usize {
+        self.outbox.queue.max_capacity() - self.outbox.queue.capacity()
+    }
+
+    /// Close the connection, failing receives with `reason`
+    fn abandon(&self, reason: ErrorKind) {
+        self.outbox.abandon(reason);
+    }
+
+    /// Ping the peer every `interval`, and close the connection once
+    /// nothing has been received from it for `timeout`
+    ///
+    /// Client pings are answered by tungstenite itself. Frames, pongs
+    /// included, only count as received while something reads the socket
+    /// through `ws_recv` or an owner process. Pings are timed on the server's
+    /// timer wheel rather than a runtime timer per socket. Returns once the
+    /// connection is closed.
+    pub async fn heartbeat(&self, interval: Duration, timeout: Duration) {
+        loop {
+            self.context.timers.sleep(interval).await;
+            let silent = self
+                .last_seen
+                .lock()
+                .map(|last_seen| last_seen.elapsed())
+                .unwrap_or_default();
+            if silent >= timeout {
+                tracing::debug!("WebSocket peer silent for {:?}, closing", silent);
+                self.abandon(self.context.errors.record(ErrorKind::Timeout));
+                return;
+            }
+            if let Err(SendError::Failed(_)) = self.send_frame(Frame::Ping(Vec::new())).await {
+                return;
+            }
         }
     }
 
+    /// Hand incoming frames over to an owner process
+    ///
+    /// Returns false if the WebSocket already has an owner.
+    pub fn claim(&self) -> bool {
+        !self.owned.swap(true, Ordering::AcqRel)
+    }
+
+    /// Whether incoming frames go to an owner process
+    pub fn is_owned(&self) -> bool {
+        self.owned.load(Ordering::Acquire)
+    }
+
     /// Receive a frame from the WebSocket (blocking until frame arrives)
-    pub async fn recv_frame(&self) -> Option<Frame> {
+    ///
+    /// Fails with `ErrorKind::Closed` once the stream has ended, and with
+    /// the reason once the server has given up on the peer, such as
+    /// `ErrorKind::Timeout` from the heartbeat.
+    pub async fn recv_frame(&self) -> Result<Frame, ErrorKind> {
         let mut stream_opt = self.stream.lock().await;
-        if let Some(stream) = stream_opt.as_mut() {
-            match stream.next().await {
-                Some(Ok(msg)) => Frame::from_ws_message(msg),
-                Some(Err(_)) | None => {
-                    // Connection closed or error
-                    *stream_opt = None;
-                    None
+        let stream = stream_opt.as_mut().ok_or(ErrorKind::Closed)?;
+        let mut abandoned = self.outbox.abandoned.subscribe();
+        let error = tokio::select! {
+            received = stream.next() => match received {
+                Some(Ok(msg)) => {
+                    if let Ok(mut last_seen) = self.last_seen.lock() {
+                        *last_seen = Instant::now();
+                    }
+                    return Frame::from_ws_message(msg).ok_or(ErrorKind::Closed);
                 }
-            }
-        } else {
-            None
+                Some(Err(e)) => {
+                    let kind = self.context.errors.record(ErrorKind::of(&e));
+                    // Have the writer tell the peer why it is being closed
+                    self.abandon(kind);
+                    kind
+                }
+                None => ErrorKind::Closed,
+            },
+            reason = abandoned.wait_for(Option::is_some) => match reason {
+                Ok(reason) => reason.unwrap_or(ErrorKind::Closed),
+                Err(_) => ErrorKind::Closed,
+            },
+        };
+        // The stream is unusable after an error or its end
+        *stream_opt = None;
+        self.context.topics.leave_all(&self.outbox);
+        Err(error)
+    }
+}
+
+/// Write queued frames to the socket until the queue closes, a write fails,
+/// or the connection is abandoned
+async fn write_frames(
+    mut sink: SplitSink<WsStream, WsMessage>,
+    mut queue: mpsc::Receiver<Outgoing>,
+    mut abandoned: watch::Receiver<Option<ErrorKind>>,
+    context: Arc<ServerContext>,
+) {
+    loop {
+        let (message, _reservation) = tokio::select! {
+            // A handle dropped right after abandoning still sends its close frame
+            biased;
+            _ = abandoned.wait_for(Option::is_some) => break,
+            outgoing = queue.recv() => match outgoing {
+                Some(outgoing) => outgoing,
+                None => return,
+            },
+        };
+        let sent = tokio::select! {
+            sent = sink.send(message) => sent,
+            _ = abandoned.wait_for(Option::is_some) => break,
+        };
+        if let Err(e) = sent {
+            let kind = context.errors.record(ErrorKind::of(&e));
+            tracing::debug!("Failed to send WebSocket frame ({:?}): {}", kind, e);
+            return;
         }
     }
+    // Abandoned: say goodbye if the client is still listening
+    let reason = abandoned.borrow().and_then(close_frame);
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, sink.send(WsMessage::Close(reason))).await;
+}
+
+/// Close frame telling the peer why the server gave up on it, for the
+/// reasons RFC 6455 has a status code for
+fn close_frame(reason: ErrorKind) -> Option<CloseFrame<'static>> {
+    let code = match reason {
+        ErrorKind::BodyTooLarge => CloseCode::Size,
+        ErrorKind::ParseError => CloseCode::Protocol,
+        _ => return None,
+    };
+    Some(CloseFrame {
+        code,
+        reason: "".into(),
+    })
 }
 
-unsafe impl Send for WebSocketHandle {}
-unsafe impl Sync for WebSocketHandle {}
+impl Drop for WebSocketHandle {
+    fn drop(&mut self) {
+        self.context.topics.leave_all(&self.outbox);
+    }
+}
 
 impl std::panic::RefUnwindSafe for WebSocketHandle {}
 
@@ -1 +1,10 @@
-ExUnit.start()
+# Tests tagged with a feature of the native crate (`@tag :simulation`) run only
+# when it is built with that feature, and those tagged `feature: false` only
+# when it is not
+features = :sparx |> Application.get_env(Sparx.Native, []) |> Keyword.get(:features, [])
+excluded =
+  for feature <- [:simulation, :profiling] do
+    {feature, to_string(feature) not in features}
+  end
+
+ExUnit.start(exclude: [:otlp | excluded])